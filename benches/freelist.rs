@@ -0,0 +1,91 @@
+//! Compares the Array and HashMap freelists at 10k, 100k and 1M free pages.
+//!
+//! Free pages are laid out in runs of 1, 16 and 256 pages separated by a
+//! page in use, from fully fragmented to mostly contiguous. Run with
+//! `cargo bench --bench freelist`.
+
+use std::time::Duration;
+
+use boltdb_rs::bench::{run_freelist, FreelistBenchOptions, FreelistResults, OpStats};
+use boltdb_rs::FreelistType;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const FREE_PAGES: [usize; 3] = [10_000, 100_000, 1_000_000];
+const RUN_LENS: [usize; 3] = [1, 16, 256];
+const TYPES: [FreelistType; 2] = [FreelistType::Array, FreelistType::HashMap];
+
+/// Measure runs fresh freelists until `iters` operations of one phase have
+/// been timed and returns their total duration. Every freelist starts from
+/// the same layout, so no operation sees a freelist drained by the others.
+fn measure(
+    opts: &FreelistBenchOptions,
+    iters: u64,
+    phase: impl Fn(&FreelistResults) -> &OpStats,
+    set_iters: impl Fn(&mut FreelistBenchOptions, usize),
+) -> Duration {
+    let mut total = Duration::default();
+    let mut done = 0;
+    while done < iters {
+        let mut opts = opts.clone();
+        set_iters(&mut opts, (iters - done) as usize);
+        let results = run_freelist(&opts);
+        let stats = phase(&results);
+        if stats.ops == 0 {
+            break;
+        }
+        done += stats.ops;
+        total += stats.duration;
+    }
+    total
+}
+
+fn bench_freelist(c: &mut Criterion) {
+    for &free_pages in &FREE_PAGES {
+        for &run_len in &RUN_LENS {
+            let mut group = c.benchmark_group(format!("freelist/{}/run{}", free_pages, run_len));
+            group.sample_size(10);
+            for &freelist_type in &TYPES {
+                let opts = FreelistBenchOptions {
+                    freelist_type,
+                    free_pages,
+                    run_len,
+                    iterations: 0,
+                    writes: 0,
+                    ..FreelistBenchOptions::default()
+                };
+                let name = format!("{:?}", freelist_type);
+
+                for &alloc_pages in &[1, 16] {
+                    // A run shorter than the request leaves nothing to allocate.
+                    if alloc_pages > run_len {
+                        continue;
+                    }
+                    let opts = FreelistBenchOptions {
+                        alloc_pages,
+                        ..opts.clone()
+                    };
+                    group.bench_function(
+                        BenchmarkId::new(format!("allocate{}", alloc_pages), &name),
+                        |b| {
+                            b.iter_custom(|iters| {
+                                measure(&opts, iters, |r| &r.allocate, |o, n| o.iterations = n)
+                            })
+                        },
+                    );
+                }
+                group.bench_function(BenchmarkId::new("free", &name), |b| {
+                    b.iter_custom(|iters| {
+                        measure(&opts, iters, |r| &r.free, |o, n| o.iterations = n)
+                    })
+                });
+                group.bench_function(BenchmarkId::new("write", &name), |b| {
+                    b.iter_custom(|iters| measure(&opts, iters, |r| &r.write, |o, n| o.writes = n))
+                });
+            }
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, bench_freelist);
+criterion_main!(benches);
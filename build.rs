@@ -0,0 +1,40 @@
+//! The build script generates the C header of the `ffi` feature and compiles
+//! the C program its tests run against.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    ffi::build();
+}
+
+#[cfg(feature = "ffi")]
+mod ffi {
+    use std::env;
+    use std::path::PathBuf;
+
+    pub fn build() {
+        let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=tests/ffi/smoke.c");
+
+        let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+            .expect("cbindgen.toml is invalid");
+        cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(config)
+            .generate()
+            .expect("generating blot.h failed")
+            .write_to_file(out_dir.join("blot.h"));
+
+        // The smoke test is only linked into the unit tests, which name it
+        // in a #[link] attribute, so the library itself does not link it.
+        cc::Build::new()
+            .file(crate_dir.join("tests/ffi/smoke.c"))
+            .include(&out_dir)
+            .warnings_into_errors(true)
+            .cargo_metadata(false)
+            .compile("blot_ffi_smoke");
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+    }
+}
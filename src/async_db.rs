@@ -0,0 +1,150 @@
+//! An async wrapper around `DB`, enabled by the `tokio` feature.
+//!
+//! Committing a transaction waits for fsync, which would stall the executor
+//! if called from an async task. `AsyncDb` runs every transaction on tokio's
+//! blocking thread pool instead.
+
+use std::io;
+use std::panic;
+
+use tokio::task::{self, JoinError};
+
+use crate::db::DB;
+use crate::errors::{Error, Result};
+use crate::tx::Tx;
+
+/// AsyncDb runs the transactions of a `DB` on tokio's blocking thread pool.
+///
+/// Clones share the same database.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: DB,
+}
+
+impl AsyncDb {
+    /// New wraps `db`.
+    pub fn new(db: DB) -> AsyncDb {
+        AsyncDb { db }
+    }
+
+    /// DB returns the wrapped database.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// View runs `f` within a managed read-only transaction, see
+    /// `DB::view_ret`.
+    pub async fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.db.clone();
+        join(task::spawn_blocking(move || db.view_ret(f)).await)
+    }
+
+    /// Update runs `f` within a managed read-write transaction, see
+    /// `DB::update_ret`.
+    pub async fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.db.clone();
+        join(task::spawn_blocking(move || db.update_ret(f)).await)
+    }
+
+    /// Batch runs `f` as part of a batch, see `DB::batch`. Concurrent tasks
+    /// calling batch share a single commit.
+    pub async fn batch<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&Tx) -> Result<()> + Send + 'static,
+    {
+        let db = self.db.clone();
+        join(task::spawn_blocking(move || db.batch(f)).await)
+    }
+}
+
+/// join returns the result of a blocking task, resuming its panic if it
+/// panicked.
+fn join<T>(result: std::result::Result<Result<T>, JoinError>) -> Result<T> {
+    match result {
+        Ok(result) => result,
+        Err(err) => match err.try_into_panic() {
+            Ok(payload) => panic::resume_unwind(payload),
+            Err(err) => Err(Error::Io(io::Error::other(err))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::db::Options;
+
+    async fn commits(db: &AsyncDb, batch: bool) -> u64 {
+        let before = db.view(|tx| Ok(tx.id())).await.unwrap();
+        let tasks: Vec<_> = (0..100u32)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let put = move |tx: &Tx| tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"x");
+                    if batch {
+                        db.batch(put).await
+                    } else {
+                        db.update(put).await
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        db.view(|tx| Ok(tx.id())).await.unwrap() - before
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[cfg_attr(miri, ignore)]
+    async fn batch_shares_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            max_batch_delay: Duration::from_millis(50),
+            ..Options::default()
+        };
+        let db = AsyncDb::new(DB::open(dir.path().join("db"), options).unwrap());
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .await
+            .unwrap();
+
+        assert_eq!(commits(&db, false).await, 100);
+        let batched = commits(&db, true).await;
+        assert!(batched < 20, "{} commits", batched);
+
+        let keys = db
+            .view(|tx| Ok(tx.bucket(b"widgets")?.stats()?.key_n))
+            .await
+            .unwrap();
+        assert_eq!(keys, 100);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn errors_and_panics_propagate() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = AsyncDb::new(DB::open(dir.path().join("db"), Options::default()).unwrap());
+        let err = db.view(|tx| tx.bucket(b"missing").map(drop)).await;
+        assert!(matches!(err, Err(Error::BucketNotFound)));
+
+        let db2 = db.clone();
+        let panicked =
+            tokio::spawn(async move { db2.update(|_| -> Result<()> { panic!("boom") }).await })
+                .await
+                .unwrap_err();
+        assert!(panicked.is_panic());
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use std::alloc::{self, Layout};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+use crate::errors::{Error, Result};
+use crate::page::{page_at, Page, Pgid};
+
+/// Backend is how the data file is made readable by transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    /// Mmap maps the file read-only and shared, so pages written to the file
+    /// show through the mapping.
+    Mmap,
+    /// Buffer reads the file into an owned buffer and copies the pages
+    /// written to the file into it. It is used where mmap(2) is unavailable.
+    Buffer,
+}
+
+impl Backend {
+    /// select returns the backend of a database: the buffer backend when it
+    /// is forced or the platform has no mmap(2), the mmap backend otherwise.
+    pub(crate) fn select(force_buffer: bool) -> Backend {
+        if force_buffer || !cfg!(unix) {
+            Backend::Buffer
+        } else {
+            Backend::Mmap
+        }
+    }
+
+    /// map makes the first `len` bytes of `file` readable. Buffers are
+    /// aligned to `page_size`, as mappings are.
+    pub(crate) fn map(self, file: &File, len: usize, flags: i32, page_size: usize) -> Result<Mmap> {
+        match self {
+            #[cfg(unix)]
+            Backend::Mmap => Mmap::map(file, len, flags),
+            #[cfg(not(unix))]
+            Backend::Mmap => unreachable!("mmap backend selected without mmap(2)"),
+            Backend::Buffer => Mmap::read(file, len, page_size),
+        }
+    }
+}
+
+/// Mmap is a read-only view of the database file: either a shared memory
+/// mapping, or a buffer the file was read into by the buffer backend.
+///
+/// The view is released when the value is dropped. Transactions hold an
+/// `Arc<Mmap>` so a remap never invalidates slices a transaction handed out.
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+    /// The layout of the buffer allocation, or None for a mapping.
+    buffer: Option<Layout>,
+}
+
+// Safety: Mmap owns the mapping or buffer, which is not tied to the thread
+// that created it: it can be read from and released on any thread.
+unsafe impl Send for Mmap {}
+
+// Safety: readers never write through the view. The file behind a mapping
+// is written with pwrite(2) by the writer, and the writer copies the same
+// bytes into a buffer with `write`; a byte changing under a reader would be
+// a data race, so the database keeps writes away from what readers see:
+//
+// - B+tree, freelist and overflow pages are only written when they are new
+//   or free, that is neither reachable from the meta page of an open
+//   transaction nor pending for one (see `RawDB::free_pages`).
+// - The meta pages are written while holding the meta lock, which every
+//   transaction holds while it copies the current meta page.
+//
+// Readers borrow only the pages they read, with `get` and `page`, never the
+// whole view, so no reference overlaps a page being written.
+//
+// `Tx::page` is the exception: it reads the header of any page below the
+// high water mark, so it is only safe for concurrent use from the writer.
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// map memory maps `len` bytes of `file` read-only.
+    #[cfg(unix)]
+    pub(crate) fn map(file: &File, len: usize, flags: i32) -> Result<Mmap> {
+        // Map the data file to memory.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED | flags,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len,
+            buffer: None,
+        })
+    }
+
+    /// read reads `file` into a zeroed buffer of `len` bytes aligned to
+    /// `align`. A file longer than the buffer is only read up to `len`.
+    pub(crate) fn read(mut file: &File, len: usize, align: usize) -> Result<Mmap> {
+        let layout = Layout::from_size_align(len.max(1), align).map_err(|_| Error::MmapTooLarge)?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(Error::MmapTooLarge);
+        }
+        let mmap = Mmap {
+            ptr,
+            len,
+            buffer: Some(layout),
+        };
+
+        // Read in page order, so that the pages a meta page refers to are
+        // read after it even if another process commits meanwhile.
+        let filesz = file.metadata()?.len();
+        let n = usize::try_from(filesz).map_or(len, |sz| sz.min(len));
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(unsafe { slice::from_raw_parts_mut(ptr, n) })?;
+        Ok(mmap)
+    }
+
+    /// is_buffer reports whether the view is a buffer of the buffer backend.
+    pub(crate) fn is_buffer(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// write copies `buf`, which was just written to the file at `offset`,
+    /// into a buffer, so that it reads like a shared mapping. Bytes past the
+    /// end of the buffer are left out; they are read from the file when the
+    /// buffer is grown. A mapping already shows the write.
+    ///
+    /// The caller keeps the write away from what readers see, as described
+    /// on the Sync impl.
+    pub(crate) fn write(&self, buf: &[u8], offset: u64) {
+        if self.buffer.is_none() {
+            return;
+        }
+        let offset = match usize::try_from(offset) {
+            Ok(offset) if offset < self.len => offset,
+            _ => return,
+        };
+        let n = buf.len().min(self.len - offset);
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(offset), n) };
+    }
+
+    /// advise passes `advice` about the whole mapping to madvise(2). A
+    /// buffer takes no advice.
+    pub(crate) fn advise(&self, advice: i32) -> io::Result<()> {
+        if self.buffer.is_some() {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if unsafe { libc::madvise(self.ptr as *mut libc::c_void, self.len, advice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(not(unix))]
+        let _ = advice;
+        Ok(())
+    }
+
+    /// buffer_mut returns the buffer of the buffer backend, or None for a
+    /// mapping, which is read-only.
+    pub(crate) fn buffer_mut(&mut self) -> Option<&mut [u8]> {
+        self.buffer?;
+        Some(unsafe { slice::from_raw_parts_mut(self.ptr, self.len) })
+    }
+
+    /// get returns the bytes in `range`, or None if it runs past the end of
+    /// the view. Only those bytes are borrowed: a slice of the whole buffer
+    /// would overlap the pages the writer copies in meanwhile.
+    pub(crate) fn get(&self, range: Range<usize>) -> Option<&[u8]> {
+        if range.start > range.end || range.end > self.len {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(self.ptr.add(range.start), range.len()) })
+    }
+
+    /// page returns the page `id` and its overflow pages, see `page_at`.
+    pub(crate) fn page(&self, page_size: usize, id: Pgid) -> Result<Page<'_>> {
+        page_at(|range| self.get(range), page_size, id)
+    }
+
+    /// as_slice borrows the whole view, which is only safe while no writer
+    /// can copy pages into it.
+    #[cfg(test)]
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        match self.buffer {
+            Some(layout) => unsafe { alloc::dealloc(self.ptr, layout) },
+            #[cfg(unix)]
+            None => unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            },
+            #[cfg(not(unix))]
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn buffer_reads_and_writes() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[1; 100]).unwrap();
+
+        let mmap = Backend::Buffer.map(&file, 256, 0, 64).unwrap();
+        assert!(mmap.is_buffer());
+        assert_eq!(mmap.as_slice().as_ptr() as usize % 64, 0);
+        assert_eq!(&mmap.as_slice()[..100], &[1; 100][..]);
+        assert_eq!(&mmap.as_slice()[100..], &[0; 156][..]);
+
+        // Writes land in the buffer, up to its end.
+        mmap.write(&[2; 8], 96);
+        mmap.write(&[3; 8], 252);
+        mmap.write(&[4; 8], 1 << 40);
+        assert_eq!(&mmap.as_slice()[96..104], &[2; 8][..]);
+        assert_eq!(&mmap.as_slice()[252..], &[3; 4][..]);
+        assert_eq!(mmap.get(252..256), Some(&[3; 4][..]));
+        assert_eq!(mmap.get(252..257), None);
+
+        // A file longer than the buffer is cut short.
+        let short = Backend::Buffer.map(&file, 16, 0, 16).unwrap();
+        assert_eq!(short.as_slice(), &[1; 16][..]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn mapping_ignores_writes() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        let mmap = Backend::Mmap.map(&file, 4096, 0, 4096).unwrap();
+        assert!(!mmap.is_buffer());
+        mmap.write(&[2; 8], 0);
+        assert_eq!(mmap.as_slice(), &[1; 4096][..]);
+    }
+}
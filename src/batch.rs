@@ -0,0 +1,315 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use crate::db::{lock, DB};
+use crate::errors::{Error, Result};
+use crate::tx::Tx;
+
+type BatchFn = dyn Fn(&Tx) -> Result<()> + Send;
+
+/// Batch holds the calls coalesced into one transaction.
+#[derive(Default)]
+pub(crate) struct Batch {
+    calls: Mutex<Vec<Call>>,
+    /// Signaled when the batch is full.
+    full: Condvar,
+}
+
+struct Call {
+    f: Box<BatchFn>,
+    done: Sender<Outcome>,
+}
+
+/// Outcome is what a batch reports back to each of its callers.
+enum Outcome {
+    /// The result of the batch transaction.
+    Done(Result<()>),
+    /// The function failed within the batch and has to be run on its own.
+    TrySolo(Box<BatchFn>),
+}
+
+impl DB {
+    /// Batch calls `f` as part of a batch. It behaves similar to update(),
+    /// except:
+    ///
+    /// 1. concurrent batch calls can be combined into a single transaction.
+    ///
+    /// 2. the function passed to batch may be called multiple times,
+    ///    regardless of whether it returns error or not.
+    ///
+    /// This means that batch function side effects must be idempotent and
+    /// take permanent effect only after a successful return is seen in
+    /// caller.
+    ///
+    /// A function that fails is taken out of the batch and run again on its
+    /// own, so only its caller sees the error. A function that panics fails
+    /// its call with an error instead of unwinding.
+    ///
+    /// The maximum batch size and delay can be adjusted with
+    /// Options::max_batch_size and Options::max_batch_delay, respectively.
+    ///
+    /// Batch is only useful when there are multiple threads calling it.
+    pub fn batch<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&Tx) -> Result<()> + Send + 'static,
+    {
+        let db = &self.0;
+        if db.max_batch_size == 0 || db.max_batch_delay.as_nanos() == 0 {
+            return self.update(f);
+        }
+
+        let (done, outcome) = mpsc::channel();
+        let mut current = lock(&db.batch);
+        let (batch, leader) = match &*current {
+            Some(batch) => (batch.clone(), false),
+            // There is no existing batch; start a new one, run by this call.
+            None => {
+                let batch = Arc::new(Batch::default());
+                *current = Some(batch.clone());
+                (batch, true)
+            }
+        };
+        let mut calls = lock(&batch.calls);
+        calls.push(Call {
+            f: Box::new(f),
+            done,
+        });
+        if calls.len() >= db.max_batch_size {
+            // The batch is full: wake up its leader and stop adding to it.
+            *current = None;
+            batch.full.notify_one();
+        }
+        drop(calls);
+        drop(current);
+
+        if leader {
+            self.run_batch(&batch);
+        }
+
+        match outcome.recv() {
+            Ok(Outcome::Done(result)) => result,
+            Ok(Outcome::TrySolo(f)) => self.update(|tx| call(&*f, tx)),
+            Err(_) => Err(Error::Io(io::Error::other("batch dropped without result"))),
+        }
+    }
+
+    /// run_batch waits until `batch` is full or its delay has passed, then
+    /// performs its calls and communicates the results back to the callers.
+    fn run_batch(&self, batch: &Arc<Batch>) {
+        let db = &self.0;
+        let deadline = Instant::now() + db.max_batch_delay;
+        let mut calls = lock(&batch.calls);
+        while calls.len() < db.max_batch_size {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            calls = batch
+                .full
+                .wait_timeout(calls, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        drop(calls);
+
+        // Make sure no new work is added to this batch, but don't break other
+        // batches.
+        let mut current = lock(&db.batch);
+        if current.as_ref().is_some_and(|b| Arc::ptr_eq(b, batch)) {
+            *current = None;
+        }
+        drop(current);
+        let mut calls = std::mem::take(&mut *lock(&batch.calls));
+
+        while !calls.is_empty() {
+            let mut failed = None;
+            let result = self.update(|tx| {
+                for (i, c) in calls.iter().enumerate() {
+                    if let Err(err) = call(&*c.f, tx) {
+                        failed = Some(i);
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            });
+
+            if let Some(i) = failed {
+                // Take the failing call out of the batch, tell its caller to
+                // re-run it solo and continue with the rest of the batch.
+                let call = calls.swap_remove(i);
+                let _ = call.done.send(Outcome::TrySolo(call.f));
+                continue;
+            }
+
+            // Pass success, or bolt internal errors, to all callers.
+            for call in calls.drain(..) {
+                let result = match &result {
+                    Ok(()) => Ok(()),
+                    Err(err) => Err(err.duplicate()),
+                };
+                let _ = call.done.send(Outcome::Done(result));
+            }
+        }
+    }
+}
+
+/// call runs a batch function, turning a panic into an error for its caller.
+fn call(f: &BatchFn, tx: &Tx) -> Result<()> {
+    panic::catch_unwind(AssertUnwindSafe(|| f(tx)))
+        .unwrap_or_else(|_| Err(Error::Io(io::Error::other("batch function panicked"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::db::Options;
+
+    fn open(options: Options) -> (tempfile::TempDir, DB) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), options).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+        (dir, db)
+    }
+
+    fn txid(db: &DB) -> u64 {
+        db.begin(false).unwrap().id()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn coalesces_concurrent_calls() {
+        let (_dir, db) = open(Options {
+            max_batch_delay: Duration::from_millis(200),
+            max_batch_size: 20,
+            ..Options::default()
+        });
+        let before = txid(&db);
+        let handles: Vec<_> = (0..20u32)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    db.batch(move |tx| tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"x"))
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        // The batch is full before its delay elapses, so every call shares
+        // one commit.
+        assert_eq!(txid(&db), before + 1);
+        db.view(|tx| {
+            assert_eq!(tx.bucket(b"widgets")?.stats()?.key_n, 20);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn failing_call_runs_solo() {
+        let (_dir, db) = open(Options {
+            max_batch_delay: Duration::from_millis(100),
+            ..Options::default()
+        });
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..10u32)
+            .map(|i| {
+                let db = db.clone();
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    db.batch(move |tx| {
+                        if i == 3 {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            return Err(Error::KeyRequired);
+                        }
+                        tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"x")
+                    })
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            let result = handle.join().unwrap();
+            if i == 3 {
+                assert!(matches!(result, Err(Error::KeyRequired)));
+            } else {
+                result.unwrap();
+            }
+        }
+        // Once in the batch, then once on its own.
+        assert!(calls.load(Ordering::SeqCst) >= 2);
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.stats()?.key_n, 9);
+            assert_eq!(b.get(&3u32.to_be_bytes())?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn panicking_call_fails_alone() {
+        let (_dir, db) = open(Options {
+            max_batch_delay: Duration::from_millis(100),
+            max_batch_size: 10,
+            ..Options::default()
+        });
+        let handles: Vec<_> = (0..10u32)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    db.batch(move |tx| {
+                        assert_ne!(i, 7, "bad handler");
+                        tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"x")
+                    })
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            // The panic reaches no thread: its caller gets an error instead.
+            let result = handle.join().unwrap();
+            if i == 7 {
+                let err = result.unwrap_err();
+                assert_eq!(err.to_string(), "io error: batch function panicked");
+            } else {
+                result.unwrap();
+            }
+        }
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.stats()?.key_n, 9);
+            assert_eq!(b.get(&7u32.to_be_bytes())?, None);
+            Ok(())
+        })
+        .unwrap();
+
+        // The database still takes batches.
+        db.batch(|tx| tx.bucket(b"widgets")?.put(b"after", b"x"))
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn disabled_batching_commits_each_call() {
+        let (_dir, db) = open(Options {
+            max_batch_size: 0,
+            ..Options::default()
+        });
+        let before = txid(&db);
+        for i in 0..3u32 {
+            db.batch(move |tx| tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"x"))
+                .unwrap();
+        }
+        assert_eq!(txid(&db), before + 3);
+    }
+}
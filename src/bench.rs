@@ -0,0 +1,598 @@
+//! A benchmark harness modeled on the `bbolt bench` command.
+//!
+//! `run` writes keys into a fresh `bench` bucket and reads them back, timing
+//! every operation. Running it against databases opened with different
+//! options gives a common way to compare freelist types, `no_sync`, madvise
+//! modes or fill percents on the same hardware. `run_freelist` times the
+//! freelist alone, without a database around it.
+//!
+//! For example, the random read latency of each madvise mode:
+//!
+//! ```no_run
+//! use boltdb_rs::bench::{run, BenchOptions, ReadMode, WriteMode};
+//! use boltdb_rs::{MadviseMode, Options, DB};
+//!
+//! let opts = BenchOptions {
+//!     write_mode: WriteMode::Random,
+//!     read_mode: ReadMode::Random,
+//!     iterations: 1_000_000,
+//!     batch_size: 10_000,
+//!     ..BenchOptions::default()
+//! };
+//! for (i, &mode) in [MadviseMode::Normal, MadviseMode::Random, MadviseMode::WillNeed]
+//!     .iter()
+//!     .enumerate()
+//! {
+//!     let options = Options {
+//!         madvise: mode,
+//!         ..Options::default()
+//!     };
+//!     let db = DB::open(format!("bench-{}.db", i), options).unwrap();
+//!     let results = run(&db, &opts).unwrap();
+//!     println!("{:?}: p50 {:?}, p99 {:?}", mode, results.read.p50, results.read.p99);
+//! }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::bucket::{Bucket, DEFAULT_FILL_PERCENT};
+use crate::db::DB;
+use crate::errors::Result;
+use crate::freelist::{Freelist, FreelistType};
+use crate::page::{Page, PageMut, Pgid, PAGE_HEADER_SIZE};
+
+/// The name of the bucket written by the benchmark.
+const BENCH_BUCKET: &[u8] = b"bench";
+
+/// WriteMode selects the order and layout of the keys written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Keys are written in ascending order.
+    Sequential,
+    /// Keys are written in random order.
+    Random,
+    /// Keys are written in ascending order, each batch into its own nested
+    /// bucket.
+    SequentialNested,
+    /// Keys are written in random order, each batch into its own nested
+    /// bucket.
+    RandomNested,
+    /// Keys are written in ascending order, each batch with a single
+    /// `put_sorted`. Every key of a batch is reported with the average
+    /// latency of the batch. Keys must not collide, so `key_size` has to be
+    /// large enough to hold the key counter.
+    SequentialSorted,
+}
+
+impl WriteMode {
+    fn random(self) -> bool {
+        matches!(self, WriteMode::Random | WriteMode::RandomNested)
+    }
+
+    fn nested(self) -> bool {
+        matches!(self, WriteMode::SequentialNested | WriteMode::RandomNested)
+    }
+}
+
+/// ReadMode selects how the written keys are read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Every key is visited in order with a cursor.
+    Sequential,
+    /// Every written key is looked up with `get`, in write order.
+    Random,
+    /// Every written key is looked up with `get_owned`, in write order. With
+    /// `Random` this shows the cost of copying values out of the mapping.
+    RandomOwned,
+}
+
+/// BenchOptions represents the options that can be set when running a
+/// benchmark.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub write_mode: WriteMode,
+    pub read_mode: ReadMode,
+    /// The number of keys written.
+    pub iterations: usize,
+    /// The number of keys written per transaction. Zero writes every key in
+    /// a single transaction.
+    pub batch_size: usize,
+    /// The size of the keys. Keys shorter than 8 bytes only hold the low
+    /// bytes of the key counter, so some of them may collide.
+    pub key_size: usize,
+    pub value_size: usize,
+    pub fill_percent: f64,
+    /// The seed of the random key generator.
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions {
+            write_mode: WriteMode::Sequential,
+            read_mode: ReadMode::Sequential,
+            iterations: 1000,
+            batch_size: 0,
+            key_size: 8,
+            value_size: 32,
+            fill_percent: DEFAULT_FILL_PERCENT,
+            seed: 1,
+        }
+    }
+}
+
+/// BenchResults holds the statistics of the write and read phases of a
+/// benchmark.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchResults {
+    pub write: OpStats,
+    pub read: OpStats,
+}
+
+/// OpStats holds the statistics of one phase of a benchmark.
+///
+/// `duration` covers the whole phase, commits included, while the latency
+/// percentiles only cover the individual operations.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpStats {
+    /// The number of completed operations.
+    pub ops: u64,
+    pub duration: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl OpStats {
+    /// OpsPerSecond returns the number of operations completed per second.
+    pub fn ops_per_sec(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.ops as f64 / secs
+    }
+
+    /// OpDuration returns the average duration of a single operation.
+    pub fn op_duration(&self) -> Duration {
+        if self.ops == 0 {
+            return Duration::default();
+        }
+        Duration::from_secs_f64(self.duration.as_secs_f64() / self.ops as f64)
+    }
+
+    fn new(mut latencies: Vec<Duration>, duration: Duration) -> OpStats {
+        latencies.sort_unstable();
+        let percentile = |p: usize| match latencies.len() {
+            0 => Duration::default(),
+            n => latencies[(n * p / 100).min(n - 1)],
+        };
+        OpStats {
+            ops: latencies.len() as u64,
+            duration,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Run writes `opts.iterations` keys into a new `bench` bucket, reads them
+/// back and returns the statistics of both phases.
+///
+/// `Error::BucketExists` is returned when the database already holds a
+/// `bench` bucket.
+pub fn run(db: &DB, opts: &BenchOptions) -> Result<BenchResults> {
+    db.update(|tx| tx.create_bucket(BENCH_BUCKET).map(drop))?;
+
+    Ok(BenchResults {
+        write: write(db, opts)?,
+        read: read(db, opts)?,
+    })
+}
+
+/// Keys generates the keys written by a benchmark, in write order.
+struct Keys {
+    random: bool,
+    key_size: usize,
+    state: u64,
+    i: u64,
+}
+
+impl Keys {
+    fn new(opts: &BenchOptions) -> Keys {
+        Keys {
+            random: opts.write_mode.random(),
+            key_size: opts.key_size,
+            state: opts.seed.max(1),
+            i: 0,
+        }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let n = if self.random {
+            // xorshift64
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state
+        } else {
+            self.i
+        };
+        self.i += 1;
+        let mut key = vec![0u8; self.key_size];
+        let n = n.to_be_bytes();
+        let len = self.key_size.min(n.len());
+        key[self.key_size - len..].copy_from_slice(&n[n.len() - len..]);
+        Some(key)
+    }
+}
+
+/// batch_bucket returns the bucket that the keys of batch `i` are written to.
+fn batch_bucket<'tx>(top: Bucket<'tx>, opts: &BenchOptions, i: usize) -> Result<Bucket<'tx>> {
+    if opts.write_mode.nested() {
+        top.create_bucket_if_not_exists(&(i as u64).to_be_bytes())
+    } else {
+        Ok(top)
+    }
+}
+
+fn batch_size(opts: &BenchOptions) -> usize {
+    match opts.batch_size {
+        0 => opts.iterations.max(1),
+        n => n,
+    }
+}
+
+fn write(db: &DB, opts: &BenchOptions) -> Result<OpStats> {
+    let value = vec![0u8; opts.value_size];
+    let mut keys = Keys::new(opts);
+    let mut latencies = Vec::with_capacity(opts.iterations);
+    let start = Instant::now();
+    let mut remaining = opts.iterations;
+    let mut batch = 0;
+    while remaining > 0 {
+        let n = remaining.min(batch_size(opts));
+        db.update(|tx| {
+            let b = batch_bucket(tx.bucket(BENCH_BUCKET)?, opts, batch)?;
+            b.set_fill_percent(opts.fill_percent);
+            if opts.write_mode == WriteMode::SequentialSorted {
+                let t = Instant::now();
+                let pairs = keys.by_ref().take(n).map(|key| (key, value.clone()));
+                let put = b.put_sorted(pairs)?;
+                let latency = t.elapsed() / put.max(1) as u32;
+                latencies.extend(std::iter::repeat_n(latency, put as usize));
+                return Ok(());
+            }
+            for key in keys.by_ref().take(n) {
+                let t = Instant::now();
+                b.put(&key, &value)?;
+                latencies.push(t.elapsed());
+            }
+            Ok(())
+        })?;
+        remaining -= n;
+        batch += 1;
+    }
+    Ok(OpStats::new(latencies, start.elapsed()))
+}
+
+fn read(db: &DB, opts: &BenchOptions) -> Result<OpStats> {
+    let mut latencies = Vec::with_capacity(opts.iterations);
+    let start = Instant::now();
+    db.view(|tx| {
+        let top = tx.bucket(BENCH_BUCKET)?;
+        match opts.read_mode {
+            ReadMode::Sequential => {
+                let mut buckets = vec![top];
+                if opts.write_mode.nested() {
+                    buckets.clear();
+                    top.for_each_bucket(|name| {
+                        buckets.push(top.bucket(name)?);
+                        Ok(())
+                    })?;
+                }
+                for b in buckets {
+                    let mut c = b.cursor();
+                    let mut t = Instant::now();
+                    let mut item = c.first()?;
+                    while item.0.is_some() {
+                        latencies.push(t.elapsed());
+                        t = Instant::now();
+                        item = c.next()?;
+                    }
+                }
+            }
+            ReadMode::Random | ReadMode::RandomOwned => {
+                let keys = Keys::new(opts).take(opts.iterations);
+                let size = batch_size(opts);
+                for (i, key) in keys.enumerate() {
+                    let b = if opts.write_mode.nested() {
+                        top.bucket(&((i / size) as u64).to_be_bytes())?
+                    } else {
+                        top
+                    };
+                    let t = Instant::now();
+                    if opts.read_mode == ReadMode::Random {
+                        b.get(&key)?;
+                    } else {
+                        b.get_owned(&key)?;
+                    }
+                    latencies.push(t.elapsed());
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(OpStats::new(latencies, start.elapsed()))
+}
+
+/// FreelistBenchOptions represents the options of a freelist benchmark.
+#[derive(Debug, Clone)]
+pub struct FreelistBenchOptions {
+    pub freelist_type: FreelistType,
+    /// The number of free pages the freelist starts with.
+    pub free_pages: usize,
+    /// The number of contiguous free pages between two pages in use. One
+    /// leaves no two free pages next to each other.
+    pub run_len: usize,
+    /// The number of contiguous pages requested by each allocation.
+    pub alloc_pages: usize,
+    /// The number of allocations timed. Every allocated block is then freed.
+    pub iterations: usize,
+    /// The number of times the freelist is serialized onto a page.
+    pub writes: usize,
+}
+
+impl Default for FreelistBenchOptions {
+    fn default() -> FreelistBenchOptions {
+        FreelistBenchOptions {
+            freelist_type: FreelistType::Array,
+            free_pages: 10_000,
+            run_len: 16,
+            alloc_pages: 1,
+            iterations: 1000,
+            writes: 10,
+        }
+    }
+}
+
+/// FreelistResults holds the statistics of a freelist benchmark.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FreelistResults {
+    /// Allocation stops at the first request that finds no free block, which
+    /// is not counted.
+    pub allocate: OpStats,
+    pub free: OpStats,
+    pub write: OpStats,
+}
+
+/// RunFreelist builds a freelist with `opts.free_pages` free pages, laid out
+/// in runs of `opts.run_len`, then times allocating blocks from it, freeing
+/// them again and writing the freelist out. No database is involved, which
+/// isolates the freelist types from the rest of a commit.
+pub fn run_freelist(opts: &FreelistBenchOptions) -> FreelistResults {
+    let run_len = opts.run_len.max(1) as Pgid;
+    let ids = (0..opts.free_pages as Pgid)
+        .map(|i| 2 + i + i / run_len)
+        .collect();
+    let mut freelist = Freelist::new(opts.freelist_type);
+    freelist.read_ids(ids);
+
+    let mut allocated = Vec::with_capacity(opts.iterations);
+    let mut latencies = Vec::with_capacity(opts.iterations);
+    let start = Instant::now();
+    let mut end = start;
+    for _ in 0..opts.iterations {
+        let t = Instant::now();
+        let id = freelist.allocate(1, opts.alloc_pages);
+        if id == 0 {
+            break;
+        }
+        end = Instant::now();
+        latencies.push(end - t);
+        allocated.push(id);
+    }
+    let allocate = OpStats::new(latencies, end - start);
+
+    let mut buf = vec![0u8; PAGE_HEADER_SIZE];
+    let mut latencies = Vec::with_capacity(allocated.len());
+    let start = Instant::now();
+    for id in allocated {
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(id);
+        p.set_overflow(opts.alloc_pages.saturating_sub(1) as u32);
+        let t = Instant::now();
+        freelist
+            .free(2, Page::new(&buf))
+            .expect("allocated pages are not free");
+        latencies.push(t.elapsed());
+    }
+    let free = OpStats::new(latencies, start.elapsed());
+
+    let mut buf = vec![0u8; freelist.size()];
+    let mut latencies = Vec::with_capacity(opts.writes);
+    let start = Instant::now();
+    for _ in 0..opts.writes {
+        let t = Instant::now();
+        freelist.write(&mut PageMut::new(&mut buf));
+        latencies.push(t.elapsed());
+    }
+    let write = OpStats::new(latencies, start.elapsed());
+
+    FreelistResults {
+        allocate,
+        free,
+        write,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Options;
+    use crate::errors::Error;
+    use crate::unix::MadviseMode;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn run() {
+        for write_mode in [
+            WriteMode::Sequential,
+            WriteMode::Random,
+            WriteMode::SequentialNested,
+            WriteMode::RandomNested,
+            WriteMode::SequentialSorted,
+        ] {
+            for read_mode in [
+                ReadMode::Sequential,
+                ReadMode::Random,
+                ReadMode::RandomOwned,
+            ] {
+                let dir = tempfile::tempdir().unwrap();
+                let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+                let opts = BenchOptions {
+                    write_mode,
+                    read_mode,
+                    iterations: 250,
+                    batch_size: 100,
+                    key_size: 16,
+                    value_size: 100,
+                    ..BenchOptions::default()
+                };
+                let results = super::run(&db, &opts).unwrap();
+
+                assert_eq!(results.write.ops, 250);
+                assert_eq!(results.read.ops, 250);
+                for stats in [&results.write, &results.read] {
+                    assert!(stats.p50 <= stats.p90);
+                    assert!(stats.p90 <= stats.p99);
+                    assert!(stats.p99 <= stats.max);
+                    assert!(stats.max <= stats.duration);
+                    assert!(stats.ops_per_sec() > 0.0);
+                }
+
+                // Three batches were written: 100, 100 and 50 keys.
+                db.view(|tx| {
+                    let b = tx.bucket(BENCH_BUCKET)?;
+                    let mut n = 0;
+                    if write_mode.nested() {
+                        b.for_each_bucket(|name| {
+                            n += b.bucket(name)?.stats()?.key_n;
+                            Ok(())
+                        })?;
+                        assert_eq!(b.stats()?.bucket_n, 4);
+                    } else {
+                        n = b.stats()?.key_n;
+                    }
+                    assert_eq!(n, 250);
+                    Ok(())
+                })
+                .unwrap();
+
+                assert!(matches!(super::run(&db, &opts), Err(Error::BucketExists)));
+            }
+        }
+    }
+
+    #[test]
+    fn freelist() {
+        for &freelist_type in &[FreelistType::Array, FreelistType::HashMap] {
+            let opts = FreelistBenchOptions {
+                freelist_type,
+                free_pages: 64,
+                run_len: 4,
+                alloc_pages: 4,
+                iterations: 20,
+                writes: 3,
+            };
+            let results = run_freelist(&opts);
+            // 64 free pages in runs of 4 hold 16 blocks of 4 pages.
+            assert_eq!(results.allocate.ops, 16);
+            assert_eq!(results.free.ops, 16);
+            assert_eq!(results.write.ops, 3);
+
+            let opts = FreelistBenchOptions { run_len: 1, ..opts };
+            let results = run_freelist(&opts);
+            assert_eq!(results.allocate.ops, 0);
+            assert_eq!(results.free.ops, 0);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn zero_copy_reads() {
+        let p50 = |read_mode| {
+            let dir = tempfile::tempdir().unwrap();
+            let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+            let opts = BenchOptions {
+                read_mode,
+                iterations: 200,
+                value_size: 64 << 10,
+                ..BenchOptions::default()
+            };
+            super::run(&db, &opts).unwrap().read.p50
+        };
+        // Borrowing a 64 KiB value from the mapping skips its copy.
+        assert!(p50(ReadMode::Random) < p50(ReadMode::RandomOwned));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sorted_load() {
+        let write = |write_mode| {
+            let dir = tempfile::tempdir().unwrap();
+            let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+            let opts = BenchOptions {
+                write_mode,
+                iterations: 100_000,
+                value_size: 16,
+                ..BenchOptions::default()
+            };
+            let results = super::run(&db, &opts).unwrap();
+            db.view(|tx| {
+                assert!(tx.check().is_empty());
+                assert_eq!(tx.bucket(BENCH_BUCKET)?.stats()?.key_n, 100_000);
+                Ok(())
+            })
+            .unwrap();
+            results.write.duration
+        };
+        // Appending to the rightmost leaf skips a tree descent and a copy of
+        // every key and value.
+        assert!(write(WriteMode::SequentialSorted) < write(WriteMode::Sequential));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn madvise_modes() {
+        for &mode in &[
+            MadviseMode::Normal,
+            MadviseMode::Random,
+            MadviseMode::WillNeed,
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let options = Options {
+                madvise: mode,
+                huge_pages: true,
+                ..Options::default()
+            };
+            let db = DB::open(dir.path().join("db"), options).unwrap();
+            let opts = BenchOptions {
+                write_mode: WriteMode::Random,
+                read_mode: ReadMode::Random,
+                iterations: 2000,
+                batch_size: 500,
+                ..BenchOptions::default()
+            };
+            let results = super::run(&db, &opts).unwrap();
+            assert_eq!(results.read.ops, 2000);
+        }
+    }
+}
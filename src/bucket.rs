@@ -1,36 +1,3565 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
 
-const (
-	// MaxKeySize is the maximum length of a key, in bytes.
-	MaxKeySize = 32768
-
-	// MaxValueSize is the maximum length of a value, in bytes.
-	MaxValueSize = (1 << 31) - 2
-)
-
-const bucketHeaderSize = int(unsafe.Sizeof(bucket{}))
-
-const (
-	minFillPercent = 0.1
-	maxFillPercent = 1.0
-)
-
-// DefaultFillPercent is the percentage that split pages are filled.
-// This value can be changed by setting Bucket.FillPercent.
-const DefaultFillPercent = 0.5
-
-// Bucket represents a collection of key/value pairs inside the database.
-type struct Bucket  {
-	*bucket
-	tx       *Tx                // the associated transaction
-	buckets  map[string]*Bucket // subbucket cache
-	page     *page              // inline page reference
-	rootNode *node              // materialized node for the root page.
-	nodes    map[pgid]*node     // node cache
-
-	// Sets the threshold for filling nodes when they split. By default,
-	// the bucket will fill to 50% but it can be useful to increase this
-	// amount if you know that your write workloads are mostly append-only.
-	//
-	// This is non-persisted across transactions so it must be set in every Tx.
-	FillPercent float64
-}
\ No newline at end of file
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::iter::Rev;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::sync::Arc;
+use std::thread;
+
+use crate::cursor::{Cursor, Item, Iter};
+use crate::errors::{Error, Result};
+use crate::keys;
+use crate::node::{Bytes, Inode, Node, NodeId};
+use crate::page::{
+    read_u64, write_u64, Page, PageMut, Pgid, BRANCH_PAGE_ELEMENT_SIZE, BRANCH_PAGE_FLAG,
+    BUCKET_LEAF_FLAG, LEAF_PAGE_ELEMENT_SIZE, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+};
+use crate::tx::{Tx, TxState};
+use crate::value_codec::ValueCodec;
+
+/// MAX_KEY_SIZE is the maximum length of a key, in bytes.
+pub const MAX_KEY_SIZE: usize = 32768;
+
+/// MAX_VALUE_SIZE is the maximum length of a value, in bytes.
+pub const MAX_VALUE_SIZE: usize = (1 << 31) - 2;
+
+/// BUCKET_HEADER_SIZE is the on-disk size of a bucket header.
+pub(crate) const BUCKET_HEADER_SIZE: usize = 16;
+
+/// InBucket represents the on-file representation of a bucket.
+/// This is stored as the "value" of a bucket key. If the bucket is small enough,
+/// then its root page can be stored inline in the "value", after the bucket
+/// header. In the case of inline buckets, the "root" will be 0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InBucket {
+    /// page id of the bucket's root-level page
+    pub(crate) root: Pgid,
+    /// monotonically incrementing, used by next_sequence()
+    pub(crate) sequence: u64,
+}
+
+impl InBucket {
+    pub(crate) fn read(buf: &[u8]) -> InBucket {
+        InBucket {
+            root: read_u64(buf, 0),
+            sequence: read_u64(buf, 8),
+        }
+    }
+
+    pub(crate) fn write(&self, buf: &mut [u8]) {
+        write_u64(buf, 0, self.root);
+        write_u64(buf, 8, self.sequence);
+    }
+}
+
+/// BucketId indexes a bucket in the transaction's bucket arena.
+pub(crate) type BucketId = usize;
+
+/// ROOT_BUCKET is the id of a transaction's root bucket, whose keys name the
+/// top-level buckets.
+pub(crate) const ROOT_BUCKET: BucketId = 0;
+
+/// PageNode is a page of a bucket, or the node materialized from it.
+#[derive(Clone, Copy)]
+pub(crate) enum PageNode<'tx> {
+    Page(Page<'tx>),
+    Node(NodeId),
+}
+
+pub(crate) const MIN_FILL_PERCENT: f64 = 0.1;
+pub(crate) const MAX_FILL_PERCENT: f64 = 1.0;
+
+/// DEFAULT_FILL_PERCENT is the percentage that split pages are filled.
+/// This value can be changed with `Bucket::set_fill_percent`.
+pub const DEFAULT_FILL_PERCENT: f64 = 0.5;
+
+/// BucketState is the state a transaction keeps for a bucket it has opened.
+pub(crate) struct BucketState {
+    pub(crate) header: InBucket,
+    /// subbucket cache
+    pub(crate) buckets: HashMap<Vec<u8>, BucketId>,
+    /// inline page reference
+    pub(crate) page: Option<Bytes>,
+    /// materialized node for the root page.
+    pub(crate) root_node: Option<NodeId>,
+    /// node cache
+    pub(crate) nodes: HashMap<Pgid, NodeId>,
+    /// Sets the threshold for filling nodes when they split. By default,
+    /// the bucket will fill to 50% but it can be useful to increase this
+    /// amount if you know that your write workloads are mostly append-only.
+    pub(crate) fill_percent: f64,
+    /// The codec values are encoded with, None to store them as they are.
+    pub(crate) codec: Option<Arc<dyn ValueCodec>>,
+    /// The parent bucket and the name of this bucket in it, None for the
+    /// root bucket.
+    pub(crate) parent: Option<(BucketId, Vec<u8>)>,
+}
+
+impl BucketState {
+    pub(crate) fn new(header: InBucket) -> BucketState {
+        BucketState {
+            header,
+            buckets: HashMap::new(),
+            page: None,
+            root_node: None,
+            nodes: HashMap::new(),
+            fill_percent: DEFAULT_FILL_PERCENT,
+            codec: None,
+            parent: None,
+        }
+    }
+}
+
+/// Bucket represents a collection of key/value pairs inside the database.
+///
+/// A Bucket is a handle borrowed from the transaction that opened it, so it
+/// cannot outlive that transaction.
+#[derive(Clone, Copy)]
+pub struct Bucket<'tx> {
+    tx: &'tx Tx,
+    id: BucketId,
+}
+
+impl<'tx> Bucket<'tx> {
+    pub(crate) fn new(tx: &'tx Tx, id: BucketId) -> Bucket<'tx> {
+        Bucket { tx, id }
+    }
+
+    /// Tx returns the tx of the bucket.
+    pub fn tx(&self) -> &'tx Tx {
+        self.tx
+    }
+
+    /// Root returns the root of the bucket.
+    pub fn root(&self) -> u64 {
+        self.tx.state.borrow().buckets[self.id].header.root
+    }
+
+    /// Writable returns whether the bucket is writable.
+    pub fn writable(&self) -> bool {
+        self.tx.writable
+    }
+
+    /// FillPercent returns the threshold for filling nodes when they split.
+    pub fn fill_percent(&self) -> f64 {
+        self.tx.state.borrow().buckets[self.id].fill_percent
+    }
+
+    /// SetFillPercent sets the threshold for filling nodes when they split. By
+    /// default, the bucket will fill to 50% but it can be useful to increase this
+    /// amount if you know that your write workloads are mostly append-only.
+    ///
+    /// The value is clamped to [0.1, 1.0] when splitting. It applies to the
+    /// nodes split when this transaction commits and is not persisted; nested
+    /// buckets start out with the default.
+    pub fn set_fill_percent(&self, fill_percent: f64) {
+        self.tx.state.borrow_mut().buckets[self.id].fill_percent = fill_percent;
+    }
+
+    /// ValueCodec returns the codec installed on the bucket, if any.
+    pub fn value_codec(&self) -> Option<Arc<dyn ValueCodec>> {
+        self.tx.state.borrow().buckets[self.id].codec.clone()
+    }
+
+    /// SetValueCodec installs a codec that values are encoded with when they
+    /// are put and decoded with when they are read through get, for_each,
+    /// cursors and iterators. Keys are never encoded.
+    ///
+    /// Like the fill percent, the codec applies to this transaction and is
+    /// not persisted; nested buckets start out without one. A bucket read
+    /// without its codec installed returns its values as stored, so get
+    /// returns the encoded bytes. Decoded values are kept until the
+    /// transaction closes, so every read of an encoded value costs memory.
+    pub fn set_value_codec(&self, codec: Arc<dyn ValueCodec>) {
+        self.tx.state.borrow_mut().buckets[self.id].codec = Some(codec);
+    }
+
+    /// Cursor creates a cursor associated with the bucket.
+    /// The cursor is only valid as long as the transaction is open.
+    pub fn cursor(&self) -> Cursor<'tx> {
+        Cursor::new(self.tx, self.id)
+    }
+
+    /// Range returns an iterator over the key/value pairs whose keys fall
+    /// within `range`, in key order. Nested buckets are skipped.
+    pub fn range<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Iter<'tx> {
+        Iter::new(*self, owned(range.start_bound()), owned(range.end_bound()))
+    }
+
+    /// IterRev returns an iterator over all the key/value pairs of the
+    /// bucket, from the last key to the first. Nested buckets are skipped.
+    pub fn iter_rev(&self) -> Rev<Iter<'tx>> {
+        self.range(..).rev()
+    }
+
+    /// Prefix returns an iterator over the key/value pairs whose keys start
+    /// with `prefix`, in key order. Nested buckets are skipped.
+    pub fn prefix(&self, prefix: &[u8]) -> Iter<'tx> {
+        Iter::new(*self, Bound::Included(prefix.to_vec()), prefix_end(prefix))
+    }
+
+    /// DeleteRange removes every key within `range` from the bucket and
+    /// returns the number of keys removed.
+    /// Returns an error if the bucket was created from a read-only
+    /// transaction, or `Error::IncompatibleValue` if the range holds a
+    /// nested bucket, in which case nothing is removed.
+    pub fn delete_range<'a, R: RangeBounds<&'a [u8]>>(&self, range: R) -> Result<u64> {
+        self.delete_bounded(owned(range.start_bound()), owned(range.end_bound()))
+    }
+
+    /// DeletePrefix removes every key starting with `prefix` from the bucket
+    /// and returns the number of keys removed, like delete_range().
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        self.delete_bounded(Bound::Included(prefix.to_vec()), prefix_end(prefix))
+    }
+
+    /// delete_bounded removes the keys between `start` and `end`. The range
+    /// is walked twice, first to reject nested buckets and then to delete
+    /// the keys one at a time, so that no keys are buffered.
+    fn delete_bounded(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Result<u64> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+
+        let before_end = |key: &[u8]| match &end {
+            Bound::Included(end) => key <= &end[..],
+            Bound::Excluded(end) => key < &end[..],
+            Bound::Unbounded => true,
+        };
+        // seek moves a cursor to the first key after the start bound.
+        let seek = |c: &mut Cursor<'tx>, state: &mut TxState| -> Result<Item<'tx>> {
+            let (key, excluded) = match &start {
+                Bound::Included(key) => (key, false),
+                Bound::Excluded(key) => (key, true),
+                Bound::Unbounded => return c.first_in(state),
+            };
+            let item = match c.seek_in(state, key)? {
+                None => c.next_in(state)?,
+                item => item,
+            };
+            match item {
+                Some((k, _, _)) if excluded && k == &key[..] => c.next_in(state),
+                item => Ok(item),
+            }
+        };
+
+        let mut state = self.tx.state.borrow_mut();
+        let mut c = Cursor::new(self.tx, self.id);
+        let mut item = seek(&mut c, &mut state)?;
+        while let Some((k, _, flags)) = item {
+            if !before_end(k) {
+                break;
+            } else if flags & BUCKET_LEAF_FLAG != 0 {
+                return Err(Error::IncompatibleValue);
+            }
+            item = c.next_in(&mut state)?;
+        }
+
+        let mut c = Cursor::new(self.tx, self.id);
+        let mut item = seek(&mut c, &mut state)?;
+        let mut deleted = 0;
+        while let Some((k, _, _)) = item {
+            if !before_end(k) {
+                break;
+            }
+            let n = c.node_in(&mut state)?;
+            state.del(n, k);
+            state.writes += 1;
+            deleted += 1;
+
+            // Removing the key shifted its successors down by one, so
+            // reposition on the first key after it.
+            item = match c.seek_in(&mut state, k)? {
+                None => c.next_in(&mut state)?,
+                item => item,
+            };
+        }
+        Ok(deleted)
+    }
+
+    /// Path returns the names of the buckets leading from the root of the
+    /// transaction to this bucket, this bucket's own name last.
+    pub fn path(&self) -> Vec<Vec<u8>> {
+        let state = self.tx.state.borrow();
+        let mut path = Vec::new();
+        let mut id = self.id;
+        while let Some((parent, name)) = &state.buckets[id].parent {
+            path.push(name.clone());
+            id = *parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Bucket retrieves a nested bucket by name.
+    /// Returns `Error::BucketNotFound` if the bucket does not exist.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn bucket(&self, name: &[u8]) -> Result<Bucket<'tx>> {
+        let mut state = self.tx.state.borrow_mut();
+        match state.open_bucket(self.tx, self.id, name)? {
+            Some(id) => Ok(Bucket::new(self.tx, id)),
+            None => Err(Error::BucketNotFound),
+        }
+    }
+
+    /// CreateBucket creates a new bucket at the given key and returns the new bucket.
+    /// Returns an error if the key already exists, if the bucket name is blank, or if the bucket name is too long.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn create_bucket(&self, key: &[u8]) -> Result<Bucket<'tx>> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        } else if key.is_empty() {
+            return Err(Error::BucketNameRequired);
+        }
+        let mut state = self.tx.state.borrow_mut();
+        let id = state.create_bucket(self.tx, self.id, key)?;
+        Ok(Bucket::new(self.tx, id))
+    }
+
+    /// CreateBucketIfNotExists creates a new bucket if it doesn't already exist and returns a reference to it.
+    /// Returns an error if the bucket name is blank, or if the bucket name is too long.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn create_bucket_if_not_exists(&self, key: &[u8]) -> Result<Bucket<'tx>> {
+        match self.create_bucket(key) {
+            Err(Error::BucketExists) => self.bucket(key),
+            res => res,
+        }
+    }
+
+    /// DeleteBucket deletes a bucket at the given key.
+    /// Returns an error if the bucket does not exist, or if the key represents a non-bucket value.
+    /// Every bucket nested in it is deleted as well.
+    pub fn delete_bucket(&self, key: &[u8]) -> Result<()> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+        let mut state = self.tx.state.borrow_mut();
+        state.delete_bucket(self.tx, self.id, key)
+    }
+
+    /// MoveBucket moves the nested bucket `key` of this bucket into `dst`,
+    /// along with everything nested in it. Only the bucket header is moved,
+    /// or the whole value for an inline bucket, not the keys of the bucket.
+    /// Returns an error if the bucket does not exist, if `key` is not a
+    /// bucket, if `dst` already has a key by that name, if `dst` is this
+    /// bucket (`Error::SameBuckets`) or if `dst` is the moved bucket or one
+    /// of its descendants (`Error::MoveIntoDescendant`).
+    pub fn move_bucket(&self, key: &[u8], dst: &Bucket<'tx>) -> Result<()> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+        let mut state = self.tx.state.borrow_mut();
+        state.move_bucket(self.tx, self.id, dst.id, key)
+    }
+
+    /// Get retrieves the value for a key in the bucket.
+    /// Returns None if the key does not exist or if the key is a nested bucket,
+    /// and `Error::Corrupted` if the pages on the path to the key are.
+    /// The returned value is borrowed from the transaction, without copying.
+    /// It can't outlive the transaction, which can't be committed or rolled
+    /// back while the value is borrowed; use `get_owned` to keep it longer:
+    ///
+    /// ```compile_fail
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+    /// let mut tx = db.begin(true).unwrap();
+    /// let value = tx.create_bucket(b"widgets").unwrap().get(b"foo")?;
+    /// tx.rollback().unwrap();
+    /// assert_eq!(value, None);
+    /// ```
+    pub fn get(&self, key: &[u8]) -> Result<Option<&'tx [u8]>> {
+        let mut state = self.tx.state.borrow_mut();
+        let item = Cursor::new(self.tx, self.id).seek_in(&mut state, key)?;
+        match item {
+            // Return None if this is a bucket or if our target node isn't the
+            // same key as what's passed in.
+            Some((k, v, flags)) if k == key && flags & BUCKET_LEAF_FLAG == 0 => {
+                Ok(Some(state.decode_value(self.id, v)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// GetOwned retrieves a copy of the value for a key in the bucket, which
+    /// can outlive the transaction, e.g. to be sent to another thread.
+    /// Returns None if the key does not exist or if the key is a nested bucket.
+    pub fn get_owned(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(<[u8]>::to_vec))
+    }
+
+    /// Put sets the value for a key in the bucket.
+    /// If the key exist then its previous value will be overwritten.
+    /// Returns an error if the bucket was created from a read-only transaction,
+    /// if the key is blank, if the key is too large, or if the value is too large.
+    /// Both slices are copied, so the caller may reuse them afterwards.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        } else if key.is_empty() {
+            return Err(Error::KeyRequired);
+        } else if key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge);
+        } else if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge);
+        }
+
+        // Move cursor to correct position.
+        let mut state = self.tx.state.borrow_mut();
+        let mut c = Cursor::new(self.tx, self.id);
+
+        // Return an error if there is an existing key with a bucket value.
+        if let Some((k, _, flags)) = c.seek_in(&mut state, key)? {
+            if k == key && flags & BUCKET_LEAF_FLAG != 0 {
+                return Err(Error::IncompatibleValue);
+            }
+        }
+
+        // Insert into node.
+        let value = state.encode_value(self.id, Cow::Borrowed(value))?;
+        let key = state.alloc(key);
+        let n = c.node_in(&mut state)?;
+        state.put(n, key.get(), key, value, 0, 0)?;
+        state.writes += 1;
+        Ok(())
+    }
+
+    /// PutReserve reserves a zero-filled value of `len` bytes for a key, to be
+    /// written in place instead of being built in a buffer and copied by put.
+    ///
+    /// The returned slot dereferences to the value and owns it until it is
+    /// finished or dropped, which stores it under the key. An error storing a
+    /// dropped slot fails the commit of the transaction instead. Other operations on
+    /// the bucket, puts of the same key included, can run while the slot is
+    /// alive: they never see the reserved value, which overwrites the key once
+    /// stored. The slot borrows the transaction, so it has to be stored before
+    /// the transaction is committed or rolled back.
+    ///
+    /// Returns the same errors as put for the key and the length.
+    pub fn put_reserve(&self, key: &[u8], len: usize) -> Result<ReservedValue<'tx>> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        } else if key.is_empty() {
+            return Err(Error::KeyRequired);
+        } else if key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge);
+        } else if len > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge);
+        }
+
+        // Return an error if there is an existing key with a bucket value.
+        let mut state = self.tx.state.borrow_mut();
+        if let Some((k, _, flags)) = Cursor::new(self.tx, self.id).seek_in(&mut state, key)? {
+            if k == key && flags & BUCKET_LEAF_FLAG != 0 {
+                return Err(Error::IncompatibleValue);
+            }
+        }
+        Ok(ReservedValue {
+            bucket: *self,
+            key: state.alloc(key),
+            value: vec![0; len].into_boxed_slice(),
+            stored: false,
+        })
+    }
+
+    /// store_reserved inserts a value reserved by put_reserve, without
+    /// copying it.
+    fn store_reserved(&self, key: Bytes, value: Box<[u8]>) -> Result<()> {
+        let mut state = self.tx.state.borrow_mut();
+        let mut c = Cursor::new(self.tx, self.id);
+        if let Some((k, _, flags)) = c.seek_in(&mut state, key.get())? {
+            if k == key.get() && flags & BUCKET_LEAF_FLAG != 0 {
+                return Err(Error::IncompatibleValue);
+            }
+        }
+        let value = state.encode_value(self.id, Cow::Owned(value.into_vec()))?;
+        let n = c.node_in(&mut state)?;
+        state.put(n, key.get(), key, value, 0, 0)?;
+        state.writes += 1;
+        Ok(())
+    }
+
+    /// PutSorted loads key/value pairs given in strictly ascending key order,
+    /// and returns how many were put.
+    ///
+    /// Keys up to the last key of the bucket are put as by put. The keys past
+    /// it are appended to the rightmost leaf without descending the tree for
+    /// each of them, and are moved into the transaction rather than copied;
+    /// the leaf is split at the fill percent of the bucket when the
+    /// transaction commits.
+    ///
+    /// Returns KeyOutOfOrder, naming the key, if a key is not greater than the
+    /// one before it, and the same errors as put otherwise. The pairs before
+    /// the failing one are kept.
+    pub fn put_sorted(&self, iter: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<u64> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+        let mut state = self.tx.state.borrow_mut();
+        let mut c = Cursor::new(self.tx, self.id);
+        let last = c.last_in(&mut state)?.map(|(k, _, _)| k.to_vec());
+
+        // The leaf the keys past the last key of the bucket are appended to.
+        let mut tail = None;
+        let mut prev: Option<Bytes> = None;
+        let mut count = 0;
+        for (key, value) in iter {
+            if key.is_empty() {
+                return Err(Error::KeyRequired);
+            } else if key.len() > MAX_KEY_SIZE {
+                return Err(Error::KeyTooLarge);
+            } else if value.len() > MAX_VALUE_SIZE {
+                return Err(Error::ValueTooLarge);
+            } else if prev.is_some_and(|prev| key.as_slice() <= prev.get()) {
+                return Err(Error::KeyOutOfOrder { key });
+            }
+
+            // Past the last key of the bucket, the key sorts after every key
+            // of the tail.
+            let append = tail.is_some() || last.as_ref().is_none_or(|last| key > *last);
+            let item = if tail.is_none() {
+                c.seek_in(&mut state, &key)?
+            } else {
+                None
+            };
+            // Return an error if there is an existing key with a bucket value.
+            if let Some((k, _, flags)) = item {
+                if k == key.as_slice() && flags & BUCKET_LEAF_FLAG != 0 {
+                    return Err(Error::IncompatibleValue);
+                }
+            }
+            let value = state.encode_value(self.id, Cow::Owned(value))?;
+            let key = state.keep(key.into_boxed_slice());
+            if append {
+                let n = match tail {
+                    Some(n) => n,
+                    None => *tail.insert(c.node_in(&mut state)?),
+                };
+                let inode = Inode {
+                    key,
+                    value,
+                    ..Inode::default()
+                };
+                state.pending_bytes += state.nodes[n].inode_size(&inode);
+                state.nodes[n].inodes.push(inode);
+            } else {
+                let n = c.node_in(&mut state)?;
+                state.put(n, key.get(), key, value, 0, 0)?;
+            }
+            state.writes += 1;
+            prev = Some(key);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Delete removes a key from the bucket.
+    /// If the key does not exist then nothing is done and a nil error is returned.
+    /// Returns an error if the bucket was created from a read-only transaction.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+
+        // Move cursor to correct position.
+        let mut state = self.tx.state.borrow_mut();
+        let mut c = Cursor::new(self.tx, self.id);
+        match c.seek_in(&mut state, key)? {
+            // Return nil if the key doesn't exist.
+            Some((k, _, flags)) if k == key => {
+                // Return an error if there is already existing bucket value.
+                if flags & BUCKET_LEAF_FLAG != 0 {
+                    return Err(Error::IncompatibleValue);
+                }
+            }
+            _ => return Ok(()),
+        }
+
+        // Delete the node if we have a matching key.
+        let n = c.node_in(&mut state)?;
+        state.del(n, key);
+        state.writes += 1;
+        Ok(())
+    }
+
+    /// PutIfAbsent sets the value for a key only if the key does not exist
+    /// yet, and reports whether it did. Returns an error under the same
+    /// conditions as put().
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// CompareAndSwap replaces the value for a key with `new` only if its
+    /// current value is `expected`, and reports whether it did. An
+    /// `expected` of None means that the key must be absent, a `new` of None
+    /// deletes the key.
+    /// Returns an error if the bucket was created from a read-only transaction,
+    /// or under the same conditions as put() and delete().
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+        if self.get(key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.put(key, value)?,
+            None => self.delete(key)?,
+        }
+        Ok(true)
+    }
+
+    /// GetU64 retrieves the value for the key `keys::encode_u64(key)`.
+    pub fn get_u64(&self, key: u64) -> Result<Option<&'tx [u8]>> {
+        self.get(&keys::encode_u64(key))
+    }
+
+    /// PutU64 sets the value for the key `keys::encode_u64(key)`, so that
+    /// cursors visit the keys in numeric order.
+    pub fn put_u64(&self, key: u64, value: &[u8]) -> Result<()> {
+        self.put(&keys::encode_u64(key), value)
+    }
+
+    /// DeleteU64 removes the key `keys::encode_u64(key)` from the bucket.
+    pub fn delete_u64(&self, key: u64) -> Result<()> {
+        self.delete(&keys::encode_u64(key))
+    }
+
+    /// ForEach executes a function for each key/value pair in a bucket, in key
+    /// order. Nested buckets are passed with a `None` value. If the provided
+    /// function returns an error then the iteration is stopped and the error
+    /// is returned to the caller.
+    ///
+    /// The function may modify the bucket in a writable transaction: the
+    /// iteration then continues with the first key after the one just
+    /// visited, so keys inserted after it are visited and deleted keys are not.
+    pub fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&'tx [u8], Option<&'tx [u8]>) -> Result<()>,
+    {
+        self.walk(|k, v, flags| {
+            if flags & BUCKET_LEAF_FLAG != 0 {
+                f(k, None)
+            } else {
+                let v = self.tx.state.borrow_mut().decode_value(self.id, v);
+                f(k, Some(v))
+            }
+        })
+    }
+
+    /// ForEachBucket executes a function for the name of each nested bucket,
+    /// in key order, with the same rules as `for_each`.
+    pub fn for_each_bucket<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&'tx [u8]) -> Result<()>,
+    {
+        self.walk(|k, _, flags| {
+            if flags & BUCKET_LEAF_FLAG != 0 {
+                f(k)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// walk calls `f` with every key, value and flags of the bucket. The
+    /// transaction state is not borrowed while `f` runs, so it may modify the
+    /// bucket.
+    fn walk<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&'tx [u8], &'tx [u8], u32) -> Result<()>,
+    {
+        let mut c = Cursor::new(self.tx, self.id);
+        let (mut item, mut writes) = {
+            let mut state = self.tx.state.borrow_mut();
+            (c.first_in(&mut state)?, state.writes)
+        };
+        while let Some((k, v, flags)) = item {
+            f(k, v, flags)?;
+
+            let mut state = self.tx.state.borrow_mut();
+            item = if state.writes == writes {
+                c.next_in(&mut state)?
+            } else {
+                // The tree changed under the cursor; move it past the last key.
+                writes = state.writes;
+                match c.seek_in(&mut state, k)? {
+                    Some(item) if item.0 != k => Some(item),
+                    _ => c.next_in(&mut state)?,
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Sequence returns the current integer for the bucket without incrementing it.
+    pub fn sequence(&self) -> u64 {
+        self.tx.state.borrow().buckets[self.id].header.sequence
+    }
+
+    /// SetSequence updates the sequence number for the bucket.
+    pub fn set_sequence(&self, v: u64) -> Result<()> {
+        self.update_sequence(|_| v).map(|_| ())
+    }
+
+    /// NextSequence returns an autoincrementing integer for the bucket.
+    pub fn next_sequence(&self) -> Result<u64> {
+        self.update_sequence(|seq| seq.wrapping_add(1))
+    }
+
+    /// update_sequence replaces the sequence number with `f` applied to it
+    /// and returns the new value.
+    fn update_sequence(&self, f: impl FnOnce(u64) -> u64) -> Result<u64> {
+        if !self.tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+        let mut state = self.tx.state.borrow_mut();
+
+        // Materialize the root node if it hasn't been already so that the
+        // bucket will be saved during commit.
+        if state.buckets[self.id].root_node.is_none() {
+            let root = state.buckets[self.id].header.root;
+            state.node(self.tx, self.id, root, None)?;
+        }
+
+        // Update the sequence.
+        let header = &mut state.buckets[self.id].header;
+        header.sequence = f(header.sequence);
+        Ok(header.sequence)
+    }
+
+    /// Count returns the number of keys in the bucket, nested bucket names
+    /// included but not the keys inside them. Unlike stats(), it sees the
+    /// changes of the current transaction.
+    ///
+    /// Only the element counts of the pages are read, so counting visits
+    /// every branch and leaf page of the bucket once but none of the keys or
+    /// values: O(leaf pages) rather than O(keys).
+    pub fn count(&self) -> Result<u64> {
+        let state = self.tx.state.borrow();
+        let root = state.buckets[self.id].header.root;
+        state.count_keys(self.tx, self.id, root)
+    }
+
+    /// Stats retrieves stats on a bucket and the buckets nested in it.
+    /// Like the other page walks, it reports the pages as last committed.
+    pub fn stats(&self) -> Result<BucketStats> {
+        let (header, page) = self.header();
+        let codec = self.value_codec();
+        bucket_stats(self.tx, &header, page.map(Page::new), codec.as_deref())
+    }
+
+    /// header returns the header of the bucket and, for an inline bucket, its
+    /// page.
+    pub(crate) fn header(&self) -> (InBucket, Option<&'tx [u8]>) {
+        let state = self.tx.state.borrow();
+        let b = &state.buckets[self.id];
+        // Safety: the inline page lives in the transaction's mmap or arena.
+        (b.header, b.page.map(|page| unsafe { page.extend() }))
+    }
+}
+
+/// ReservedValue is a value reserved by `Bucket::put_reserve`. It
+/// dereferences to the value, to be filled in place, and stores it under its
+/// key when finished or dropped.
+pub struct ReservedValue<'tx> {
+    bucket: Bucket<'tx>,
+    key: Bytes,
+    value: Box<[u8]>,
+    /// Whether finish took the value, leaving nothing for drop to store.
+    stored: bool,
+}
+
+impl ReservedValue<'_> {
+    /// Finish stores the value under its key. Returns
+    /// `Error::IncompatibleValue` if the key was made a nested bucket since
+    /// the value was reserved.
+    pub fn finish(mut self) -> Result<()> {
+        self.stored = true;
+        let value = std::mem::take(&mut self.value);
+        self.bucket.store_reserved(self.key, value)
+    }
+}
+
+impl Deref for ReservedValue<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+impl DerefMut for ReservedValue<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.value
+    }
+}
+
+impl Drop for ReservedValue<'_> {
+    /// Drop stores the value, unless the thread is panicking while filling
+    /// it in. If the value can't be stored, the error is returned by the
+    /// commit of the transaction; call `finish` to handle it here instead.
+    fn drop(&mut self) {
+        if self.stored || thread::panicking() {
+            return;
+        }
+        let value = std::mem::take(&mut self.value);
+        if let Err(err) = self.bucket.store_reserved(self.key, value) {
+            let mut state = self.bucket.tx.state.borrow_mut();
+            state.deferred_err.get_or_insert(err);
+        }
+    }
+}
+
+/// BucketStats records statistics about resources used by a bucket.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BucketStats {
+    // Page count statistics.
+    /// number of logical branch pages
+    pub branch_page_n: usize,
+    /// number of physical branch overflow pages
+    pub branch_overflow_n: usize,
+    /// number of logical leaf pages
+    pub leaf_page_n: usize,
+    /// number of physical leaf overflow pages
+    pub leaf_overflow_n: usize,
+
+    // Tree statistics.
+    /// number of keys/value pairs
+    pub key_n: usize,
+    /// number of levels in B+tree
+    pub depth: usize,
+
+    // Page size utilization.
+    /// bytes allocated for physical branch pages
+    pub branch_alloc: usize,
+    /// bytes actually used for branch data
+    pub branch_inuse: usize,
+    /// bytes allocated for physical leaf pages
+    pub leaf_alloc: usize,
+    /// bytes actually used for leaf data
+    pub leaf_inuse: usize,
+
+    // Bucket statistics
+    /// total number of buckets including the top bucket
+    pub bucket_n: usize,
+    /// total number on inlined buckets
+    pub inline_bucket_n: usize,
+    /// bytes used for inlined buckets (also accounted for in leaf_inuse)
+    pub inline_bucket_inuse: usize,
+
+    // Value statistics.
+    /// bytes of values as stored, nested buckets excluded
+    pub value_bytes: usize,
+    /// bytes of values as decoded by the codec installed on the bucket,
+    /// equal to value_bytes for buckets without one
+    pub logical_value_bytes: usize,
+}
+
+impl BucketStats {
+    /// Add accumulates the stats of `other` into `self`. The depth is the
+    /// maximum of both depths.
+    pub fn add(&mut self, other: &BucketStats) {
+        self.branch_page_n += other.branch_page_n;
+        self.branch_overflow_n += other.branch_overflow_n;
+        self.leaf_page_n += other.leaf_page_n;
+        self.leaf_overflow_n += other.leaf_overflow_n;
+        self.key_n += other.key_n;
+        if self.depth < other.depth {
+            self.depth = other.depth;
+        }
+        self.branch_alloc += other.branch_alloc;
+        self.branch_inuse += other.branch_inuse;
+        self.leaf_alloc += other.leaf_alloc;
+        self.leaf_inuse += other.leaf_inuse;
+
+        self.bucket_n += other.bucket_n;
+        self.inline_bucket_n += other.inline_bucket_n;
+        self.inline_bucket_inuse += other.inline_bucket_inuse;
+
+        self.value_bytes += other.value_bytes;
+        self.logical_value_bytes += other.logical_value_bytes;
+    }
+}
+
+/// bucket_stats computes the stats of the bucket with the given header, whose
+/// root is the inline page `page` when the bucket is inline. Its values are
+/// decoded with `codec` to count their logical size; nested buckets have no
+/// codec installed.
+fn bucket_stats(
+    tx: &Tx,
+    header: &InBucket,
+    page: Option<Page<'_>>,
+    codec: Option<&dyn ValueCodec>,
+) -> Result<BucketStats> {
+    let mut s = BucketStats::default();
+    let mut sub_stats = BucketStats::default();
+    let page_size = tx.db.page_size;
+    s.bucket_n += 1;
+    if header.root == 0 {
+        s.inline_bucket_n += 1;
+    }
+
+    let mut visit = |p: Page<'_>, depth: usize| -> Result<()> {
+        let count = p.count() as usize;
+        if p.flags() & LEAF_PAGE_FLAG != 0 {
+            s.key_n += count;
+
+            // used totals the used bytes for the page
+            let mut used = PAGE_HEADER_SIZE;
+            for i in 0..count {
+                let e = p.leaf_element(i)?;
+                used += LEAF_PAGE_ELEMENT_SIZE + e.key().len() + e.value().len();
+                if e.flags() & BUCKET_LEAF_FLAG == 0 {
+                    s.value_bytes += e.value().len();
+                    s.logical_value_bytes += match codec {
+                        Some(codec) => codec.decode(e.value()).len(),
+                        None => e.value().len(),
+                    };
+                }
+            }
+
+            if header.root == 0 {
+                // For inlined bucket just update the inline stats
+                s.inline_bucket_inuse += used;
+            } else {
+                // For non-inlined bucket update all the leaf stats
+                s.leaf_page_n += 1;
+                s.leaf_inuse += used;
+                s.leaf_overflow_n += p.overflow() as usize;
+
+                // Collect stats from sub-buckets.
+                for i in 0..count {
+                    let e = p.leaf_element(i)?;
+                    if e.flags() & BUCKET_LEAF_FLAG != 0 {
+                        // For any bucket element, open the element value
+                        // and recursively call stats on the contained bucket.
+                        let (child, page) = read_bucket_value(p.id(), e.value())?;
+                        sub_stats.add(&bucket_stats(tx, &child, page.map(Page::new), None)?);
+                    }
+                }
+            }
+        } else if p.flags() & BRANCH_PAGE_FLAG != 0 {
+            s.branch_page_n += 1;
+
+            // used totals the used bytes for the page
+            let mut used = PAGE_HEADER_SIZE;
+            for i in 0..count {
+                used += BRANCH_PAGE_ELEMENT_SIZE + p.branch_element(i)?.key().len();
+            }
+            s.branch_inuse += used;
+            s.branch_overflow_n += p.overflow() as usize;
+        }
+
+        // Keep track of maximum page depth.
+        if depth + 1 > s.depth {
+            s.depth = depth + 1;
+        }
+        Ok(())
+    };
+    match page {
+        Some(p) => visit(p, 0)?,
+        None if header.root != 0 => for_each_page(tx, header.root, 0, &mut visit)?,
+        None => {}
+    }
+
+    // Alloc stats can be computed from page counts and pageSize.
+    s.branch_alloc = (s.branch_page_n + s.branch_overflow_n) * page_size;
+    s.leaf_alloc = (s.leaf_page_n + s.leaf_overflow_n) * page_size;
+
+    // Add the max depth of sub-buckets to get total nested depth.
+    s.depth += sub_stats.depth;
+    // Add the stats for all sub-buckets
+    s.add(&sub_stats);
+    Ok(s)
+}
+
+/// for_each_page calls `f` for every committed page of the tree rooted at
+/// `pgid`, with its depth.
+fn for_each_page<'tx, F>(tx: &'tx Tx, pgid: Pgid, depth: usize, f: &mut F) -> Result<()>
+where
+    F: FnMut(Page<'tx>, usize) -> Result<()>,
+{
+    let p = tx.mmap_page(pgid)?;
+    f(p, depth)?;
+    if p.flags() & BRANCH_PAGE_FLAG != 0 {
+        for i in 0..p.count() as usize {
+            for_each_page(tx, p.branch_element(i)?.pgid(), depth + 1, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// read_bucket_value decodes the value of a bucket element of page `pgid`
+/// into its header and, for an inline bucket, its page.
+pub(crate) fn read_bucket_value(pgid: Pgid, value: &[u8]) -> Result<(InBucket, Option<&[u8]>)> {
+    if value.len() < BUCKET_HEADER_SIZE {
+        return Err(Error::corrupted(pgid, "bucket header too short"));
+    }
+    let header = InBucket::read(value);
+    if header.root != 0 {
+        return Ok((header, None));
+    }
+    let page = &value[BUCKET_HEADER_SIZE..];
+    if page.len() < PAGE_HEADER_SIZE {
+        return Err(Error::corrupted(pgid, "inline bucket page too short"));
+    }
+    Ok((header, Some(page)))
+}
+
+/// owned copies the key of a range bound.
+fn owned(bound: Bound<&&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// prefix_end returns the end bound of the keys starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    // Every key with the prefix sorts before the prefix with its last
+    // byte incremented, ignoring trailing 0xff bytes that can't be.
+    match prefix.iter().rposition(|&b| b != 0xff) {
+        Some(i) => {
+            let mut end = prefix[..=i].to_vec();
+            end[i] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    }
+}
+
+impl TxState {
+    /// encode_value moves `value` into the transaction, encoded with the
+    /// codec installed on `bucket`. Returns ValueTooLarge if the encoded
+    /// value is.
+    pub(crate) fn encode_value(&mut self, bucket: BucketId, value: Cow<'_, [u8]>) -> Result<Bytes> {
+        let encoded = match &self.buckets[bucket].codec {
+            Some(codec) => match codec.encode(&value) {
+                Cow::Owned(encoded) => Some(encoded),
+                Cow::Borrowed(_) => None,
+            },
+            None => None,
+        };
+        let value = encoded.map_or(value, Cow::Owned);
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(Error::ValueTooLarge);
+        }
+        Ok(match value {
+            Cow::Borrowed(value) => self.alloc(value),
+            Cow::Owned(value) => self.keep(value.into_boxed_slice()),
+        })
+    }
+
+    /// decode_value decodes a value of `bucket` with the codec installed on
+    /// it. A decoded copy is kept in the transaction, so it lives as long as
+    /// the value it was decoded from.
+    pub(crate) fn decode_value<'tx>(&mut self, bucket: BucketId, value: &'tx [u8]) -> &'tx [u8] {
+        let decoded = match &self.buckets[bucket].codec {
+            Some(codec) => codec.decode(value),
+            None => return value,
+        };
+        match decoded {
+            Cow::Borrowed(value) => value,
+            // Safety: the arena is only dropped with the transaction state.
+            Cow::Owned(value) => unsafe { self.keep(value.into_boxed_slice()).extend() },
+        }
+    }
+
+    /// open_bucket returns the id of the nested bucket `name` of `parent`,
+    /// reading its header the first time it is opened. Returns None if there
+    /// is no bucket by that name.
+    pub(crate) fn open_bucket(
+        &mut self,
+        tx: &Tx,
+        parent: BucketId,
+        name: &[u8],
+    ) -> Result<Option<BucketId>> {
+        if let Some(&child) = self.buckets[parent].buckets.get(name) {
+            return Ok(Some(child));
+        }
+
+        // Move cursor to key.
+        let mut c = Cursor::new(tx, parent);
+        let value = match c.seek_in(self, name)? {
+            // Return None if the key doesn't exist or it is not a bucket.
+            Some((k, v, flags)) if k == name && flags & BUCKET_LEAF_FLAG != 0 => v,
+            _ => return Ok(None),
+        };
+
+        // Otherwise create a bucket and cache it.
+        let (header, page) = read_bucket_value(self.root_pgid(parent), value)?;
+        let mut child = BucketState::new(header);
+        child.parent = Some((parent, name.to_vec()));
+
+        // If this is an inline bucket then reference the page that follows the header.
+        // Safety: the value lives in the transaction's mmap or arena.
+        child.page = page.map(|page| unsafe { Bytes::new(page) });
+
+        self.buckets.push(child);
+        let id = self.buckets.len() - 1;
+        self.buckets[parent].buckets.insert(name.to_vec(), id);
+        Ok(Some(id))
+    }
+
+    /// create_bucket adds an empty inline bucket named `key` to `parent`.
+    fn create_bucket(&mut self, tx: &Tx, parent: BucketId, key: &[u8]) -> Result<BucketId> {
+        // Move cursor to correct position.
+        let mut c = Cursor::new(tx, parent);
+        if let Some((k, _, flags)) = c.seek_in(self, key)? {
+            // Return an error if there is an existing key.
+            if k == key {
+                if flags & BUCKET_LEAF_FLAG != 0 {
+                    return Err(Error::BucketExists);
+                }
+                return Err(Error::IncompatibleValue);
+            }
+        }
+
+        // Create empty, inline bucket.
+        let root = Node::new(parent, true, None);
+        let value = inline_value(&InBucket::default(), &root)?;
+
+        // Insert into node.
+        let key = self.alloc(key);
+        let value = self.alloc(&value);
+        let n = c.node_in(self)?;
+        self.put(n, key.get(), key, value, 0, BUCKET_LEAF_FLAG)?;
+        self.writes += 1;
+
+        // Since subbuckets are not allowed on inline buckets, we need to
+        // dereference the inline page, if it exists. This will cause the bucket
+        // to be treated as a regular, non-inline bucket for the rest of the tx.
+        self.buckets[parent].page = None;
+
+        self.open_bucket(tx, parent, key.get())?
+            .ok_or_else(|| Error::corrupted(self.root_pgid(parent), "created bucket not found"))
+    }
+
+    /// move_bucket moves the nested bucket `key` of `src` into `dst`.
+    fn move_bucket(&mut self, tx: &Tx, src: BucketId, dst: BucketId, key: &[u8]) -> Result<()> {
+        // Return an error if bucket doesn't exist or is not a bucket.
+        let mut c = Cursor::new(tx, src);
+        let value = match c.seek_in(self, key)? {
+            Some((k, v, flags)) if k == key => {
+                if flags & BUCKET_LEAF_FLAG == 0 {
+                    return Err(Error::IncompatibleValue);
+                }
+                v
+            }
+            _ => return Err(Error::BucketNotFound),
+        };
+        if src == dst {
+            return Err(Error::SameBuckets);
+        }
+
+        // An opened destination has every ancestor opened as well, so it can
+        // only be nested in the moved bucket if that one is cached.
+        let child = self.buckets[src].buckets.get(key).copied();
+        if let Some(child) = child {
+            let mut ancestor = Some(dst);
+            while let Some(b) = ancestor {
+                if b == child {
+                    return Err(Error::MoveIntoDescendant);
+                }
+                ancestor = self.buckets[b].parent.as_ref().map(|(parent, _)| *parent);
+            }
+        }
+
+        // Return an error if there is an existing key in the destination.
+        let mut dc = Cursor::new(tx, dst);
+        if let Some((k, _, flags)) = dc.seek_in(self, key)? {
+            if k == key {
+                if flags & BUCKET_LEAF_FLAG != 0 {
+                    return Err(Error::BucketExists);
+                }
+                return Err(Error::IncompatibleValue);
+            }
+        }
+
+        // Remove the bucket from the source.
+        self.buckets[src].buckets.remove(key);
+        let n = c.node_in(self)?;
+        self.del(n, key);
+
+        // Add it to the destination. A cached bucket keeps its state, so its
+        // pending changes are written under its new parent when it spills.
+        let key = self.alloc(key);
+        let value = self.alloc(value);
+        let n = dc.node_in(self)?;
+        self.put(n, key.get(), key, value, 0, BUCKET_LEAF_FLAG)?;
+        self.writes += 1;
+        if let Some(child) = child {
+            self.buckets[child].parent = Some((dst, key.get().to_vec()));
+            self.buckets[dst].buckets.insert(key.get().to_vec(), child);
+        }
+
+        // Since subbuckets are not allowed on inline buckets, the destination
+        // can no longer be written inline.
+        self.buckets[dst].page = None;
+        Ok(())
+    }
+
+    /// delete_bucket removes the nested bucket `key` of `parent` and frees
+    /// its pages and those of every bucket nested in it.
+    fn delete_bucket(&mut self, tx: &Tx, parent: BucketId, key: &[u8]) -> Result<()> {
+        // Move cursor to correct position.
+        let mut c = Cursor::new(tx, parent);
+
+        // Return an error if bucket doesn't exist or is not a bucket.
+        match c.seek_in(self, key)? {
+            Some((k, _, flags)) if k == key => {
+                if flags & BUCKET_LEAF_FLAG == 0 {
+                    return Err(Error::IncompatibleValue);
+                }
+            }
+            _ => return Err(Error::BucketNotFound),
+        }
+
+        // Recursively delete all child buckets.
+        let child = self
+            .open_bucket(tx, parent, key)?
+            .ok_or(Error::BucketNotFound)?;
+        let mut names = Vec::new();
+        let mut cc = Cursor::new(tx, child);
+        let mut item = cc.first_in(self)?;
+        while let Some((k, _, flags)) = item {
+            if flags & BUCKET_LEAF_FLAG != 0 {
+                names.push(k);
+            }
+            item = cc.next_in(self)?;
+        }
+        for name in names {
+            self.delete_bucket(tx, child, name)?;
+        }
+
+        // Remove cached copy.
+        self.buckets[parent].buckets.remove(key);
+
+        // Release all bucket pages to freelist.
+        for &n in self.buckets[child].nodes.values() {
+            self.pending_bytes = self.pending_bytes.saturating_sub(self.nodes[n].size());
+        }
+        self.buckets[child].nodes.clear();
+        self.buckets[child].root_node = None;
+        self.free_bucket(tx, child)?;
+
+        // Delete the node if we have a matching key.
+        let n = c.node_in(self)?;
+        self.del(n, key);
+        self.writes += 1;
+        Ok(())
+    }
+
+    /// spill writes all the nodes for this bucket to dirty pages.
+    pub(crate) fn spill(&mut self, tx: &Tx, b: BucketId) -> Result<()> {
+        // Spill all child buckets first.
+        let mut children: Vec<_> = self.buckets[b]
+            .buckets
+            .iter()
+            .map(|(name, &child)| (name.clone(), child))
+            .collect();
+        children.sort();
+        for (name, child) in children {
+            // If the child bucket is small enough and it has no child buckets then
+            // write it inline into the parent bucket's page. Otherwise spill it
+            // like a normal bucket and make the parent value a pointer to the page.
+            let value = match self.inlineable(tx, child) {
+                Some(root_node) => {
+                    self.free_bucket(tx, child)?;
+                    inline_value(&self.buckets[child].header, &self.nodes[root_node])?
+                }
+                None => {
+                    self.spill(tx, child)?;
+
+                    // Update the child bucket header in this bucket.
+                    let mut value = vec![0; BUCKET_HEADER_SIZE];
+                    self.buckets[child].header.write(&mut value);
+                    value
+                }
+            };
+
+            // Skip writing the bucket if there are no materialized nodes.
+            if self.buckets[child].root_node.is_none() {
+                continue;
+            }
+            let mut c = Cursor::new(tx, b);
+            match c.seek_in(self, &name)? {
+                Some((k, _, flags)) if k == &name[..] => {
+                    if flags & BUCKET_LEAF_FLAG == 0 {
+                        return Err(Error::corrupted(
+                            self.root_pgid(b),
+                            format!("unexpected bucket header flag: {:x}", flags),
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(Error::corrupted(
+                        self.root_pgid(b),
+                        format!("misplaced bucket header: {:?}", name),
+                    ))
+                }
+            }
+            let key = self.alloc(&name);
+            let value = self.alloc(&value);
+            let n = c.node_in(self)?;
+            self.nodes[n].put(&name, key, value, 0, BUCKET_LEAF_FLAG)?;
+        }
+
+        // Ignore if there's not a materialized root node.
+        let root_node = match self.buckets[b].root_node {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+
+        // Spill nodes.
+        self.spill_node(tx, root_node)?;
+
+        // Update the root node for this bucket.
+        let root_node = self.node_root(root_node);
+        let pgid = self.nodes[root_node].pgid;
+        self.buckets[b].root_node = Some(root_node);
+        if pgid >= tx.meta.get().pgid {
+            let reason = format!("above high water mark ({})", tx.meta.get().pgid);
+            return Err(Error::corrupted(pgid, reason));
+        }
+        self.buckets[b].header.root = pgid;
+        Ok(())
+    }
+
+    /// inlineable returns the root node of the bucket if it is small enough
+    /// to be written inline and if it contains no subbuckets.
+    fn inlineable(&self, tx: &Tx, b: BucketId) -> Option<NodeId> {
+        // Bucket must only contain a single leaf node.
+        let root_node = self.buckets[b].root_node?;
+        let n = &self.nodes[root_node];
+        if !n.is_leaf {
+            return None;
+        }
+
+        // Bucket is not inlineable if it contains subbuckets or if it goes beyond
+        // our threshold for inline bucket size.
+        let mut size = PAGE_HEADER_SIZE;
+        for inode in &n.inodes {
+            size += LEAF_PAGE_ELEMENT_SIZE + inode.key.len() + inode.value.len();
+            if inode.flags & BUCKET_LEAF_FLAG != 0 || size > max_inline_bucket_size(tx) {
+                return None;
+            }
+        }
+        Some(root_node)
+    }
+
+    /// rebalance attempts to balance all nodes.
+    pub(crate) fn rebalance(&mut self, tx: &Tx, b: BucketId) -> Result<()> {
+        let nodes: Vec<_> = self.buckets[b]
+            .nodes
+            .iter()
+            .map(|(&pgid, &n)| (pgid, n))
+            .collect();
+        for (pgid, n) in nodes {
+            // Skip nodes merged away while rebalancing an earlier one.
+            if self.buckets[b].nodes.get(&pgid) == Some(&n) {
+                self.rebalance_node(tx, n)?;
+            }
+        }
+        let children: Vec<_> = self.buckets[b].buckets.values().copied().collect();
+        for child in children {
+            self.rebalance(tx, child)?;
+        }
+        Ok(())
+    }
+
+    /// count_keys returns the number of keys under page `id` of bucket `b`,
+    /// preferring materialized nodes over their pages.
+    fn count_keys(&self, tx: &Tx, b: BucketId, id: Pgid) -> Result<u64> {
+        match self.page_node(tx, b, id)? {
+            PageNode::Node(n) => {
+                let node = &self.nodes[n];
+                if node.is_leaf {
+                    return Ok(node.inodes.len() as u64);
+                }
+                node.inodes
+                    .iter()
+                    .map(|inode| self.count_keys(tx, b, inode.pgid))
+                    .sum()
+            }
+            PageNode::Page(p) => {
+                if p.flags() & LEAF_PAGE_FLAG != 0 {
+                    return Ok(u64::from(p.count()));
+                } else if p.flags() & BRANCH_PAGE_FLAG == 0 {
+                    return Err(Error::corrupted(p.id(), "invalid page type in bucket"));
+                }
+                (0..p.count() as usize)
+                    .map(|i| self.count_keys(tx, b, p.branch_element(i)?.pgid()))
+                    .sum()
+            }
+        }
+    }
+
+    /// page_node returns the in-memory node, if it exists.
+    /// Otherwise returns the underlying page.
+    pub(crate) fn page_node<'tx>(
+        &self,
+        tx: &'tx Tx,
+        b: BucketId,
+        id: Pgid,
+    ) -> Result<PageNode<'tx>> {
+        let bucket = &self.buckets[b];
+
+        // Inline buckets have a fake page embedded in their value so treat them
+        // differently. We'll return the rootNode (if available) or the fake page.
+        if bucket.header.root == 0 {
+            if id != 0 {
+                return Err(Error::corrupted(id, "inline bucket non-zero page access"));
+            }
+            if let Some(n) = bucket.root_node {
+                return Ok(PageNode::Node(n));
+            }
+            let page = bucket
+                .page
+                .ok_or_else(|| Error::corrupted(id, "inline bucket without page"))?;
+            // Safety: the inline page lives in the transaction's mmap or arena.
+            return Ok(PageNode::Page(Page::new(unsafe { page.extend() })));
+        }
+
+        // Check the node cache for non-inline buckets.
+        if let Some(&n) = bucket.nodes.get(&id) {
+            return Ok(PageNode::Node(n));
+        }
+
+        // Finally lookup the page from the transaction if no node is materialized.
+        Ok(PageNode::Page(tx.mmap_page(id)?))
+    }
+
+    /// node creates a node from a page and associates it with a given parent.
+    pub(crate) fn node(
+        &mut self,
+        tx: &Tx,
+        b: BucketId,
+        pgid: Pgid,
+        parent: Option<NodeId>,
+    ) -> Result<NodeId> {
+        // Retrieve node if it's already been created.
+        if let Some(&n) = self.buckets[b].nodes.get(&pgid) {
+            return Ok(n);
+        }
+
+        // Otherwise create a node and cache it.
+        let mut node = Node::new(b, false, parent);
+
+        // Use the inline page if this is an inline bucket.
+        let p = match self.buckets[b].page {
+            // Safety: the inline page lives in the transaction's mmap or arena.
+            Some(page) => Page::new(unsafe { page.extend() }),
+            None => tx.mmap_page(pgid)?,
+        };
+
+        // Read the page into the node and cache it.
+        // Safety: the page lives in the transaction's mmap or arena.
+        unsafe { node.read(p)? };
+        self.pending_bytes += node.size();
+        self.nodes.push(node);
+        let n = self.nodes.len() - 1;
+        match parent {
+            None => self.buckets[b].root_node = Some(n),
+            Some(parent) => self.nodes[parent].children.push(n),
+        }
+        self.buckets[b].nodes.insert(pgid, n);
+
+        // Update statistics.
+        tx.stats.inc_node_count(1);
+
+        Ok(n)
+    }
+
+    /// free_bucket recursively frees all pages in the bucket.
+    fn free_bucket(&mut self, tx: &Tx, b: BucketId) -> Result<()> {
+        let root = self.buckets[b].header.root;
+        if root == 0 {
+            return Ok(());
+        }
+        self.free_pages(tx, b, root)?;
+        self.buckets[b].header.root = 0;
+        Ok(())
+    }
+
+    /// free_pages frees the page or node `pgid` of bucket `b` and everything below it.
+    fn free_pages(&mut self, tx: &Tx, b: BucketId, pgid: Pgid) -> Result<()> {
+        let children = match self.page_node(tx, b, pgid)? {
+            PageNode::Node(n) => {
+                let node = &self.nodes[n];
+                let children: Vec<_> = if node.is_leaf {
+                    Vec::new()
+                } else {
+                    node.inodes.iter().map(|inode| inode.pgid).collect()
+                };
+                self.free_node(tx, n)?;
+                children
+            }
+            PageNode::Page(p) => {
+                let mut children = Vec::new();
+                if p.flags() & BRANCH_PAGE_FLAG != 0 {
+                    for i in 0..p.count() as usize {
+                        children.push(p.branch_element(i)?.pgid());
+                    }
+                }
+                tx.free_page(pgid)?;
+                children
+            }
+        };
+        for child in children {
+            self.free_pages(tx, b, child)?;
+        }
+        Ok(())
+    }
+
+    /// root_pgid returns the root page of a bucket, used to report corruption.
+    fn root_pgid(&self, b: BucketId) -> Pgid {
+        self.buckets[b].header.root
+    }
+}
+
+/// max_inline_bucket_size returns the maximum total size of a bucket to make it a candidate for inlining.
+fn max_inline_bucket_size(tx: &Tx) -> usize {
+    tx.db.page_size / 4
+}
+
+/// inline_value serializes a bucket header followed by its root node, in the
+/// layout used to store small buckets inline in their parent's value.
+pub(crate) fn inline_value(header: &InBucket, root: &Node) -> Result<Vec<u8>> {
+    // Allocate the appropriate size.
+    let mut value = vec![0; BUCKET_HEADER_SIZE + root.size()];
+
+    // Write a bucket header.
+    header.write(&mut value);
+
+    // Convert byte slice to a fake page and write the root node.
+    root.write(&mut PageMut::new(&mut value[BUCKET_HEADER_SIZE..]))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    use crate::db::{lock, Options, DB};
+    use crate::errors::Error;
+    use crate::tx::Tx;
+    use proptest::prelude::*;
+
+    fn open(dir: &tempfile::TempDir) -> DB {
+        DB::open(dir.path().join("db"), Options::default()).unwrap()
+    }
+
+    fn free_count(tx: &Tx) -> usize {
+        lock(&tx.db.freelist).free_count()
+    }
+
+    fn names(n: usize) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|i| format!("bucket-{:05}", i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn create_bucket_persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"widgets")?;
+            for name in names(300) {
+                tx.create_bucket(&name)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        let db = open(&dir);
+        db.view(|tx| {
+            tx.bucket(b"widgets")?;
+            for name in names(300) {
+                tx.bucket(&name)?;
+            }
+            assert!(matches!(tx.bucket(b"gadgets"), Err(Error::BucketNotFound)));
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn create_bucket_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"widgets")?;
+            assert!(matches!(
+                tx.create_bucket(b"widgets"),
+                Err(Error::BucketExists)
+            ));
+            assert!(matches!(
+                tx.create_bucket(b""),
+                Err(Error::BucketNameRequired)
+            ));
+
+            // An existing bucket is returned as is.
+            tx.create_bucket_if_not_exists(b"widgets")?;
+            tx.create_bucket_if_not_exists(b"gadgets")?;
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            tx.bucket(b"gadgets")?;
+            assert!(matches!(
+                tx.create_bucket(b"sprockets"),
+                Err(Error::TxNotWritable)
+            ));
+            assert!(matches!(
+                tx.create_bucket_if_not_exists(b"sprockets"),
+                Err(Error::TxNotWritable)
+            ));
+            assert!(matches!(
+                tx.delete_bucket(b"widgets"),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        let mut tx = db.begin(true).unwrap();
+        tx.rollback().unwrap();
+        assert!(matches!(tx.bucket(b"widgets"), Err(Error::TxClosed)));
+        assert!(matches!(tx.create_bucket(b"x"), Err(Error::TxClosed)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn uncommitted_bucket_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let mut tx = db.begin(true).unwrap();
+        tx.create_bucket(b"widgets").unwrap();
+        tx.bucket(b"widgets").unwrap();
+        tx.rollback().unwrap();
+
+        db.view(|tx| {
+            assert!(matches!(tx.bucket(b"widgets"), Err(Error::BucketNotFound)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"widgets")?;
+            assert!(matches!(
+                tx.delete_bucket(b"gadgets"),
+                Err(Error::BucketNotFound)
+            ));
+            tx.delete_bucket(b"widgets")?;
+            assert!(matches!(tx.bucket(b"widgets"), Err(Error::BucketNotFound)));
+
+            // The name can be reused within the same transaction.
+            tx.create_bucket(b"widgets")?;
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+
+        db.close().unwrap();
+        let db = open(&dir);
+        db.view(|tx| {
+            assert!(matches!(tx.bucket(b"widgets"), Err(Error::BucketNotFound)));
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_bucket_frees_nested_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+
+        // Build a bucket whose nested buckets span several pages of their own.
+        db.update(|tx| {
+            let parent = tx.create_bucket(b"parent")?;
+            for name in names(200) {
+                let child = parent.create_bucket(&name)?;
+                for name in names(3) {
+                    child.create_bucket(&name)?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        let tx = db.begin(true).unwrap();
+        let (free_before, size) = (free_count(&tx), tx.size());
+        drop(tx);
+
+        db.update(|tx| tx.delete_bucket(b"parent")).unwrap();
+
+        // Once no reader can see them, the pages are free for reuse.
+        let tx = db.begin(true).unwrap();
+        let free_after = free_count(&tx);
+        drop(tx);
+        assert!(
+            free_after > free_before + 200,
+            "{} -> {}",
+            free_before,
+            free_after
+        );
+        db.update(|tx| {
+            assert!(tx.check().is_empty());
+            for name in names(300) {
+                tx.create_bucket(&name)?;
+            }
+            assert_eq!(tx.size(), size);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"foo", b"bar")?;
+            assert_eq!(b.get(b"foo")?, Some(&b"bar"[..]));
+            assert_eq!(b.get(b"fo")?, None);
+            assert_eq!(b.get(b"fooo")?, None);
+
+            // Overwriting replaces the value; earlier slices stay valid.
+            let old = b.get(b"foo")?.unwrap();
+            b.put(b"foo", b"baz")?;
+            assert_eq!(b.get(b"foo")?, Some(&b"baz"[..]));
+            assert_eq!(old, b"bar");
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        let db = open(&dir);
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let v = b.get(b"foo")?.unwrap();
+            assert_eq!(v, b"baz");
+
+            // Committed values are read straight from the mmap.
+            assert!(tx.mmap.as_slice().as_ptr_range().contains(&v.as_ptr()));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_copies_caller_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            let mut key = Vec::new();
+            let mut value = Vec::new();
+            for i in 0..100u32 {
+                key.clear();
+                key.extend_from_slice(&i.to_be_bytes());
+                value.clear();
+                value.extend_from_slice(format!("value-{}", i).as_bytes());
+                b.put(&key, &value)?;
+            }
+            key.fill(0xff);
+            value.fill(0xff);
+            for i in 0..100u32 {
+                let want = format!("value-{}", i);
+                assert_eq!(b.get(&i.to_be_bytes())?, Some(want.as_bytes()));
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_get_many_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let key = |i: u32| format!("key-{:08}", (i * 7919) % 10000).into_bytes();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..10000u32 {
+                b.put(&key(i), &i.to_le_bytes())?;
+            }
+            // A value larger than a page goes on overflow pages.
+            b.put(b"large", &vec![0x42; 3 * 4096 + 100])?;
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in (0..10000).step_by(2) {
+                b.delete(&key(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        let db = open(&dir);
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in 0..10000u32 {
+                let want = i.to_le_bytes();
+                let want = if i % 2 == 0 { None } else { Some(&want[..]) };
+                assert_eq!(b.get(&key(i))?, want, "key {}", i);
+            }
+            assert_eq!(b.get(b"large")?, Some(&vec![0x42; 3 * 4096 + 100][..]));
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"foo", b"bar")?;
+            b.delete(b"foo")?;
+            assert_eq!(b.get(b"foo")?, None);
+
+            // Deleting a missing key is a no-op.
+            b.delete(b"foo")?;
+            b.delete(b"missing")?;
+            b.put(b"foo", b"baz")?;
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.delete(b"foo"))
+            .unwrap();
+        db.view(|tx| {
+            assert_eq!(tx.bucket(b"widgets")?.get(b"foo")?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn overflow_values() {
+        for pages in [1usize, 3, 1000] {
+            let dir = tempfile::tempdir().unwrap();
+            let db = open(&dir);
+            let page_size = db.begin(false).unwrap().db.page_size;
+            let value: Vec<u8> = (0..pages * page_size).map(|i| i as u8).collect();
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                b.put(b"big", &value)?;
+                b.put(b"small", b"")?;
+                Ok(())
+            })
+            .unwrap();
+
+            // The value, its key and the page header need one more page.
+            db.close().unwrap();
+            let db = open(&dir);
+            let mut leaf = 0;
+            db.view(|tx| {
+                let b = tx.bucket(b"widgets")?;
+                assert_eq!(b.get(b"big")?, Some(&value[..]));
+                assert_eq!(b.stats()?.leaf_overflow_n, pages);
+                leaf = b.root();
+                assert_eq!(tx.page(leaf)?.unwrap().overflow_count, pages);
+                Ok(())
+            })
+            .unwrap();
+
+            // Deleting it frees the leaf along with every overflow page.
+            db.update(|tx| tx.bucket(b"widgets")?.delete(b"big"))
+                .unwrap();
+            let stats = db.stats();
+            db.view(|tx| {
+                for id in leaf..=leaf + pages as u64 {
+                    assert_eq!(tx.page(id)?.unwrap().typ, "free", "page {}", id);
+                }
+                let free = (0..tx.meta.get().pgid)
+                    .filter(|&id| tx.page(id).unwrap().unwrap().typ == "free")
+                    .count();
+                assert_eq!((stats.free_page_n + stats.pending_page_n) as usize, free);
+                assert!(free > pages);
+                assert!(tx.check().is_empty());
+                Ok(())
+            })
+            .unwrap();
+
+            // The next writer releases them for reuse.
+            db.update(|_| Ok(())).unwrap();
+            assert!(db.stats().free_page_n as usize > pages);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            assert!(matches!(b.put(b"", b"bar"), Err(Error::KeyRequired)));
+            assert!(matches!(
+                b.put(&vec![0; MAX_KEY_SIZE + 1], b"bar"),
+                Err(Error::KeyTooLarge)
+            ));
+            b.put(&vec![1; MAX_KEY_SIZE], b"bar")?;
+
+            // The zeroed allocation is never touched, so it stays cheap.
+            let value = vec![0u8; MAX_VALUE_SIZE + 1];
+            assert!(matches!(b.put(b"foo", &value), Err(Error::ValueTooLarge)));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.get(&vec![1; MAX_KEY_SIZE])?, Some(&b"bar"[..]));
+            assert!(matches!(b.put(b"foo", b"bar"), Err(Error::TxNotWritable)));
+            assert!(matches!(b.delete(b"foo"), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn nested_buckets_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let users = tx.create_bucket(b"users")?;
+            for user in [&b"alice"[..], b"bob"] {
+                let u = users.create_bucket(user)?;
+                u.put(b"name", user)?;
+                let posts = u.create_bucket(b"posts")?;
+                for i in 0..50u32 {
+                    posts.put(&i.to_be_bytes(), &[b'p'; 100])?;
+                }
+            }
+            assert!(matches!(
+                users.create_bucket(b"alice"),
+                Err(Error::BucketExists)
+            ));
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        let db = open(&dir);
+        db.view(|tx| {
+            let users = tx.bucket(b"users")?;
+            for user in [&b"alice"[..], b"bob"] {
+                let u = users.bucket(user)?;
+                assert_eq!(u.get(b"name")?, Some(user));
+                let posts = u.bucket(b"posts")?;
+                for i in 0..50u32 {
+                    assert_eq!(posts.get(&i.to_be_bytes())?, Some(&[b'p'; 100][..]));
+                }
+                assert!(matches!(u.bucket(b"name"), Err(Error::BucketNotFound)));
+            }
+            assert!(matches!(users.bucket(b"carol"), Err(Error::BucketNotFound)));
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn nested_bucket_incompatible_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.create_bucket(b"sub")?;
+            b.put(b"key", b"value")?;
+
+            // A sub-bucket is not a value.
+            assert_eq!(b.get(b"sub")?, None);
+            assert!(matches!(b.put(b"sub", b"x"), Err(Error::IncompatibleValue)));
+            assert!(matches!(b.delete(b"sub"), Err(Error::IncompatibleValue)));
+
+            // And a value is not a sub-bucket.
+            assert!(matches!(
+                b.create_bucket(b"key"),
+                Err(Error::IncompatibleValue)
+            ));
+            assert!(matches!(
+                b.create_bucket_if_not_exists(b"key"),
+                Err(Error::IncompatibleValue)
+            ));
+            assert!(matches!(
+                b.delete_bucket(b"key"),
+                Err(Error::IncompatibleValue)
+            ));
+            assert_eq!(b.get(b"key")?, Some(&b"value"[..]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_bucket_cascades() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let a = tx.create_bucket(b"a")?;
+            let b = a.create_bucket(b"b")?;
+            let c = b.create_bucket(b"c")?;
+            c.put(b"foo", b"bar")?;
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            tx.bucket(b"a")?.delete_bucket(b"b")?;
+            let a = tx.bucket(b"a")?;
+            assert!(matches!(a.bucket(b"b"), Err(Error::BucketNotFound)));
+
+            // A recreated bucket starts out empty.
+            let b = a.create_bucket(b"b")?;
+            assert!(matches!(b.bucket(b"c"), Err(Error::BucketNotFound)));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"a")?.bucket(b"b")?;
+            assert!(matches!(b.bucket(b"c"), Err(Error::BucketNotFound)));
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn inline_bucket_transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.root(), 0);
+            let stats = b.stats()?;
+            assert_eq!(stats.inline_bucket_n, 1);
+            assert_eq!(stats.inline_bucket_inuse, PAGE_HEADER_SIZE + 16 + 6);
+            assert_eq!(stats.leaf_page_n, 0);
+            Ok(())
+        })
+        .unwrap();
+
+        // Growing past a quarter page materializes the root page.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_ne!(b.root(), 0);
+            let stats = b.stats()?;
+            assert_eq!(stats.inline_bucket_n, 0);
+            assert_eq!(stats.key_n, 101);
+            assert!(stats.leaf_page_n > 1);
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+
+        // Once it shrinks back to a small leaf, it is inlined again.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.delete(&i.to_be_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.root(), 0);
+            assert_eq!(b.get(b"foo")?, Some(&b"bar"[..]));
+            assert_eq!(b.stats()?.inline_bucket_n, 1);
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn bucket_with_subbuckets_is_not_inlined() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"a")?.create_bucket(b"b")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let a = tx.bucket(b"a")?;
+            assert_ne!(a.root(), 0);
+            assert_eq!(a.bucket(b"b")?.root(), 0);
+            let stats = a.stats()?;
+            assert_eq!((stats.bucket_n, stats.inline_bucket_n), (2, 1));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn inline_buckets_save_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            for name in names(10000) {
+                tx.create_bucket(&name)?.put(b"id", &name)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let page_size = tx.db.page_size as u64;
+            let stats = tx.root().stats()?;
+            assert_eq!(stats.bucket_n, 10001);
+            assert_eq!(stats.inline_bucket_n, 10000);
+
+            // The bucket names plus one key in each bucket.
+            assert_eq!(stats.key_n, 20000);
+
+            // A page per bucket would take 10000 pages; inlined they share
+            // the leaves of the root bucket.
+            assert!(
+                tx.size() < 10000 * page_size / 20,
+                "size {} for 10000 buckets",
+                tx.size()
+            );
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sequences() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let a = tx.create_bucket(b"a")?;
+            let nested = a.create_bucket(b"nested")?;
+            tx.create_bucket(b"b")?;
+            assert_eq!(a.sequence(), 0);
+            assert_eq!(a.next_sequence()?, 1);
+            assert_eq!(a.next_sequence()?, 2);
+            nested.set_sequence(1000)?;
+            assert_eq!(nested.next_sequence()?, 1001);
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            // An otherwise untouched bucket still persists its sequence.
+            assert_eq!(tx.bucket(b"b")?.next_sequence()?, 1);
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        let db = open(&dir);
+        db.view(|tx| {
+            let a = tx.bucket(b"a")?;
+            assert_eq!(a.sequence(), 2);
+            assert_eq!(a.bucket(b"nested")?.sequence(), 1001);
+            assert_eq!(tx.bucket(b"b")?.sequence(), 1);
+            assert!(matches!(a.next_sequence(), Err(Error::TxNotWritable)));
+            assert!(matches!(a.set_sequence(5), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sequence_snapshot_isolation() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| tx.create_bucket(b"a")?.set_sequence(7))
+            .unwrap();
+
+        let reader = db.begin(false).unwrap();
+        assert_eq!(reader.bucket(b"a").unwrap().sequence(), 7);
+        db.update(|tx| tx.bucket(b"a")?.next_sequence().map(|_| ()))
+            .unwrap();
+        assert_eq!(reader.bucket(b"a").unwrap().sequence(), 7);
+        drop(reader);
+
+        db.view(|tx| {
+            assert_eq!(tx.bucket(b"a")?.sequence(), 8);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn for_each_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in (0..1000u32).rev() {
+                b.put(&i.to_be_bytes(), &i.to_le_bytes())?;
+            }
+            b.create_bucket(b"sub")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let mut seen = Vec::new();
+            b.for_each(|k, v| {
+                seen.push((k, v));
+                Ok(())
+            })?;
+            assert_eq!(seen.len(), 1001);
+            for (i, (k, v)) in seen[..1000].iter().enumerate() {
+                assert_eq!(*k, (i as u32).to_be_bytes());
+                assert_eq!(*v, Some(&(i as u32).to_le_bytes()[..]));
+            }
+            assert_eq!(seen[1000], (&b"sub"[..], None));
+
+            let mut buckets = Vec::new();
+            b.for_each_bucket(|k| {
+                buckets.push(k);
+                Ok(())
+            })?;
+            assert_eq!(buckets, [&b"sub"[..]]);
+            Ok(())
+        })
+        .unwrap();
+
+        // An empty bucket visits nothing.
+        db.update(|tx| {
+            tx.create_bucket(b"empty")?
+                .for_each(|_, _| panic!("unexpected key"))
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn for_each_early_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), b"")?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut n = 0;
+            let res = tx.bucket(b"widgets")?.for_each(|_, _| {
+                n += 1;
+                if n == 10 {
+                    return Err(Error::KeyRequired);
+                }
+                Ok(())
+            });
+            assert!(matches!(res, Err(Error::KeyRequired)));
+            assert_eq!(n, 10);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn for_each_with_mutation() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in (0..200u32).step_by(2) {
+                b.put(&i.to_be_bytes(), b"")?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let mut seen = Vec::new();
+            b.for_each(|k, _| {
+                let i = u32::from_be_bytes(k.try_into().unwrap());
+                seen.push(i);
+
+                // Delete every key visited, add one right after it, which is
+                // visited next, and one before it, which is not.
+                b.delete(k)?;
+                if i % 2 == 0 && i < 100 {
+                    b.put(&(i + 1).to_be_bytes(), b"")?;
+                }
+                if i > 0 {
+                    b.put(&(i - 1).to_be_bytes(), b"x")?;
+                }
+                Ok(())
+            })?;
+            assert_eq!(
+                seen,
+                (0..100).chain((100..200).step_by(2)).collect::<Vec<_>>()
+            );
+
+            let mut left = Vec::new();
+            b.for_each(|k, v| {
+                assert_eq!(v, Some(&b"x"[..]));
+                left.push(u32::from_be_bytes(k.try_into().unwrap()));
+                Ok(())
+            })?;
+            let want: Vec<u32> = (0..99).chain((99..199).step_by(2)).collect();
+            assert_eq!(left, want);
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let big_key = b"really-big-value";
+        db.update(|tx| {
+            let b = tx.create_bucket(b"woojits")?;
+            for i in 0..500 {
+                b.put(format!("{:03}", i).as_bytes(), i.to_string().as_bytes())?;
+            }
+            b.put(big_key, &[b'*'; 10000])?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            let stats = tx.bucket(b"woojits")?.stats()?;
+            assert_eq!(stats.branch_page_n, 1);
+            assert_eq!(stats.branch_overflow_n, 0);
+            assert_eq!(stats.leaf_page_n, 7);
+            // The large value spans three pages.
+            assert_eq!(stats.leaf_overflow_n, 2);
+            assert_eq!(stats.key_n, 501);
+            assert_eq!(stats.depth, 2);
+
+            let branch_inuse = 16 // branch page header
+                + 7 * 16 // branch elements
+                + 7 * 3; // branch keys (7 3-byte keys)
+            assert_eq!(stats.branch_inuse, branch_inuse);
+            let leaf_inuse = 7 * 16 // leaf page header
+                + 501 * 16 // leaf elements
+                + 500 * 3 + big_key.len() // leaf keys
+                + 10 + 2 * 90 + 3 * 400 + 10000; // leaf values
+            assert_eq!(stats.leaf_inuse, leaf_inuse);
+            assert_eq!(stats.branch_alloc, 4096);
+            assert_eq!(stats.leaf_alloc, 9 * 4096);
+            assert_eq!((stats.bucket_n, stats.inline_bucket_n), (1, 0));
+            assert_eq!(stats.inline_bucket_inuse, 0);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats_inline() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"empty")?;
+            tx.create_bucket(b"small")?.put(b"foo", b"bar")
+        })
+        .unwrap();
+        db.view(|tx| {
+            let want = BucketStats {
+                depth: 1,
+                bucket_n: 1,
+                inline_bucket_n: 1,
+                inline_bucket_inuse: 16,
+                ..Default::default()
+            };
+            assert_eq!(tx.bucket(b"empty")?.stats()?, want);
+            let want = BucketStats {
+                key_n: 1,
+                inline_bucket_inuse: 16 + 16 + 6,
+                value_bytes: 3,
+                logical_value_bytes: 3,
+                ..want
+            };
+            assert_eq!(tx.bucket(b"small")?.stats()?, want);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let foo = tx.create_bucket(b"foo")?;
+            for i in 0..100 {
+                foo.put(
+                    format!("{:02}", i).as_bytes(),
+                    format!("{:02}", i).as_bytes(),
+                )?;
+            }
+            let bar = foo.create_bucket(b"bar")?;
+            for i in 0..10 {
+                bar.put(i.to_string().as_bytes(), i.to_string().as_bytes())?;
+            }
+            let baz = bar.create_bucket(b"baz")?;
+            for i in 0..10 {
+                baz.put(i.to_string().as_bytes(), i.to_string().as_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let stats = tx.bucket(b"foo")?.stats()?;
+            assert_eq!(stats.branch_page_n, 0);
+            assert_eq!(stats.branch_overflow_n, 0);
+            // foo and bar have a leaf page each; baz is inlined into bar.
+            assert_eq!(stats.leaf_page_n, 2);
+            assert_eq!(stats.leaf_overflow_n, 0);
+            assert_eq!(stats.key_n, 122);
+            assert_eq!(stats.depth, 3);
+            assert_eq!(stats.branch_inuse, 0);
+
+            let foo = 16 // foo (pghdr)
+                + 101 * 16 // foo leaf elements
+                + 100 * 2 + 100 * 2 // foo leaf key/values
+                + 3 + 16; // foo -> bar key/value
+            let bar = 16 // bar (pghdr)
+                + 11 * 16 // bar leaf elements
+                + 10 + 10 // bar leaf key/values
+                + 3 + 16 + 16 + 10 * 16 + 10 + 10; // bar -> baz key/inline value
+            let baz = 16 // baz (inline) (pghdr)
+                + 10 * 16 // baz leaf elements
+                + 10 + 10; // baz leaf key/values
+            assert_eq!(stats.leaf_inuse, foo + bar);
+            assert_eq!(stats.leaf_alloc, 2 * 4096);
+            assert_eq!(stats.bucket_n, 3);
+            assert_eq!(stats.inline_bucket_n, 1);
+            assert_eq!(stats.inline_bucket_inuse, baz);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn stats_add() {
+        let mut a = BucketStats {
+            leaf_page_n: 1,
+            key_n: 10,
+            depth: 3,
+            bucket_n: 1,
+            ..Default::default()
+        };
+        a.add(&BucketStats {
+            leaf_page_n: 2,
+            key_n: 5,
+            depth: 2,
+            bucket_n: 2,
+            inline_bucket_n: 1,
+            ..Default::default()
+        });
+        let want = BucketStats {
+            leaf_page_n: 3,
+            key_n: 15,
+            depth: 3,
+            bucket_n: 3,
+            inline_bucket_n: 1,
+            ..Default::default()
+        };
+        assert_eq!(a, want);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fill_percent() {
+        fn leaf_pages(fill_percent: f64) -> usize {
+            let dir = tempfile::tempdir().unwrap();
+            let db = open(&dir);
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                assert_eq!(b.fill_percent(), DEFAULT_FILL_PERCENT);
+                b.set_fill_percent(fill_percent);
+                for i in 0..10000u32 {
+                    b.put(&i.to_be_bytes(), &[0; 20])?;
+                }
+
+                // Nested buckets keep the default.
+                assert_eq!(
+                    b.create_bucket(b"sub")?.fill_percent(),
+                    DEFAULT_FILL_PERCENT
+                );
+                Ok(())
+            })
+            .unwrap();
+
+            let mut stats = BucketStats::default();
+            db.view(|tx| {
+                let b = tx.bucket(b"widgets")?;
+                assert_eq!(b.fill_percent(), DEFAULT_FILL_PERCENT);
+                stats = b.stats()?;
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(stats.key_n, 10001);
+            stats.leaf_page_n
+        }
+
+        // Appending with a full fill packs nodes nearly twice as densely.
+        let full = leaf_pages(1.0);
+        let half = leaf_pages(0.5);
+        assert!(full * 18 < half * 10, "{} vs {} pages", full, half);
+
+        // Out of range values are clamped.
+        assert_eq!(leaf_pages(2.0), full);
+        assert_eq!(leaf_pages(0.0), leaf_pages(MIN_FILL_PERCENT));
+    }
+
+    /// rng returns a deterministic xorshift generator for shuffling test data.
+    fn rng(mut seed: u64) -> impl FnMut() -> u64 {
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        }
+    }
+
+    /// spill_and_verify inserts `keys` with values of `value_size` bytes in one
+    /// transaction, then checks the stats of the commit and reads them back.
+    fn spill_and_verify(keys: &[Vec<u8>], value_size: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let before = db.stats();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for k in keys {
+                b.put(k, &vec![k[k.len() - 1]; value_size])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let stats = db.stats().sub(&before).tx_stats;
+        if keys.len() > 1 {
+            assert!(stats.split() > 0);
+        }
+        assert!(stats.spill() > 0);
+        assert!(stats.spill_time() > std::time::Duration::ZERO);
+
+        db.close().unwrap();
+        let db = open(&dir);
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for k in keys {
+                assert_eq!(b.get(k)?, Some(&vec![k[k.len() - 1]; value_size][..]));
+            }
+            assert_eq!(b.stats()?.key_n, keys.len());
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn spill_sequential_inserts() {
+        let keys: Vec<Vec<u8>> = (0..10000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        spill_and_verify(&keys, 100);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn spill_random_inserts() {
+        let mut next = rng(0x9e37_79b9_7f4a_7c15);
+        let mut keys: Vec<Vec<u8>> = (0..10000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for i in (1..keys.len()).rev() {
+            keys.swap(i, (next() % (i as u64 + 1)) as usize);
+        }
+        spill_and_verify(&keys, 100);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn spill_huge_value() {
+        // A single value larger than a page lands on an overflow page of its own.
+        spill_and_verify(&[b"huge".to_vec()], 5 * 4096);
+
+        // Or alongside small neighbours.
+        let mut keys: Vec<Vec<u8>> = (0..10u8).map(|i| vec![b'a', i]).collect();
+        keys.push(b"b".to_vec());
+        spill_and_verify(&keys, 3 * 4096);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn rebalance_after_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let n = 100_000u32;
+        let key = |i: u32| i.to_be_bytes();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..n {
+                b.put(&key(i), &[0; 32])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let pages = |db: &DB| {
+            let tx = db.begin(false).unwrap();
+            assert!(tx.check().is_empty());
+            let stats = tx.bucket(b"widgets").unwrap().stats().unwrap();
+            (
+                stats.key_n,
+                stats.leaf_page_n,
+                stats.branch_page_n,
+                stats.depth,
+            )
+        };
+        let (key_n, full_leaves, full_branches, full_depth) = pages(&db);
+        assert_eq!(key_n, n as usize);
+
+        // Delete 95% of the keys, a batch per transaction.
+        let before = db.stats();
+        for batch in 0..4u32 {
+            db.update(|tx| {
+                let b = tx.bucket(b"widgets")?;
+                for i in (0..n).filter(|i| i % 20 != 0 && i % 4 == batch) {
+                    b.delete(&key(i))?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            pages(&db);
+        }
+        let stats = db.stats().sub(&before).tx_stats;
+        assert!(stats.rebalance() > 0);
+        assert!(stats.rebalance_time() > std::time::Duration::ZERO);
+
+        // Merged leaves account for the space of the remaining keys only.
+        let (key_n, leaves, branches, depth) = pages(&db);
+        assert_eq!(key_n, n as usize / 20);
+        assert!(leaves * 10 < full_leaves, "{} vs {}", leaves, full_leaves);
+        assert!(branches < full_branches);
+        assert!(depth <= full_depth);
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let mut c = b.cursor();
+            let mut i = 0;
+            let mut item = c.first()?;
+            while let (Some(k), _) = item {
+                assert_eq!(k, key(i));
+                i += 20;
+                item = c.next()?;
+            }
+            assert_eq!(i, n);
+            Ok(())
+        })
+        .unwrap();
+
+        // Deleting the rest collapses the tree back to a single leaf.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in (0..n).step_by(20) {
+                b.delete(&key(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(pages(&db), (0, 0, 0, 1));
+    }
+
+    fn collect<'a>(
+        iter: impl Iterator<Item = Result<(&'a [u8], &'a [u8])>>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        iter.map(|item| item.map(|(k, v)| (k.to_vec(), v.to_vec())))
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    fn keys<'a>(iter: impl Iterator<Item = Result<(&'a [u8], &'a [u8])>>) -> Vec<Vec<u8>> {
+        iter.map(|item| item.map(|(k, _)| k.to_vec()))
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn range_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for k in [&b"a"[..], b"b", b"c", b"d"] {
+                b.put(k, k)?;
+            }
+            b.create_bucket(b"bb")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let v = |ks: &[&[u8]]| ks.iter().map(|k| k.to_vec()).collect::<Vec<_>>();
+
+            assert_eq!(keys(b.range(..)), v(&[b"a", b"b", b"c", b"d"]));
+            assert_eq!(keys(b.range(&b"b"[..]..&b"d"[..])), v(&[b"b", b"c"]));
+            assert_eq!(keys(b.range(&b"b"[..]..=&b"d"[..])), v(&[b"b", b"c", b"d"]));
+            assert_eq!(keys(b.range(&b"bb"[..]..)), v(&[b"c", b"d"]));
+            assert_eq!(keys(b.range(..&b"b"[..])), v(&[b"a"]));
+            assert_eq!(
+                keys(b.range((Bound::Excluded(&b"a"[..]), Bound::Excluded(&b"d"[..])))),
+                v(&[b"b", b"c"])
+            );
+            assert_eq!(keys(b.range(..).rev()), v(&[b"d", b"c", b"b", b"a"]));
+            assert_eq!(keys(b.iter_rev()), v(&[b"d", b"c", b"b", b"a"]));
+            assert_eq!(keys(b.range(&b"a"[..]..&b"c"[..]).rev()), v(&[b"b", b"a"]));
+
+            // Empty and inverted ranges yield nothing.
+            assert!(keys(b.range(&b"b"[..]..&b"b"[..])).is_empty());
+            assert!(keys(b.range(&b"c"[..]..&b"b"[..])).is_empty());
+            assert!(keys(b.range(&b"c"[..]..&b"b"[..]).rev()).is_empty());
+            assert!(keys(b.range(&b"e"[..]..)).is_empty());
+
+            // Both ends meet in the middle.
+            let mut iter = b.range(..);
+            assert_eq!(iter.next().unwrap()?.0, b"a");
+            assert_eq!(iter.next_back().unwrap()?.0, b"d");
+            assert_eq!(iter.next_back().unwrap()?.0, b"c");
+            assert_eq!(iter.next().unwrap()?.0, b"b");
+            assert!(iter.next().is_none());
+            assert!(iter.next_back().is_none());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for k in [
+                &b"a"[..],
+                b"ab",
+                b"abc",
+                b"ac",
+                b"b",
+                b"\xff",
+                b"\xff\xff\x01",
+            ] {
+                b.put(k, b"")?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(keys(b.prefix(b"ab")), vec![b"ab".to_vec(), b"abc".to_vec()]);
+            assert_eq!(keys(b.prefix(b"a")).len(), 4);
+            assert_eq!(keys(b.prefix(b"")).len(), 7);
+            assert_eq!(
+                keys(b.prefix(b"\xff")),
+                vec![b"\xff".to_vec(), b"\xff\xff\x01".to_vec()]
+            );
+            assert_eq!(keys(b.prefix(b"\xff\xff")), vec![b"\xff\xff\x01".to_vec()]);
+            assert!(keys(b.prefix(b"abd")).is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn in_range(key: &[u8], start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+        let after_start = match start {
+            Bound::Included(s) => key >= &s[..],
+            Bound::Excluded(s) => key > &s[..],
+            Bound::Unbounded => true,
+        };
+        let before_end = match end {
+            Bound::Included(e) => key <= &e[..],
+            Bound::Excluded(e) => key < &e[..],
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    fn key_strategy() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(prop::sample::select(vec![0u8, 1, 2, 0xfe, 0xff]), 1..5)
+    }
+
+    fn bound_strategy() -> impl Strategy<Value = Bound<Vec<u8>>> {
+        prop_oneof![
+            key_strategy().prop_map(Bound::Included),
+            key_strategy().prop_map(Bound::Excluded),
+            Just(Bound::Unbounded),
+        ]
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn move_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            let a = tx.create_bucket(b"a")?;
+            a.put(b"ka", b"va")?;
+            a.next_sequence()?;
+            let b = a.create_bucket(b"b")?;
+            for i in 0..500u32 {
+                b.put(&i.to_be_bytes(), &[1; 50])?;
+            }
+            b.set_sequence(7)?;
+            b.create_bucket(b"c")?.put(b"kc", b"vc")?;
+            tx.create_bucket(b"dst")?.put(b"x", b"y")?;
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let a = tx.bucket(b"a")?;
+            let dst = tx.bucket(b"dst")?;
+            // Pending changes of an opened bucket move along with it.
+            a.bucket(b"b")?.bucket(b"c")?.put(b"kc2", b"vc2")?;
+            a.move_bucket(b"b", &dst)?;
+            assert!(matches!(a.bucket(b"b"), Err(Error::BucketNotFound)));
+            assert_eq!(
+                dst.bucket(b"b")?.path(),
+                vec![b"dst".to_vec(), b"b".to_vec()]
+            );
+            // Move the whole tree to the top level and back.
+            tx.move_bucket(b"a", None, Some(&dst))?;
+            tx.move_bucket(b"a", Some(&dst), None)?;
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            let a = tx.bucket(b"a")?;
+            assert_eq!(a.get(b"ka")?, Some(&b"va"[..]));
+            assert_eq!(a.sequence(), 1);
+            assert!(a.get(b"b")?.is_none());
+            let b = tx.bucket_path(&["dst", "b"])?;
+            assert_eq!(b.sequence(), 7);
+            assert_eq!(b.count()?, 501);
+            assert_eq!(b.get(&499u32.to_be_bytes())?, Some(&[1; 50][..]));
+            let c = b.bucket(b"c")?;
+            assert_eq!(c.get(b"kc")?, Some(&b"vc"[..]));
+            assert_eq!(c.get(b"kc2")?, Some(&b"vc2"[..]));
+            assert_eq!(tx.bucket(b"dst")?.get(b"x")?, Some(&b"y"[..]));
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let dst = tx.bucket(b"dst")?;
+            let b = dst.bucket(b"b")?;
+            let c = b.bucket(b"c")?;
+            assert!(matches!(
+                tx.move_bucket(b"b", Some(&dst), Some(&b)),
+                Err(Error::MoveIntoDescendant)
+            ));
+            assert!(matches!(
+                tx.move_bucket(b"b", Some(&dst), Some(&c)),
+                Err(Error::MoveIntoDescendant)
+            ));
+            // An inline bucket is copied into an inline destination.
+            let x = tx.create_bucket(b"x")?;
+            x.put(b"k", b"v")?;
+            tx.move_bucket(b"c", Some(&b), Some(&x))?;
+            assert!(matches!(
+                dst.move_bucket(b"b", &dst),
+                Err(Error::SameBuckets)
+            ));
+            assert!(matches!(
+                dst.move_bucket(b"x", &b),
+                Err(Error::IncompatibleValue)
+            ));
+            assert!(matches!(
+                dst.move_bucket(b"missing", &b),
+                Err(Error::BucketNotFound)
+            ));
+            tx.create_bucket(b"b")?;
+            assert!(matches!(
+                tx.move_bucket(b"b", Some(&dst), None),
+                Err(Error::BucketExists)
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            let c = tx.bucket_path(&["x", "c"])?;
+            assert_eq!(c.get(b"kc2")?, Some(&b"vc2"[..]));
+            assert_eq!(tx.bucket(b"x")?.get(b"k")?, Some(&b"v"[..]));
+            assert!(tx.bucket_path(&["dst", "b", "c"]).is_err());
+            assert!(matches!(
+                tx.bucket(b"a")?.move_bucket(b"b", &tx.root()),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn get_corrupted() {
+        use crate::tx_check::tests::{bucket_value, build, write_branch, write_leaf};
+
+        // The root of widgets refers to a page past the high water mark.
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 5, |buf| {
+            let widgets = bucket_value(4);
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"widgets", &widgets)]);
+            write_branch(buf, 4, &[(b"a", 9)]);
+        });
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert!(matches!(b.get(b"foo"), Err(Error::Corrupted { .. })));
+            assert!(matches!(b.get_owned(b"foo"), Err(Error::Corrupted { .. })));
+            assert!(matches!(b.get_u64(7), Err(Error::Corrupted { .. })));
+
+            // Cursor moves and iterators report the page too, and an
+            // iterator ends after its error.
+            let mut c = b.cursor();
+            assert!(matches!(c.first(), Err(Error::Corrupted { .. })));
+            assert!(matches!(c.seek(b"foo"), Err(Error::Corrupted { .. })));
+            assert!(matches!(c.seek_exact(b"foo"), Err(Error::Corrupted { .. })));
+            assert!(matches!(c.current(), Ok(None)));
+            let mut iter = b.iter_rev();
+            assert!(matches!(iter.next(), Some(Err(Error::Corrupted { .. }))));
+            assert!(iter.next().is_none());
+            assert!(matches!(
+                b.range(..).next(),
+                Some(Err(Error::Corrupted { .. }))
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_reserve() {
+        /// record serializes a record of `n` u64s, as big-endian words.
+        fn record(out: &mut [u8], n: u64) {
+            for (i, chunk) in out.chunks_exact_mut(8).enumerate() {
+                chunk.copy_from_slice(&(n * 1000 + i as u64).to_be_bytes());
+            }
+        }
+
+        // The same records written with put and with put_reserve, small ones
+        // and ones spanning overflow pages.
+        let sizes = [8usize, 800, 20_000];
+        let image = |reserve: bool| {
+            let dir = tempfile::tempdir().unwrap();
+            let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+            db.update(|tx| {
+                let b = tx.create_bucket(b"records")?;
+                for (n, &size) in sizes.iter().enumerate() {
+                    let key = [n as u8 + 1];
+                    if reserve {
+                        let mut slot = b.put_reserve(&key, size)?;
+                        assert!(slot.iter().all(|&b| b == 0));
+                        record(&mut slot, n as u64);
+                        slot.finish()?;
+                    } else {
+                        let mut buf = vec![0; size];
+                        record(&mut buf, n as u64);
+                        b.put(&key, &buf)?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+            let mut image = Vec::new();
+            db.view(|tx| tx.write_to(&mut image).map(drop)).unwrap();
+            image
+        };
+        assert_eq!(image(true), image(false));
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            // The reserved value is stored when the slot is dropped, over any
+            // put of the same key made in the meantime.
+            {
+                let mut slot = b.put_reserve(b"foo", 3)?;
+                slot.copy_from_slice(b"new");
+                b.put(b"foo", b"old")?;
+                assert_eq!(b.get(b"foo")?, Some(&b"old"[..]));
+            }
+            assert_eq!(b.get(b"foo")?, Some(&b"new"[..]));
+
+            let slot = b.put_reserve(b"bar", 1)?;
+            b.create_bucket(b"bar")?;
+            assert!(matches!(slot.finish(), Err(Error::IncompatibleValue)));
+            assert!(matches!(
+                b.put_reserve(b"bar", 1),
+                Err(Error::IncompatibleValue)
+            ));
+            assert!(matches!(b.put_reserve(b"", 1), Err(Error::KeyRequired)));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(matches!(
+                tx.bucket(b"widgets")?.put_reserve(b"foo", 1),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        // A dropped slot that can't be stored fails the commit.
+        let result = db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let slot = b.put_reserve(b"baz", 1)?;
+            b.create_bucket(b"baz")?;
+            drop(slot);
+            b.put(b"qux", b"lost")
+        });
+        assert!(matches!(result, Err(Error::IncompatibleValue)));
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert!(b.bucket(b"baz").is_err());
+            assert_eq!(b.get(b"qux")?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn put_sorted() {
+        let kv = |i: u32| (i.to_be_bytes().to_vec(), vec![i as u8; 100]);
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            // The same keys loaded with put and with put_sorted split into
+            // the same pages, also at a lower fill percent.
+            let naive = tx.create_bucket(b"naive")?;
+            let sorted = tx.create_bucket(b"sorted")?;
+            naive.set_fill_percent(0.3);
+            sorted.set_fill_percent(0.3);
+            for i in 0..10_000 {
+                let (k, v) = kv(i);
+                naive.put(&k, &v)?;
+            }
+            assert_eq!(sorted.put_sorted((0..10_000).map(kv))?, 10_000);
+            assert_eq!(sorted.get(&kv(1234).0)?, Some(&kv(1234).1[..]));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            let (naive, sorted) = (tx.bucket(b"naive")?, tx.bucket(b"sorted")?);
+            assert_eq!(naive.stats()?, sorted.stats()?);
+            assert!(naive.stats()?.leaf_page_n > 100);
+            assert_eq!(collect(naive.range(..)), collect(sorted.range(..)));
+            Ok(())
+        })
+        .unwrap();
+
+        // Keys below the last key of the bucket are put, overwriting existing
+        // ones, and the rest appended.
+        db.update(|tx| {
+            let b = tx.bucket(b"sorted")?;
+            let pairs = [(5000, 1), (5001, 2), (20_000, 3), (20_001, 4)];
+            let pairs = pairs.iter().map(|&(i, v)| (kv(i).0, vec![v]));
+            assert_eq!(b.put_sorted(pairs)?, 4);
+            assert_eq!(b.get(&kv(5000).0)?, Some(&[1][..]));
+            assert_eq!(b.get(&kv(20_001).0)?, Some(&[4][..]));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            assert_eq!(tx.bucket(b"sorted")?.stats()?.key_n, 10_002);
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.create_bucket(b"bar")?;
+            let pairs = vec![(b"a".to_vec(), vec![]), (b"c".to_vec(), vec![])];
+            let pairs = pairs.into_iter().chain(vec![(b"b".to_vec(), vec![])]);
+            match b.put_sorted(pairs) {
+                Err(Error::KeyOutOfOrder { key }) => assert_eq!(key, b"b"),
+                res => panic!("unexpected {:?}", res),
+            }
+            // The keys before the offending one are kept.
+            assert_eq!(b.get(b"c")?, Some(&[][..]));
+
+            let pairs = vec![(b"bar".to_vec(), vec![])].into_iter();
+            assert!(matches!(b.put_sorted(pairs), Err(Error::IncompatibleValue)));
+            let pairs = vec![(b"d".to_vec(), vec![]), (b"d".to_vec(), vec![])];
+            assert!(matches!(
+                b.put_sorted(pairs.into_iter()),
+                Err(Error::KeyOutOfOrder { .. })
+            ));
+            let pairs = vec![(vec![], vec![])].into_iter();
+            assert!(matches!(b.put_sorted(pairs), Err(Error::KeyRequired)));
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let pairs = vec![(b"e".to_vec(), vec![])].into_iter();
+            assert!(matches!(
+                tx.bucket(b"widgets")?.put_sorted(pairs),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn count_matches_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            let small = tx.create_bucket(b"small")?;
+            assert_eq!(b.count()?, 0);
+            for i in 0..5000u32 {
+                b.put(&i.to_be_bytes(), &[0; 20])?;
+            }
+            small.put(b"foo", b"bar")?;
+            // Uncommitted keys are counted.
+            assert_eq!(b.count()?, 5000);
+            assert_eq!(small.count()?, 1);
+            Ok(())
+        })
+        .unwrap();
+
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.count()? as usize, b.stats()?.key_n);
+            assert_eq!(
+                tx.bucket(b"small")?.count()? as usize,
+                tx.bucket(b"small")?.stats()?.key_n
+            );
+
+            // Changes materialize some nodes while others stay pages.
+            for i in (0..5000u32).step_by(7) {
+                b.delete(&i.to_be_bytes())?;
+            }
+            b.put(b"\xff", b"last")?;
+            assert_eq!(b.count()?, 5000 - 715 + 1);
+
+            // Nested bucket names count as keys of their parent only.
+            b.create_bucket(b"nested")?.put(b"a", b"b")?;
+            assert_eq!(b.count()?, 5000 - 715 + 2);
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let stats = b.stats()?;
+            assert!(stats.branch_page_n > 0);
+            // key_n includes the keys of nested buckets.
+            assert_eq!(b.count()? as usize, stats.key_n - 1);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let key = |i: u32| i.to_be_bytes();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..2000 {
+                b.put(&key(i), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // The first keys of the leaf pages of the bucket, in order.
+        let tx = db.begin(false).unwrap();
+        let hwm = tx.meta.get().pgid;
+        let mut firsts: Vec<Vec<u8>> = (2..hwm)
+            .filter(|&id| tx.page(id).unwrap().is_some_and(|p| p.typ == "leaf"))
+            .map(|id| db.page_item(id, 0).unwrap().0)
+            .filter(|k| k.len() == 4)
+            .collect();
+        firsts.sort();
+        assert!(firsts.len() > 10);
+        assert!(firsts.windows(2).all(|w| w[0] < w[1]));
+        drop(tx);
+        let boundary = firsts
+            .iter()
+            .map(|k| u32::from_be_bytes(k[..].try_into().unwrap()))
+            .find(|&i| i > 200)
+            .unwrap();
+
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            // Empty ranges.
+            assert_eq!(b.delete_range(&key(10)[..]..&key(10)[..])?, 0);
+            assert_eq!(b.delete_range(&b"zzzz"[..]..)?, 0);
+            assert_eq!(b.delete_prefix(b"\xff")?, 0);
+
+            // A range ending exactly on the first key of a leaf, and one
+            // starting right after it.
+            assert_eq!(
+                b.delete_range(&key(100)[..]..&key(boundary)[..])?,
+                u64::from(boundary - 100)
+            );
+            assert_eq!(b.get(&key(boundary))?, Some(&[0; 100][..]));
+            assert_eq!(b.get(&key(boundary - 1))?, None);
+            assert_eq!(
+                b.delete_range((
+                    Bound::Excluded(&key(boundary)[..]),
+                    Bound::Included(&key(boundary + 10)[..])
+                ))?,
+                10
+            );
+            assert_eq!(b.get(&key(boundary))?, Some(&[0; 100][..]));
+
+            // Keys 0x00000100 to 0x000001ff share the prefix [0, 0, 1].
+            let remaining = b.range(&key(0x100)[..]..&key(0x200)[..]).count() as u64;
+            assert_eq!(b.delete_prefix(&[0, 0, 1])?, remaining);
+
+            // Nested buckets in the range are rejected and nothing is removed.
+            b.create_bucket(&key(5000))?;
+            assert!(matches!(b.delete_range(..), Err(Error::IncompatibleValue)));
+            b.delete_bucket(&key(5000))?;
+            assert_eq!(b.get(&key(0))?, Some(&[0; 100][..]));
+
+            let left = b.range(..).count() as u64;
+            assert_eq!(b.delete_range(..)?, left);
+            assert_eq!(b.range(..).count(), 0);
+            Ok(())
+        })
+        .unwrap();
+
+        let tx = db.begin(false).unwrap();
+        assert!(tx.check().is_empty());
+        assert_eq!(tx.bucket(b"widgets").unwrap().stats().unwrap().key_n, 0);
+        drop(tx);
+        db.view(|tx| {
+            assert!(matches!(
+                tx.bucket(b"widgets")?.delete_prefix(b""),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn compare_and_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            assert!(b.put_if_absent(b"foo", b"1")?);
+            assert!(!b.put_if_absent(b"foo", b"2")?);
+            assert_eq!(b.get(b"foo")?, Some(&b"1"[..]));
+
+            assert!(!b.compare_and_swap(b"foo", None, Some(b"2"))?);
+            assert!(!b.compare_and_swap(b"foo", Some(b"2"), Some(b"3"))?);
+            assert!(b.compare_and_swap(b"foo", Some(b"1"), Some(b"2"))?);
+            assert_eq!(b.get(b"foo")?, Some(&b"2"[..]));
+
+            // A None value deletes the key; a None expectation requires it
+            // to be absent.
+            assert!(b.compare_and_swap(b"foo", Some(b"2"), None)?);
+            assert_eq!(b.get(b"foo")?, None);
+            assert!(!b.compare_and_swap(b"foo", Some(b"2"), None)?);
+            assert!(b.compare_and_swap(b"foo", None, None)?);
+            assert!(b.compare_and_swap(b"foo", None, Some(b""))?);
+            assert_eq!(b.get(b"foo")?, Some(&b""[..]));
+
+            // Nested buckets read as absent, but can't be overwritten.
+            b.create_bucket(b"sub")?;
+            assert!(matches!(
+                b.put_if_absent(b"sub", b"x"),
+                Err(Error::IncompatibleValue)
+            ));
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert!(matches!(
+                b.put_if_absent(b"bar", b"1"),
+                Err(Error::TxNotWritable)
+            ));
+            assert!(matches!(
+                b.compare_and_swap(b"foo", Some(b""), None),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn compare_and_swap_counter() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| tx.create_bucket(b"counters").map(drop))
+            .unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        db.batch(|tx| {
+                            let b = tx.bucket(b"counters")?;
+                            loop {
+                                let current = b.get(b"hits")?.map(<[u8]>::to_vec);
+                                let n = current
+                                    .as_deref()
+                                    .map_or(0, |v| u64::from_be_bytes(v.try_into().unwrap()));
+                                let next = (n + 1).to_be_bytes();
+                                if b.compare_and_swap(b"hits", current.as_deref(), Some(&next))? {
+                                    return Ok(());
+                                }
+                            }
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        db.view(|tx| {
+            let v = tx.bucket(b"counters")?.get(b"hits")?.unwrap();
+            assert_eq!(u64::from_be_bytes(v.try_into().unwrap()), 200);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn range_matches_btreemap(
+            entries in prop::collection::btree_map(key_strategy(), prop::collection::vec(any::<u8>(), 0..200), 0..300),
+            start in bound_strategy(),
+            end in bound_strategy(),
+            pulls in prop::collection::vec(any::<bool>(), 0..400),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let db = open(&dir);
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for (k, v) in &entries {
+                    b.put(k, v)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+            let expected: Vec<(Vec<u8>, Vec<u8>)> = entries
+                .iter()
+                .filter(|(k, _)| in_range(k, &start, &end))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+
+            let tx = db.begin(false).unwrap();
+            let b = tx.bucket(b"widgets").unwrap();
+            prop_assert_eq!(&collect(b.range(range)), &expected);
+            let mut reversed = collect(b.range(range).rev());
+            reversed.reverse();
+            prop_assert_eq!(&reversed, &expected);
+
+            // Pull from both ends in a random order.
+            let mut iter = b.range(range);
+            let mut oracle: std::collections::VecDeque<_> = expected.iter().collect();
+            for front in pulls {
+                let got = if front { iter.next() } else { iter.next_back() }.transpose().unwrap();
+                let want = if front { oracle.pop_front() } else { oracle.pop_back() };
+                prop_assert_eq!(got, want.map(|(k, v)| (&k[..], &v[..])));
+            }
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn prefix_matches_btreemap(
+            keys in prop::collection::btree_set(key_strategy(), 0..300),
+            prefix in prop::collection::vec(prop::sample::select(vec![0u8, 1, 0xff]), 0..3),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let db = open(&dir);
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for k in &keys {
+                    b.put(k, k)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+            let expected: Vec<_> = keys.iter().filter(|k| k.starts_with(&prefix)).cloned().collect();
+            let tx = db.begin(false).unwrap();
+            let b = tx.bucket(b"widgets").unwrap();
+            let got: Vec<_> = b.prefix(&prefix).map(|item| item.unwrap().0.to_vec()).collect();
+            prop_assert_eq!(&got, &expected);
+            let mut got: Vec<_> = b.prefix(&prefix).rev().map(|item| item.unwrap().0.to_vec()).collect();
+            got.reverse();
+            prop_assert_eq!(&got, &expected);
+        }
+
+        /// Every read of a write transaction must see its own puts and
+        /// deletes, whether they land on dirty nodes or shadow committed
+        /// pages.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn reads_own_writes(
+            committed in prop::collection::btree_map(key_strategy(), prop::collection::vec(any::<u8>(), 0..200), 0..300),
+            ops in prop::collection::vec(
+                (key_strategy(), prop::option::of(prop::collection::vec(any::<u8>(), 0..200)), key_strategy()),
+                1..100,
+            ),
+            start in bound_strategy(),
+            end in bound_strategy(),
+        ) {
+            let dir = tempfile::tempdir().unwrap();
+            let db = open(&dir);
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for (k, v) in &committed {
+                    b.put(k, v)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+            let mut oracle = committed;
+            let mut tx = db.begin(true).unwrap();
+            let b = tx.bucket(b"widgets").unwrap();
+            for (key, value, seek) in ops {
+                // None deletes the key.
+                match value {
+                    Some(v) => {
+                        b.put(&key, &v).unwrap();
+                        oracle.insert(key.clone(), v);
+                    }
+                    None => {
+                        b.delete(&key).unwrap();
+                        oracle.remove(&key);
+                    }
+                }
+
+                let expected: Vec<_> = oracle.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                prop_assert_eq!(b.get(&key).unwrap(), oracle.get(&key).map(Vec::as_slice));
+                prop_assert_eq!(b.get(&seek).unwrap(), oracle.get(&seek).map(Vec::as_slice));
+                prop_assert_eq!(&collect(b.range(..)), &expected);
+                let mut reversed = collect(b.iter_rev());
+                reversed.reverse();
+                prop_assert_eq!(&reversed, &expected);
+
+                let mut visited = Vec::new();
+                b.for_each(|k, v| {
+                    visited.push((k.to_vec(), v.unwrap().to_vec()));
+                    Ok(())
+                })
+                .unwrap();
+                prop_assert_eq!(&visited, &expected);
+
+                let range = (start.as_ref().map(|k| &k[..]), end.as_ref().map(|k| &k[..]));
+                let in_bounds: Vec<_> = expected
+                    .iter()
+                    .filter(|(k, _)| in_range(k, &start, &end))
+                    .cloned()
+                    .collect();
+                prop_assert_eq!(&collect(b.range(range)), &in_bounds);
+
+                // Seek lands on the first key at or after `seek`, and the
+                // cursor walks on from there in both directions.
+                let mut c = b.cursor();
+                let mut after = oracle.range(seek.clone()..);
+                let (k, v) = c.seek(&seek)?;
+                let want = after.next();
+                prop_assert_eq!(k, want.map(|(k, _)| &k[..]));
+                prop_assert_eq!(v, want.map(|(_, v)| &v[..]));
+                prop_assert_eq!(c.next()?.0, after.next().map(|(k, _)| &k[..]));
+                let mut c = b.cursor();
+                c.seek(&seek)?;
+                let before = oracle.range(..seek.clone()).next_back();
+                prop_assert_eq!(c.prev()?.0, before.map(|(k, _)| &k[..]));
+            }
+            tx.rollback().unwrap();
+        }
+    }
+}
@@ -0,0 +1,212 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::path::Path;
+
+use crate::bucket::MAX_FILL_PERCENT;
+use crate::db::{Options, DB};
+use crate::errors::Result;
+use crate::tx::Tx;
+
+impl DB {
+    /// CompactTo creates a copy of the database at `dst` and writes every
+    /// bucket and key of this database into it, in order. This may reclaim
+    /// space that this database no longer has use for.
+    ///
+    /// The destination pages are filled completely, and `tx_max_size` limits
+    /// the pending bytes of each destination transaction, see
+    /// `Tx::pending_bytes`, triggering intermittent commits. A value of zero ignores transaction
+    /// sizes. The source is read through a single read-only transaction, so
+    /// writers are never blocked by the copy.
+    pub fn compact_to<P: AsRef<Path>>(&self, dst: P, tx_max_size: u64) -> Result<()> {
+        let src = self.begin(false)?;
+        let dst = DB::open(
+            dst,
+            Options {
+                page_size: src.db.page_size,
+                page_transform: src.db.page_transform.clone(),
+                ..Options::default()
+            },
+        )?;
+
+        // Commit regularly, or we'll run out of memory for large datasets if
+        // using one transaction.
+        let mut tx = dst.begin(true)?;
+        tx.set_global_sequence(src.global_sequence()?)?;
+        walk(&src, &mut |keys, k, v, seq| {
+            // On each key/value, check if we have exceeded tx size.
+            if tx_max_size != 0 && tx.pending_bytes() as u64 >= tx_max_size {
+                // Commit previous transaction.
+                tx.commit()?;
+
+                // Start new transaction.
+                tx = dst.begin(true)?;
+            }
+
+            // Create bucket on the root transaction if this is the first level.
+            let b = match keys.split_first() {
+                None => {
+                    let b = tx.create_bucket(k)?;
+                    return b.set_sequence(seq);
+                }
+                Some((first, rest)) => {
+                    // Otherwise find the bucket for the parent path of the key.
+                    let mut b = tx.bucket(first)?;
+                    for k in rest {
+                        b = b.bucket(k)?;
+                    }
+                    b
+                }
+            };
+
+            // Fill the entire page for best compaction.
+            b.set_fill_percent(MAX_FILL_PERCENT);
+
+            // If there is no value then this is a bucket call.
+            match v {
+                None => b.create_bucket(k)?.set_sequence(seq),
+                // Otherwise treat it as a key/value pair.
+                Some(v) => b.put(k, v),
+            }
+        })?;
+        tx.commit()?;
+        dst.close()
+    }
+}
+
+/// WalkFunc is the type of the function called for keys (buckets and "normal"
+/// values) discovered by walk. `keys` is the list of keys to descend to the
+/// bucket owning the discovered key/value pair `k`/`v`, and `seq` is the
+/// sequence of a discovered bucket.
+type WalkFunc<'a> = dyn FnMut(&[&[u8]], &[u8], Option<&[u8]>, u64) -> Result<()> + 'a;
+
+/// walk walks recursively the bolt database, calling `f` for every bucket and
+/// key/value pair. A bucket is always visited before its contents.
+fn walk(tx: &Tx, f: &mut WalkFunc<'_>) -> Result<()> {
+    tx.for_each_recursive(|path, b| {
+        // Execute callback for the bucket itself.
+        // The walk starts below the root, so the path always names a bucket.
+        let (name, keypath) = match path.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        f(keypath, name, None, b.sequence())?;
+
+        // Then for each of its key/value pairs; nested buckets are visited
+        // by the walk itself.
+        b.for_each(|k, v| match v {
+            Some(v) => f(path, k, Some(v), 0),
+            None => Ok(()),
+        })
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Dump maps the key path of every bucket and key/value pair to its value
+    /// and, for buckets, sequence.
+    type Dump = BTreeMap<Vec<Vec<u8>>, (Option<Vec<u8>>, u64)>;
+
+    /// dump flattens every bucket, sequence and key/value pair of the database.
+    fn dump(db: &DB) -> Dump {
+        let mut out = BTreeMap::new();
+        db.view(|tx| {
+            walk(tx, &mut |keys, k, v, seq| {
+                let mut path: Vec<Vec<u8>> = keys.iter().map(|k| k.to_vec()).collect();
+                path.push(k.to_vec());
+                out.insert(path, (v.map(<[u8]>::to_vec), seq));
+                Ok(())
+            })
+        })
+        .unwrap();
+        out
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn compact_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("src"), Options::default()).unwrap();
+
+        // Churn: fill the database, then delete most of it again.
+        db.update(|tx| {
+            tx.set_global_sequence(7)?;
+            for name in [&b"widgets"[..], b"gadgets"] {
+                let b = tx.create_bucket(name)?;
+                b.set_sequence(42)?;
+                for i in 0..5000u32 {
+                    b.put(&i.to_be_bytes(), &[i as u8; 200])?;
+                }
+                let nested = b.create_bucket(b"nested")?;
+                nested.create_bucket(b"empty")?;
+                for i in 0..100u32 {
+                    nested.put(&i.to_be_bytes(), b"value")?;
+                }
+                nested.next_sequence()?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            for name in [&b"widgets"[..], b"gadgets"] {
+                let b = tx.bucket(name)?;
+                for i in (0..5000u32).filter(|i| i % 10 != 0) {
+                    b.delete(&i.to_be_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let dst = dir.path().join("dst");
+        db.compact_to(&dst, 64 * 1024).unwrap();
+
+        let compacted = DB::open(&dst, Options::default()).unwrap();
+        let want = dump(&db);
+        assert_eq!(want.len(), 2 * (500 + 1 + 1 + 100 + 1));
+        assert_eq!(dump(&compacted), want);
+        assert_eq!(
+            want[&vec![b"widgets".to_vec(), b"nested".to_vec()]],
+            (None, 1)
+        );
+        compacted
+            .view(|tx| {
+                assert!(tx.check().is_empty());
+                assert_eq!(tx.global_sequence()?, 7);
+                Ok(())
+            })
+            .unwrap();
+
+        let size = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().len();
+        assert!(
+            size("dst") * 2 < size("src"),
+            "{} vs {}",
+            size("dst"),
+            size("src")
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn compact_to_single_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("src"), Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let dst = dir.path().join("dst");
+        db.compact_to(&dst, 0).unwrap();
+        let compacted = DB::open(&dst, Options::default()).unwrap();
+        assert_eq!(dump(&compacted), dump(&db));
+    }
+}
@@ -0,0 +1,568 @@
+//! Tests pinning the on-disk format to bbolt's documented page layout.
+//!
+//! The expected bytes are derived from bbolt's page and meta layouts: a 16
+//! byte page header (`id u64, flags u16, count u16, overflow u32`), followed
+//! by the meta (`magic u32, version u32, pageSize u32, flags u32, root
+//! {root u64, sequence u64}, freelist u64, pgid u64, txid u64, checksum
+//! u64`), every field little-endian and fixed width on all targets.
+//!
+//! The files under `tests/fixtures` are read as well. They were assembled by
+//! `write_fixtures` from the same layout, not written by Go bbolt, so they
+//! check that the crate reads and copies files it did not write itself, not
+//! compatibility with Go; see the README there.
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bucket::{InBucket, BUCKET_HEADER_SIZE};
+use crate::db::{lock, Options, DB};
+use crate::freelist::FreelistType;
+use crate::meta::{Meta, MAGIC, VERSION};
+use crate::page::{
+    write_u32, write_u64, PageMut, Pgid, BRANCH_PAGE_ELEMENT_SIZE, BRANCH_PAGE_FLAG,
+    BUCKET_LEAF_FLAG, FREELIST_PAGE_FLAG, LEAF_PAGE_ELEMENT_SIZE, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+    PGID_SIZE,
+};
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+/// header returns the id, flags, count and overflow of page `id`.
+fn header(buf: &[u8], page_size: usize, id: usize) -> (u64, u16, u16, u32) {
+    let off = id * page_size;
+    (
+        u64_at(buf, off),
+        u16_at(buf, off + 8),
+        u16_at(buf, off + 10),
+        u32_at(buf, off + 12),
+    )
+}
+
+fn open(path: &Path, page_size: usize, freelist_type: FreelistType) -> DB {
+    DB::open(
+        path,
+        Options {
+            page_size,
+            freelist_type,
+            ..Options::default()
+        },
+    )
+    .unwrap()
+}
+
+/// fill writes nested buckets, small values and values spanning overflow
+/// pages.
+fn fill(db: &DB) {
+    db.update(|tx| {
+        let b = tx.create_bucket(b"widgets")?;
+        b.set_sequence(7)?;
+        for i in 0..500u32 {
+            b.put(&i.to_be_bytes(), &[i as u8; 50])?;
+        }
+        let nested = b.create_bucket(b"nested")?;
+        nested.put(b"inline", b"value")?;
+        let deep = nested.create_bucket(b"deep")?;
+        for i in 0..3u32 {
+            deep.put(&i.to_be_bytes(), &vec![i as u8; 70_000])?;
+        }
+        Ok(())
+    })
+    .unwrap();
+    db.update(|tx| {
+        let b = tx.bucket(b"widgets")?;
+        for i in (0..500u32).step_by(3) {
+            b.delete(&i.to_be_bytes())?;
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+fn dump(db: &DB) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut out = Vec::new();
+    db.view(|tx| {
+        let b = tx.bucket(b"widgets")?;
+        assert_eq!(b.sequence(), 7);
+        b.for_each(|k, v| {
+            out.push((k.to_vec(), v.map(<[u8]>::to_vec)));
+            Ok(())
+        })?;
+        let deep = b.bucket(b"nested")?.bucket(b"deep")?;
+        deep.for_each(|k, v| {
+            out.push((k.to_vec(), v.map(<[u8]>::to_vec)));
+            Ok(())
+        })
+    })
+    .unwrap();
+    out
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn init_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    open(&path, 4096, FreelistType::Array).close().unwrap();
+
+    let buf = fs::read(&path).unwrap();
+    assert_eq!(buf.len(), 4 * 4096);
+
+    for id in 0..2 {
+        assert_eq!(header(&buf, 4096, id), (id as u64, 0x04, 0, 0));
+        let m = &buf[id * 4096 + 16..];
+        assert_eq!(&m[0..4], &[0xed, 0xda, 0x0c, 0xed], "magic");
+        assert_eq!(u32_at(m, 4), 2, "version");
+        assert_eq!(u32_at(m, 8), 4096, "page size");
+        assert_eq!(u32_at(m, 12), 0, "flags");
+        assert_eq!((u64_at(m, 16), u64_at(m, 24)), (3, 0), "root bucket");
+        assert_eq!(u64_at(m, 32), 2, "freelist");
+        assert_eq!(u64_at(m, 40), 4, "high water mark");
+        assert_eq!(u64_at(m, 48), id as u64, "txid");
+    }
+    assert_eq!(header(&buf, 4096, 2), (2, 0x10, 0, 0));
+    assert_eq!(header(&buf, 4096, 3), (3, 0x02, 0, 0));
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn page_sizes() {
+    for &page_size in &[4096, 8192, 16384, 32768, 65536] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = open(&path, page_size, FreelistType::Array);
+        fill(&db);
+        let want = dump(&db);
+        db.close().unwrap();
+
+        // The page size is read back from the file, whatever is requested.
+        let db = open(&path, 4096, FreelistType::Array);
+        assert_eq!(db.begin(false).unwrap().db.page_size, page_size);
+        assert_eq!(dump(&db), want);
+        assert!(db.begin(false).unwrap().check().is_empty());
+
+        // Every page starts with its own id.
+        let buf = fs::read(&path).unwrap();
+        let hwm = db.begin(false).unwrap().meta.get().pgid;
+        let mut id = 0;
+        while id < hwm as usize {
+            let (pgid, _, _, overflow) = header(&buf, page_size, id);
+            if pgid != 0 || id == 0 {
+                assert_eq!(pgid, id as u64);
+            }
+            id += 1 + overflow as usize;
+        }
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn freelist_backends() {
+    // Both backends write the same freelist page, so either can read a file
+    // written by the other.
+    let types = [FreelistType::Array, FreelistType::HashMap];
+    for &from in &types {
+        for &to in &types {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db");
+            let db = open(&path, 4096, from);
+            fill(&db);
+            // Pages freed by the last commit are still pending, but written
+            // to the freelist page all the same.
+            let free = lock(&db.begin(false).unwrap().db.freelist).count();
+            assert!(free > 0);
+            let want = dump(&db);
+            db.close().unwrap();
+
+            let db = open(&path, 4096, to);
+            let tx = db.begin(false).unwrap();
+            assert_eq!(lock(&tx.db.freelist).free_count(), free);
+            drop(tx);
+            assert_eq!(dump(&db), want);
+            db.update(|tx| tx.create_bucket(b"more")?.put(b"k", &[0; 10_000]))
+                .unwrap();
+            assert!(db.begin(false).unwrap().check().is_empty());
+        }
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn write_to_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+    let db = open(&path, 4096, FreelistType::Array);
+    fill(&db);
+    let want = dump(&db);
+
+    let copy = dir.path().join("copy");
+    let mut out = Vec::new();
+    db.begin(false).unwrap().write_to(&mut out).unwrap();
+    fs::write(&copy, &out).unwrap();
+    let src = fs::read(&path).unwrap();
+
+    // Data pages are copied unchanged. Both meta pages of the copy hold the
+    // meta of the copying transaction, the second one with the previous txid.
+    let meta = db.begin(false).unwrap().meta.get();
+    let hwm = meta.pgid as usize;
+    assert_eq!(out.len(), hwm * 4096);
+    assert_eq!(&out[2 * 4096..], &src[2 * 4096..hwm * 4096]);
+    assert_eq!(&out[16..16 + 48], &out[4096 + 16..4096 + 16 + 48]);
+    assert_eq!(u64_at(&out, 16 + 48), meta.txid);
+    assert_eq!(u64_at(&out, 4096 + 16 + 48), meta.txid - 1);
+    db.close().unwrap();
+
+    let db = open(&copy, 4096, FreelistType::Array);
+    assert_eq!(dump(&db), want);
+    assert!(db.begin(false).unwrap().check().is_empty());
+}
+
+/// Fixture is a file under `tests/fixtures`, holding the entries of
+/// `fixture_entries`.
+struct Fixture {
+    name: &'static str,
+    page_size: usize,
+    /// Whether the free pages are scattered between the data pages, as the
+    /// hashmap freelist leaves them, rather than at the end of the file.
+    fragmented: bool,
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "handmade-1024.db",
+        page_size: 1024,
+        fragmented: false,
+    },
+    Fixture {
+        name: "handmade-4096.db",
+        page_size: 4096,
+        fragmented: false,
+    },
+    Fixture {
+        name: "handmade-16384.db",
+        page_size: 16384,
+        fragmented: false,
+    },
+    Fixture {
+        name: "handmade-hashmap-4096.db",
+        page_size: 4096,
+        fragmented: true,
+    },
+];
+
+fn fixture_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Entry is a key of a fixture: the path of buckets holding it, the key and
+/// its value, None for a nested bucket.
+type Entry = (Vec<Vec<u8>>, Vec<u8>, Option<Vec<u8>>);
+
+/// big is the value spanning overflow pages.
+fn big() -> Vec<u8> {
+    (0..20_000).map(|i| (i % 251) as u8).collect()
+}
+
+/// fixture_entries lists the entries every fixture holds, in the order a
+/// depth-first walk visits them. The widgets bucket has a sequence of 7.
+fn fixture_entries() -> Vec<Entry> {
+    let path = |names: &[&[u8]]| names.iter().map(|n| n.to_vec()).collect::<Vec<_>>();
+    let mut out = vec![(path(&[]), b"widgets".to_vec(), None)];
+    for i in (0..100u32).filter(|i| i % 3 != 0) {
+        let value = vec![i as u8; 50];
+        out.push((path(&[b"widgets"]), i.to_be_bytes().to_vec(), Some(value)));
+    }
+    out.push((path(&[b"widgets"]), b"inline".to_vec(), None));
+    out.push((
+        path(&[b"widgets", b"inline"]),
+        b"k".to_vec(),
+        Some(b"value".to_vec()),
+    ));
+    out.push((path(&[b"widgets"]), b"nested".to_vec(), None));
+    out.push((path(&[b"widgets", b"nested"]), b"big".to_vec(), Some(big())));
+    out.push((path(&[b"widgets", b"nested"]), b"deep".to_vec(), None));
+    let deep = path(&[b"widgets", b"nested", b"deep"]);
+    out.push((deep, b"x".to_vec(), Some(b"y".to_vec())));
+    out
+}
+
+/// walk lists the entries of a database as fixture_entries does.
+fn walk(db: &DB) -> Vec<Entry> {
+    fn visit(b: &crate::Bucket<'_>, path: &mut Vec<Vec<u8>>, out: &mut Vec<Entry>) {
+        let mut items = Vec::new();
+        b.for_each(|k, v| {
+            items.push((k.to_vec(), v.map(<[u8]>::to_vec)));
+            Ok(())
+        })
+        .unwrap();
+        for (k, v) in items {
+            out.push((path.clone(), k.clone(), v.clone()));
+            if v.is_none() {
+                path.push(k.clone());
+                visit(&b.bucket(&k).unwrap(), path, out);
+                path.pop();
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    db.view(|tx| {
+        assert_eq!(tx.bucket(b"widgets")?.sequence(), 7);
+        visit(&tx.root(), &mut Vec::new(), &mut out);
+        Ok(())
+    })
+    .unwrap();
+    out
+}
+
+/// LeafElem is a leaf page element: its flags, key and value.
+type LeafElem = (u32, Vec<u8>, Vec<u8>);
+
+/// FixtureWriter lays out the pages of a fixture following bbolt's page
+/// layout, independently of the node and commit code.
+struct FixtureWriter {
+    buf: Vec<u8>,
+    page_size: usize,
+    next: Pgid,
+    fragmented: bool,
+    free: Vec<Pgid>,
+}
+
+impl FixtureWriter {
+    /// allocate returns the first of `n` contiguous pages, leaving a free page
+    /// before it when fragmented.
+    fn allocate(&mut self, n: usize) -> Pgid {
+        if self.fragmented && self.next > 3 {
+            self.free.push(self.next);
+            self.next += 1;
+        }
+        let id = self.next;
+        self.next += n as Pgid;
+        self.buf.resize(self.next as usize * self.page_size, 0);
+        id
+    }
+
+    /// page allocates room for `data`, a page image without its header.
+    fn page(&mut self, flags: u16, count: usize, data: &[u8]) -> Pgid {
+        let n = (PAGE_HEADER_SIZE + data.len()).div_ceil(self.page_size);
+        let id = self.allocate(n);
+        let off = id as usize * self.page_size;
+        let mut p = PageMut::new(&mut self.buf[off..off + n * self.page_size]);
+        p.set_id(id);
+        p.set_flags(flags);
+        p.set_count(count as u16);
+        p.set_overflow(n as u32 - 1);
+        p.data_mut()[..data.len()].copy_from_slice(data);
+        id
+    }
+
+    /// leaf writes a leaf page and returns its id.
+    fn leaf(&mut self, elems: &[LeafElem]) -> Pgid {
+        self.page(LEAF_PAGE_FLAG, elems.len(), &leaf_data(elems))
+    }
+
+    /// branch writes a branch page and returns its id.
+    fn branch(&mut self, elems: &[(Vec<u8>, Pgid)]) -> Pgid {
+        let mut data = vec![0; elems.len() * BRANCH_PAGE_ELEMENT_SIZE];
+        for (i, (key, pgid)) in elems.iter().enumerate() {
+            let off = i * BRANCH_PAGE_ELEMENT_SIZE;
+            let pos = (data.len() - off) as u32;
+            write_u32(&mut data, off, pos);
+            write_u32(&mut data, off + 4, key.len() as u32);
+            write_u64(&mut data, off + 8, *pgid);
+            data.extend_from_slice(key);
+        }
+        self.page(BRANCH_PAGE_FLAG, elems.len(), &data)
+    }
+
+    /// tree writes the leaves holding `elems`, filling each page as far as
+    /// it goes, under a branch page when there are several, and returns the
+    /// root.
+    fn tree(&mut self, elems: Vec<LeafElem>) -> Pgid {
+        let mut leaves: Vec<Vec<LeafElem>> = vec![Vec::new()];
+        let mut size = PAGE_HEADER_SIZE;
+        for elem in elems {
+            let elem_size = LEAF_PAGE_ELEMENT_SIZE + elem.1.len() + elem.2.len();
+            if size + elem_size > self.page_size {
+                leaves.push(Vec::new());
+                size = PAGE_HEADER_SIZE;
+            }
+            size += elem_size;
+            leaves.last_mut().unwrap().push(elem);
+        }
+        if leaves.len() == 1 {
+            return self.leaf(&leaves[0]);
+        }
+        let children: Vec<_> = leaves
+            .iter()
+            .map(|leaf| (leaf[0].1.clone(), self.leaf(leaf)))
+            .collect();
+        self.branch(&children)
+    }
+}
+
+/// leaf_data returns the elements and data of a leaf page.
+fn leaf_data(elems: &[LeafElem]) -> Vec<u8> {
+    let mut data = vec![0; elems.len() * LEAF_PAGE_ELEMENT_SIZE];
+    for (i, (flags, key, value)) in elems.iter().enumerate() {
+        let off = i * LEAF_PAGE_ELEMENT_SIZE;
+        write_u32(&mut data, off, *flags);
+        let pos = (data.len() - off) as u32;
+        write_u32(&mut data, off + 4, pos);
+        write_u32(&mut data, off + 8, key.len() as u32);
+        write_u32(&mut data, off + 12, value.len() as u32);
+        data.extend_from_slice(key);
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+/// bucket returns the value of a bucket rooted at `root`.
+fn bucket(root: Pgid, sequence: u64) -> Vec<u8> {
+    let mut v = vec![0; BUCKET_HEADER_SIZE];
+    InBucket { root, sequence }.write(&mut v);
+    v
+}
+
+/// inline_bucket returns the value of an inline bucket holding `elems`.
+fn inline_bucket(elems: &[LeafElem]) -> Vec<u8> {
+    let mut v = bucket(0, 0);
+    let mut page = vec![0; PAGE_HEADER_SIZE];
+    let mut p = PageMut::new(&mut page);
+    p.set_flags(LEAF_PAGE_FLAG);
+    p.set_count(elems.len() as u16);
+    v.extend_from_slice(&page);
+    v.extend_from_slice(&leaf_data(elems));
+    v
+}
+
+/// build_fixture lays out the entries of fixture_entries in a file image.
+fn build_fixture(f: &Fixture) -> Vec<u8> {
+    let mut w = FixtureWriter {
+        buf: Vec::new(),
+        page_size: f.page_size,
+        next: 3,
+        fragmented: f.fragmented,
+        free: Vec::new(),
+    };
+    let kv = |k: &[u8], v: &[u8]| (0, k.to_vec(), v.to_vec());
+    let sub = |k: &[u8], v: Vec<u8>| (BUCKET_LEAF_FLAG, k.to_vec(), v);
+
+    // The root bucket is written last, after the pages it refers to, so it
+    // takes page 3 by hand.
+    w.allocate(1);
+    let deep = inline_bucket(&[kv(b"x", b"y")]);
+    let nested = w.leaf(&[kv(b"big", &big()), sub(b"deep", deep)]);
+    let mut widgets: Vec<_> = (0..100u32)
+        .filter(|i| i % 3 != 0)
+        .map(|i| kv(&i.to_be_bytes(), &[i as u8; 50]))
+        .collect();
+    widgets.push(sub(b"inline", inline_bucket(&[kv(b"k", b"value")])));
+    widgets.push(sub(b"nested", bucket(nested, 0)));
+    let widgets = w.tree(widgets);
+    let root = leaf_data(&[sub(b"widgets", bucket(widgets, 7))]);
+    let off = 3 * f.page_size;
+    let mut p = PageMut::new(&mut w.buf[off..off + f.page_size]);
+    p.set_id(3);
+    p.set_flags(LEAF_PAGE_FLAG);
+    p.set_count(1);
+    p.data_mut()[..root.len()].copy_from_slice(&root);
+
+    // Two pages freed by the last commit, then the freelist and metas.
+    if !f.fragmented {
+        let id = w.allocate(2);
+        w.free.extend([id, id + 1]);
+    }
+    let pgid = w.next;
+    w.buf.resize((pgid as usize + 1) * f.page_size, 0);
+    let mut data = vec![0; w.free.len() * PGID_SIZE];
+    for (i, &id) in w.free.iter().enumerate() {
+        write_u64(&mut data, i * PGID_SIZE, id);
+    }
+    let off = 2 * f.page_size;
+    let mut p = PageMut::new(&mut w.buf[off..off + f.page_size]);
+    p.set_id(2);
+    p.set_flags(FREELIST_PAGE_FLAG);
+    p.set_count(w.free.len() as u16);
+    p.data_mut()[..data.len()].copy_from_slice(&data);
+    w.buf.truncate(pgid as usize * f.page_size);
+    for txid in 2..4 {
+        let mut m = Meta {
+            magic: MAGIC,
+            version: VERSION,
+            page_size: f.page_size as u32,
+            root: InBucket {
+                root: 3,
+                sequence: 0,
+            },
+            freelist: 2,
+            pgid,
+            txid,
+            ..Meta::default()
+        };
+        let off = (txid % 2) as usize * f.page_size;
+        m.write(&mut PageMut::new(&mut w.buf[off..off + f.page_size]));
+    }
+    w.buf
+}
+
+/// write_fixtures rewrites the hand-built fixtures. Run it with
+/// `cargo test write_fixtures -- --ignored`.
+#[test]
+#[ignore]
+fn write_fixtures() {
+    for f in FIXTURES {
+        fs::write(fixture_path(f.name), build_fixture(f)).unwrap();
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn fixtures() {
+    for f in FIXTURES {
+        let src = fs::read(fixture_path(f.name)).unwrap();
+        for &freelist_type in &[FreelistType::Array, FreelistType::HashMap] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("db");
+            fs::write(&path, &src).unwrap();
+
+            let db = open(&path, 4096, freelist_type);
+            assert_eq!(db.0.page_size, f.page_size, "{}", f.name);
+            assert_eq!(walk(&db), fixture_entries(), "{}", f.name);
+            assert!(db.begin(false).unwrap().check().is_empty(), "{}", f.name);
+            assert!(lock(&db.0.freelist).free_count() > 0, "{}", f.name);
+
+            // Tx::write_to copies the data pages unchanged, and the copy
+            // reads the same.
+            let mut out = Vec::new();
+            db.begin(false).unwrap().write_to(&mut out).unwrap();
+            let hwm = db.begin(false).unwrap().meta.get().pgid as usize;
+            let data = 2 * f.page_size..hwm * f.page_size;
+            assert_eq!(out[data.clone()], src[data], "{}", f.name);
+            let copy = dir.path().join("copy");
+            fs::write(&copy, &out).unwrap();
+            let copied = open(&copy, 4096, freelist_type);
+            assert_eq!(walk(&copied), fixture_entries(), "{}", f.name);
+
+            // The file takes further writes.
+            db.update(|tx| {
+                let b = tx.bucket(b"widgets")?.bucket(b"nested")?;
+                b.put(b"more", &[1; 10_000])?;
+                b.delete(b"big")
+            })
+            .unwrap();
+            assert!(db.begin(false).unwrap().check().is_empty(), "{}", f.name);
+        }
+    }
+}
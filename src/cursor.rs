@@ -0,0 +1,1178 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::iter::FusedIterator;
+use std::ops::Bound;
+
+use crate::bucket::{Bucket, BucketId, PageNode};
+use crate::errors::{Error, Result};
+use crate::node::NodeId;
+use crate::page::{Page, BRANCH_PAGE_FLAG, BUCKET_LEAF_FLAG, LEAF_PAGE_FLAG};
+use crate::tx::{Tx, TxState};
+
+/// Item is a key, value and flags triple the cursor is positioned on.
+pub(crate) type Item<'tx> = Option<(&'tx [u8], &'tx [u8], u32)>;
+
+/// Pair is the key and value a cursor move returns. Both are `None` past
+/// either end of the bucket, and the value is `None` for a nested bucket.
+pub type Pair<'tx> = (Option<&'tx [u8]>, Option<&'tx [u8]>);
+
+/// Entry is a key and its value, which is `None` for a nested bucket.
+pub type Entry<'tx> = (&'tx [u8], Option<&'tx [u8]>);
+
+/// Cursor represents an iterator that can traverse over all key/value pairs in a bucket
+/// in lexicographical order.
+/// Cursors see nested buckets with value == nil.
+/// Cursors can be obtained from a transaction and are valid as long as the transaction is open.
+///
+/// Changing data while traversing with a cursor may cause it to be invalidated
+/// and return unexpected keys and/or values. You must reposition your cursor
+/// after mutating data.
+///
+/// Every method returns a `(key, value)` pair, which is `(None, None)` once the
+/// cursor moves past either end of the bucket, or `Error::Corrupted` if it
+/// runs into a corrupted page.
+pub struct Cursor<'tx> {
+    tx: &'tx Tx,
+    bucket: BucketId,
+    stack: Vec<ElemRef<'tx>>,
+    // deleted is set once delete removes the current element: the cursor is
+    // then already on its successor, which the next call to next returns.
+    deleted: bool,
+    // positioned is set while the last move landed on an element. Moving
+    // past either end leaves the stack on the last element visited.
+    positioned: bool,
+}
+
+/// Iter is an iterator over the key/value pairs of a bucket within a range of
+/// keys, in lexicographical order. It is created by [`Bucket::range`] and
+/// [`Bucket::prefix`], and can also be consumed from the back for reverse scans.
+///
+/// Nested buckets have no value and are skipped. As with a [`Cursor`], the
+/// bucket must not be modified while it is being iterated. An error, such as
+/// `Error::Corrupted` for a corrupted page, ends the iteration.
+pub struct Iter<'tx> {
+    front: Cursor<'tx>,
+    back: Cursor<'tx>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // The last keys returned from either end. Iteration stops once the two
+    // ends meet.
+    front_key: Option<&'tx [u8]>,
+    back_key: Option<&'tx [u8]>,
+    done: bool,
+}
+
+impl<'tx> Iter<'tx> {
+    pub(crate) fn new(
+        bucket: Bucket<'tx>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Iter<'tx> {
+        Iter {
+            front: bucket.cursor(),
+            back: bucket.cursor(),
+            start,
+            end,
+            front_key: None,
+            back_key: None,
+            done: false,
+        }
+    }
+
+    /// before_end reports whether `key` lies on the lower side of the end bound.
+    fn before_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= &end[..],
+            Bound::Excluded(end) => key < &end[..],
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// after_start reports whether `key` lies on the upper side of the start bound.
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= &start[..],
+            Bound::Excluded(start) => key > &start[..],
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// step_front moves the front cursor on to the next pair in range.
+    fn step_front(&mut self) -> Result<Option<(&'tx [u8], &'tx [u8])>> {
+        // Position on the first key inside the start bound, or move on.
+        let mut item = match (self.front_key, &self.start) {
+            (Some(_), _) => self.front.next()?,
+            (None, Bound::Included(start)) => self.front.seek(start)?,
+            (None, Bound::Excluded(start)) => match self.front.seek(start)? {
+                (Some(k), _) if k == &start[..] => self.front.next()?,
+                item => item,
+            },
+            (None, Bound::Unbounded) => self.front.first()?,
+        };
+
+        // Skip over nested buckets.
+        while let (Some(_), None) = item {
+            item = self.front.next()?;
+        }
+
+        match item {
+            (Some(k), Some(v))
+                if self.before_end(k) && self.back_key.is_none_or(|back| k < back) =>
+            {
+                self.front_key = Some(k);
+                Ok(Some((k, v)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// step_back moves the back cursor on to the previous pair in range.
+    fn step_back(&mut self) -> Result<Option<(&'tx [u8], &'tx [u8])>> {
+        // Position on the last key inside the end bound, or move on.
+        let mut item = match (self.back_key, &self.end) {
+            (Some(_), _) => self.back.prev()?,
+            (None, Bound::Included(end)) => self.back.seek_rev(end)?,
+            (None, Bound::Excluded(end)) => match self.back.seek(end)? {
+                (Some(_), _) => self.back.prev()?,
+                (None, _) => self.back.last()?,
+            },
+            (None, Bound::Unbounded) => self.back.last()?,
+        };
+
+        // Skip over nested buckets.
+        while let (Some(_), None) = item {
+            item = self.back.prev()?;
+        }
+
+        match item {
+            (Some(k), Some(v))
+                if self.after_start(k) && self.front_key.is_none_or(|front| k > front) =>
+            {
+                self.back_key = Some(k);
+                Ok(Some((k, v)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<'tx> Iterator for Iter<'tx> {
+    type Item = Result<(&'tx [u8], &'tx [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_front().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+impl<'tx> DoubleEndedIterator for Iter<'tx> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_back().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+impl FusedIterator for Iter<'_> {}
+
+/// ElemRef represents a reference to an element on a given page/node.
+#[derive(Clone, Copy)]
+struct ElemRef<'tx> {
+    page_node: PageNode<'tx>,
+    index: usize,
+}
+
+impl<'tx> ElemRef<'tx> {
+    /// is_leaf returns whether the ref is pointing at a leaf page/node.
+    fn is_leaf(&self, state: &TxState) -> bool {
+        match self.page_node {
+            PageNode::Node(n) => state.nodes[n].is_leaf,
+            PageNode::Page(p) => p.flags() & LEAF_PAGE_FLAG != 0,
+        }
+    }
+
+    /// count returns the number of inodes or page elements.
+    fn count(&self, state: &TxState) -> usize {
+        match self.page_node {
+            PageNode::Node(n) => state.nodes[n].inodes.len(),
+            PageNode::Page(p) => p.count() as usize,
+        }
+    }
+
+    /// search returns the index of the first inode or page element whose key
+    /// is not less than `key`, and whether that key is an exact match.
+    fn search(&self, state: &TxState, key: &[u8]) -> Result<(usize, bool)> {
+        match self.page_node {
+            PageNode::Node(n) => Ok(state.nodes[n].search(key)),
+            PageNode::Page(p) => search_page(p, key),
+        }
+    }
+
+    /// child_pgid returns the page id of the child the ref points at in a branch.
+    fn child_pgid(&self, state: &TxState) -> Result<u64> {
+        match self.page_node {
+            PageNode::Node(n) => match state.nodes[n].inodes.get(self.index) {
+                Some(inode) => Ok(inode.pgid),
+                None => Err(Error::corrupted(
+                    state.nodes[n].pgid,
+                    format!("branch element {} out of bounds", self.index),
+                )),
+            },
+            PageNode::Page(p) => Ok(p.branch_element(self.index)?.pgid()),
+        }
+    }
+}
+
+// The methods with an `_in` suffix take the transaction state explicitly, so
+// that the bucket layer can drive a cursor while it holds the state itself.
+impl<'tx> Cursor<'tx> {
+    /// Bucket returns the bucket that this cursor was created from.
+    pub fn bucket(&self) -> Bucket<'tx> {
+        Bucket::new(self.tx, self.bucket)
+    }
+
+    /// First moves the cursor to the first item in the bucket and returns its key and value.
+    /// If the bucket is empty then a nil key and value are returned.
+    pub fn first(&mut self) -> Result<Pair<'tx>> {
+        self.deleted = false;
+        self.with_state(|c, state| c.first_in(state))
+    }
+
+    /// Last moves the cursor to the last item in the bucket and returns its key and value.
+    /// If the bucket is empty then a nil key and value are returned.
+    pub fn last(&mut self) -> Result<Pair<'tx>> {
+        self.deleted = false;
+        self.with_state(|c, state| c.last_in(state))
+    }
+
+    /// Next moves the cursor to the next item in the bucket and returns its key and value.
+    /// If the cursor is at the end of the bucket then a nil key and value are returned.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Pair<'tx>> {
+        if !std::mem::replace(&mut self.deleted, false) {
+            return self.with_state(|c, state| c.next_in(state));
+        }
+
+        // The element after a deleted one is the one the cursor is already on.
+        self.with_state(|c, state| match c.key_value(state)? {
+            None => c.next_in(state),
+            item => Ok(item),
+        })
+    }
+
+    /// Prev moves the cursor to the previous item in the bucket and returns its key and value.
+    /// If the cursor is at the beginning of the bucket then a nil key and value are returned.
+    pub fn prev(&mut self) -> Result<Pair<'tx>> {
+        self.deleted = false;
+        self.with_state(|c, state| c.prev_in(state))
+    }
+
+    /// Seek moves the cursor to a given key and returns it.
+    /// If the key does not exist then the next key is used. If no keys
+    /// follow, a nil key is returned.
+    pub fn seek(&mut self, seek: &[u8]) -> Result<Pair<'tx>> {
+        self.deleted = false;
+        self.with_state(|c, state| match c.seek_in(state, seek)? {
+            // If we ended up after the last element of a page then move to the next one.
+            None => c.next_in(state),
+            item => Ok(item),
+        })
+    }
+
+    /// SeekRev moves the cursor to a given key and returns it, like Seek.
+    /// If the key does not exist then the previous key is used. If no keys
+    /// precede it, a nil key is returned. This is where a reverse scan from
+    /// `seek` downwards starts.
+    pub fn seek_rev(&mut self, seek: &[u8]) -> Result<Pair<'tx>> {
+        self.deleted = false;
+        self.with_state(|c, state| match c.seek_in(state, seek)? {
+            Some(item) if item.0 == seek => Ok(Some(item)),
+            // Seek landed on the next key, or past the end of its leaf.
+            _ => c.prev_in(state),
+        })
+    }
+
+    /// SeekExact moves the cursor to a given key like Seek, but only returns
+    /// the key and value if the key exists; a bucket is returned with a nil
+    /// value. On a miss the cursor is left where Seek leaves it.
+    pub fn seek_exact(&mut self, key: &[u8]) -> Result<Option<Entry<'tx>>> {
+        match self.seek(key)? {
+            (Some(k), v) if k == key => Ok(Some((k, v))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Current returns the key and value the cursor is positioned on without
+    /// moving it. None is returned if the cursor was never positioned, moved
+    /// past either end of the bucket or had its element deleted.
+    pub fn current(&self) -> Result<Option<Entry<'tx>>> {
+        if !self.positioned || self.deleted {
+            return Ok(None);
+        }
+        let mut state = self.tx.state.borrow_mut();
+        let item = self.key_value(&state)?;
+        Ok(self.pair(&mut state, item))
+    }
+
+    /// Delete removes the current key/value under the cursor from the bucket.
+    /// Delete fails if current key/value is a bucket or if the transaction is not writable.
+    ///
+    /// The cursor stays usable afterwards: a following call to next returns
+    /// the element that came after the deleted one.
+    pub fn delete(&mut self) -> Result<()> {
+        let tx = self.tx;
+        if !tx.writable {
+            return Err(Error::TxNotWritable);
+        }
+
+        let mut state = tx.state.borrow_mut();
+        let key = match self.key_value(&state)? {
+            // Return an error if current value is a bucket.
+            Some((_, _, flags)) if flags & BUCKET_LEAF_FLAG != 0 => {
+                return Err(Error::IncompatibleValue)
+            }
+            Some((key, _, _)) => key,
+            None => return Ok(()),
+        };
+        let n = self.node_in(&mut state)?;
+        state.del(n, key);
+        state.writes += 1;
+
+        // Removing the element shifted its successors down by one, so
+        // reposition on the first key after the deleted one.
+        self.seek_in(&mut state, key)?;
+        self.deleted = true;
+        Ok(())
+    }
+
+    /// with_state runs `f` with the transaction state and converts the item it
+    /// lands on into a key/value pair, decoding the value.
+    fn with_state<F>(&mut self, f: F) -> Result<Pair<'tx>>
+    where
+        F: FnOnce(&mut Cursor<'tx>, &mut TxState) -> Result<Item<'tx>>,
+    {
+        let tx = self.tx;
+        let mut state = tx.state.borrow_mut();
+        let item = f(self, &mut state);
+        self.positioned = matches!(item, Ok(Some(_)));
+        match self.pair(&mut state, item?) {
+            Some((k, v)) => Ok((Some(k), v)),
+            None => Ok((None, None)),
+        }
+    }
+
+    /// pair converts an item into a key and decoded value, with a nil value
+    /// for a bucket.
+    fn pair(&self, state: &mut TxState, item: Item<'tx>) -> Option<(&'tx [u8], Option<&'tx [u8]>)> {
+        match item {
+            Some((k, _, flags)) if flags & BUCKET_LEAF_FLAG != 0 => Some((k, None)),
+            Some((k, v, _)) => Some((k, Some(state.decode_value(self.bucket, v)))),
+            None => None,
+        }
+    }
+
+    pub(crate) fn new(tx: &'tx Tx, bucket: BucketId) -> Cursor<'tx> {
+        tx.stats.inc_cursor_count(1);
+        Cursor {
+            tx,
+            bucket,
+            stack: Vec::new(),
+            deleted: false,
+            positioned: false,
+        }
+    }
+
+    fn root(&self, state: &TxState) -> u64 {
+        state.buckets[self.bucket].header.root
+    }
+
+    /// first_in moves the cursor to the first item in the bucket and returns it.
+    pub(crate) fn first_in(&mut self, state: &mut TxState) -> Result<Item<'tx>> {
+        self.stack.clear();
+        let page_node = state.page_node(self.tx, self.bucket, self.root(state))?;
+        self.stack.push(ElemRef {
+            page_node,
+            index: 0,
+        });
+        self.go_first(state)?;
+
+        // If we land on an empty page then move to the next value.
+        if self.top_count(state) == 0 {
+            return self.next_in(state);
+        }
+        self.key_value(state)
+    }
+
+    /// last_in moves the cursor to the last item in the bucket and returns it.
+    pub(crate) fn last_in(&mut self, state: &mut TxState) -> Result<Item<'tx>> {
+        self.stack.clear();
+        let page_node = state.page_node(self.tx, self.bucket, self.root(state))?;
+        let mut r = ElemRef {
+            page_node,
+            index: 0,
+        };
+        r.index = r.count(state).saturating_sub(1);
+        self.stack.push(r);
+        self.go_last(state)?;
+
+        // If this is an empty page (calling Delete may result in empty pages)
+        // we call prev to find another page.
+        while self.stack.len() > 1 && self.top_count(state) == 0 {
+            self.prev_in(state)?;
+        }
+        if self.stack.is_empty() {
+            return Ok(None);
+        }
+        self.key_value(state)
+    }
+
+    /// next_in moves to the next leaf element and returns it.
+    pub(crate) fn next_in(&mut self, state: &mut TxState) -> Result<Item<'tx>> {
+        loop {
+            // Attempt to move over one element until we're successful.
+            // Move up the stack as we hit the end of each page in our stack.
+            let mut found = None;
+            for i in (0..self.stack.len()).rev() {
+                let count = self.stack[i].count(state);
+                let elem = &mut self.stack[i];
+                if elem.index + 1 < count {
+                    elem.index += 1;
+                    found = Some(i);
+                    break;
+                }
+            }
+
+            // If we've hit the root page then stop and return. This will leave the
+            // cursor on the last element of the last page.
+            let i = match found {
+                Some(i) => i,
+                None => return Ok(None),
+            };
+
+            // Otherwise start from where we left off in the stack and find the
+            // first element of the first leaf page.
+            self.stack.truncate(i + 1);
+            self.go_first(state)?;
+
+            // If this is an empty page then restart and move back up the stack.
+            if self.top_count(state) == 0 {
+                continue;
+            }
+            return self.key_value(state);
+        }
+    }
+
+    /// prev_in moves the cursor to the previous item in the bucket and returns it.
+    pub(crate) fn prev_in(&mut self, state: &mut TxState) -> Result<Item<'tx>> {
+        loop {
+            // Attempt to move back one element until we're successful.
+            // Move up the stack as we hit the beginning of each page in our stack.
+            while let Some(elem) = self.stack.last_mut() {
+                if elem.index > 0 {
+                    elem.index -= 1;
+                    break;
+                }
+                self.stack.pop();
+            }
+
+            // If we've hit the end then return nil.
+            if self.stack.is_empty() {
+                return Ok(None);
+            }
+
+            // Move down the stack to find the last element of the last leaf under this branch.
+            self.go_last(state)?;
+
+            // If this is an empty page then restart and move back up the stack.
+            if self.top_count(state) == 0 {
+                continue;
+            }
+            return self.key_value(state);
+        }
+    }
+
+    /// seek_in moves the cursor to a given key and returns it.
+    /// If the key does not exist then the next key is used.
+    pub(crate) fn seek_in(&mut self, state: &mut TxState, seek: &[u8]) -> Result<Item<'tx>> {
+        // Start from root page/node and traverse to correct page.
+        self.stack.clear();
+        self.search(state, seek, self.root(state))?;
+
+        // If this is a bucket then return a nil value.
+        self.key_value(state)
+    }
+
+    /// go_first moves the cursor to the first leaf element under the last page in the stack.
+    fn go_first(&mut self, state: &TxState) -> Result<()> {
+        loop {
+            // Exit when we hit a leaf page.
+            let r = match self.stack.last() {
+                Some(r) if !r.is_leaf(state) => *r,
+                _ => return Ok(()),
+            };
+
+            // Keep adding pages pointing to the first element to the stack.
+            let pgid = r.child_pgid(state)?;
+            let page_node = state.page_node(self.tx, self.bucket, pgid)?;
+            self.stack.push(ElemRef {
+                page_node,
+                index: 0,
+            });
+        }
+    }
+
+    /// go_last moves the cursor to the last leaf element under the last page in the stack.
+    fn go_last(&mut self, state: &TxState) -> Result<()> {
+        loop {
+            // Exit when we hit a leaf page.
+            let r = match self.stack.last() {
+                Some(r) if !r.is_leaf(state) => *r,
+                _ => return Ok(()),
+            };
+
+            // Keep adding pages pointing to the last element in the stack.
+            let pgid = r.child_pgid(state)?;
+            let page_node = state.page_node(self.tx, self.bucket, pgid)?;
+            let mut next = ElemRef {
+                page_node,
+                index: 0,
+            };
+            next.index = next.count(state).saturating_sub(1);
+            self.stack.push(next);
+        }
+    }
+
+    /// search recursively performs a binary search against a given page/node until it finds a given key.
+    fn search(&mut self, state: &TxState, key: &[u8], pgid: u64) -> Result<()> {
+        let page_node = state.page_node(self.tx, self.bucket, pgid)?;
+        if let PageNode::Page(p) = page_node {
+            if p.flags() & (BRANCH_PAGE_FLAG | LEAF_PAGE_FLAG) == 0 {
+                return Err(Error::corrupted(
+                    p.id(),
+                    format!("invalid page type: {}", p.typ()),
+                ));
+            }
+        }
+        let mut e = ElemRef {
+            page_node,
+            index: 0,
+        };
+
+        // If we're on a leaf page/node then find the specific node.
+        if e.is_leaf(state) {
+            e.index = e.search(state, key)?.0;
+            self.stack.push(e);
+            return Ok(());
+        }
+
+        // Otherwise binary search the branch for the child to descend into.
+        let (index, exact) = e.search(state, key)?;
+        if e.count(state) == 0 {
+            return Err(Error::corrupted(pgid, "empty branch page"));
+        }
+
+        // If we didn't find an exact match, the key lives under the previous child.
+        let index = if !exact && index > 0 {
+            index - 1
+        } else {
+            index
+        };
+        e.index = index.min(e.count(state) - 1);
+        self.stack.push(e);
+
+        // Recursively search to the next page.
+        self.search(state, key, e.child_pgid(state)?)
+    }
+
+    /// key_value returns the key and value of the current leaf element.
+    fn key_value(&self, state: &TxState) -> Result<Item<'tx>> {
+        let r = match self.stack.last() {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        // If the cursor is pointing to the end of page/node then return nil.
+        if r.count(state) == 0 || r.index >= r.count(state) {
+            return Ok(None);
+        }
+
+        match r.page_node {
+            // Retrieve value from node.
+            PageNode::Node(n) => {
+                let inode = &state.nodes[n].inodes[r.index];
+                // Safety: inodes only refer to the transaction's mmap or arena,
+                // which outlive the 'tx borrow.
+                let (key, value) = unsafe { (inode.key.extend(), inode.value.extend()) };
+                Ok(Some((key, value, inode.flags)))
+            }
+            // Or retrieve value from page.
+            PageNode::Page(p) => {
+                let elem = p.leaf_element(r.index)?;
+                Ok(Some((elem.key(), elem.value(), elem.flags())))
+            }
+        }
+    }
+
+    /// node_in returns the node that the cursor is currently positioned on,
+    /// materializing the nodes on the path from the root.
+    pub(crate) fn node_in(&mut self, state: &mut TxState) -> Result<NodeId> {
+        let (branches, top) = match self.stack.split_last() {
+            Some((top, branches)) => (branches, *top),
+            None => {
+                return Err(Error::corrupted(
+                    self.root(state),
+                    "accessing a node with a zero-length cursor stack",
+                ))
+            }
+        };
+
+        // If the top of the stack is a leaf node then just return it.
+        if let PageNode::Node(n) = top.page_node {
+            if top.is_leaf(state) {
+                return Ok(n);
+            }
+        }
+
+        // Start from root and traverse down the hierarchy.
+        let mut n = match self.stack[0].page_node {
+            PageNode::Node(n) => n,
+            PageNode::Page(_) => state.node(self.tx, self.bucket, self.root(state), None)?,
+        };
+        for r in branches {
+            if state.nodes[n].is_leaf {
+                return Err(Error::corrupted(
+                    state.nodes[n].pgid,
+                    "expected branch node",
+                ));
+            }
+            n = state.child_at(self.tx, n, r.index)?;
+        }
+        if !state.nodes[n].is_leaf {
+            return Err(Error::corrupted(state.nodes[n].pgid, "expected leaf node"));
+        }
+        Ok(n)
+    }
+
+    /// top_count returns the number of elements on the top of the stack, or
+    /// zero when the stack is empty.
+    fn top_count(&self, state: &TxState) -> usize {
+        self.stack.last().map_or(0, |r| r.count(state))
+    }
+}
+
+/// search_page returns the index of the first element of a leaf or branch
+/// page whose key is not less than `key`, and whether that key is an exact match.
+fn search_page(p: Page<'_>, key: &[u8]) -> Result<(usize, bool)> {
+    let elem_key = |i: usize| -> Result<&[u8]> {
+        if p.flags() & LEAF_PAGE_FLAG != 0 {
+            Ok(p.leaf_element(i)?.key())
+        } else {
+            Ok(p.branch_element(i)?.key())
+        }
+    };
+    let (mut lo, mut hi) = (0, p.count() as usize);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if elem_key(mid)? < key {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let exact = lo < p.count() as usize && elem_key(lo)? == key;
+    Ok((lo, exact))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+
+    use crate::db::{Options, DB};
+    use crate::errors::{Error, Result};
+
+    fn open(dir: &tempfile::TempDir) -> DB {
+        DB::open(dir.path().join("db"), Options::default()).unwrap()
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        i.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn empty_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            assert_eq!(c.first()?, (None, None));
+            assert_eq!(c.last()?, (None, None));
+            assert_eq!(c.next()?, (None, None));
+            assert_eq!(c.prev()?, (None, None));
+            assert_eq!(c.seek(b"foo")?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn seek() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"foo", b"0001")?;
+            b.put(b"bar", b"0002")?;
+            b.put(b"baz", b"0003")?;
+            b.create_bucket(b"bkt")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+
+            // Exact match should go to the key.
+            assert_eq!(c.seek(b"bar")?, (Some(&b"bar"[..]), Some(&b"0002"[..])));
+
+            // Inexact match should go to the next key.
+            assert_eq!(c.seek(b"bas")?, (Some(&b"baz"[..]), Some(&b"0003"[..])));
+
+            // Low key should go to the first key.
+            assert_eq!(c.seek(b"")?, (Some(&b"bar"[..]), Some(&b"0002"[..])));
+
+            // High key should return no key.
+            assert_eq!(c.seek(b"zzz")?, (None, None));
+
+            // Buckets should return their key but no value.
+            assert_eq!(c.seek(b"bkt")?, (Some(&b"bkt"[..]), None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn iterate_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"baz", b"")?;
+            b.put(b"foo", b"0000")?;
+            b.put(b"bar", b"0001")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            assert_eq!(c.first()?, (Some(&b"bar"[..]), Some(&b"0001"[..])));
+            assert_eq!(c.next()?, (Some(&b"baz"[..]), Some(&b""[..])));
+            assert_eq!(c.next()?, (Some(&b"foo"[..]), Some(&b"0000"[..])));
+            assert_eq!(c.next()?, (None, None));
+            assert_eq!(c.next()?, (None, None));
+
+            assert_eq!(c.last()?, (Some(&b"foo"[..]), Some(&b"0000"[..])));
+            assert_eq!(c.prev()?, (Some(&b"baz"[..]), Some(&b""[..])));
+            assert_eq!(c.prev()?, (Some(&b"bar"[..]), Some(&b"0001"[..])));
+            assert_eq!(c.prev()?, (None, None));
+            assert_eq!(c.prev()?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn iterate_many_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let n = 1000;
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..n {
+                b.put(&key(i), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let check = |tx: &crate::Tx| -> Result<()> {
+            let mut c = tx.bucket(b"widgets").unwrap().cursor();
+            let mut i = 0;
+            let mut item = c.first()?;
+            while let (Some(k), Some(v)) = item {
+                assert_eq!(k, &key(i)[..]);
+                assert_eq!(v.len(), 100);
+                i += 1;
+                item = c.next()?;
+            }
+            assert_eq!(i, n);
+
+            let mut item = c.last()?;
+            while let (Some(k), _) = item {
+                i -= 1;
+                assert_eq!(k, &key(i)[..]);
+                item = c.prev()?;
+            }
+            assert_eq!(i, 0);
+            Ok(())
+        };
+
+        // Read from the clean pages.
+        db.view(|tx| check(tx)).unwrap();
+
+        // Read through nodes that were materialized by a write.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            b.put(&key(n / 2), &[1; 100])?;
+            check(tx)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn single_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            assert_eq!(c.first()?, (Some(&b"foo"[..]), Some(&b"bar"[..])));
+            assert_eq!(c.prev()?, (None, None));
+            assert_eq!(c.last()?, (Some(&b"foo"[..]), Some(&b"bar"[..])));
+            assert_eq!(c.next()?, (None, None));
+            assert_eq!(c.seek(b"foo")?, (Some(&b"foo"[..]), Some(&b"bar"[..])));
+            assert_eq!(c.seek(b"foo\x00")?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn seek_between_leaves() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let n = 2000;
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in (0..n).step_by(2) {
+                b.put(&key(i), &[0; 50])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            // Every odd key is missing, so seeking one lands on the next even
+            // key, which sits on the following leaf at each page boundary.
+            for i in (1..n - 1).step_by(2) {
+                assert_eq!(c.seek(&key(i))?.0, Some(&key(i + 1)[..]));
+                assert_eq!(c.prev()?.0, Some(&key(i - 1)[..]));
+            }
+            assert_eq!(c.seek(&key(n - 1))?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn seek_rev() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+
+        // Timestamps a multiple of 10 apart, spread over many leaves, and
+        // one nested bucket.
+        let mut oracle = BTreeMap::new();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"events")?;
+            for i in 1..=2000u64 {
+                let ts = (i * 10).to_be_bytes();
+                b.put(&ts, &i.to_be_bytes())?;
+                oracle.insert(ts.to_vec(), Some(i.to_be_bytes().to_vec()));
+            }
+            let ts = 10_005u64.to_be_bytes();
+            b.create_bucket(&ts)?;
+            oracle.insert(ts.to_vec(), None);
+            Ok(())
+        })
+        .unwrap();
+
+        let check = |tx: &crate::Tx, oracle: &BTreeMap<Vec<u8>, Option<Vec<u8>>>| -> Result<()> {
+            let b = tx.bucket(b"events").unwrap();
+            let mut c = b.cursor();
+            for ts in (0..20_020u64).step_by(5).chain(vec![u64::MAX]) {
+                let target = ts.to_be_bytes();
+                let want = oracle
+                    .range(..=target.to_vec())
+                    .next_back()
+                    .map(|(k, v)| (Some(&k[..]), v.as_deref()))
+                    .unwrap_or((None, None));
+                assert_eq!(c.seek_rev(&target)?, want, "seek_rev({})", ts);
+            }
+
+            // A target smaller than every key, including the empty key.
+            assert_eq!(c.seek_rev(&[])?, (None, None));
+            assert_eq!(c.seek_rev(&9u64.to_be_bytes())?, (None, None));
+            assert_eq!(c.prev()?, (None, None));
+
+            // prev continues from where seek_rev landed.
+            let target = 12_345u64.to_be_bytes().to_vec();
+            let mut before = oracle.range(..=target.clone()).rev().map(|(k, _)| &k[..]);
+            assert_eq!(c.seek_rev(&target)?.0, before.next());
+            assert_eq!(c.prev()?.0, before.next());
+            assert_eq!(c.prev()?.0, before.next());
+
+            let want: Vec<_> = oracle
+                .iter()
+                .rev()
+                .filter_map(|(k, v)| Some((k.clone(), v.clone()?)))
+                .collect();
+            let got: Vec<_> = b
+                .iter_rev()
+                .map(|item| item.map(|(k, v)| (k.to_vec(), v.to_vec())))
+                .collect::<Result<_>>()?;
+            assert_eq!(got, want);
+            Ok(())
+        };
+        db.view(|tx| check(tx, &oracle)).unwrap();
+
+        // Through the nodes of a write, with a whole leaf emptied by deletes
+        // before the transaction rebalances it.
+        db.update(|tx| {
+            let b = tx.bucket(b"events")?;
+            for i in 500..=1500u64 {
+                let ts = (i * 10).to_be_bytes();
+                b.delete(&ts)?;
+                oracle.remove(&ts[..]);
+            }
+            check(tx, &oracle)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn seek_exact_and_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in (0..1000).step_by(2) {
+                b.put(&key(i), &key(i * 2))?;
+            }
+            b.create_bucket(&key(501))?;
+            Ok(())
+        })
+        .unwrap();
+
+        let check = |tx: &crate::Tx, deleted: &[u32]| -> Result<()> {
+            let b = tx.bucket(b"widgets").unwrap();
+            let mut c = b.cursor();
+            assert_eq!(c.current()?, None);
+            for i in 0..1000 {
+                let k = key(i);
+                let got = c.seek_exact(&k)?;
+                if i == 501 {
+                    assert_eq!(got, Some((&k[..], None)));
+                } else if i % 2 == 0 && !deleted.contains(&i) {
+                    assert_eq!(got, Some((&k[..], Some(&key(i * 2)[..]))));
+                } else {
+                    assert_eq!(got, None, "key {}", i);
+                    // The cursor is left on the next key, as with seek.
+                    assert_eq!(c.current()?.map(|(k, _)| k), c.seek(&k)?.0);
+                    continue;
+                }
+                assert_eq!(c.current()?, got);
+            }
+
+            // Current follows every move without moving itself.
+            let first = c.first()?;
+            assert_eq!(c.current()?, Some((first.0.unwrap(), first.1)));
+            assert_eq!(c.current()?, Some((first.0.unwrap(), first.1)));
+            let next = c.next()?;
+            assert_eq!(c.current()?, Some((next.0.unwrap(), next.1)));
+            c.last()?;
+            assert_eq!(c.next()?, (None, None));
+            assert_eq!(c.current()?, None);
+            assert!(c.prev()?.0.is_some());
+            assert!(c.current()?.is_some());
+            c.first()?;
+            assert_eq!(c.prev()?, (None, None));
+            assert_eq!(c.current()?, None);
+            assert_eq!(c.seek(&key(1000))?, (None, None));
+            assert_eq!(c.current()?, None);
+            Ok(())
+        };
+
+        // Over committed pages.
+        db.view(|tx| check(tx, &[])).unwrap();
+
+        // Over the dirty nodes of a write, including keys deleted in it.
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let deleted = [0, 2, 498, 500, 502, 998];
+            for &i in &deleted {
+                b.delete(&key(i))?;
+            }
+            b.put(&key(10), b"changed")?;
+            let mut c = b.cursor();
+            assert_eq!(c.seek_exact(&key(500))?, None);
+            assert_eq!(c.current()?, Some((&key(501)[..], None)));
+            assert_eq!(
+                c.seek_exact(&key(10))?,
+                Some((&key(10)[..], Some(&b"changed"[..])))
+            );
+            b.put(&key(10), &key(20))?;
+            check(tx, &deleted)?;
+
+            // Deleting the current element leaves nothing to return.
+            let mut c = b.cursor();
+            assert!(c.seek_exact(&key(4))?.is_some());
+            c.delete()?;
+            assert_eq!(c.current()?, None);
+            assert_eq!(c.next()?.0, Some(&key(6)[..]));
+            assert_eq!(c.current()?.map(|(k, _)| k), Some(&key(6)[..]));
+            assert_eq!(c.seek_exact(&key(4))?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"foo", b"bar")?;
+            b.create_bucket(b"sub")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            c.first()?;
+            assert!(matches!(c.delete(), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            c.seek(b"sub")?;
+            assert!(matches!(c.delete(), Err(Error::IncompatibleValue)));
+            assert_eq!(c.next()?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_while_iterating() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let n = 1000;
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..n {
+                b.put(&key(i), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        // Drop whole runs of keys so that some leaves empty out entirely.
+        let doomed = |i: u32| !i.is_multiple_of(3) || (200..400).contains(&i);
+        db.update(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            let mut seen = 0;
+            let mut item = c.first()?;
+            while let (Some(k), _) = item {
+                assert_eq!(k, &key(seen)[..]);
+                seen += 1;
+                if doomed(u32::from_be_bytes(k.try_into().unwrap())) {
+                    c.delete()?;
+                }
+                item = c.next()?;
+            }
+            assert_eq!(seen, n);
+            Ok(())
+        })
+        .unwrap();
+
+        let survivors: Vec<Vec<u8>> = (0..n).filter(|&i| !doomed(i)).map(key).collect();
+        db.view(|tx| {
+            let mut c = tx.bucket(b"widgets")?.cursor();
+            let mut got = Vec::new();
+            let mut item = c.first()?;
+            while let (Some(k), _) = item {
+                got.push(k.to_vec());
+                item = c.next()?;
+            }
+            assert_eq!(got, survivors);
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn delete_then_prev() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for k in [b"a", b"b", b"c"] {
+                b.put(k, b"")?;
+            }
+            let mut c = b.cursor();
+            c.seek(b"b")?;
+            c.delete()?;
+            assert_eq!(c.prev()?, (Some(&b"a"[..]), Some(&b""[..])));
+            c.last()?;
+            c.delete()?;
+            assert_eq!(c.next()?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn root_bucket_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            tx.create_bucket(b"widgets")?;
+            tx.create_bucket(b"woojits")?;
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            let mut c = tx.cursor()?;
+            assert_eq!(c.first()?, (Some(&b"widgets"[..]), None));
+            assert_eq!(c.next()?, (Some(&b"woojits"[..]), None));
+            assert_eq!(c.next()?, (None, None));
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,3005 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, Mmap};
+use crate::batch::Batch;
+use crate::bucket::InBucket;
+use crate::errors::{Error, Result};
+use crate::freelist::{Freelist, FreelistType};
+use crate::logger::{self, Logger};
+use crate::meta::{valid_page_size, Meta, MAGIC, VERSION};
+use crate::ops::{DbOps, FileOps, SyncMode};
+use crate::page::{
+    PageMut, Pgid, FREELIST_PAGE_FLAG, LEAF_PAGE_FLAG, META_PAGE_FLAG, PAGE_HEADER_SIZE,
+    PGID_NO_FREELIST,
+};
+use crate::snapshot::Snapshot;
+use crate::transform::{self, PageTransform};
+use crate::tx::{Tx, TxStats, Txid};
+use crate::unix::{self, MadviseMode};
+
+/// The largest step that can be taken when remapping the mmap.
+const MAX_MMAP_STEP: usize = 1 << 30; // 1GB
+
+/// MAX_MAP_SIZE represents the largest mmap size supported by Bolt.
+#[cfg(target_pointer_width = "64")]
+const MAX_MAP_SIZE: usize = 0xFFFF_FFFF_FFFF; // 256TB
+
+/// MAX_MAP_SIZE represents the largest mmap size supported by Bolt.
+#[cfg(target_pointer_width = "32")]
+const MAX_MAP_SIZE: usize = 0x7FFF_FFFF; // 2GB
+
+/// MAX_ALLOC_SIZE is the size of the largest contiguous run of pages a
+/// transaction may allocate.
+#[cfg(target_pointer_width = "64")]
+const MAX_ALLOC_SIZE: usize = 0xFFFF_FFFF;
+
+/// MAX_ALLOC_SIZE is the size of the largest contiguous run of pages a
+/// transaction may allocate.
+#[cfg(target_pointer_width = "32")]
+const MAX_ALLOC_SIZE: usize = 0x0FFF_FFFF;
+
+/// DEFAULT_ALLOC_SIZE is the default amount of space allocated when the
+/// database needs to create new pages.
+pub const DEFAULT_ALLOC_SIZE: usize = 16 * 1024 * 1024;
+
+/// DEFAULT_PAGE_POOL_SIZE is the default number of single-page buffers kept
+/// for reuse by write transactions.
+pub const DEFAULT_PAGE_POOL_SIZE: usize = 64;
+
+/// DEFAULT_MAX_BATCH_SIZE is the default maximum number of calls coalesced
+/// into one batch transaction.
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 1000;
+
+/// DEFAULT_MAX_BATCH_DELAY is the default time a batch waits for more calls
+/// before it runs.
+pub const DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(10);
+
+/// Page sizes tried when the first meta page is unreadable and the page size
+/// has to be discovered from the second one.
+const POSSIBLE_PAGE_SIZES: [usize; 5] = [4096, 8192, 16384, 32768, 65536];
+
+/// Options represents the options that can be set when opening a database.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Timeout is the amount of time to wait to obtain a file lock.
+    /// When set to None it will wait indefinitely.
+    pub timeout: Option<Duration>,
+
+    /// When enabled, the database skips the fsync after growing the file.
+    /// This is only safe on non-ext3/ext4 systems.
+    pub no_grow_sync: bool,
+
+    /// FreelistType sets the backend freelist type. There are two options. Array which is simple but endures
+    /// dramatic performance degradation if database is large and fragmentation in freelist is common.
+    /// The alternative one is using hashmap, it is faster in almost all circumstances
+    /// but it doesn't guarantee that it offers the smallest page id available. In normal case it is safe.
+    /// The default type is hashmap: `benches/freelist.rs` shows it allocating 3-400x faster from 10k to 1M
+    /// free pages, which outweighs its slower write of a fragmented freelist once per commit.
+    pub freelist_type: FreelistType,
+
+    /// Open database in read-only mode. Uses a shared lock instead of an exclusive one.
+    pub read_only: bool,
+
+    /// When enabled along with read_only, the file is not locked at all, so
+    /// that a database held open read-write by another process can be
+    /// inspected without stopping it. Transactions then see its commits as
+    /// they land, but its writer may reuse the pages they read, so keep
+    /// them short. Writable databases are always locked.
+    pub no_lock: bool,
+
+    /// When enabled along with read_only, the freelist is loaded when the
+    /// database is opened rather than the first time it is needed, e.g. by
+    /// `Tx::page` or `DB::stats`. Writable databases always load it when
+    /// opened.
+    pub pre_load_freelist: bool,
+
+    /// Sets extra flags passed to mmap(2), e.g. `libc::MAP_POPULATE`.
+    pub mmap_flags: i32,
+
+    /// Madvise is the access pattern passed to madvise(2) every time the
+    /// data file is mapped. The default is random access.
+    pub madvise: MadviseMode,
+
+    /// When enabled, the mapping is also advised with MADV_HUGEPAGE, which
+    /// reduces TLB misses on very large databases where the kernel supports
+    /// transparent huge pages for files. It is ignored outside Linux.
+    pub huge_pages: bool,
+
+    /// When enabled, the data file is read into an owned buffer instead of
+    /// being memory mapped, and pages written by commits are copied into it.
+    /// Slices read from transactions still borrow the buffer without copying,
+    /// but the whole file is held in memory. Platforms without mmap(2)
+    /// always use the buffer. Tests default to it when built with
+    /// `--cfg bolt_buffer_backend`.
+    pub force_buffer_backend: bool,
+
+    /// PageTransform, when set, encrypts every page but the meta pages
+    /// before it is written to the file, and decrypts it when the file is
+    /// read. It implies force_buffer_backend. A file written with a
+    /// transform must always be opened with the same one. See
+    /// `PageTransform`.
+    pub page_transform: Option<Arc<dyn PageTransform>>,
+
+    /// InitialMmapSize is the initial mmap size of the database
+    /// in bytes.
+    ///
+    /// If <= 0, the initial map size is the size of the database file.
+    pub initial_mmap_size: usize,
+
+    /// PageSize overrides the default OS page size of new files. It must be a
+    /// power of two no larger than 64 KiB.
+    pub page_size: usize,
+
+    /// Setting the no_sync flag will cause the database to skip fsync()
+    /// calls after each commit. This can be useful when bulk loading data
+    /// into a database and you can restart the bulk load in the event of
+    /// a system failure or database corruption. Do not set this flag for
+    /// normal use.
+    ///
+    /// THIS IS UNSAFE. PLEASE USE WITH CAUTION.
+    pub no_sync: bool,
+
+    /// When enabled, commits don't write the freelist to disk. This speeds up
+    /// writes to a file with a large freelist, but every open of the file
+    /// then rebuilds the freelist by scanning all the pages reachable from
+    /// the meta pages, see `Stats::freelist_rebuild_time`. Opening the file
+    /// without it writes the freelist out again.
+    pub no_freelist_sync: bool,
+
+    /// SyncMode is the system call used to flush the data file to disk.
+    /// Defaults to the cheapest durable call for the platform. It has no
+    /// effect on the commits skipped by no_sync, or on the growth syncs
+    /// skipped by no_grow_sync.
+    pub sync_mode: SyncMode,
+
+    /// AllocSize is the amount of space allocated when the database
+    /// needs to create new pages. This is done to amortize the cost
+    /// of truncate() and fsync() when growing the data file.
+    pub alloc_size: usize,
+
+    /// When enabled, the space of every growth of the data file is reserved
+    /// with fallocate() before the file is extended, so the file system can
+    /// lay it out in large contiguous extents. File systems and platforms
+    /// without support for it grow the file as usual.
+    pub prealloc: bool,
+
+    /// When enabled, every commit runs a consistency check of the database
+    /// before writing it out, and fails with `Error::CheckFailed` if the check
+    /// finds any inconsistency. This flag is for debugging purposes only and
+    /// has a large performance impact.
+    pub strict_mode: bool,
+
+    /// PagePoolSize caps the number of single-page buffers kept around for
+    /// reuse by write transactions, so that one huge transaction does not pin
+    /// its dirty page memory forever. Zero disables pooling.
+    pub page_pool_size: usize,
+
+    /// When enabled, a writable database fills the page pool up to
+    /// page_pool_size when it is opened, so the first write transactions
+    /// don't pay for allocating their dirty pages.
+    pub page_pool_prealloc: bool,
+
+    /// Logger is the logger used by the database. When None, messages are
+    /// discarded.
+    pub logger: Option<Arc<dyn Logger>>,
+
+    /// MaxSnapshotAge is how long a snapshot may stay open before reading
+    /// from it is reported. A snapshot pins the pages it reads, so the writer
+    /// can't reclaim them until it is dropped. When None, snapshots never
+    /// expire.
+    pub max_snapshot_age: Option<Duration>,
+
+    /// When enabled, reading from a snapshot older than max_snapshot_age
+    /// returns Error::SnapshotExpired instead of logging a warning.
+    pub strict_snapshot_age: bool,
+
+    /// MaxBatchSize is the maximum size of a batch. Zero disables batching:
+    /// DB::batch then runs every call in its own transaction.
+    pub max_batch_size: usize,
+
+    /// MaxBatchDelay is the maximum delay before a batch starts. A zero
+    /// delay disables batching.
+    pub max_batch_delay: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            timeout: None,
+            no_grow_sync: false,
+            freelist_type: FreelistType::default(),
+            read_only: false,
+            no_lock: false,
+            pre_load_freelist: false,
+            mmap_flags: 0,
+            madvise: MadviseMode::default(),
+            huge_pages: false,
+            force_buffer_backend: cfg!(all(test, bolt_buffer_backend)),
+            page_transform: None,
+            initial_mmap_size: 0,
+            page_size: 0,
+            no_sync: false,
+            no_freelist_sync: false,
+            sync_mode: SyncMode::default(),
+            alloc_size: DEFAULT_ALLOC_SIZE,
+            prealloc: false,
+            strict_mode: false,
+            page_pool_size: DEFAULT_PAGE_POOL_SIZE,
+            page_pool_prealloc: false,
+            logger: None,
+            max_snapshot_age: None,
+            strict_snapshot_age: false,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_batch_delay: DEFAULT_MAX_BATCH_DELAY,
+        }
+    }
+}
+
+/// MetaSlot names one of the two meta pages of a database. Commits write
+/// them in turn, so one holds the current commit and the other the previous
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaSlot {
+    /// The meta page stored on page 0.
+    Page0,
+    /// The meta page stored on page 1.
+    Page1,
+}
+
+impl MetaSlot {
+    fn pgid(self) -> Pgid {
+        match self {
+            MetaSlot::Page0 => 0,
+            MetaSlot::Page1 => 1,
+        }
+    }
+}
+
+/// WriterLock allows only one read-write transaction at a time. Unlike a
+/// mutex guard it can be released from whichever place finishes the
+/// transaction, and it is never poisoned.
+///
+/// It remembers the thread that acquired it, so that a thread trying to
+/// acquire it a second time fails instead of waiting on itself forever. A
+/// transaction moved to another thread still counts as held by the thread
+/// that began it.
+#[derive(Default)]
+struct WriterLock {
+    owner: Mutex<Option<ThreadId>>,
+    cond: Condvar,
+}
+
+impl WriterLock {
+    fn lock(&self) -> Result<()> {
+        let current = thread::current().id();
+        let mut owner = lock(&self.owner);
+        while let Some(id) = *owner {
+            if id == current {
+                return Err(Error::NestedWriteTx);
+            }
+            owner = self.cond.wait(owner).unwrap_or_else(|e| e.into_inner());
+        }
+        *owner = Some(current);
+        Ok(())
+    }
+
+    fn unlock(&self) {
+        *lock(&self.owner) = None;
+        self.cond.notify_one();
+    }
+}
+
+/// lock acquires a mutex, ignoring poisoning: the data guarded by the
+/// database's mutexes is kept consistent by the code holding them.
+pub(crate) fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// DB represents a collection of buckets persisted to a file on disk.
+/// All data access is performed through transactions which can be obtained through the DB.
+/// All the functions on DB will return a `Error::DatabaseNotOpen` if accessed before open() is called.
+///
+/// A `DB` is a cheap handle: clones share the same underlying database.
+/// It carries no lifetime and is `Send + Sync`, so it can be stored in
+/// `'static` state shared between threads. Transactions hold their own
+/// references to the database and to the mapping they read from, so they do
+/// not borrow the handle either.
+#[derive(Clone)]
+pub struct DB(pub(crate) Arc<RawDB>);
+
+/// RawDB holds the state shared by every handle and transaction of an open database.
+pub(crate) struct RawDB {
+    pub(crate) path: PathBuf,
+    pub(crate) file: File,
+    pub(crate) page_size: usize,
+    read_only: bool,
+    pub(crate) no_sync: bool,
+    pub(crate) no_freelist_sync: bool,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) strict_mode: AtomicBool,
+    no_grow_sync: bool,
+    alloc_size: usize,
+    prealloc: bool,
+    mmap_flags: i32,
+    madvise: MadviseMode,
+    huge_pages: bool,
+    backend: Backend,
+    pub(crate) page_transform: Option<Arc<dyn PageTransform>>,
+    pub(crate) ops: Arc<dyn DbOps>,
+    pub(crate) logger: Arc<dyn Logger>,
+    pub(crate) max_snapshot_age: Option<Duration>,
+    pub(crate) strict_snapshot_age: bool,
+    pub(crate) max_batch_size: usize,
+    pub(crate) max_batch_delay: Duration,
+    /// The batch currently accepting calls, if any.
+    pub(crate) batch: Mutex<Option<Arc<Batch>>>,
+
+    // Locks are acquired in the order they are declared in: the freelist
+    // load lock, the writer lock, the meta lock, the mmap lock, then the
+    // freelist and the page pool.
+    /// Held while the freelist is loaded, see load_freelist.
+    freelist_load: Mutex<()>,
+    /// Allows only one writer at a time.
+    rwlock: WriterLock,
+    /// Protects meta page access; holds the txids of the open read transactions.
+    pub(crate) metalock: Mutex<Vec<Txid>>,
+    /// The txid of the meta page the last write transaction began from,
+    /// updated under the meta lock. Until a write transaction begins from the
+    /// current meta page, the pages only the previous one refers to are
+    /// still pending.
+    writer_base: AtomicU64,
+    /// Protects mmap access during remapping. Transactions keep their own
+    /// reference to the mapping they started on.
+    mmaplock: RwLock<Arc<Mmap>>,
+    /// current on disk file size
+    filesz: AtomicUsize,
+
+    pub(crate) freelist: Mutex<Freelist>,
+    /// Whether the freelist has been loaded.
+    freelist_loaded: AtomicBool,
+    /// Zeroed single-page buffers reused for dirty pages.
+    pub(crate) page_pool: Mutex<Vec<Vec<u8>>>,
+    page_pool_size: usize,
+    pub(crate) stats: AtomicStats,
+    opened: AtomicBool,
+    /// Extra open(2) flags for the destination of Tx::copy_file.
+    pub(crate) write_flag: AtomicI32,
+}
+
+impl DB {
+    /// Open creates and opens a database at the given path.
+    /// If the file does not exist then it will be created automatically.
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<DB> {
+        DB::open_with_ops(path.as_ref(), options, Arc::new(FileOps))
+    }
+
+    /// OpenWithReport opens a database like [`DB::open`], and also reports
+    /// what was found while opening it, such as a meta page that failed
+    /// validation. Applications can log it on startup to notice a damaged
+    /// meta page while the other one still holds.
+    pub fn open_with_report<P: AsRef<Path>>(path: P, options: Options) -> Result<(DB, OpenReport)> {
+        DB::open_with_report_ops(path.as_ref(), options, Arc::new(FileOps))
+    }
+
+    pub(crate) fn open_with_ops(path: &Path, options: Options, ops: Arc<dyn DbOps>) -> Result<DB> {
+        DB::open_with_report_ops(path, options, ops).map(|(db, _)| db)
+    }
+
+    fn open_with_report_ops(
+        path: &Path,
+        options: Options,
+        ops: Arc<dyn DbOps>,
+    ) -> Result<(DB, OpenReport)> {
+        let logger = options.logger.clone().unwrap_or_else(logger::discard);
+        logger.info(format_args!(
+            "opening db file ({}) with options: {:?}",
+            path.display(),
+            options
+        ));
+        match DB::open_logged(path, options, ops, logger.clone()) {
+            Ok(opened) => {
+                logger.info(format_args!("opened db ({}) successfully", path.display()));
+                Ok(opened)
+            }
+            Err(err) => {
+                logger.error(format_args!(
+                    "opening db ({}) failed: {}",
+                    path.display(),
+                    err
+                ));
+                Err(err)
+            }
+        }
+    }
+
+    fn open_logged(
+        path: &Path,
+        options: Options,
+        ops: Arc<dyn DbOps>,
+        logger: Arc<dyn Logger>,
+    ) -> Result<(DB, OpenReport)> {
+        let mut open_options = OpenOptions::new();
+        open_options.read(true);
+        if !options.read_only {
+            open_options.write(true).create(true).mode(0o600);
+        }
+        let file = open_options.open(path)?;
+
+        // Lock file so that other processes using Bolt in read-write mode cannot
+        // use the database  at the same time. This would cause corruption since
+        // the two processes would write meta pages and free pages separately.
+        // The database file is locked exclusively (only one process can grab the lock)
+        // if !options.read_only.
+        // The database file is locked using the shared lock (more than one process may
+        // hold a lock at the same time) otherwise (options.read_only is set).
+        // Read-only databases may also opt out of locking altogether.
+        if !(options.read_only && options.no_lock) {
+            unix::flock(&file, !options.read_only, options.timeout)?;
+        }
+
+        // Default values for test hooks
+        let mut page_size = if options.page_size == 0 {
+            unix::page_size()
+        } else {
+            options.page_size
+        };
+
+        // Initialize the database if it doesn't exist.
+        let filesz = file_size(&file)?;
+        let created = filesz == 0;
+        if created {
+            if options.read_only {
+                return Err(Error::Invalid);
+            }
+            let requested = u32::try_from(page_size).unwrap_or(u32::MAX);
+            if !valid_page_size(requested) {
+                return Err(Error::InvalidPageSize {
+                    page_size: requested,
+                });
+            }
+            // Initialize new files with meta pages.
+            init(
+                &file,
+                &*ops,
+                options.page_transform.as_deref(),
+                page_size,
+                options.sync_mode,
+            )?;
+        } else {
+            // try to get the page size from the metadata pages
+            page_size = read_page_size(&file, &*ops)?;
+        }
+
+        let backend =
+            Backend::select(options.force_buffer_backend || options.page_transform.is_some());
+        let mmap = mmap_region(
+            &file,
+            backend,
+            options.page_transform.as_deref(),
+            page_size,
+            options.initial_mmap_size,
+            options.mmap_flags,
+        )?;
+        logger.debug(format_args!(
+            "mapped db file ({}) with size {} bytes",
+            path.display(),
+            mmap.len()
+        ));
+        advise(&mmap, &*ops, &*logger, options.madvise, options.huge_pages);
+
+        let db = RawDB {
+            path: path.to_path_buf(),
+            filesz: AtomicUsize::new(file_size(&file)?),
+            file,
+            page_size,
+            read_only: options.read_only,
+            no_sync: options.no_sync,
+            no_freelist_sync: options.no_freelist_sync,
+            sync_mode: options.sync_mode,
+            strict_mode: AtomicBool::new(options.strict_mode),
+            no_grow_sync: options.no_grow_sync,
+            alloc_size: options.alloc_size,
+            prealloc: options.prealloc,
+            mmap_flags: options.mmap_flags,
+            madvise: options.madvise,
+            huge_pages: options.huge_pages,
+            backend,
+            page_transform: options.page_transform.clone(),
+            ops,
+            logger,
+            max_snapshot_age: options.max_snapshot_age,
+            strict_snapshot_age: options.strict_snapshot_age,
+            max_batch_size: options.max_batch_size,
+            max_batch_delay: options.max_batch_delay,
+            batch: Mutex::new(None),
+            freelist_load: Mutex::new(()),
+            rwlock: WriterLock::default(),
+            metalock: Mutex::new(Vec::new()),
+            writer_base: AtomicU64::new(0),
+            mmaplock: RwLock::new(Arc::new(mmap)),
+            freelist: Mutex::new(Freelist::new(options.freelist_type)),
+            freelist_loaded: AtomicBool::new(false),
+            page_pool: Mutex::new(Vec::new()),
+            page_pool_size: options.page_pool_size,
+            stats: AtomicStats::default(),
+            opened: AtomicBool::new(true),
+            write_flag: AtomicI32::new(0),
+        };
+
+        let mmap = db.mmap();
+        let (slot, meta, alternate_valid) = db.select_meta(&mmap)?;
+        // Likewise every page below the high water mark, or the file was cut
+        // short.
+        let filesz = db.filesz.load(Ordering::SeqCst);
+        if mmap_offset(meta.pgid, db.page_size)? > filesz {
+            let reason = format!(
+                "high water mark past the end of the file ({} bytes)",
+                filesz
+            );
+            return Err(Error::corrupted(meta.pgid, reason));
+        }
+        if !alternate_valid {
+            let other = [MetaSlot::Page1, MetaSlot::Page0][slot.pgid() as usize];
+            db.logger.warn(format_args!(
+                "meta page {} of db ({}) failed validation, opened from meta page {}",
+                other.pgid(),
+                db.path.display(),
+                slot.pgid()
+            ));
+        }
+        let mut report = OpenReport {
+            meta: slot,
+            txid: meta.txid,
+            alternate_corrupt: !alternate_valid,
+            created,
+            freelist_loaded: false,
+            freelist_load_time: Duration::default(),
+            page_size: db.page_size,
+            file_size: db.filesz.load(Ordering::SeqCst) as u64,
+        };
+
+        drop(mmap);
+        let db = Arc::new(db);
+
+        // Stats count from the moment the database is open.
+        db.stats.reset();
+
+        // Read in the freelist. Read-only databases never allocate, so they
+        // load it the first time it is needed unless asked to pre-load it.
+        if !db.read_only || options.pre_load_freelist {
+            let start = Instant::now();
+            // The freelist on disk counts the pending pages as free.
+            db.writer_base.store(meta.txid, Ordering::SeqCst);
+            db.load_freelist()?;
+            report.freelist_loaded = true;
+            report.freelist_load_time = start.elapsed();
+        }
+        if !db.read_only && options.page_pool_prealloc {
+            let mut pool = lock(&db.page_pool);
+            pool.resize_with(db.page_pool_size, || vec![0u8; db.page_size]);
+        }
+        let db = DB(db);
+
+        // Write the freelist out when the file is opened without
+        // no_freelist_sync again, so that versions of Bolt unaware of the
+        // option can open it later.
+        if !db.0.read_only && !db.0.no_freelist_sync && meta.freelist == PGID_NO_FREELIST {
+            db.update(|_| Ok(()))?;
+        }
+
+        Ok((db, report))
+    }
+
+    /// Path returns the path to currently open database file.
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+
+    /// IsReadOnly reports whether the database was opened read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.0.read_only
+    }
+
+    /// SetStrictMode turns the consistency check run by every commit on or
+    /// off, overriding `Options::strict_mode`. It applies from the next commit.
+    pub fn set_strict_mode(&self, strict_mode: bool) {
+        self.0.strict_mode.store(strict_mode, Ordering::Relaxed);
+    }
+
+    /// SetWriteFlag sets the flag used when opening the destination of
+    /// Tx::copy_file and DB::backup. Use `libc::O_DIRECT` (or `O_SYNC`) when
+    /// copying a large database so the copy does not trash the page cache.
+    pub fn set_write_flag(&self, flag: i32) {
+        self.0.write_flag.store(flag, Ordering::Relaxed);
+    }
+
+    /// Close releases all database resources.
+    /// It will block waiting for any open read-write transaction to finish
+    /// before closing the database and returning, or returns
+    /// `Error::NestedWriteTx` if that transaction belongs to the calling
+    /// thread. Read-only transactions that are still open keep their view of
+    /// the data until they end.
+    pub fn close(&self) -> Result<()> {
+        let db = &self.0;
+        db.rwlock.lock()?;
+        let _metalock = lock(&db.metalock);
+        let result = if db.opened.swap(false, Ordering::SeqCst) {
+            db.logger
+                .info(format_args!("closing db ({})", db.path.display()));
+
+            // Unlock the file.
+            unix::funlock(&db.file)
+        } else {
+            Ok(())
+        };
+        db.rwlock.unlock();
+        result
+    }
+
+    /// Begin starts a new transaction.
+    /// Multiple read-only transactions can be used concurrently but only one
+    /// write transaction can be used at a time. Starting multiple write transactions
+    /// will cause the calls to block and be serialized until the current write
+    /// transaction finishes.
+    ///
+    /// Transactions should not be dependent on one another. Opening a read
+    /// transaction and a write transaction in the same thread is safe: the
+    /// writer never waits for readers, even when it has to remap the file.
+    /// Opening a second write transaction in a thread that already holds one
+    /// returns `Error::NestedWriteTx` rather than deadlocking.
+    ///
+    /// On a read-only database, the file is mapped again when it has grown
+    /// since it was last mapped, so that the transaction sees the commits of
+    /// a writer in another process. That writer doesn't know about the
+    /// transactions of this process, so it may reuse the pages they read:
+    /// keep them short.
+    ///
+    /// IMPORTANT: You must close read-only transactions after you are finished or
+    /// else the database will not reclaim old pages.
+    pub fn begin(&self, writable: bool) -> Result<Tx> {
+        if writable {
+            self.0.begin_rw_tx()
+        } else {
+            self.0.begin_tx(None)
+        }
+    }
+
+    /// BeginAtMeta starts a read-only transaction on the meta page stored in
+    /// `slot`, instead of the valid meta page with the highest txid. Pinned to
+    /// the other meta page, it sees the database as of the previous commit,
+    /// which helps inspecting a suspected bad write.
+    ///
+    /// The meta page must pass validation. The previous commit can only be
+    /// read until a write transaction begins after the current one: that
+    /// transaction may reuse its pages, so `Error::StaleMeta` is returned from
+    /// then on, and right after the database is opened. A transaction begun
+    /// before keeps the pages from being reused until it is closed. Read-only
+    /// databases have no writer and always allow it, but a write that failed
+    /// before being committed may have overwritten the pages of the previous
+    /// commit on disk: use `Tx::check` to find out.
+    pub fn begin_at_meta(&self, slot: MetaSlot) -> Result<Tx> {
+        self.0.begin_tx(Some(slot))
+    }
+
+    /// Update executes a function within the context of a read-write managed transaction.
+    /// If no error is returned from the function then the transaction is committed.
+    /// If an error is returned then the entire transaction is rolled back.
+    /// Any error that is returned from the function or returned from the commit is
+    /// returned from the update() method.
+    ///
+    /// The function only receives a shared reference to the transaction, so it
+    /// cannot commit or roll it back itself:
+    ///
+    /// ```compile_fail
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+    /// db.update(|tx| tx.commit()).unwrap();
+    /// ```
+    pub fn update<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&Tx) -> Result<()>,
+    {
+        self.update_ret(f)
+    }
+
+    /// UpdateRet works like update() but returns the value produced by the
+    /// function once the transaction is committed.
+    ///
+    /// The value cannot borrow from the transaction, since the transaction is
+    /// closed before it is returned:
+    ///
+    /// ```compile_fail
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+    /// let v = db.update_ret(|tx| {
+    ///     let b = tx.create_bucket(b"widgets")?;
+    ///     b.put(b"foo", b"bar")?;
+    ///     Ok(b.get(b"foo"))
+    /// });
+    /// ```
+    pub fn update_ret<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let mut tx = self.begin(true)?;
+
+        // If an error is returned from the function then rollback and return error.
+        let value = match f(&tx) {
+            Ok(value) => value,
+            Err(err) => {
+                let _ = tx.rollback();
+                return Err(err);
+            }
+        };
+
+        tx.commit()?;
+        Ok(value)
+    }
+
+    /// View executes a function within the context of a managed read-only transaction.
+    /// Any error that is returned from the function is returned from the view() method.
+    ///
+    /// ```
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+    /// db.view(|tx| {
+    ///     assert!(!tx.writable());
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// ```
+    pub fn view<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&Tx) -> Result<()>,
+    {
+        self.view_ret(f)
+    }
+
+    /// ViewRet works like view() but returns the value produced by the
+    /// function, which must own its data:
+    ///
+    /// ```
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+    /// db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+    ///     .unwrap();
+    /// let v = db
+    ///     .view_ret(|tx| Ok(tx.bucket(b"widgets")?.get(b"foo")?.map(<[u8]>::to_vec)))
+    ///     .unwrap();
+    /// assert_eq!(v.as_deref(), Some(&b"bar"[..]));
+    /// ```
+    pub fn view_ret<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx) -> Result<T>,
+    {
+        let mut tx = self.begin(false)?;
+
+        // If an error is returned from the function then pass it through.
+        let result = f(&tx);
+        tx.rollback()?;
+        result
+    }
+
+    /// Snapshot starts a read-only transaction that can be kept open for a
+    /// long time and moved to another thread. See `Snapshot`.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::new(self.begin(false)?))
+    }
+
+    /// Sync flushes the database file to disk with the system call chosen by
+    /// Options.sync_mode.
+    ///
+    /// This is not necessary under normal operation, however, if you use no_sync
+    /// then it allows you to force the database file to sync against the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.0.ops.sync(&self.0.file, self.0.sync_mode)?;
+        Ok(())
+    }
+
+    /// Backup writes a consistent copy of the database to `path` from within a
+    /// read-only transaction, so writers can keep committing while it runs.
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.view(|tx| tx.copy_file(path, 0o600))
+    }
+
+    /// Stats retrieves ongoing performance stats for the database.
+    /// This is only updated when a transaction closes. A read-only database
+    /// loads its freelist the first time, so that the freelist gauges are set.
+    pub fn stats(&self) -> Stats {
+        self.load_freelist_stats();
+        self.0.stats.snapshot()
+    }
+
+    /// FreelistStats retrieves the freelist gauges of the database, without
+    /// copying the transaction stats.
+    /// This is updated when a writable transaction begins or closes.
+    pub fn freelist_stats(&self) -> FreelistStats {
+        self.load_freelist_stats();
+        self.0.stats.freelist()
+    }
+
+    /// load_freelist_stats loads the freelist, if it has not been yet, for
+    /// the freelist gauges to describe it. A failure leaves them at zero.
+    fn load_freelist_stats(&self) {
+        if let Err(err) = self.0.load_freelist() {
+            self.0
+                .logger
+                .warn(format_args!("loading freelist failed: {}", err));
+        }
+    }
+}
+
+impl RawDB {
+    fn begin_tx(self: &Arc<RawDB>, slot: Option<MetaSlot>) -> Result<Tx> {
+        // Lock the meta pages while we initialize the transaction. We obtain
+        // the meta lock before the mmap lock because that's the order that the
+        // write transaction will obtain them.
+        let start = Instant::now();
+        let mut txs = lock(&self.metalock);
+        let mut waited = start.elapsed();
+        if !self.opened.load(Ordering::SeqCst) {
+            return Err(Error::DatabaseNotOpen);
+        }
+
+        // A read-only database has no writer of its own, but the writer of
+        // another process may have grown the file past the mapping since.
+        // Map it again so the new pages are readable. A buffer does not see
+        // that writer's commits at all, so it is read again once the meta
+        // pages change.
+        if self.read_only {
+            let filesz = file_size(&self.file)?;
+            if filesz > self.mmap().len() || self.buffer_stale()? {
+                self.remap(filesz)?;
+            }
+        }
+
+        // Create a transaction associated with the database.
+        let start = Instant::now();
+        let mmap = self.mmap();
+        waited += start.elapsed();
+        self.stats.add_reader_wait_time(waited);
+        let mut meta = self.meta(&mmap)?;
+        if let Some(slot) = slot {
+            let current = meta;
+            meta = read_meta(&mmap, self.page_size, slot.pgid())?;
+            meta.validate()?;
+
+            // A write transaction releases the pages only the previous meta
+            // page refers to, and may reuse them. A read-only database has
+            // no writer.
+            if meta.txid < current.txid
+                && !self.read_only
+                && self.writer_base.load(Ordering::SeqCst) >= current.txid
+            {
+                return Err(Error::StaleMeta);
+            }
+        }
+
+        // Keep track of transaction until it closes.
+        txs.push(meta.txid);
+        let n = txs.len();
+
+        // Unlock the meta pages.
+        drop(txs);
+
+        // Update the transaction stats.
+        self.stats.inc_tx_n();
+        self.stats.set_open_tx_n(n as i64);
+
+        Ok(Tx::new(self.clone(), mmap, meta, false))
+    }
+
+    fn begin_rw_tx(self: &Arc<RawDB>) -> Result<Tx> {
+        // If the database was opened with Options.read_only, return an error.
+        if self.read_only {
+            return Err(Error::DatabaseReadOnly);
+        }
+
+        // Obtain writer lock. This is released by the transaction when it closes.
+        // This enforces only one writer transaction at a time.
+        let start = Instant::now();
+        self.rwlock.lock()?;
+
+        // Once we have the writer lock then we can lock the meta pages so that
+        // we can set up the transaction.
+        let txs = lock(&self.metalock);
+        let mut stalled = start.elapsed();
+
+        // Exit if the database is not open yet.
+        if !self.opened.load(Ordering::SeqCst) {
+            self.rwlock.unlock();
+            return Err(Error::DatabaseNotOpen);
+        }
+
+        // Create a transaction associated with the database.
+        let start = Instant::now();
+        let mmap = self.mmap();
+        stalled += start.elapsed();
+        self.stats.add_writer_stall_time(stalled);
+        let meta = match self.meta(&mmap) {
+            Ok(meta) => meta,
+            Err(err) => {
+                self.rwlock.unlock();
+                return Err(err);
+            }
+        };
+
+        self.writer_base.store(meta.txid, Ordering::SeqCst);
+        self.free_pages(&txs);
+        self.update_freelist_stats();
+
+        Ok(Tx::new(self.clone(), mmap, meta, true))
+    }
+
+    /// free_pages releases the pending pages no open read transaction, with
+    /// a txid from `txs`, can see anymore.
+    fn free_pages(&self, txs: &[Txid]) {
+        let mut txids = txs.to_vec();
+        txids.sort_unstable();
+        let mut freelist = lock(&self.freelist);
+
+        // Free all pending pages prior to earliest open transaction.
+        let mut minid = txids.first().copied().unwrap_or(Txid::MAX);
+        if minid > 0 {
+            freelist.release(minid - 1);
+        }
+
+        // Release unused txid extents: pages allocated and freed between two
+        // open read transactions are seen by neither of them.
+        for &txid in &txids {
+            if txid > 0 {
+                freelist.release_range(minid, txid - 1);
+            }
+            minid = txid.saturating_add(1);
+        }
+        freelist.release_range(minid, Txid::MAX);
+    }
+
+    /// update_freelist_stats refreshes the freelist gauges from the freelist.
+    pub(crate) fn update_freelist_stats(&self) {
+        let freelist = lock(&self.freelist);
+        let free_page_n = freelist.free_count() as i64;
+        let pending_page_n = freelist.pending_count() as i64;
+        self.stats.set_freelist(
+            free_page_n,
+            pending_page_n,
+            (free_page_n + pending_page_n) * self.page_size as i64,
+            freelist.size() as i64,
+        );
+    }
+
+    /// free_page_ids returns the pages below the high water mark that the
+    /// last committed transaction does not reach. They make up the freelist
+    /// when it is not persisted. The time the scan took is added to
+    /// `Stats::freelist_rebuild_time`.
+    pub(crate) fn free_page_ids(self: &Arc<RawDB>) -> Result<Vec<Pgid>> {
+        let start = Instant::now();
+        let tx = self.begin_tx(None)?;
+        let ids = tx.unreachable_pages()?;
+        let high_water = tx.meta.get().pgid;
+        drop(tx);
+
+        let elapsed = start.elapsed();
+        self.stats.add_freelist_rebuild_time(elapsed);
+        self.logger.debug(format_args!(
+            "rebuilt freelist by scanning {} pages in {:?}: {} free pages",
+            high_water,
+            elapsed,
+            ids.len()
+        ));
+        Ok(ids)
+    }
+
+    /// load_freelist reads the freelist of the last committed transaction,
+    /// or rebuilds it when it was not persisted, unless that was done
+    /// already. A writable database loads it when opened, a read-only one the
+    /// first time it is needed.
+    pub(crate) fn load_freelist(self: &Arc<RawDB>) -> Result<()> {
+        if self.freelist_loaded() {
+            return Ok(());
+        }
+        let _guard = lock(&self.freelist_load);
+        if self.freelist_loaded() {
+            return Ok(());
+        }
+
+        let mmap = self.mmap();
+        let meta = self.meta(&mmap)?;
+        if meta.freelist == PGID_NO_FREELIST {
+            // The freelist was not persisted: every page the committed
+            // state does not reach is free.
+            drop(mmap);
+            let ids = self.free_page_ids()?;
+            lock(&self.freelist).read_ids(ids);
+        } else {
+            let p = mmap.page(self.page_size, meta.freelist)?;
+            let mut freelist = lock(&self.freelist);
+            freelist.read(p)?;
+            self.logger.debug(format_args!(
+                "read freelist from page {}: {} free pages",
+                meta.freelist,
+                freelist.free_count()
+            ));
+        }
+        self.update_freelist_stats();
+        self.freelist_loaded.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// freelist_loaded reports whether the freelist has been loaded.
+    pub(crate) fn freelist_loaded(&self) -> bool {
+        self.freelist_loaded.load(Ordering::Acquire)
+    }
+
+    /// page_buf returns a zeroed buffer for `count` contiguous pages. Single
+    /// pages are taken from the page pool when one is available.
+    pub(crate) fn page_buf(&self, count: usize) -> Vec<u8> {
+        if count == 1 {
+            if let Some(buf) = lock(&self.page_pool).pop() {
+                self.stats.inc_page_pool(true);
+                return buf;
+            }
+            self.stats.inc_page_pool(false);
+        }
+        vec![0u8; count * self.page_size]
+    }
+
+    /// put_page_buf returns a buffer obtained from page_buf. Single-page
+    /// buffers are kept for reuse while the pool is below its cap.
+    pub(crate) fn put_page_buf(&self, mut buf: Vec<u8>) {
+        if buf.len() != self.page_size {
+            return;
+        }
+        let mut pool = lock(&self.page_pool);
+        if pool.len() < self.page_pool_size {
+            buf.fill(0);
+            pool.push(buf);
+        }
+    }
+
+    /// release_writer releases the writer lock held by a read-write transaction.
+    pub(crate) fn release_writer(&self) {
+        self.rwlock.unlock();
+    }
+
+    /// remove_tx removes a read-only transaction from the database.
+    pub(crate) fn remove_tx(&self, txid: Txid, stats: &TxStats) {
+        // Use the meta lock to restrict access to the DB object.
+        let mut txs = lock(&self.metalock);
+
+        // Remove the transaction.
+        if let Some(i) = txs.iter().position(|&id| id == txid) {
+            txs.swap_remove(i);
+        }
+        let n = txs.len();
+
+        // Unlock the meta pages.
+        drop(txs);
+
+        // Merge statistics.
+        self.stats.set_open_tx_n(n as i64);
+        self.stats.add_tx_stats(stats);
+    }
+
+    /// mmap returns the current mapping of the data file.
+    pub(crate) fn mmap(&self) -> Arc<Mmap> {
+        self.mmaplock
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// remap memory maps the data file again, so that it covers at least `minsz` bytes.
+    /// Transactions that still hold the previous mapping keep it alive until they close.
+    pub(crate) fn remap(&self, minsz: usize) -> Result<()> {
+        let mut mmap = self.mmaplock.write().unwrap_or_else(|e| e.into_inner());
+        let minsz = minsz.max(mmap.len());
+        let old = mmap.len();
+        *mmap = Arc::new(mmap_region(
+            &self.file,
+            self.backend,
+            self.page_transform.as_deref(),
+            self.page_size,
+            minsz,
+            self.mmap_flags,
+        )?);
+        advise(
+            &mmap,
+            &*self.ops,
+            &*self.logger,
+            self.madvise,
+            self.huge_pages,
+        );
+        self.stats.inc_remap(mmap.len());
+        self.logger.debug(format_args!(
+            "remapped db file ({}) from {} to {} bytes",
+            self.path.display(),
+            old,
+            mmap.len()
+        ));
+        Ok(())
+    }
+
+    /// buffer_stale reports whether the meta pages in the file differ from
+    /// the ones in the buffer of the buffer backend, which happens when
+    /// another process commits to a database opened read-only.
+    fn buffer_stale(&self) -> Result<bool> {
+        let mmap = self.mmap();
+        if !mmap.is_buffer() {
+            return Ok(false);
+        }
+        let mut buf = vec![0u8; 2 * self.page_size];
+        self.ops.read_at(&self.file, &mut buf, 0)?;
+        Ok(mmap.get(0..buf.len()) != Some(&buf[..]))
+    }
+
+    /// write_at writes `buf` to the data file at `offset`, and copies it
+    /// into the buffer of the buffer backend, which does not see writes to
+    /// the file by itself.
+    /// With a page transform, the file gets the encrypted pages and the
+    /// buffer the plaintext; `buf` must then hold whole pages.
+    pub(crate) fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        match &self.page_transform {
+            Some(t) => {
+                let mut encrypted = buf.to_vec();
+                let first = offset / self.page_size as u64;
+                transform::encrypt_pages(&**t, self.page_size, first, &mut encrypted);
+                self.ops.write_at(&self.file, &encrypted, offset)?;
+            }
+            None => self.ops.write_at(&self.file, buf, offset)?,
+        }
+        if self.backend == Backend::Buffer {
+            self.mmap().write(buf, offset);
+        }
+        Ok(())
+    }
+
+    /// meta retrieves the current meta page reference.
+    pub(crate) fn meta(&self, mmap: &Mmap) -> Result<Meta> {
+        self.select_meta(mmap).map(|(_, meta, _)| meta)
+    }
+
+    /// select_meta returns the current meta page, the slot it is stored in,
+    /// and whether the meta page in the other slot is valid.
+    fn select_meta(&self, mmap: &Mmap) -> Result<(MetaSlot, Meta, bool)> {
+        // We have to return the meta with the highest txid which doesn't fail
+        // validation. Otherwise, we can cause errors when in fact the database is
+        // in a consistent state. meta_a is the one with the higher txid.
+        let meta0 = read_meta(mmap, self.page_size, 0)?;
+        let meta1 = read_meta(mmap, self.page_size, 1)?;
+        let ((slot_a, meta_a), (slot_b, meta_b)) = if meta1.txid > meta0.txid {
+            ((MetaSlot::Page1, meta1), (MetaSlot::Page0, meta0))
+        } else {
+            ((MetaSlot::Page0, meta0), (MetaSlot::Page1, meta1))
+        };
+
+        // Use higher meta page if valid. Otherwise fallback to previous, if valid.
+        match (meta_a.validate(), meta_b.validate()) {
+            (Ok(()), valid) => Ok((slot_a, meta_a, valid.is_ok())),
+            (Err(_), Ok(())) => Ok((slot_b, meta_b, false)),
+            (Err(err), Err(_)) => Err(err),
+        }
+    }
+
+    /// grow grows the size of the database to the given sz.
+    pub(crate) fn grow(&self, sz: usize) -> Result<()> {
+        // Ignore if the new size is less than available file size.
+        let filesz = self.filesz.load(Ordering::SeqCst);
+        if sz <= filesz {
+            return Ok(());
+        }
+
+        // If the data is smaller than the alloc size then only allocate what's needed.
+        // Once it goes over the allocation size then allocate in chunks.
+        let datasz = self.mmap().len();
+        let sz = if datasz <= self.alloc_size {
+            sz.max(datasz)
+        } else {
+            sz + self.alloc_size
+        };
+
+        // Reserve the new region first so that it is allocated in one go
+        // rather than page by page as it gets written.
+        if self.prealloc {
+            self.ops
+                .allocate(&self.file, filesz as u64, (sz - filesz) as u64)?;
+        }
+
+        // Truncate and fsync to ensure file size metadata is flushed.
+        self.ops.truncate(&self.file, sz as u64)?;
+        if !self.no_grow_sync {
+            self.ops.sync(&self.file, self.sync_mode)?;
+        }
+
+        self.filesz.store(sz, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for RawDB {
+    /// Drop closes a database that was not closed. It runs once every handle
+    /// and transaction of the database is gone, and releases the file lock
+    /// before the mapping and the file are released.
+    fn drop(&mut self) {
+        if self.opened.swap(false, Ordering::SeqCst) {
+            self.logger
+                .info(format_args!("closing db ({}) on drop", self.path.display()));
+            if let Err(err) = unix::funlock(&self.file) {
+                self.logger.warn(format_args!(
+                    "unlocking db ({}) failed: {}",
+                    self.path.display(),
+                    err
+                ));
+            }
+        }
+    }
+}
+
+/// init creates a new database file and initializes its meta pages. The
+/// freelist and root pages are encrypted with `transform`.
+fn init(
+    file: &File,
+    ops: &dyn DbOps,
+    transform: Option<&dyn PageTransform>,
+    page_size: usize,
+    sync_mode: SyncMode,
+) -> Result<()> {
+    // Create two meta pages on a buffer.
+    let mut buf = vec![0u8; page_size * 4];
+    for i in 0..2 {
+        let mut p = PageMut::new(&mut buf[i * page_size..(i + 1) * page_size]);
+        p.set_flags(META_PAGE_FLAG);
+
+        // Initialize the meta page.
+        let mut m = Meta {
+            magic: MAGIC,
+            version: VERSION,
+            page_size: page_size as u32,
+            freelist: 2,
+            root: InBucket {
+                root: 3,
+                sequence: 0,
+            },
+            pgid: 4,
+            txid: i as Txid,
+            ..Meta::default()
+        };
+        m.write(&mut p);
+    }
+
+    // Write an empty freelist at page 3.
+    let mut p = PageMut::new(&mut buf[2 * page_size..3 * page_size]);
+    p.set_id(2);
+    p.set_flags(FREELIST_PAGE_FLAG);
+    p.set_count(0);
+
+    // Write an empty leaf page at page 4.
+    let mut p = PageMut::new(&mut buf[3 * page_size..4 * page_size]);
+    p.set_id(3);
+    p.set_flags(LEAF_PAGE_FLAG);
+    p.set_count(0);
+
+    // Write the buffer to our data file.
+    if let Some(t) = transform {
+        transform::encrypt_pages(t, page_size, 0, &mut buf);
+    }
+    ops.write_at(file, &buf, 0)?;
+    ops.sync(file, sync_mode)?;
+    Ok(())
+}
+
+/// read_meta decodes the meta stored in page `id` of `data`.
+fn read_meta(mmap: &Mmap, page_size: usize, id: Pgid) -> Result<Meta> {
+    let p = mmap.page(page_size, id)?;
+    if p.data().len() < crate::meta::META_SIZE {
+        return Err(Error::Invalid);
+    }
+    Ok(Meta::read(p.data()))
+}
+
+/// read_page_size determines the page size of an existing database file from
+/// whichever meta page is valid.
+pub(crate) fn read_page_size(file: &File, ops: &dyn DbOps) -> Result<usize> {
+    let mut buf = [0u8; PAGE_HEADER_SIZE + crate::meta::META_SIZE];
+
+    // Check the first page.
+    let first = read_meta_at(file, ops, 0, &mut buf);
+    if let Ok(m) = &first {
+        return Ok(m.page_size as usize);
+    }
+
+    // Check the second page, trying every page size we might have been
+    // created with.
+    for &page_size in &POSSIBLE_PAGE_SIZES {
+        if let Ok(m) = read_meta_at(file, ops, page_size as u64, &mut buf) {
+            if m.page_size as usize == page_size {
+                return Ok(page_size);
+            }
+        }
+    }
+
+    // If both pages are invalid then report why the first one failed.
+    first.map(|m| m.page_size as usize)
+}
+
+fn read_meta_at(file: &File, ops: &dyn DbOps, offset: u64, buf: &mut [u8]) -> Result<Meta> {
+    if ops.read_at(file, buf, offset).is_err() {
+        return Err(Error::Invalid);
+    }
+    let m = Meta::read(&buf[PAGE_HEADER_SIZE..]);
+    m.validate()?;
+    Ok(m)
+}
+
+/// mmap_region memory maps the data file, or reads it into a buffer, sizing
+/// the view to hold at least `minsz` bytes, and validates the meta pages in it.
+/// A buffer is decrypted with `transform`, which requires the buffer backend.
+fn mmap_region(
+    file: &File,
+    backend: Backend,
+    transform: Option<&dyn PageTransform>,
+    page_size: usize,
+    minsz: usize,
+    flags: i32,
+) -> Result<Mmap> {
+    // The file must hold both meta pages: past its end, a mapping faults on
+    // access rather than reading garbage.
+    let filesz = file_size(file)?;
+    if filesz < 2 * page_size {
+        return Err(Error::Invalid);
+    }
+
+    // Ensure the size is at least the minimum size.
+    let size = mmap_size(page_size, filesz.max(minsz))?;
+
+    // Memory-map the data file as a byte slice.
+    let mut mmap = backend.map(file, size, flags, page_size)?;
+    if let (Some(t), Some(buf)) = (transform, mmap.buffer_mut()) {
+        let n = filesz.min(buf.len());
+        transform::decrypt_pages(t, page_size, 0, &mut buf[..n]);
+    }
+
+    // Validate the meta pages. We only return an error if both meta
+    // pages fail validation, since meta0 failing validation means that it
+    // wasn't saved properly -- but we can recover using meta1. And vice-versa.
+    let err0 = read_meta(&mmap, page_size, 0).and_then(|m| m.validate());
+    let err1 = read_meta(&mmap, page_size, 1).and_then(|m| m.validate());
+    if let (Err(err0), Err(_)) = (err0, err1) {
+        return Err(err0);
+    }
+
+    Ok(mmap)
+}
+
+/// advise passes the access pattern of `mmap` to the kernel. A failure only
+/// costs performance, so it is logged rather than returned. Buffers are not
+/// advised.
+fn advise(mmap: &Mmap, ops: &dyn DbOps, logger: &dyn Logger, mode: MadviseMode, huge_pages: bool) {
+    if mmap.is_buffer() {
+        return;
+    }
+    let huge = unix::MADV_HUGEPAGE.filter(|_| huge_pages);
+    for advice in std::iter::once(mode.advice()).chain(huge) {
+        if let Err(err) = ops.madvise(mmap, advice) {
+            logger.warn(format_args!("madvise({}) failed: {}", advice, err));
+        }
+    }
+}
+
+/// mmap_offset returns the offset of page `id` in the mmap. Offsets are
+/// computed as u64 and only converted once they are known to fit in the
+/// largest mmap of the platform, otherwise `Error::MmapTooLarge` is returned.
+pub(crate) fn mmap_offset(id: Pgid, page_size: usize) -> Result<usize> {
+    id.checked_mul(page_size as u64)
+        .filter(|&off| off <= MAX_MAP_SIZE as u64)
+        .map(|off| off as usize)
+        .ok_or(Error::MmapTooLarge)
+}
+
+/// alloc_size returns the size in bytes of `count` contiguous pages, failing
+/// with `Error::MmapTooLarge` above MAX_ALLOC_SIZE.
+pub(crate) fn alloc_size(count: usize, page_size: usize) -> Result<usize> {
+    count
+        .checked_mul(page_size)
+        .filter(|&sz| sz <= MAX_ALLOC_SIZE)
+        .ok_or(Error::MmapTooLarge)
+}
+
+/// file_size returns the size of the data file, failing with
+/// `Error::MmapTooLarge` when it cannot be addressed on this platform.
+fn file_size(file: &File) -> Result<usize> {
+    usize::try_from(file.metadata()?.len()).map_err(|_| Error::MmapTooLarge)
+}
+
+/// mmap_size determines the appropriate size for the mmap given the current size
+/// of the database. The minimum size is 32KB and doubles until it reaches 1GB.
+/// Returns an error if the new mmap size is greater than the max allowed.
+fn mmap_size(page_size: usize, size: usize) -> Result<usize> {
+    // Double the size from 32KB until 1GB.
+    for i in 15..=30 {
+        if size <= 1 << i {
+            return Ok(1 << i);
+        }
+    }
+
+    // Verify the requested size is not above the maximum allowed.
+    if size > MAX_MAP_SIZE {
+        return Err(Error::MmapTooLarge);
+    }
+
+    // If larger than 1GB then grow by 1GB at a time.
+    let mut sz = size;
+    let remainder = sz % MAX_MMAP_STEP;
+    if remainder > 0 {
+        sz += MAX_MMAP_STEP - remainder;
+    }
+
+    // Ensure that the mmap size is a multiple of the page size.
+    // This should always be true since we're incrementing in MBs.
+    if !sz.is_multiple_of(page_size) {
+        sz = (sz / page_size + 1) * page_size;
+    }
+
+    // If we've exceeded the max size then only grow up to the max size.
+    Ok(sz.min(MAX_MAP_SIZE))
+}
+
+/// Stats represents statistics about the database.
+///
+/// A `Stats` value is a snapshot: it is copied out of the live counters at the
+/// time it is requested and never changes afterwards.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    // Freelist stats
+    /// total number of free pages on the freelist
+    pub free_page_n: i64,
+    /// total number of pending pages on the freelist
+    pub pending_page_n: i64,
+    /// total bytes allocated in free pages
+    pub free_alloc: i64,
+    /// total bytes used by the freelist
+    pub freelist_inuse: i64,
+
+    // Transaction stats
+    /// total number of started read transactions
+    pub tx_n: i64,
+    /// number of currently open read transactions
+    pub open_tx_n: i64,
+
+    // Page pool stats
+    /// number of single-page buffers taken from the page pool
+    pub page_pool_hits: i64,
+    /// number of single-page buffers allocated because the page pool was empty
+    pub page_pool_misses: i64,
+
+    // Mapping and lock stats
+    /// number of times the data file was mapped again to cover its growth
+    pub remap_count: i64,
+    /// total size of the mappings created by remaps, in bytes
+    pub remap_bytes: i64,
+    /// total time write transactions waited for the locks when beginning,
+    /// mostly for the previous writer to finish
+    pub writer_stall_time: Duration,
+    /// total time read transactions waited for the locks when beginning,
+    /// mostly for a remap to finish
+    pub reader_wait_time: Duration,
+    /// total time spent rebuilding the freelist by scanning the file, when
+    /// it is not persisted
+    pub freelist_rebuild_time: Duration,
+
+    /// global, ongoing stats.
+    pub tx_stats: TxStats,
+}
+
+impl Stats {
+    /// Sub calculates and returns the difference between two sets of database stats.
+    /// This is useful when obtaining stats at two different points and time and
+    /// you need the performance counters that occurred within that time span.
+    ///
+    /// Counters (`tx_n`, the page pool, remap, lock wait and freelist rebuild
+    /// counters and everything inside `tx_stats`) are diffed. Gauges
+    /// (the freelist fields and `open_tx_n`) describe a point in time rather
+    /// than an accumulation, so they are taken from `self` unchanged.
+    pub fn sub(&self, other: &Stats) -> Stats {
+        Stats {
+            free_page_n: self.free_page_n,
+            pending_page_n: self.pending_page_n,
+            free_alloc: self.free_alloc,
+            freelist_inuse: self.freelist_inuse,
+            tx_n: self.tx_n - other.tx_n,
+            open_tx_n: self.open_tx_n,
+            page_pool_hits: self.page_pool_hits - other.page_pool_hits,
+            page_pool_misses: self.page_pool_misses - other.page_pool_misses,
+            remap_count: self.remap_count - other.remap_count,
+            remap_bytes: self.remap_bytes - other.remap_bytes,
+            writer_stall_time: self
+                .writer_stall_time
+                .saturating_sub(other.writer_stall_time),
+            reader_wait_time: self.reader_wait_time.saturating_sub(other.reader_wait_time),
+            freelist_rebuild_time: self
+                .freelist_rebuild_time
+                .saturating_sub(other.freelist_rebuild_time),
+            tx_stats: self.tx_stats.sub(&other.tx_stats),
+        }
+    }
+
+    /// Add accumulates the counters of `other` into `self`.
+    ///
+    /// It is the inverse of `sub` for counters: `a.sub(&b)` followed by
+    /// `add(&b)` yields `a` again. Gauges are left as they are in `self`.
+    pub fn add(&mut self, other: &Stats) {
+        self.tx_n += other.tx_n;
+        self.page_pool_hits += other.page_pool_hits;
+        self.page_pool_misses += other.page_pool_misses;
+        self.remap_count += other.remap_count;
+        self.remap_bytes += other.remap_bytes;
+        self.writer_stall_time += other.writer_stall_time;
+        self.reader_wait_time += other.reader_wait_time;
+        self.freelist_rebuild_time += other.freelist_rebuild_time;
+        self.tx_stats.add(&other.tx_stats);
+    }
+
+    /// Reset zeroes every counter and gauge.
+    pub fn reset(&mut self) {
+        *self = Stats::default();
+    }
+}
+
+/// OpenReport describes what [`DB::open_with_report`] found while opening a
+/// database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenReport {
+    /// the meta page the database was opened from
+    pub meta: MetaSlot,
+    /// the txid of that meta page
+    pub txid: u64,
+    /// whether the other meta page failed validation. Opening falls back to
+    /// the previous commit when the latest meta page is damaged; either way,
+    /// one more damaged meta page makes the database unreadable.
+    pub alternate_corrupt: bool,
+    /// whether the file was empty and has been initialized
+    pub created: bool,
+    /// whether the freelist was read from disk; read-only databases skip it
+    pub freelist_loaded: bool,
+    /// time spent reading the freelist
+    pub freelist_load_time: Duration,
+    /// the page size of the database
+    pub page_size: usize,
+    /// the size of the data file once opened, in bytes
+    pub file_size: u64,
+}
+
+/// FreelistStats represents statistics about the freelist of the database.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FreelistStats {
+    /// total number of free pages on the freelist
+    pub free_page_n: i64,
+    /// total number of pending pages on the freelist
+    pub pending_page_n: i64,
+    /// total bytes allocated in free pages
+    pub free_alloc: i64,
+    /// total bytes used by the freelist
+    pub freelist_inuse: i64,
+}
+
+/// AtomicStats holds the live database counters.
+///
+/// Counters are updated with relaxed atomics by transactions as they close, so
+/// readers never contend on a lock just to report their activity.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicStats {
+    free_page_n: AtomicI64,
+    pending_page_n: AtomicI64,
+    free_alloc: AtomicI64,
+    freelist_inuse: AtomicI64,
+    tx_n: AtomicI64,
+    open_tx_n: AtomicI64,
+    page_pool_hits: AtomicI64,
+    page_pool_misses: AtomicI64,
+    remap_count: AtomicI64,
+    remap_bytes: AtomicI64,
+    /// in nanoseconds
+    writer_stall_time: AtomicI64,
+    /// in nanoseconds
+    reader_wait_time: AtomicI64,
+    /// in nanoseconds
+    freelist_rebuild_time: AtomicI64,
+    tx_stats: TxStats,
+}
+
+impl AtomicStats {
+    /// snapshot copies the current counter values into a plain `Stats`.
+    pub(crate) fn snapshot(&self) -> Stats {
+        Stats {
+            free_page_n: self.free_page_n.load(Ordering::Relaxed),
+            pending_page_n: self.pending_page_n.load(Ordering::Relaxed),
+            free_alloc: self.free_alloc.load(Ordering::Relaxed),
+            freelist_inuse: self.freelist_inuse.load(Ordering::Relaxed),
+            tx_n: self.tx_n.load(Ordering::Relaxed),
+            open_tx_n: self.open_tx_n.load(Ordering::Relaxed),
+            page_pool_hits: self.page_pool_hits.load(Ordering::Relaxed),
+            page_pool_misses: self.page_pool_misses.load(Ordering::Relaxed),
+            remap_count: self.remap_count.load(Ordering::Relaxed),
+            remap_bytes: self.remap_bytes.load(Ordering::Relaxed),
+            writer_stall_time: nanos(&self.writer_stall_time),
+            reader_wait_time: nanos(&self.reader_wait_time),
+            freelist_rebuild_time: nanos(&self.freelist_rebuild_time),
+            tx_stats: self.tx_stats.clone(),
+        }
+    }
+
+    /// freelist copies the current freelist gauges.
+    pub(crate) fn freelist(&self) -> FreelistStats {
+        FreelistStats {
+            free_page_n: self.free_page_n.load(Ordering::Relaxed),
+            pending_page_n: self.pending_page_n.load(Ordering::Relaxed),
+            free_alloc: self.free_alloc.load(Ordering::Relaxed),
+            freelist_inuse: self.freelist_inuse.load(Ordering::Relaxed),
+        }
+    }
+
+    /// inc_tx_n records a newly started read transaction.
+    pub(crate) fn inc_tx_n(&self) {
+        self.tx_n.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// inc_page_pool records whether a single-page buffer was taken from the
+    /// page pool or had to be allocated.
+    pub(crate) fn inc_page_pool(&self, hit: bool) {
+        let counter = if hit {
+            &self.page_pool_hits
+        } else {
+            &self.page_pool_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// inc_remap records a remap that created a mapping of `len` bytes.
+    pub(crate) fn inc_remap(&self, len: usize) {
+        self.remap_count.fetch_add(1, Ordering::Relaxed);
+        self.remap_bytes.fetch_add(len as i64, Ordering::Relaxed);
+    }
+
+    /// add_writer_stall_time records how long a write transaction waited
+    /// for the locks when beginning.
+    pub(crate) fn add_writer_stall_time(&self, d: Duration) {
+        self.writer_stall_time
+            .fetch_add(d.as_nanos() as i64, Ordering::Relaxed);
+    }
+
+    /// add_reader_wait_time records how long a read transaction waited for
+    /// the locks when beginning.
+    pub(crate) fn add_reader_wait_time(&self, d: Duration) {
+        self.reader_wait_time
+            .fetch_add(d.as_nanos() as i64, Ordering::Relaxed);
+    }
+
+    /// add_freelist_rebuild_time records how long a rebuild of the freelist
+    /// took.
+    pub(crate) fn add_freelist_rebuild_time(&self, d: Duration) {
+        self.freelist_rebuild_time
+            .fetch_add(d.as_nanos() as i64, Ordering::Relaxed);
+    }
+
+    /// set_open_tx_n updates the number of currently open read transactions.
+    pub(crate) fn set_open_tx_n(&self, n: i64) {
+        self.open_tx_n.store(n, Ordering::Relaxed);
+    }
+
+    /// set_freelist updates the freelist gauges.
+    pub(crate) fn set_freelist(
+        &self,
+        free_page_n: i64,
+        pending_page_n: i64,
+        free_alloc: i64,
+        freelist_inuse: i64,
+    ) {
+        self.free_page_n.store(free_page_n, Ordering::Relaxed);
+        self.pending_page_n.store(pending_page_n, Ordering::Relaxed);
+        self.free_alloc.store(free_alloc, Ordering::Relaxed);
+        self.freelist_inuse.store(freelist_inuse, Ordering::Relaxed);
+    }
+
+    /// add_tx_stats merges the stats of a closed transaction into the totals.
+    pub(crate) fn add_tx_stats(&self, other: &TxStats) {
+        self.tx_stats.add(other);
+    }
+
+    /// reset zeroes all counters and gauges. Open calls it before the
+    /// freelist is loaded.
+    pub(crate) fn reset(&self) {
+        self.set_freelist(0, 0, 0, 0);
+        self.tx_n.store(0, Ordering::Relaxed);
+        self.open_tx_n.store(0, Ordering::Relaxed);
+        self.page_pool_hits.store(0, Ordering::Relaxed);
+        self.page_pool_misses.store(0, Ordering::Relaxed);
+        self.remap_count.store(0, Ordering::Relaxed);
+        self.remap_bytes.store(0, Ordering::Relaxed);
+        self.writer_stall_time.store(0, Ordering::Relaxed);
+        self.reader_wait_time.store(0, Ordering::Relaxed);
+        self.freelist_rebuild_time.store(0, Ordering::Relaxed);
+        self.tx_stats.reset();
+    }
+}
+
+/// nanos reads a duration counted in nanoseconds.
+fn nanos(counter: &AtomicI64) -> Duration {
+    Duration::from_nanos(counter.load(Ordering::Relaxed).max(0) as u64)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use super::*;
+    use crate::json::JsonEncoding;
+    use proptest::prelude::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// assert_open_fails checks that opening `path` returns an error rather
+    /// than panicking, read-only and, unless `read_only_only`, read-write.
+    fn assert_open_fails(path: &Path, read_only_only: bool) {
+        for read_only in [true, false] {
+            if !read_only && read_only_only {
+                continue;
+            }
+            let options = Options {
+                read_only,
+                ..Options::default()
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| DB::open(path, options)));
+            match result {
+                Ok(Err(_)) => {}
+                Ok(Ok(_)) => panic!("opened {} (read-only: {})", path.display(), read_only),
+                Err(_) => panic!(
+                    "panicked opening {} (read-only: {})",
+                    path.display(),
+                    read_only
+                ),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn open_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A zero-byte file is a new database, unless it can't be initialized.
+        let empty = dir.path().join("empty");
+        File::create(&empty).unwrap();
+        assert_open_fails(&empty, true);
+
+        // A directory is no database.
+        assert_open_fails(dir.path(), false);
+
+        // Neither is a file of random bytes, whatever its length.
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let random = dir.path().join("random");
+        for len in [1, 100, 4096, 3 * 4096, 64 * 1024] {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect();
+            std::fs::write(&random, data).unwrap();
+            assert_open_fails(&random, false);
+        }
+
+        // Nor is a database cut short, even with both meta pages intact.
+        let path = dir.path().join("db");
+        let db = DB::open(&path, Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let page_size = db.0.page_size as u64;
+        let size = db.begin(false).unwrap().size() as usize;
+        db.close().unwrap();
+        drop(db);
+        let data = std::fs::read(&path).unwrap();
+        let truncated = dir.path().join("truncated");
+        for n in [100, page_size + 10, 2 * page_size, 3 * page_size] {
+            std::fs::write(&truncated, &data[..n as usize]).unwrap();
+            assert_open_fails(&truncated, false);
+        }
+        for n in [size / 2, size - 1] {
+            std::fs::write(&truncated, &data[..n]).unwrap();
+            assert_open_fails(&truncated, false);
+        }
+
+        // The slack past the high water mark is not needed.
+        std::fs::write(&truncated, &data[..size]).unwrap();
+        DB::open(&truncated, Options::default()).unwrap();
+    }
+
+    #[test]
+    fn mmap_size_steps() {
+        assert_eq!(mmap_size(4096, 0).unwrap(), 1 << 15);
+        assert_eq!(mmap_size(4096, (1 << 20) + 1).unwrap(), 1 << 21);
+        assert_eq!(mmap_size(4096, 1 << 30).unwrap(), 1 << 30);
+
+        // Above 1GB the mmap grows by 1GB at a time, up to the maximum.
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(mmap_size(4096, (1 << 30) + 1).unwrap(), 2 << 30);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(mmap_size(4096, (1 << 30) + 1).unwrap(), MAX_MAP_SIZE);
+        assert!(mmap_size(4096, MAX_MAP_SIZE).unwrap() <= MAX_MAP_SIZE);
+        assert!(matches!(
+            mmap_size(4096, MAX_MAP_SIZE + 1),
+            Err(Error::MmapTooLarge)
+        ));
+    }
+
+    #[test]
+    fn mmap_offset_bounds() {
+        assert_eq!(mmap_offset(0, 4096).unwrap(), 0);
+        assert_eq!(mmap_offset(3, 4096).unwrap(), 3 * 4096);
+        let last = (MAX_MAP_SIZE / 4096) as Pgid;
+        assert_eq!(mmap_offset(last, 4096).unwrap(), last as usize * 4096);
+        assert!(matches!(
+            mmap_offset(last + 1, 4096),
+            Err(Error::MmapTooLarge)
+        ));
+        assert!(matches!(
+            mmap_offset(u64::MAX, 4096),
+            Err(Error::MmapTooLarge)
+        ));
+
+        // A page 4GiB into the file only fits in a 64-bit mapping.
+        let four_gib = (1 << 32) / 4096;
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(mmap_offset(four_gib, 4096).unwrap() as u64, 1 << 32);
+        #[cfg(target_pointer_width = "32")]
+        assert!(matches!(
+            mmap_offset(four_gib, 4096),
+            Err(Error::MmapTooLarge)
+        ));
+    }
+
+    #[test]
+    fn alloc_size_bounds() {
+        assert_eq!(alloc_size(3, 4096).unwrap(), 3 * 4096);
+        assert_eq!(
+            alloc_size(MAX_ALLOC_SIZE / 4096, 4096).unwrap(),
+            MAX_ALLOC_SIZE / 4096 * 4096
+        );
+        assert!(matches!(
+            alloc_size(MAX_ALLOC_SIZE / 4096 + 1, 4096),
+            Err(Error::MmapTooLarge)
+        ));
+        assert!(matches!(
+            alloc_size(usize::MAX, 4096),
+            Err(Error::MmapTooLarge)
+        ));
+
+        // The largest value fits in a single allocation on 64-bit targets.
+        #[cfg(target_pointer_width = "64")]
+        assert!(alloc_size(crate::MAX_VALUE_SIZE / 4096 + 2, 4096).is_ok());
+    }
+
+    #[test]
+    fn snapshot_is_detached_from_counters() {
+        let stats = AtomicStats::default();
+        stats.inc_tx_n();
+        let before = stats.snapshot();
+        stats.inc_tx_n();
+        assert_eq!(before.tx_n, 1);
+        assert_eq!(stats.snapshot().tx_n, 2);
+        assert_eq!(stats.snapshot().sub(&before).tx_n, 1);
+    }
+
+    #[test]
+    fn concurrent_tx_n_is_monotonic() {
+        let stats = Arc::new(AtomicStats::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let poller = {
+            let (stats, done) = (stats.clone(), done.clone());
+            thread::spawn(move || {
+                let mut last = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let tx_n = stats.snapshot().tx_n;
+                    assert!(tx_n >= last, "tx_n went backwards: {} < {}", tx_n, last);
+                    last = tx_n;
+                }
+            })
+        };
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        stats.inc_tx_n();
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        poller.join().unwrap();
+
+        assert_eq!(stats.snapshot().tx_n, 80_000);
+    }
+
+    fn stats_strategy() -> impl Strategy<Value = Stats> {
+        (
+            (0..1i64 << 40, 0..1i64 << 40, 0..1i64 << 40, 0..1i64 << 40),
+            (0..1i64 << 40, 0..1i64 << 40, 0..1i64 << 40, 0..1i64 << 40),
+            (0..1i64 << 40, 0..1i64 << 40, 0..1u64 << 40),
+            (0..1i64 << 40, 0..1i64 << 40, 0..1u64 << 40, 0..1u64 << 40),
+        )
+            .prop_map(
+                |(
+                    (free_page_n, pending_page_n, free_alloc, freelist_inuse),
+                    (tx_n, open_tx_n, page_count, page_alloc),
+                    (page_pool_hits, page_pool_misses, rebuild_nanos),
+                    (remap_count, remap_bytes, writer_stall_nanos, reader_wait_nanos),
+                )| Stats {
+                    free_page_n,
+                    pending_page_n,
+                    free_alloc,
+                    freelist_inuse,
+                    tx_n,
+                    open_tx_n,
+                    page_pool_hits,
+                    page_pool_misses,
+                    remap_count,
+                    remap_bytes,
+                    writer_stall_time: Duration::from_nanos(writer_stall_nanos),
+                    reader_wait_time: Duration::from_nanos(reader_wait_nanos),
+                    freelist_rebuild_time: Duration::from_nanos(rebuild_nanos),
+                    tx_stats: {
+                        let tx_stats = TxStats::default();
+                        tx_stats.inc_page_count(page_count);
+                        tx_stats.inc_page_alloc(page_alloc);
+                        tx_stats
+                    },
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn sub_then_add_restores_counters(a in stats_strategy(), b in stats_strategy()) {
+            let mut diff = a.sub(&b);
+            diff.add(&b);
+            prop_assert_eq!(diff.tx_n, a.tx_n);
+            prop_assert_eq!(diff.page_pool_hits, a.page_pool_hits);
+            prop_assert_eq!(diff.page_pool_misses, a.page_pool_misses);
+            prop_assert_eq!(diff.remap_count, a.remap_count);
+            prop_assert_eq!(diff.remap_bytes, a.remap_bytes);
+            prop_assert_eq!(diff.tx_stats, a.tx_stats);
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn sub_takes_gauges_from_self(a in stats_strategy(), b in stats_strategy()) {
+            let diff = a.sub(&b);
+            prop_assert_eq!(diff.free_page_n, a.free_page_n);
+            prop_assert_eq!(diff.pending_page_n, a.pending_page_n);
+            prop_assert_eq!(diff.free_alloc, a.free_alloc);
+            prop_assert_eq!(diff.freelist_inuse, a.freelist_inuse);
+            prop_assert_eq!(diff.open_tx_n, a.open_tx_n);
+        }
+    }
+
+    #[test]
+    fn reset_zeroes_everything() {
+        let stats = AtomicStats::default();
+        stats.inc_tx_n();
+        stats.inc_page_pool(true);
+        stats.inc_page_pool(false);
+        stats.set_freelist(1, 2, 3, 4);
+        let tx_stats = TxStats::default();
+        tx_stats.inc_page_count(5);
+        tx_stats.inc_node_count(6);
+        stats.add_tx_stats(&tx_stats);
+        stats.reset();
+        assert_eq!(stats.snapshot(), Stats::default());
+
+        let mut snapshot = Stats {
+            tx_n: 3,
+            free_page_n: 7,
+            ..Stats::default()
+        };
+        snapshot.reset();
+        assert_eq!(snapshot, Stats::default());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn no_freelist_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            no_freelist_sync: true,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options.clone()).unwrap();
+        db.update(|tx| {
+            for name in [&b"a"[..], b"b"] {
+                let b = tx.create_bucket(name)?;
+                for i in 0..1000u32 {
+                    b.put(&i.to_be_bytes(), &[0; 100])?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.delete_bucket(b"a")).unwrap();
+        db.update(|_| Ok(())).unwrap();
+        let free = lock(&db.0.freelist).free_page_ids();
+        assert!(!free.is_empty());
+        let tx = db.begin(false).unwrap();
+        assert_eq!(tx.meta.get().freelist, PGID_NO_FREELIST);
+        assert!(tx.check().is_empty());
+        drop(tx);
+        db.close().unwrap();
+        drop(db);
+
+        // Reopening rebuilds the freelist from the pages nothing reaches.
+        let (db, report) = DB::open_with_report(&path, options.clone()).unwrap();
+        assert!(report.freelist_loaded);
+        assert!(db.stats().freelist_rebuild_time > Duration::ZERO);
+        assert_eq!(lock(&db.0.freelist).free_page_ids(), free);
+        assert_eq!(db.stats().free_page_n, free.len() as i64);
+        let high_water = db.begin(false).unwrap().meta.get().pgid;
+
+        // A rolled back writer rebuilds it too, and the free pages are
+        // handed out before the file grows.
+        let tx = db.begin(true).unwrap();
+        let pgid = tx.allocate(1).unwrap();
+        assert!(free.contains(&pgid));
+        drop(tx);
+        let tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(1).unwrap(), pgid);
+        drop(tx);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"c")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert_eq!(tx.meta.get().pgid, high_water);
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+        drop(db);
+
+        // Without the option the freelist is rebuilt one last time and
+        // written out again.
+        let db = DB::open(&path, Options::default()).unwrap();
+        assert!(db.stats().freelist_rebuild_time > Duration::ZERO);
+        db.view(|tx| {
+            assert_ne!(tx.meta.get().freelist, PGID_NO_FREELIST);
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+        drop(db);
+        let db = DB::open(&path, Options::default()).unwrap();
+        assert_eq!(db.stats().freelist_rebuild_time, Duration::ZERO);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats_start_at_zero_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path, Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+        db.view(|_| Ok(())).unwrap();
+        assert!(db.stats().tx_n > 0);
+        db.close().unwrap();
+        drop(db);
+
+        let db = DB::open(&path, Options::default()).unwrap();
+        let stats = db.stats();
+        assert_eq!(stats.tx_n, 0);
+        assert_eq!(stats.open_tx_n, 0);
+        assert_eq!(stats.tx_stats, TxStats::default());
+        assert_eq!((stats.page_pool_hits, stats.page_pool_misses), (0, 0));
+        // The gauges describe the freelist read from the file.
+        let freelist = lock(&db.0.freelist);
+        assert!(stats.free_page_n > 0);
+        assert_eq!(stats.free_page_n, freelist.free_count() as i64);
+        assert_eq!(stats.freelist_inuse, freelist.size() as i64);
+    }
+
+    /// deleted_widgets creates a database at `path` with free pages.
+    fn deleted_widgets(path: &Path) {
+        let db = DB::open(path, Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+        db.close().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn lazy_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        deleted_widgets(&path);
+
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let (db, report) = DB::open_with_report(&path, options).unwrap();
+        assert!(!report.freelist_loaded);
+        assert_eq!(report.freelist_load_time, Duration::default());
+        assert!(!db.0.freelist_loaded());
+
+        // Reading does not need the freelist.
+        db.view(|_| Ok(())).unwrap();
+        assert!(!db.0.freelist_loaded());
+
+        // Stats load it, once, and describe it.
+        let stats = db.stats();
+        assert!(db.0.freelist_loaded());
+        let free_n = lock(&db.0.freelist).free_count();
+        assert!(free_n > 0);
+        assert_eq!(stats.free_page_n, free_n as i64);
+        db.0.load_freelist().unwrap();
+        assert_eq!(lock(&db.0.freelist).free_count(), free_n);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pre_load_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        deleted_widgets(&path);
+
+        let options = Options {
+            read_only: true,
+            pre_load_freelist: true,
+            ..Options::default()
+        };
+        let (db, report) = DB::open_with_report(&path, options).unwrap();
+        assert!(report.freelist_loaded);
+        assert!(db.0.freelist_loaded());
+        assert!(lock(&db.0.freelist).free_count() > 0);
+        db.view(|tx| {
+            assert_eq!(tx.page(tx.meta.get().freelist)?.unwrap().typ, "freelist");
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn freelist_gauges_follow_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..2000u32 {
+                b.put(&i.to_be_bytes(), &[0; 500])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let before = db.freelist_stats();
+
+        // The pages of a large delete are pending until the next writer.
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+        let deleted = db.freelist_stats();
+        assert!(deleted.pending_page_n > 200);
+
+        // Beginning the next writer releases them.
+        let tx = db.begin(true).unwrap();
+        let released = db.freelist_stats();
+        assert_eq!(released.pending_page_n, 0);
+        assert!(released.free_page_n >= before.free_page_n + deleted.pending_page_n);
+        assert_eq!(
+            released.free_alloc,
+            released.free_page_n * tx.db.page_size as i64
+        );
+        assert!(released.freelist_inuse > before.freelist_inuse);
+        drop(tx);
+
+        let stats = db.stats();
+        let gauges = db.freelist_stats();
+        assert_eq!(
+            (
+                stats.free_page_n,
+                stats.pending_page_n,
+                stats.free_alloc,
+                stats.freelist_inuse
+            ),
+            (
+                gauges.free_page_n,
+                gauges.pending_page_n,
+                gauges.free_alloc,
+                gauges.freelist_inuse
+            )
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn view_and_update_return_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        let seq = db
+            .update_ret(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                b.put(b"foo", b"bar")?;
+                b.next_sequence()
+            })
+            .unwrap();
+        assert_eq!(seq, 1);
+
+        let value: Option<Vec<u8>> = db
+            .view_ret(|tx| Ok(tx.bucket(b"widgets")?.get(b"foo")?.map(<[u8]>::to_vec)))
+            .unwrap();
+        assert_eq!(value, Some(b"bar".to_vec()));
+
+        // Errors roll the transaction back and are returned unchanged.
+        let err = db
+            .update_ret(|tx| -> Result<Vec<u8>> {
+                tx.create_bucket(b"gadgets")?;
+                Err(Error::BucketNotFound)
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::BucketNotFound));
+        let exists = db.view_ret(|tx| Ok(tx.bucket(b"gadgets").is_ok())).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn handle_is_static() {
+        fn assert_handle<T: Clone + Send + Sync + 'static>() {}
+        assert_handle::<DB>();
+
+        // A service holding the database in 'static shared state.
+        struct Service {
+            db: DB,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let service = Arc::new(Service {
+            db: DB::open(dir.path().join("db"), Options::default()).unwrap(),
+        });
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let service = service.clone();
+                thread::spawn(move || {
+                    service.db.update(|tx| {
+                        tx.create_bucket_if_not_exists(b"widgets")?
+                            .put(&[i + 1], &[i])
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        // A transaction outlives the handle it was started from.
+        let tx = service.db.clone().begin(false).unwrap();
+        drop(service);
+        assert_eq!(
+            tx.bucket(b"widgets").unwrap().get(&[4]).unwrap(),
+            Some(&[3][..])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn release_between_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let churn = |n: u32| {
+            for i in 0..n {
+                db.update(|tx| {
+                    tx.create_bucket_if_not_exists(b"widgets")?
+                        .put(b"foo", &i.to_be_bytes())
+                })
+                .unwrap();
+            }
+        };
+        churn(1);
+        let first = db.begin(false).unwrap();
+        churn(50);
+        let second = db.begin(false).unwrap();
+        churn(50);
+        db.update(|_| Ok(())).unwrap();
+
+        // Releasing only below the oldest reader keeps every page freed by
+        // the 100 commits since it pending, over 200 pages. Pages both
+        // allocated and freed between the readers are released instead.
+        let pending = db.freelist_stats().pending_page_n;
+        assert!(pending < 20, "pending {}", pending);
+
+        // Both readers still see their own snapshot.
+        for (tx, value) in [(&first, 0u32), (&second, 49)] {
+            assert!(tx.check().is_empty());
+            let b = tx.bucket(b"widgets").unwrap();
+            assert_eq!(b.get(b"foo").unwrap(), Some(&value.to_be_bytes()[..]));
+        }
+        drop(first);
+        drop(second);
+        let _tx = db.begin(true).unwrap();
+        assert_eq!(db.freelist_stats().pending_page_n, 0);
+    }
+
+    /// within runs `f` on another thread and fails the test if it doesn't
+    /// finish within a few seconds, instead of hanging it.
+    fn within<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || tx.send(f()).unwrap());
+        // Miri runs the threads a hundred times slower.
+        let timeout = Duration::from_secs(if cfg!(miri) { 600 } else { 10 });
+        rx.recv_timeout(timeout).expect("deadlocked")
+    }
+
+    #[test]
+    fn thread_safety() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn assert_send<T: Send>() {}
+        // The mapping, shared by every handle and transaction, is the only
+        // shared state with an explicit impl; the rest follows from it.
+        assert_send_sync::<Mmap>();
+        assert_send_sync::<RawDB>();
+        assert_send_sync::<DB>();
+        // Transactions move between threads but are not shared: see the
+        // compile_fail example on Tx.
+        assert_send::<Tx>();
+    }
+
+    /// Every path taking more than one of the database locks runs at once:
+    /// readers beginning and ending, a writer growing the file, which
+    /// remaps it, and committing, batches, stats and usage reports, then
+    /// close. Taking the locks out of order deadlocks this test.
+    ///
+    /// Miri runs it on the buffer backend, which takes the same locks as the
+    /// mapping; see the README for the command.
+    #[test]
+    fn lock_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            force_buffer_backend: cfg!(miri),
+            ..Options::default()
+        };
+        let db = DB::open(dir.path().join("db"), options).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+        within(move || {
+            let stop = Arc::new(AtomicBool::new(false));
+            let mut handles = Vec::new();
+            for i in 0..4u8 {
+                let (db, stop) = (db.clone(), stop.clone());
+                handles.push(thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match i {
+                            0 => db.view(|tx| tx.bucket(b"widgets").map(drop)).unwrap(),
+                            1 => db
+                                .batch(move |tx| tx.bucket(b"widgets")?.put(&[i], &[i]))
+                                .unwrap(),
+                            2 => drop(db.usage_report().unwrap()),
+                            _ => drop(db.stats()),
+                        }
+                    }
+                }));
+            }
+            for i in 0..if cfg!(miri) { 5 } else { 50u32 } {
+                db.update(|tx| tx.bucket(b"widgets")?.put(&i.to_be_bytes(), &[0; 50_000]))
+                    .unwrap();
+            }
+            stop.store(true, Ordering::SeqCst);
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            db.close().unwrap();
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn remap_and_lock_wait_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+        let before = db.stats();
+        assert_eq!(before.remap_count, 0);
+
+        // A reader pins the initial mapping while a slow writer grows the
+        // file past it, and a second writer waits for the first one.
+        let reader = db.begin(false).unwrap();
+        let mapped = reader.mmap.len();
+        let mut tx = db.begin(true).unwrap();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                db.update(|tx| tx.bucket(b"widgets")?.put(b"last", b"x"))
+                    .unwrap()
+            })
+        };
+        let b = tx.bucket(b"widgets").unwrap();
+        for i in 0..100u32 {
+            b.put(&i.to_be_bytes(), &[0; 4096]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        tx.commit().unwrap();
+        writer.join().unwrap();
+
+        let stats = db.stats().sub(&before);
+        assert!(stats.remap_count > 0);
+        assert!(stats.remap_bytes > mapped as i64);
+        assert!(stats.writer_stall_time >= Duration::from_millis(20));
+        assert!(stats.reader_wait_time > Duration::ZERO);
+        assert!(reader
+            .bucket(b"widgets")
+            .unwrap()
+            .get(b"last")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn buffer_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            force_buffer_backend: true,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options.clone()).unwrap();
+        assert!(db.0.mmap().is_buffer());
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+
+        // Growing the buffer leaves the one a reader holds in place.
+        let reader = db.begin(false).unwrap();
+        let before = reader
+            .bucket(b"widgets")
+            .unwrap()
+            .get(b"foo")
+            .unwrap()
+            .unwrap();
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), &[1; 4096])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert!(!Arc::ptr_eq(&reader.mmap, &db.0.mmap()));
+        assert_eq!(before, b"bar");
+        assert_eq!(reader.bucket(b"widgets").unwrap().stats().unwrap().key_n, 1);
+        drop(reader);
+
+        // A read-only buffer reads the file again once another handle commits.
+        let ro = DB::open(
+            &path,
+            Options {
+                read_only: true,
+                no_lock: true,
+                ..options
+            },
+        )
+        .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.put(b"foo", b"baz"))
+            .unwrap();
+        ro.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.get(b"foo")?, Some(&b"baz"[..]));
+            assert_eq!(b.stats()?.key_n, 101);
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn drop_unlocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+
+        // The last open transaction keeps the database open.
+        let db = DB::open(&path, options.clone()).unwrap();
+        let handle = db.clone();
+        let tx = db.begin(false).unwrap();
+        drop(db);
+        drop(handle);
+        assert!(matches!(
+            DB::open(&path, options.clone()),
+            Err(Error::Timeout)
+        ));
+        assert!(tx.bucket_names().unwrap().is_empty());
+        drop(tx);
+
+        // Once it is gone the lock is released without a close.
+        let db = DB::open(&path, options.clone()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+        drop(db);
+        let db = DB::open(&path, options).unwrap();
+        db.view(|tx| tx.bucket(b"widgets").map(drop)).unwrap();
+        db.close().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn read_only_while_write_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let writer = DB::open(&path, Options::default()).unwrap();
+        let put = |n: u32| {
+            writer
+                .update(|tx| {
+                    let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                    for i in 0..n {
+                        b.put(&i.to_be_bytes(), &[1; 100])?;
+                    }
+                    b.create_bucket_if_not_exists(b"nested")?
+                        .put(b"foo", b"bar")
+                })
+                .unwrap()
+        };
+        put(100);
+
+        // The writer holds an exclusive lock, which keeps a shared one out.
+        let options = Options {
+            read_only: true,
+            timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+        assert!(matches!(
+            DB::open(&path, options.clone()),
+            Err(Error::Timeout)
+        ));
+
+        let reader = DB::open(
+            &path,
+            Options {
+                no_lock: true,
+                ..options
+            },
+        )
+        .unwrap();
+        let before = std::fs::read(&path).unwrap();
+        let export = |db: &DB| {
+            let mut out = Vec::new();
+            db.export_json(&mut out, JsonEncoding::Hex).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let check = |db: &DB| {
+            db.view_ret(|tx| {
+                assert!(tx.check().is_empty());
+                Ok(tx.bucket(b"widgets")?.stats()?.key_n)
+            })
+            .unwrap()
+        };
+        assert_eq!(check(&reader), 102);
+        assert_eq!(export(&reader), export(&writer));
+        assert!(matches!(
+            reader.update(|_| Ok(())),
+            Err(Error::DatabaseReadOnly)
+        ));
+        // The reader never writes to the file.
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+
+        // Commits of the writer, growing the file, show up in new
+        // transactions of the reader.
+        put(5000);
+        assert_eq!(check(&reader), 5002);
+        assert_eq!(export(&reader), export(&writer));
+
+        // Writable databases always take the lock.
+        assert!(matches!(
+            DB::open(
+                &path,
+                Options {
+                    no_lock: true,
+                    timeout: Some(Duration::from_millis(100)),
+                    ..Options::default()
+                }
+            ),
+            Err(Error::Timeout)
+        ));
+        reader.close().unwrap();
+        writer.close().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn read_only_follows_growth() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        DB::open(&path, Options::default())
+            .unwrap()
+            .update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+
+        let reader = DB::open(
+            &path,
+            Options {
+                read_only: true,
+                no_lock: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let mapped = reader.0.mmap().len();
+        let writer = DB::open(&path, Options::default()).unwrap();
+
+        for batch in 0..10u32 {
+            writer
+                .update(|tx| {
+                    let b = tx.bucket(b"widgets")?;
+                    for i in 0..100u32 {
+                        b.put(&(batch * 100 + i).to_be_bytes(), &[0; 1000])?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+
+            let n = reader
+                .view_ret(|tx| Ok(tx.bucket(b"widgets")?.stats()?.key_n))
+                .unwrap();
+            assert_eq!(n, (batch as usize + 1) * 100);
+        }
+        assert!(reader.0.mmap().len() > mapped);
+        reader
+            .view(|tx| {
+                assert!(tx.check().is_empty());
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn begin_at_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path, Options::default()).unwrap();
+        let put = |value: &[u8]| {
+            db.update(|tx| {
+                tx.create_bucket_if_not_exists(b"widgets")?
+                    .put(b"foo", value)
+            })
+            .unwrap()
+        };
+        let get = |tx: &Tx| {
+            tx.bucket(b"widgets")
+                .ok()
+                .and_then(|b| b.get_owned(b"foo").unwrap())
+        };
+        // Commits write the meta page of their txid modulo two.
+        let slots = |db: &DB| {
+            let txid = db.begin(false).unwrap().id();
+            let slot = |txid: u64| [MetaSlot::Page0, MetaSlot::Page1][txid as usize % 2];
+            (slot(txid), slot(txid + 1))
+        };
+
+        put(b"old");
+        put(b"new");
+        let (current, previous) = slots(&db);
+        let mut tx = db.begin_at_meta(previous).unwrap();
+        assert_eq!(get(&tx).as_deref(), Some(&b"old"[..]));
+        assert_eq!(tx.id() + 1, db.begin(false).unwrap().id());
+        assert!(!tx.writable());
+        assert!(matches!(tx.commit(), Err(Error::TxNotWritable)));
+        let tx = db.begin_at_meta(current).unwrap();
+        assert_eq!(get(&tx).as_deref(), Some(&b"new"[..]));
+
+        // The pinned transaction keeps the pages of the previous commit
+        // while later writes churn.
+        let pinned = db.begin_at_meta(previous).unwrap();
+        for i in 0..20u32 {
+            put(&i.to_be_bytes());
+        }
+        assert!(pinned.check().is_empty());
+        assert_eq!(get(&pinned).as_deref(), Some(&b"old"[..]));
+        drop(pinned);
+
+        // Once a write transaction begins, the previous commit is stale until
+        // the next one.
+        let (_, previous) = slots(&db);
+        db.begin(true).unwrap().rollback().unwrap();
+        assert!(matches!(db.begin_at_meta(previous), Err(Error::StaleMeta)));
+        put(b"last");
+        let (_, previous) = slots(&db);
+        let tx = db.begin_at_meta(previous).unwrap();
+        assert_eq!(get(&tx).as_deref(), Some(&19u32.to_be_bytes()[..]));
+        drop(tx);
+        db.close().unwrap();
+
+        // Reopened, the pending pages are free again, unless nothing writes.
+        let db = DB::open(&path, Options::default()).unwrap();
+        assert!(matches!(db.begin_at_meta(previous), Err(Error::StaleMeta)));
+        db.close().unwrap();
+        let ro = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let db = DB::open(&path, ro.clone()).unwrap();
+        assert_eq!(
+            get(&db.begin_at_meta(previous).unwrap()).as_deref(),
+            Some(&19u32.to_be_bytes()[..])
+        );
+        db.close().unwrap();
+
+        // A meta page that fails validation is refused.
+        let offset = previous.pgid() * db.0.page_size as u64 + PAGE_HEADER_SIZE as u64;
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &[0xff; 4], offset).unwrap();
+        let db = DB::open(&path, ro).unwrap();
+        assert!(matches!(db.begin_at_meta(previous), Err(Error::Invalid)));
+        assert_eq!(
+            get(&db.begin(false).unwrap()).as_deref(),
+            Some(&b"last"[..])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn nested_write_tx() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        let nested = within({
+            let db = db.clone();
+            move || {
+                db.update(|tx| {
+                    tx.create_bucket(b"widgets")?;
+                    assert!(matches!(db.begin(true), Err(Error::NestedWriteTx)));
+                    assert!(matches!(db.close(), Err(Error::NestedWriteTx)));
+                    db.update(|_| Ok(()))
+                })
+            }
+        });
+        assert!(matches!(nested, Err(Error::NestedWriteTx)));
+
+        // The failed attempts leave the writer lock to the outer transaction,
+        // which releases it as usual.
+        let tx = db.begin(true).unwrap();
+        assert!(tx.bucket(b"widgets").is_err());
+        let other = thread::spawn({
+            let db = db.clone();
+            move || db.update(|tx| tx.create_bucket(b"other").map(drop))
+        });
+        drop(tx);
+        other.join().unwrap().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_while_reading_on_same_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let initial = db.0.mmap().len();
+
+        // A writer growing the file past the mapping of a reader opened by
+        // the same thread remaps without waiting for that reader.
+        let (len, value) = within(move || {
+            let reader = db.begin(false).unwrap();
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for i in 0..100u32 {
+                    b.put(&i.to_be_bytes(), &[0; 4096])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert!(reader.bucket(b"widgets").is_err());
+            drop(reader);
+            let value = db
+                .view_ret(|tx| Ok(tx.bucket(b"widgets")?.get(&[0; 4])?.map(<[u8]>::to_vec)))
+                .unwrap();
+            (db.0.mmap().len(), value)
+        });
+        assert!(len > initial);
+        assert_eq!(value, Some(vec![0; 4096]));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn open_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let logger = Arc::new(crate::logger::tests::Recorder::default());
+        let options = Options {
+            logger: Some(logger.clone()),
+            ..Options::default()
+        };
+
+        // A new file is initialized with txids 0 and 1.
+        let (db, report) = DB::open_with_report(&path, options.clone()).unwrap();
+        let page_size = db.0.page_size;
+        assert_eq!(
+            report,
+            OpenReport {
+                meta: MetaSlot::Page1,
+                txid: 1,
+                alternate_corrupt: false,
+                created: true,
+                freelist_loaded: true,
+                freelist_load_time: report.freelist_load_time,
+                page_size,
+                file_size: 4 * page_size as u64,
+            }
+        );
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        db.close().unwrap();
+
+        let (db, report) = DB::open_with_report(&path, options.clone()).unwrap();
+        assert_eq!((report.meta, report.txid), (MetaSlot::Page0, 2));
+        assert!(!report.created && !report.alternate_corrupt);
+        assert_eq!(report.file_size, std::fs::metadata(&path).unwrap().len());
+        db.close().unwrap();
+        logger.take();
+
+        let corrupt = |slot: MetaSlot| {
+            let offset = slot.pgid() * page_size as u64 + PAGE_HEADER_SIZE as u64;
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            std::os::unix::fs::FileExt::write_all_at(&file, &[0xff; 4], offset).unwrap();
+        };
+
+        // Damaging the previous commit leaves the current one in use.
+        corrupt(MetaSlot::Page1);
+        let (db, report) = DB::open_with_report(&path, options.clone()).unwrap();
+        assert_eq!((report.meta, report.txid), (MetaSlot::Page0, 2));
+        assert!(report.alternate_corrupt);
+        assert!(logger.take().contains(&(
+            "warn",
+            format!(
+                "meta page 1 of db ({}) failed validation, opened from meta page 0",
+                path.display()
+            )
+        )));
+
+        // A commit rewrites the damaged page, then damaging the latest
+        // commit falls back to the previous one.
+        db.update(|tx| tx.bucket(b"widgets").unwrap().put(b"foo", b"baz"))
+            .unwrap();
+        db.close().unwrap();
+        corrupt(MetaSlot::Page1);
+        let read_only = Options {
+            read_only: true,
+            ..options
+        };
+        let (db, report) = DB::open_with_report(&path, read_only).unwrap();
+        assert_eq!((report.meta, report.txid), (MetaSlot::Page0, 2));
+        assert!(report.alternate_corrupt);
+        assert!(!report.freelist_loaded);
+        let tx = db.begin(false).unwrap();
+        assert_eq!(
+            tx.bucket(b"widgets").unwrap().get(b"foo").unwrap(),
+            Some(&b"bar"[..])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn open_rejects_bad_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            page_size: 4096,
+            ..Options::default()
+        };
+
+        // rewrite creates a database, then applies `f` to both of its meta
+        // pages. `f` returns whether to recompute the checksum.
+        let rewrite = |name: &str, f: &dyn Fn(&mut Meta) -> bool| {
+            let path = dir.path().join(name);
+            DB::open(&path, options.clone()).unwrap().close().unwrap();
+            let mut buf = std::fs::read(&path).unwrap();
+            for id in 0..2 {
+                let page = &mut buf[id * 4096..(id + 1) * 4096];
+                let mut m = Meta::read(&page[PAGE_HEADER_SIZE..]);
+                if f(&mut m) {
+                    m.write(&mut PageMut::new(page));
+                } else {
+                    // Flip a byte of the high water mark.
+                    page[PAGE_HEADER_SIZE + 40] ^= 0xff;
+                }
+            }
+            std::fs::write(&path, &buf).unwrap();
+            DB::open(&path, options.clone())
+        };
+
+        assert!(matches!(
+            rewrite("magic", &|m| {
+                m.magic = 0x1234_5678;
+                true
+            }),
+            Err(Error::Invalid)
+        ));
+        assert!(matches!(
+            rewrite("version", &|m| {
+                m.version = VERSION + 1;
+                true
+            }),
+            Err(Error::VersionMismatch { found, supported })
+                if found == VERSION + 1 && supported == VERSION
+        ));
+        assert!(matches!(
+            rewrite("checksum", &|_| false),
+            Err(Error::Checksum)
+        ));
+        for page_size in [3000, 128 << 10] {
+            assert!(matches!(
+                rewrite(&page_size.to_string(), &|m| {
+                    m.page_size = page_size;
+                    true
+                }),
+                Err(Error::InvalidPageSize { page_size: p }) if p == page_size
+            ));
+        }
+
+        // New files cannot be created with such page sizes either.
+        let options = Options {
+            page_size: 3000,
+            ..Options::default()
+        };
+        assert!(matches!(
+            DB::open(dir.path().join("new"), options),
+            Err(Error::InvalidPageSize { page_size: 3000 })
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::io;
+
+use crate::tx_check::CheckError;
+
+/// Result is the result type returned by every fallible operation in this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error is the error taxonomy of the database, matching bbolt's exported errors.
+///
+/// Callers are expected to match on the variants, so new variants may be added
+/// in minor releases.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // These errors can be returned when opening or calling methods on a DB.
+    /// DatabaseNotOpen is returned when a DB instance is accessed before it
+    /// is opened or after it is closed.
+    DatabaseNotOpen,
+    /// DatabaseOpen is returned when opening a database that is
+    /// already open.
+    DatabaseOpen,
+    /// Invalid is returned when both meta pages on a database are invalid.
+    /// This typically occurs when a file is not a bolt database.
+    Invalid,
+    /// VersionMismatch is returned when the data file was created with a
+    /// different version of Bolt.
+    VersionMismatch {
+        /// the version recorded in the meta page
+        found: u32,
+        /// the version this binary reads and writes
+        supported: u32,
+    },
+    /// InvalidPageSize is returned when the page size recorded in a meta
+    /// page, or requested for a new file, is not a power of two up to 64 KiB.
+    InvalidPageSize {
+        /// the rejected page size
+        page_size: u32,
+    },
+    /// Checksum is returned when either meta page checksum does not match.
+    Checksum,
+    /// Timeout is returned when a database cannot obtain an exclusive lock
+    /// on the data file after the timeout passed to Open().
+    Timeout,
+    /// MmapTooLarge is returned when the database grows beyond the largest
+    /// mmap, or allocation, supported by the platform.
+    MmapTooLarge,
+
+    // These errors can occur when beginning or committing a Tx.
+    /// TxNotWritable is returned when performing a write operation on a
+    /// read-only transaction.
+    TxNotWritable,
+    /// TxClosed is returned when committing or rolling back a transaction
+    /// that has already been committed or rolled back.
+    TxClosed,
+    /// DatabaseReadOnly is returned when a mutating transaction is started on a
+    /// read-only database.
+    DatabaseReadOnly,
+    /// NestedWriteTx is returned when a thread starts a read-write
+    /// transaction while it already holds one, which would wait on itself
+    /// forever.
+    NestedWriteTx,
+    /// FreePagesNotLoaded is returned when a readonly transaction without
+    /// preloading the free pages is trying to access the free pages.
+    FreePagesNotLoaded,
+    /// SnapshotExpired is returned when reading from a snapshot that has
+    /// been open longer than the configured maximum snapshot age.
+    SnapshotExpired,
+    /// StaleMeta is returned when beginning a transaction on the previous
+    /// meta page after a write transaction may have reused its pages.
+    StaleMeta,
+    /// CheckFailed is returned when committing in strict mode a transaction
+    /// that fails the consistency check. The transaction is rolled back.
+    CheckFailed {
+        /// the inconsistencies found by the check
+        errors: Vec<CheckError>,
+    },
+
+    // These errors can occur when putting or deleting a value or a bucket.
+    /// BucketNotFound is returned when trying to access a bucket that has
+    /// not been created yet.
+    BucketNotFound,
+    /// BucketPathNotFound is returned when walking a path of nested buckets
+    /// whose component at index `depth`, named `name`, does not exist.
+    BucketPathNotFound {
+        /// the index of the missing component in the path
+        depth: usize,
+        /// the name of the missing bucket
+        name: Vec<u8>,
+    },
+    /// BucketExists is returned when creating a bucket that already exists.
+    BucketExists,
+    /// BucketNameRequired is returned when creating a bucket with a blank name.
+    BucketNameRequired,
+    /// KeyRequired is returned when inserting a zero-length key.
+    KeyRequired,
+    /// KeyTooLarge is returned when inserting a key that is larger than MAX_KEY_SIZE.
+    KeyTooLarge,
+    /// ValueTooLarge is returned when inserting a value that is larger than MAX_VALUE_SIZE.
+    ValueTooLarge,
+    /// IncompatibleValue is returned when trying create or delete a bucket
+    /// on an existing non-bucket key or when trying to create or delete a
+    /// non-bucket key on an existing bucket key.
+    IncompatibleValue,
+    /// KeyOutOfOrder is returned by put_sorted when a key is not greater than
+    /// the key before it.
+    KeyOutOfOrder {
+        /// the offending key
+        key: Vec<u8>,
+    },
+    /// SameBuckets is returned when moving a bucket to the bucket it is
+    /// already in.
+    SameBuckets,
+    /// MoveIntoDescendant is returned when moving a bucket into itself or
+    /// into one of the buckets nested in it.
+    MoveIntoDescendant,
+
+    // These errors can occur when inspecting pages.
+    /// PageNotFound is returned when inspecting a page beyond the end of the
+    /// database.
+    PageNotFound,
+    /// PageItemNotFound is returned when inspecting a page item that does not
+    /// exist, either because the index is beyond the item count or because
+    /// the page is not a leaf page.
+    PageItemNotFound,
+
+    /// Corrupted is returned when a page fails a structural check, for
+    /// instance a page that is freed twice or a freelist that is not sorted.
+    Corrupted {
+        /// the page at which the corruption was detected
+        pgid: u64,
+        /// what is wrong with the page
+        reason: String,
+    },
+
+    /// Io wraps a failure of the underlying file operations.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DatabaseNotOpen => f.write_str("database not open"),
+            Error::DatabaseOpen => f.write_str("database already open"),
+            Error::Invalid => f.write_str("invalid database"),
+            Error::VersionMismatch { found, supported } => write!(
+                f,
+                "version mismatch: file version {}, supported version {}",
+                found, supported
+            ),
+            Error::InvalidPageSize { page_size } => write!(f, "invalid page size: {}", page_size),
+            Error::Checksum => f.write_str("checksum error"),
+            Error::Timeout => f.write_str("timeout"),
+            Error::MmapTooLarge => f.write_str("mmap too large"),
+            Error::TxNotWritable => f.write_str("tx not writable"),
+            Error::TxClosed => f.write_str("tx closed"),
+            Error::DatabaseReadOnly => f.write_str("database is in read-only mode"),
+            Error::NestedWriteTx => f.write_str("write transaction already open on this thread"),
+            Error::FreePagesNotLoaded => f.write_str("free pages are not pre-loaded"),
+            Error::SnapshotExpired => f.write_str("snapshot expired"),
+            Error::StaleMeta => f.write_str("meta page is stale"),
+            Error::CheckFailed { errors } => {
+                f.write_str("check fail:")?;
+                for err in errors {
+                    write!(f, "\n{}", err)?;
+                }
+                Ok(())
+            }
+            Error::BucketNotFound => f.write_str("bucket not found"),
+            Error::BucketPathNotFound { depth, name } => write!(
+                f,
+                "bucket not found: \"{}\" (path component {})",
+                name.escape_ascii(),
+                depth
+            ),
+            Error::BucketExists => f.write_str("bucket already exists"),
+            Error::BucketNameRequired => f.write_str("bucket name required"),
+            Error::KeyRequired => f.write_str("key required"),
+            Error::KeyTooLarge => f.write_str("key too large"),
+            Error::ValueTooLarge => f.write_str("value too large"),
+            Error::IncompatibleValue => f.write_str("incompatible value"),
+            Error::KeyOutOfOrder { key } => {
+                write!(f, "key out of order: \"{}\"", key.escape_ascii())
+            }
+            Error::SameBuckets => f.write_str("the source and target are the same bucket"),
+            Error::MoveIntoDescendant => {
+                f.write_str("cannot move a bucket into itself or its descendants")
+            }
+            Error::PageNotFound => f.write_str("page not found"),
+            Error::PageItemNotFound => f.write_str("page item not found"),
+            Error::Corrupted { pgid, reason } => write!(f, "page {} corrupted: {}", pgid, reason),
+            Error::Io(err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl Error {
+    pub(crate) fn corrupted(pgid: u64, reason: impl Into<String>) -> Error {
+        Error::Corrupted {
+            pgid,
+            reason: reason.into(),
+        }
+    }
+
+    /// duplicate returns a copy of the error, for when one failure has to be
+    /// reported to several callers. Io errors keep their kind and message.
+    pub(crate) fn duplicate(&self) -> Error {
+        match self {
+            Error::DatabaseNotOpen => Error::DatabaseNotOpen,
+            Error::DatabaseOpen => Error::DatabaseOpen,
+            Error::Invalid => Error::Invalid,
+            Error::VersionMismatch { found, supported } => Error::VersionMismatch {
+                found: *found,
+                supported: *supported,
+            },
+            Error::InvalidPageSize { page_size } => Error::InvalidPageSize {
+                page_size: *page_size,
+            },
+            Error::Checksum => Error::Checksum,
+            Error::Timeout => Error::Timeout,
+            Error::MmapTooLarge => Error::MmapTooLarge,
+            Error::TxNotWritable => Error::TxNotWritable,
+            Error::TxClosed => Error::TxClosed,
+            Error::DatabaseReadOnly => Error::DatabaseReadOnly,
+            Error::NestedWriteTx => Error::NestedWriteTx,
+            Error::FreePagesNotLoaded => Error::FreePagesNotLoaded,
+            Error::SnapshotExpired => Error::SnapshotExpired,
+            Error::StaleMeta => Error::StaleMeta,
+            Error::CheckFailed { errors } => Error::CheckFailed {
+                errors: errors.clone(),
+            },
+            Error::BucketNotFound => Error::BucketNotFound,
+            Error::BucketPathNotFound { depth, name } => Error::BucketPathNotFound {
+                depth: *depth,
+                name: name.clone(),
+            },
+            Error::BucketExists => Error::BucketExists,
+            Error::BucketNameRequired => Error::BucketNameRequired,
+            Error::KeyRequired => Error::KeyRequired,
+            Error::KeyTooLarge => Error::KeyTooLarge,
+            Error::ValueTooLarge => Error::ValueTooLarge,
+            Error::IncompatibleValue => Error::IncompatibleValue,
+            Error::KeyOutOfOrder { key } => Error::KeyOutOfOrder { key: key.clone() },
+            Error::SameBuckets => Error::SameBuckets,
+            Error::MoveIntoDescendant => Error::MoveIntoDescendant,
+            Error::PageNotFound => Error::PageNotFound,
+            Error::PageItemNotFound => Error::PageItemNotFound,
+            Error::Corrupted { pgid, reason } => Error::corrupted(*pgid, reason.clone()),
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), err.to_string())),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_check::CheckErrorKind;
+    use std::error::Error as _;
+
+    #[test]
+    fn display_matches_bbolt() {
+        let table: Vec<(Error, &str)> = vec![
+            (Error::DatabaseNotOpen, "database not open"),
+            (Error::DatabaseOpen, "database already open"),
+            (Error::Invalid, "invalid database"),
+            (
+                Error::VersionMismatch {
+                    found: 3,
+                    supported: 2,
+                },
+                "version mismatch: file version 3, supported version 2",
+            ),
+            (
+                Error::InvalidPageSize { page_size: 3000 },
+                "invalid page size: 3000",
+            ),
+            (Error::Checksum, "checksum error"),
+            (Error::Timeout, "timeout"),
+            (Error::MmapTooLarge, "mmap too large"),
+            (Error::TxNotWritable, "tx not writable"),
+            (Error::TxClosed, "tx closed"),
+            (Error::DatabaseReadOnly, "database is in read-only mode"),
+            (
+                Error::NestedWriteTx,
+                "write transaction already open on this thread",
+            ),
+            (Error::FreePagesNotLoaded, "free pages are not pre-loaded"),
+            (Error::SnapshotExpired, "snapshot expired"),
+            (Error::StaleMeta, "meta page is stale"),
+            (
+                Error::CheckFailed {
+                    errors: vec![CheckError {
+                        pgid: 4,
+                        bucket: Vec::new(),
+                        kind: CheckErrorKind::UnreachableUnfreed,
+                        message: "unreachable unfreed".to_string(),
+                    }],
+                },
+                "check fail:\npage 4 corrupted: unreachable unfreed",
+            ),
+            (Error::BucketNotFound, "bucket not found"),
+            (
+                Error::BucketPathNotFound {
+                    depth: 1,
+                    name: b"by-\xffmail".to_vec(),
+                },
+                "bucket not found: \"by-\\xffmail\" (path component 1)",
+            ),
+            (Error::BucketExists, "bucket already exists"),
+            (Error::BucketNameRequired, "bucket name required"),
+            (Error::KeyRequired, "key required"),
+            (Error::KeyTooLarge, "key too large"),
+            (Error::ValueTooLarge, "value too large"),
+            (Error::IncompatibleValue, "incompatible value"),
+            (
+                Error::KeyOutOfOrder {
+                    key: b"k\x00".to_vec(),
+                },
+                "key out of order: \"k\\x00\"",
+            ),
+            (
+                Error::SameBuckets,
+                "the source and target are the same bucket",
+            ),
+            (
+                Error::MoveIntoDescendant,
+                "cannot move a bucket into itself or its descendants",
+            ),
+            (Error::PageNotFound, "page not found"),
+            (Error::PageItemNotFound, "page item not found"),
+            (
+                Error::corrupted(7, "page already freed"),
+                "page 7 corrupted: page already freed",
+            ),
+        ];
+        for (err, msg) in table {
+            assert_eq!(err.to_string(), msg);
+            assert!(err.source().is_none());
+        }
+    }
+
+    #[test]
+    fn io_error_is_source() {
+        let err = Error::from(io::Error::other("disk on fire"));
+        assert_eq!(err.to_string(), "io error: disk on fire");
+        assert_eq!(err.source().unwrap().to_string(), "disk on fire");
+    }
+}
@@ -0,0 +1,671 @@
+//! A C ABI over the database, enabled by the `ffi` feature.
+//!
+//! The build script generates the matching header, `blot.h`, into the
+//! build's `OUT_DIR` with cbindgen, and the same header can be produced with
+//! `cbindgen --config cbindgen.toml --output blot.h`. Build a shared or static
+//! library with `cargo rustc --release --features ffi --crate-type cdylib`
+//! (or `staticlib`).
+//!
+//! Every function returns a `BlotStatus`. Panics are caught at the boundary
+//! and reported as `BLOT_STATUS_PANIC`, so they never unwind into C.
+//!
+//! Memory ownership:
+//!
+//! - `BlotDb` and `BlotCursor` handles are allocated by the library and
+//!   released with `blot_close` and `blot_cursor_close`.
+//! - Values returned by `blot_view_get` are allocated with `malloc` and owned
+//!   by the caller, who releases them with `blot_free`.
+//! - Keys and values returned by the cursor functions are borrowed from the
+//!   cursor's read transaction and stay valid until `blot_cursor_close`.
+//! - Input buffers are only read during the call. A buffer may be NULL when
+//!   its length is zero.
+//!
+//! A `BlotDb` can be shared between threads. A `BlotCursor` can be moved to
+//! another thread but must not be used from two threads at once.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use crate::cursor::{Cursor, Pair};
+use crate::db::{Options, DB};
+use crate::errors::Error;
+use crate::tx::Tx;
+
+/// BlotStatus is the result of every function of the C ABI. The values are
+/// part of the ABI and are never renumbered.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlotStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// The key does not exist, or the cursor moved past the end of the bucket.
+    NotFound = 1,
+    /// A required pointer was NULL or the path was not valid UTF-8.
+    InvalidArgument = 2,
+    /// The library panicked; the handles passed to the call must not be used
+    /// again, except to close them.
+    Panic = 3,
+
+    DatabaseNotOpen = 10,
+    DatabaseOpen = 11,
+    Invalid = 12,
+    VersionMismatch = 13,
+    InvalidPageSize = 14,
+    Checksum = 15,
+    Timeout = 16,
+    MmapTooLarge = 17,
+
+    TxNotWritable = 30,
+    TxClosed = 31,
+    DatabaseReadOnly = 32,
+    NestedWriteTx = 33,
+    FreePagesNotLoaded = 34,
+    SnapshotExpired = 35,
+    StaleMeta = 36,
+    CheckFailed = 37,
+
+    BucketNotFound = 50,
+    BucketExists = 51,
+    BucketNameRequired = 52,
+    KeyRequired = 53,
+    KeyTooLarge = 54,
+    ValueTooLarge = 55,
+    IncompatibleValue = 56,
+    KeyOutOfOrder = 57,
+    SameBuckets = 58,
+    MoveIntoDescendant = 59,
+
+    PageNotFound = 70,
+    PageItemNotFound = 71,
+    Corrupted = 72,
+
+    Io = 90,
+}
+
+impl From<&Error> for BlotStatus {
+    fn from(err: &Error) -> BlotStatus {
+        match err {
+            Error::DatabaseNotOpen => BlotStatus::DatabaseNotOpen,
+            Error::DatabaseOpen => BlotStatus::DatabaseOpen,
+            Error::Invalid => BlotStatus::Invalid,
+            Error::VersionMismatch { .. } => BlotStatus::VersionMismatch,
+            Error::InvalidPageSize { .. } => BlotStatus::InvalidPageSize,
+            Error::Checksum => BlotStatus::Checksum,
+            Error::Timeout => BlotStatus::Timeout,
+            Error::MmapTooLarge => BlotStatus::MmapTooLarge,
+            Error::TxNotWritable => BlotStatus::TxNotWritable,
+            Error::TxClosed => BlotStatus::TxClosed,
+            Error::DatabaseReadOnly => BlotStatus::DatabaseReadOnly,
+            Error::NestedWriteTx => BlotStatus::NestedWriteTx,
+            Error::FreePagesNotLoaded => BlotStatus::FreePagesNotLoaded,
+            Error::SnapshotExpired => BlotStatus::SnapshotExpired,
+            Error::StaleMeta => BlotStatus::StaleMeta,
+            Error::CheckFailed { .. } => BlotStatus::CheckFailed,
+            Error::BucketNotFound | Error::BucketPathNotFound { .. } => BlotStatus::BucketNotFound,
+            Error::BucketExists => BlotStatus::BucketExists,
+            Error::BucketNameRequired => BlotStatus::BucketNameRequired,
+            Error::KeyRequired => BlotStatus::KeyRequired,
+            Error::KeyTooLarge => BlotStatus::KeyTooLarge,
+            Error::ValueTooLarge => BlotStatus::ValueTooLarge,
+            Error::IncompatibleValue => BlotStatus::IncompatibleValue,
+            Error::KeyOutOfOrder { .. } => BlotStatus::KeyOutOfOrder,
+            Error::SameBuckets => BlotStatus::SameBuckets,
+            Error::MoveIntoDescendant => BlotStatus::MoveIntoDescendant,
+            Error::PageNotFound => BlotStatus::PageNotFound,
+            Error::PageItemNotFound => BlotStatus::PageItemNotFound,
+            Error::Corrupted { .. } => BlotStatus::Corrupted,
+            Error::Io(_) => BlotStatus::Io,
+        }
+    }
+}
+
+impl BlotStatus {
+    fn message(self) -> &'static CStr {
+        let msg: &'static [u8] = match self {
+            BlotStatus::Ok => b"ok\0",
+            BlotStatus::NotFound => b"not found\0",
+            BlotStatus::InvalidArgument => b"invalid argument\0",
+            BlotStatus::Panic => b"internal panic\0",
+            BlotStatus::DatabaseNotOpen => b"database not open\0",
+            BlotStatus::DatabaseOpen => b"database already open\0",
+            BlotStatus::Invalid => b"invalid database\0",
+            BlotStatus::VersionMismatch => b"version mismatch\0",
+            BlotStatus::InvalidPageSize => b"invalid page size\0",
+            BlotStatus::Checksum => b"checksum error\0",
+            BlotStatus::Timeout => b"timeout\0",
+            BlotStatus::MmapTooLarge => b"mmap too large\0",
+            BlotStatus::TxNotWritable => b"tx not writable\0",
+            BlotStatus::TxClosed => b"tx closed\0",
+            BlotStatus::DatabaseReadOnly => b"database is in read-only mode\0",
+            BlotStatus::NestedWriteTx => b"write transaction already open on this thread\0",
+            BlotStatus::FreePagesNotLoaded => b"free pages are not pre-loaded\0",
+            BlotStatus::SnapshotExpired => b"snapshot expired\0",
+            BlotStatus::StaleMeta => b"meta page is stale\0",
+            BlotStatus::CheckFailed => b"check fail\0",
+            BlotStatus::BucketNotFound => b"bucket not found\0",
+            BlotStatus::BucketExists => b"bucket already exists\0",
+            BlotStatus::BucketNameRequired => b"bucket name required\0",
+            BlotStatus::KeyRequired => b"key required\0",
+            BlotStatus::KeyTooLarge => b"key too large\0",
+            BlotStatus::ValueTooLarge => b"value too large\0",
+            BlotStatus::IncompatibleValue => b"incompatible value\0",
+            BlotStatus::KeyOutOfOrder => b"key out of order\0",
+            BlotStatus::SameBuckets => b"the source and target are the same bucket\0",
+            BlotStatus::MoveIntoDescendant => {
+                b"cannot move a bucket into itself or its descendants\0"
+            }
+            BlotStatus::PageNotFound => b"page not found\0",
+            BlotStatus::PageItemNotFound => b"page item not found\0",
+            BlotStatus::Corrupted => b"page corrupted\0",
+            BlotStatus::Io => b"io error\0",
+        };
+        CStr::from_bytes_with_nul(msg).unwrap_or_default()
+    }
+}
+
+/// BlotDb is an open database handle.
+pub struct BlotDb {
+    db: DB,
+}
+
+/// BlotCursor is a cursor over one bucket, together with the read
+/// transaction it reads from.
+pub struct BlotCursor {
+    // cursor borrows *tx, which is owned through a raw pointer so that the
+    // borrow stays valid when the handle moves. blot_cursor_close drops the
+    // cursor before taking the transaction back.
+    cursor: Cursor<'static>,
+    tx: NonNull<Tx>,
+}
+
+/// guard runs f, turning its error or panic into a status.
+fn guard(f: impl FnOnce() -> Result<BlotStatus, BlotStatus>) -> BlotStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) | Ok(Err(status)) => status,
+        Err(_) => BlotStatus::Panic,
+    }
+}
+
+impl From<Error> for BlotStatus {
+    fn from(err: Error) -> BlotStatus {
+        BlotStatus::from(&err)
+    }
+}
+
+/// bytes borrows the buffer `(ptr, len)` passed in by the caller.
+///
+/// # Safety
+///
+/// ptr must be NULL or point to len readable bytes that outlive the call.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], BlotStatus> {
+    if len == 0 {
+        Ok(&[])
+    } else if ptr.is_null() {
+        Err(BlotStatus::InvalidArgument)
+    } else {
+        Ok(slice::from_raw_parts(ptr, len))
+    }
+}
+
+/// set writes v through the out parameter `out`, which must not be NULL.
+///
+/// # Safety
+///
+/// out must be NULL or valid for writes.
+unsafe fn set<T>(out: *mut T, v: T) -> Result<(), BlotStatus> {
+    if out.is_null() {
+        return Err(BlotStatus::InvalidArgument);
+    }
+    out.write(v);
+    Ok(())
+}
+
+/// blot_open opens the database at the NUL-terminated UTF-8 `path`,
+/// creating it if it does not exist and `read_only` is false, and stores its
+/// handle in `*db`.
+///
+/// # Safety
+///
+/// path must be a NUL-terminated string and db must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_open(
+    path: *const c_char,
+    read_only: bool,
+    db: *mut *mut BlotDb,
+) -> BlotStatus {
+    guard(|| {
+        if path.is_null() || db.is_null() {
+            return Err(BlotStatus::InvalidArgument);
+        }
+        let path = CStr::from_ptr(path)
+            .to_str()
+            .map_err(|_| BlotStatus::InvalidArgument)?;
+        let options = Options {
+            read_only,
+            ..Options::default()
+        };
+        let handle = Box::new(BlotDb {
+            db: DB::open(path, options)?,
+        });
+        set(db, Box::into_raw(handle))?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// blot_close closes the database and releases its handle, which must not
+/// be used again even if an error is returned. Passing NULL does nothing.
+///
+/// # Safety
+///
+/// db must be NULL or a handle returned by blot_open that has not been
+/// closed, and no cursor of the database may still be open.
+#[no_mangle]
+pub unsafe extern "C" fn blot_close(db: *mut BlotDb) -> BlotStatus {
+    guard(|| {
+        if db.is_null() {
+            return Ok(BlotStatus::Ok);
+        }
+        let handle = Box::from_raw(db);
+        handle.db.close()?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// blot_view_get looks up `key` in the top-level bucket `bucket`. On
+/// success `*value` points to a copy of the value allocated with malloc,
+/// which the caller releases with blot_free, and `*value_len` holds its
+/// length. A missing key returns BLOT_STATUS_NOT_FOUND and a nested bucket
+/// returns BLOT_STATUS_INCOMPATIBLE_VALUE.
+///
+/// # Safety
+///
+/// db must be an open handle, bucket and key must point to bucket_len and
+/// key_len readable bytes, and value and value_len must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_view_get(
+    db: *const BlotDb,
+    bucket: *const u8,
+    bucket_len: usize,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    guard(|| {
+        let db = db.as_ref().ok_or(BlotStatus::InvalidArgument)?;
+        let name = bytes(bucket, bucket_len)?;
+        let key = bytes(key, key_len)?;
+        if value.is_null() || value_len.is_null() {
+            return Err(BlotStatus::InvalidArgument);
+        }
+        let found = db.db.view_ret(|tx| {
+            let b = tx.bucket(name)?;
+            match b.get(key)? {
+                Some(v) => Ok(Some(malloc_copy(v))),
+                None if b.bucket(key).is_ok() => Err(Error::IncompatibleValue),
+                None => Ok(None),
+            }
+        })?;
+        let (ptr, len) = found.ok_or(BlotStatus::NotFound)?;
+        set(value, ptr)?;
+        set(value_len, len)?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// malloc_copy copies v into a buffer allocated with malloc. An empty value
+/// still gets a one-byte buffer, so the result is never NULL; running out of
+/// memory aborts, as it does everywhere else in Rust.
+fn malloc_copy(v: &[u8]) -> (*mut u8, usize) {
+    let size = v.len().max(1);
+    // SAFETY: the buffer has room for size >= v.len() bytes and v cannot
+    // overlap a fresh allocation.
+    unsafe {
+        let ptr = libc::malloc(size) as *mut u8;
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(std::alloc::Layout::array::<u8>(size).unwrap());
+        }
+        ptr::copy_nonoverlapping(v.as_ptr(), ptr, v.len());
+        (ptr, v.len())
+    }
+}
+
+/// blot_free releases a value returned by blot_view_get. Passing NULL does
+/// nothing.
+///
+/// # Safety
+///
+/// value must be NULL or a value returned by blot_view_get that has not
+/// been freed.
+#[no_mangle]
+pub unsafe extern "C" fn blot_free(value: *mut u8) {
+    libc::free(value as *mut c_void);
+}
+
+/// blot_update_put sets `key` to `value` in the top-level bucket `bucket`,
+/// creating the bucket if it does not exist, and commits the change.
+///
+/// # Safety
+///
+/// db must be an open handle and bucket, key and value must point to
+/// bucket_len, key_len and value_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_update_put(
+    db: *const BlotDb,
+    bucket: *const u8,
+    bucket_len: usize,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> BlotStatus {
+    guard(|| {
+        let db = db.as_ref().ok_or(BlotStatus::InvalidArgument)?;
+        let name = bytes(bucket, bucket_len)?;
+        let key = bytes(key, key_len)?;
+        let value = bytes(value, value_len)?;
+        db.db
+            .update(|tx| tx.create_bucket_if_not_exists(name)?.put(key, value))?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// blot_cursor_open starts a read transaction and stores a cursor over the
+/// top-level bucket `bucket` in `*cursor`. The cursor sees the database as
+/// of this call until it is closed with blot_cursor_close.
+///
+/// # Safety
+///
+/// db must be an open handle, bucket must point to bucket_len readable
+/// bytes and cursor must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_open(
+    db: *const BlotDb,
+    bucket: *const u8,
+    bucket_len: usize,
+    cursor: *mut *mut BlotCursor,
+) -> BlotStatus {
+    guard(|| {
+        let db = db.as_ref().ok_or(BlotStatus::InvalidArgument)?;
+        let name = bytes(bucket, bucket_len)?;
+        if cursor.is_null() {
+            return Err(BlotStatus::InvalidArgument);
+        }
+        let tx = NonNull::from(Box::leak(Box::new(db.db.begin(false)?)));
+        // SAFETY: the transaction stays allocated until blot_cursor_close,
+        // which drops the cursor before freeing it.
+        let c = match tx.as_ref().bucket(name) {
+            Ok(b) => b.cursor(),
+            Err(err) => {
+                drop(Box::from_raw(tx.as_ptr()));
+                return Err(err.into());
+            }
+        };
+        let c = std::mem::transmute::<Cursor<'_>, Cursor<'static>>(c);
+        let handle = Box::new(BlotCursor { cursor: c, tx });
+        set(cursor, Box::into_raw(handle))?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// blot_cursor_close releases the cursor and ends its read transaction.
+/// Passing NULL does nothing.
+///
+/// # Safety
+///
+/// cursor must be NULL or a cursor returned by blot_cursor_open that has
+/// not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_close(cursor: *mut BlotCursor) -> BlotStatus {
+    guard(|| {
+        if cursor.is_null() {
+            return Ok(BlotStatus::Ok);
+        }
+        let BlotCursor { cursor, tx } = *Box::from_raw(cursor);
+        // The cursor borrows the transaction, so it must be gone before the
+        // transaction is taken back and rolled back.
+        drop(cursor);
+        let mut tx = Box::from_raw(tx.as_ptr());
+        tx.rollback()?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// move_cursor applies a move to the cursor and stores the key and value it
+/// lands on. A nested bucket has a NULL value of length 0.
+///
+/// # Safety
+///
+/// cursor must be an open cursor and the out parameters valid for writes.
+unsafe fn move_cursor(
+    cursor: *mut BlotCursor,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+    f: impl FnOnce(&mut Cursor<'static>) -> Result<Pair<'static>, Error>,
+) -> BlotStatus {
+    guard(|| {
+        let handle = cursor.as_mut().ok_or(BlotStatus::InvalidArgument)?;
+        if key.is_null() || key_len.is_null() || value.is_null() || value_len.is_null() {
+            return Err(BlotStatus::InvalidArgument);
+        }
+        let (k, v) = f(&mut handle.cursor)?;
+        let k = k.ok_or(BlotStatus::NotFound)?;
+        set(key, k.as_ptr())?;
+        set(key_len, k.len())?;
+        set(value, v.map_or(ptr::null(), <[u8]>::as_ptr))?;
+        set(value_len, v.map_or(0, <[u8]>::len))?;
+        Ok(BlotStatus::Ok)
+    })
+}
+
+/// blot_cursor_first moves the cursor to the first item in the bucket.
+/// BLOT_STATUS_NOT_FOUND is returned if the bucket is empty.
+///
+/// # Safety
+///
+/// cursor must be an open cursor and the out parameters valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_first(
+    cursor: *mut BlotCursor,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    move_cursor(cursor, key, key_len, value, value_len, |c| c.first())
+}
+
+/// blot_cursor_last moves the cursor to the last item in the bucket.
+/// BLOT_STATUS_NOT_FOUND is returned if the bucket is empty.
+///
+/// # Safety
+///
+/// cursor must be an open cursor and the out parameters valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_last(
+    cursor: *mut BlotCursor,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    move_cursor(cursor, key, key_len, value, value_len, |c| c.last())
+}
+
+/// blot_cursor_next moves the cursor to the next item in the bucket.
+/// BLOT_STATUS_NOT_FOUND is returned past the end of the bucket.
+///
+/// # Safety
+///
+/// cursor must be an open cursor and the out parameters valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_next(
+    cursor: *mut BlotCursor,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    move_cursor(cursor, key, key_len, value, value_len, |c| c.next())
+}
+
+/// blot_cursor_prev moves the cursor to the previous item in the bucket.
+/// BLOT_STATUS_NOT_FOUND is returned past the start of the bucket.
+///
+/// # Safety
+///
+/// cursor must be an open cursor and the out parameters valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_prev(
+    cursor: *mut BlotCursor,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    move_cursor(cursor, key, key_len, value, value_len, |c| c.prev())
+}
+
+/// blot_cursor_seek moves the cursor to `seek`, or to the next key after
+/// it if it does not exist. BLOT_STATUS_NOT_FOUND is returned if no key is
+/// greater than or equal to `seek`.
+///
+/// # Safety
+///
+/// cursor must be an open cursor, seek must point to seek_len readable bytes
+/// and the out parameters must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn blot_cursor_seek(
+    cursor: *mut BlotCursor,
+    seek: *const u8,
+    seek_len: usize,
+    key: *mut *const u8,
+    key_len: *mut usize,
+    value: *mut *const u8,
+    value_len: *mut usize,
+) -> BlotStatus {
+    let seek = match bytes(seek, seek_len) {
+        Ok(seek) => seek,
+        Err(status) => return status,
+    };
+    move_cursor(cursor, key, key_len, value, value_len, |c| c.seek(seek))
+}
+
+/// blot_status_message returns a static, NUL-terminated description of a
+/// status, which must be one of the BlotStatus values. The string must not
+/// be freed.
+#[no_mangle]
+pub extern "C" fn blot_status_message(status: BlotStatus) -> *const c_char {
+    status.message().as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::os::raw::c_int;
+
+    #[link(name = "blot_ffi_smoke", kind = "static")]
+    extern "C" {
+        fn blot_ffi_smoke(path: *const c_char) -> c_int;
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn c_smoke() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("db").to_str().unwrap()).unwrap();
+        // The C program returns the line of the first failed check.
+        assert_eq!(unsafe { blot_ffi_smoke(path.as_ptr()) }, 0);
+    }
+
+    #[test]
+    fn guard_catches_panics() {
+        assert_eq!(guard(|| panic!("boom")), BlotStatus::Panic);
+        assert_eq!(guard(|| Err(Error::TxClosed.into())), BlotStatus::TxClosed);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn view_get_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("db").to_str().unwrap()).unwrap();
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(blot_open(path.as_ptr(), false, &mut db), BlotStatus::Ok);
+            let (mut v, mut n) = (ptr::null_mut(), 0);
+            let get = |db, key: &[u8], v: &mut *mut u8, n: &mut usize| {
+                blot_view_get(db, b"widgets".as_ptr(), 7, key.as_ptr(), key.len(), v, n)
+            };
+            assert_eq!(get(db, b"foo", &mut v, &mut n), BlotStatus::BucketNotFound);
+
+            (*db)
+                .db
+                .update(|tx| {
+                    tx.create_bucket(b"widgets")?
+                        .create_bucket(b"sub")
+                        .map(drop)
+                })
+                .unwrap();
+            assert_eq!(get(db, b"foo", &mut v, &mut n), BlotStatus::NotFound);
+            assert_eq!(
+                get(db, b"sub", &mut v, &mut n),
+                BlotStatus::IncompatibleValue
+            );
+            assert!(v.is_null());
+            assert_eq!(
+                blot_view_get(db, ptr::null(), 3, b"foo".as_ptr(), 3, &mut v, &mut n),
+                BlotStatus::InvalidArgument
+            );
+            assert_eq!(blot_close(db), BlotStatus::Ok);
+        }
+    }
+
+    // Run by Miri too, with --cfg bolt_buffer_backend and isolation
+    // disabled: closing a cursor must not touch the transaction while the
+    // cursor still borrows it.
+    #[test]
+    fn cursor_open_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("db").to_str().unwrap()).unwrap();
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(blot_open(path.as_ptr(), false, &mut db), BlotStatus::Ok);
+            (*db)
+                .db
+                .update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+                .unwrap();
+
+            let mut c = ptr::null_mut();
+            assert_eq!(
+                blot_cursor_open(db, b"gadgets".as_ptr(), 7, &mut c),
+                BlotStatus::BucketNotFound
+            );
+            assert!(c.is_null());
+            assert_eq!(
+                blot_cursor_open(db, b"widgets".as_ptr(), 7, &mut c),
+                BlotStatus::Ok
+            );
+            let (mut k, mut kn, mut v, mut vn) = (ptr::null(), 0, ptr::null(), 0);
+            assert_eq!(
+                blot_cursor_first(c, &mut k, &mut kn, &mut v, &mut vn),
+                BlotStatus::Ok
+            );
+            assert_eq!(slice::from_raw_parts(k, kn), b"foo");
+            assert_eq!(slice::from_raw_parts(v, vn), b"bar");
+            assert_eq!(
+                blot_cursor_next(c, &mut k, &mut kn, &mut v, &mut vn),
+                BlotStatus::NotFound
+            );
+            assert_eq!(blot_cursor_close(c), BlotStatus::Ok);
+            assert_eq!(blot_cursor_close(ptr::null_mut()), BlotStatus::Ok);
+            assert_eq!((*db).db.stats().open_tx_n, 0);
+            assert_eq!(blot_close(db), BlotStatus::Ok);
+        }
+    }
+}
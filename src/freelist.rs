@@ -0,0 +1,1039 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use crate::errors::{Error, Result};
+use crate::page::{
+    read_u64, write_u64, Page, PageMut, Pgid, FREELIST_PAGE_FLAG, PAGE_HEADER_SIZE, PGID_SIZE,
+};
+use crate::tx::Txid;
+
+/// FreelistType is the type of the freelist backend.
+///
+/// Both backends persist the same sorted list of page ids, so a file written
+/// with one can be opened with the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FreelistType {
+    /// Array keeps the free page ids in a sorted array. Allocation is a
+    /// linear scan for a run of contiguous ids.
+    Array,
+    /// HashMap keeps the free pages as spans of contiguous ids indexed by
+    /// their size, making allocation close to constant time on large,
+    /// fragmented freelists. It is the default.
+    #[default]
+    HashMap,
+}
+
+/// TxPending holds the pages freed by a single transaction.
+#[derive(Debug, Default)]
+struct TxPending {
+    ids: Vec<Pgid>,
+    /// txids allocating the ids
+    alloctx: Vec<Txid>,
+    /// beginning txid of last matching release_range
+    last_release_begin: Txid,
+}
+
+/// Freelist represents a list of all pages that are available for allocation.
+/// It also tracks pages that have been freed but are still in use by open transactions.
+#[derive(Debug)]
+pub(crate) struct Freelist {
+    freelist_type: FreelistType,
+    /// all free and available free page ids (array backend only).
+    ids: Vec<Pgid>,
+    /// mapping of txid that allocated a pgid.
+    allocs: HashMap<Pgid, Txid>,
+    /// mapping of soon-to-be free page ids by tx.
+    pending: HashMap<Txid, TxPending>,
+    /// fast lookup of all free and pending page ids.
+    cache: HashSet<Pgid>,
+    /// key is the size of a span of contiguous pages, value is the set of
+    /// starting pgids of spans with that size (hashmap backend only).
+    freemaps: HashMap<u64, HashSet<Pgid>>,
+    /// key is the start pgid of a span, value is its size.
+    forward_map: HashMap<Pgid, u64>,
+    /// key is the end pgid of a span, value is its size.
+    backward_map: HashMap<Pgid, u64>,
+    /// number of pages held in spans, maintained by add_span/del_span.
+    span_page_n: usize,
+    /// smallest run length the array backend failed to find since the free
+    /// ids last grew; larger or equal requests can fail without a scan.
+    min_failed_alloc: Option<usize>,
+}
+
+impl Freelist {
+    /// new returns an empty, initialized freelist.
+    pub(crate) fn new(freelist_type: FreelistType) -> Freelist {
+        Freelist {
+            freelist_type,
+            ids: Vec::new(),
+            allocs: HashMap::new(),
+            pending: HashMap::new(),
+            cache: HashSet::new(),
+            freemaps: HashMap::new(),
+            forward_map: HashMap::new(),
+            backward_map: HashMap::new(),
+            span_page_n: 0,
+            min_failed_alloc: None,
+        }
+    }
+
+    /// free_count returns count of free pages.
+    pub(crate) fn free_count(&self) -> usize {
+        match self.freelist_type {
+            FreelistType::Array => self.ids.len(),
+            FreelistType::HashMap => self.span_page_n,
+        }
+    }
+
+    /// size returns the size of the page after serialization.
+    pub(crate) fn size(&self) -> usize {
+        let mut n = self.count();
+        if n >= 0xFFFF {
+            // The first element will be used to store the count. See Freelist::write.
+            n += 1;
+        }
+        PAGE_HEADER_SIZE + PGID_SIZE * n
+    }
+
+    /// count returns count of pages on the freelist.
+    pub(crate) fn count(&self) -> usize {
+        self.free_count() + self.pending_count()
+    }
+
+    /// pending_count returns count of pending pages.
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending.values().map(|txp| txp.ids.len()).sum()
+    }
+
+    /// copyall copies a list of all free ids and all pending ids into `dst`,
+    /// as one sorted list.
+    pub(crate) fn copyall(&self, dst: &mut Vec<Pgid>) {
+        let mut m: Vec<Pgid> = Vec::with_capacity(self.pending_count());
+        for txp in self.pending.values() {
+            m.extend_from_slice(&txp.ids);
+        }
+        m.sort_unstable();
+        merge_pgids(dst, &self.free_page_ids(), &m);
+    }
+
+    /// free releases a page and its overflow for a given transaction id.
+    /// If the page is already free then `Error::Corrupted` is returned and the
+    /// freelist is left unchanged.
+    pub(crate) fn free(&mut self, txid: Txid, p: Page<'_>) -> Result<()> {
+        if p.id() <= 1 {
+            return Err(Error::corrupted(p.id(), "cannot free page 0 or 1"));
+        }
+
+        // Verify that neither the page nor its overflow pages are already free.
+        let ids = p.id()..=p.id() + p.overflow() as Pgid;
+        if let Some(id) = ids.clone().find(|id| self.cache.contains(id)) {
+            return Err(Error::corrupted(id, "page already freed"));
+        }
+
+        // Free page and all its overflow pages.
+        let alloc_txid = match self.allocs.remove(&p.id()) {
+            Some(alloc_txid) => alloc_txid,
+            // Freelist is always allocated by prior tx.
+            None if p.flags() & FREELIST_PAGE_FLAG != 0 => txid - 1,
+            None => 0,
+        };
+
+        let txp = self.pending.entry(txid).or_default();
+        for id in ids {
+            // Add to the freelist and cache.
+            txp.ids.push(id);
+            txp.alloctx.push(alloc_txid);
+            self.cache.insert(id);
+        }
+        Ok(())
+    }
+
+    /// release moves all page ids for a transaction id (or older) to the freelist.
+    pub(crate) fn release(&mut self, txid: Txid) {
+        let mut m = Vec::new();
+        self.pending.retain(|&tid, txp| {
+            if tid <= txid {
+                // Move transaction's pending pages to the available freelist.
+                // Don't remove from the cache since the page is still free.
+                m.append(&mut txp.ids);
+                false
+            } else {
+                true
+            }
+        });
+        self.merge_spans(m);
+    }
+
+    /// release_range moves pending pages allocated within an extent [begin,end] to the free list.
+    pub(crate) fn release_range(&mut self, begin: Txid, end: Txid) {
+        if begin > end {
+            return;
+        }
+        let mut m = Vec::new();
+        self.pending.retain(|&tid, txp| {
+            if tid < begin || tid > end {
+                return true;
+            }
+            // Don't recompute freed pages if ranges haven't updated.
+            if txp.last_release_begin == begin {
+                return true;
+            }
+            let mut i = 0;
+            while i < txp.ids.len() {
+                let atx = txp.alloctx[i];
+                if atx < begin || atx > end {
+                    i += 1;
+                    continue;
+                }
+                m.push(txp.ids.swap_remove(i));
+                txp.alloctx.swap_remove(i);
+            }
+            txp.last_release_begin = begin;
+            !txp.ids.is_empty()
+        });
+        self.merge_spans(m);
+    }
+
+    /// rollback removes the pages from a given pending tx.
+    pub(crate) fn rollback(&mut self, txid: Txid) {
+        // Remove page ids from cache.
+        let mut m = Vec::new();
+        if let Some(txp) = self.pending.remove(&txid) {
+            for (&pgid, &tx) in txp.ids.iter().zip(txp.alloctx.iter()) {
+                self.cache.remove(&pgid);
+                if tx == 0 {
+                    continue;
+                }
+                if tx != txid {
+                    // Pending free aborted; restore page back to alloc list.
+                    self.allocs.insert(pgid, tx);
+                } else {
+                    // Freed page was allocated by this txn; OK to throw away.
+                    m.push(pgid);
+                }
+            }
+        }
+
+        // Remove pgids which are allocated by this txid.
+        self.allocs.retain(|_, tid| *tid != txid);
+
+        // Mark as free the pages allocated and freed by txid.
+        self.cache.extend(m.iter().copied());
+        self.merge_spans(m);
+    }
+
+    /// reload reads the freelist from a page and filters out pending items.
+    pub(crate) fn reload(&mut self, p: Page<'_>) -> Result<()> {
+        self.read(p)?;
+
+        // Build a cache of only pending pages.
+        let pcache: HashSet<Pgid> = self
+            .pending
+            .values()
+            .flat_map(|txp| txp.ids.iter().copied())
+            .collect();
+
+        // Check each page in the freelist and build a new available freelist
+        // with any pages not in the pending lists.
+        let a = self
+            .free_page_ids()
+            .into_iter()
+            .filter(|id| !pcache.contains(id))
+            .collect();
+        self.read_ids(a);
+        Ok(())
+    }
+
+    /// free_page_ids returns the sorted free page ids.
+    pub(crate) fn free_page_ids(&self) -> Vec<Pgid> {
+        match self.freelist_type {
+            FreelistType::Array => self.ids.clone(),
+            FreelistType::HashMap => {
+                let mut spans: Vec<(Pgid, u64)> = self
+                    .forward_map
+                    .iter()
+                    .map(|(&start, &size)| (start, size))
+                    .collect();
+                spans.sort_unstable();
+                let mut ids = Vec::with_capacity(self.span_page_n);
+                for (start, size) in spans {
+                    ids.extend(start..start + size);
+                }
+                ids
+            }
+        }
+    }
+
+    /// freed returns whether a given page is in the free list.
+    pub(crate) fn freed(&self, pgid: Pgid) -> bool {
+        self.cache.contains(&pgid)
+    }
+
+    /// read_ids initializes the freelist from a sorted list of free ids.
+    pub(crate) fn read_ids(&mut self, ids: Vec<Pgid>) {
+        match self.freelist_type {
+            FreelistType::Array => {
+                self.ids = ids;
+                self.min_failed_alloc = None;
+            }
+            FreelistType::HashMap => self.init_spans(&ids),
+        }
+        self.reindex();
+    }
+
+    /// read initializes the freelist from a freelist page.
+    pub(crate) fn read(&mut self, p: Page<'_>) -> Result<()> {
+        let ids = read_page_ids(p)?;
+
+        // Freelist pages are always written sorted and without duplicates,
+        // anything else means the page was damaged.
+        if let Some(&id) = ids.iter().find(|&&id| id <= 1) {
+            return Err(Error::corrupted(
+                p.id(),
+                format!("freelist contains reserved id {}", id),
+            ));
+        }
+        for w in ids.windows(2) {
+            if w[0] == w[1] {
+                return Err(Error::corrupted(
+                    p.id(),
+                    format!("freelist contains duplicate id {}", w[1]),
+                ));
+            }
+            if w[0] > w[1] {
+                return Err(Error::corrupted(
+                    p.id(),
+                    format!("freelist ids out of order at {}", w[1]),
+                ));
+            }
+        }
+
+        self.read_ids(ids);
+        Ok(())
+    }
+
+    /// write writes the page ids onto a freelist page. All free and pending ids are
+    /// saved to disk since in the event of a program crash, all pending ids will
+    /// become free.
+    ///
+    /// `p` must be at least `size()` bytes long.
+    pub(crate) fn write(&self, p: &mut PageMut<'_>) {
+        // Combine the old free pgids and pgids waiting on an open transaction.
+
+        // Update the header flag.
+        p.set_flags(p.as_page().flags() | FREELIST_PAGE_FLAG);
+
+        // The page.count can only hold up to 64k elements so if we overflow that
+        // number then we handle it by putting the size in the first element.
+        let l = self.count();
+        let mut ids = Vec::with_capacity(l + 1);
+        if l < 0xFFFF {
+            p.set_count(l as u16);
+        } else {
+            p.set_count(0xFFFF);
+            ids.push(l as Pgid);
+        }
+        self.copyall(&mut ids);
+
+        let data = p.data_mut();
+        for (i, &id) in ids.iter().enumerate() {
+            write_u64(data, i * PGID_SIZE, id);
+        }
+    }
+
+    /// no_sync_reload reads the freelist from a list of page ids, as rebuilt by
+    /// scanning the file when the freelist is not persisted, and filters out
+    /// pending items.
+    pub(crate) fn no_sync_reload(&mut self, mut ids: Vec<Pgid>) {
+        // Build a cache of only pending pages.
+        let pcache: HashSet<Pgid> = self
+            .pending
+            .values()
+            .flat_map(|txp| txp.ids.iter().copied())
+            .collect();
+
+        ids.retain(|id| !pcache.contains(id));
+        ids.sort_unstable();
+        self.read_ids(ids);
+    }
+
+    /// check verifies the internal consistency of the freelist: no page may be
+    /// both free and pending, listed twice, or be one of the meta pages.
+    pub(crate) fn check(&self) -> Result<()> {
+        let mut seen = HashSet::with_capacity(self.count());
+        for id in self.free_page_ids() {
+            if id <= 1 {
+                return Err(Error::corrupted(id, "meta page is on the freelist"));
+            }
+            if !seen.insert(id) {
+                return Err(Error::corrupted(id, "page is free twice"));
+            }
+        }
+        let free = seen.clone();
+        for txp in self.pending.values() {
+            for &id in &txp.ids {
+                if free.contains(&id) {
+                    return Err(Error::corrupted(id, "page is both free and pending"));
+                }
+                if !seen.insert(id) {
+                    return Err(Error::corrupted(id, "page is pending twice"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// reindex rebuilds the free cache based on available and pending free lists.
+    pub(crate) fn reindex(&mut self) {
+        self.cache = self.free_page_ids().into_iter().collect();
+        for txp in self.pending.values() {
+            self.cache.extend(txp.ids.iter().copied());
+        }
+    }
+
+    /// allocate returns the starting page id of a contiguous list of pages of a given size.
+    /// If a contiguous block cannot be found then 0 is returned.
+    pub(crate) fn allocate(&mut self, txid: Txid, n: usize) -> Pgid {
+        if n == 0 {
+            return 0;
+        }
+        let start = match self.freelist_type {
+            FreelistType::Array => self.array_allocate(n),
+            FreelistType::HashMap => self.hashmap_allocate(n as u64),
+        };
+        if start != 0 {
+            self.allocs.insert(start, txid);
+            for id in start..start + n as Pgid {
+                self.cache.remove(&id);
+            }
+        }
+        start
+    }
+
+    fn array_allocate(&mut self, n: usize) -> Pgid {
+        if self.ids.is_empty() {
+            return 0;
+        }
+
+        // A single page never needs a scan: take the lowest free id.
+        if n == 1 {
+            let id = self.ids.remove(0);
+            assert!(id > 1, "invalid page allocation: {}", id);
+            return id;
+        }
+
+        // Nothing was freed since a run this long (or shorter) was last
+        // searched for in vain, so the scan cannot succeed either.
+        if self.min_failed_alloc.is_some_and(|min| n >= min) {
+            return 0;
+        }
+
+        let mut initial: Pgid = 0;
+        let mut previd: Pgid = 0;
+        for (i, &id) in self.ids.iter().enumerate() {
+            assert!(id > 1, "invalid page allocation: {}", id);
+
+            // Reset initial page if this is not contiguous.
+            if previd == 0 || id - previd != 1 {
+                initial = id;
+            }
+
+            // If we found a contiguous block then remove it and return it.
+            if (id - initial) + 1 == n as Pgid {
+                self.ids.drain(i + 1 - n..=i);
+                return initial;
+            }
+
+            previd = id;
+        }
+
+        self.min_failed_alloc = Some(self.min_failed_alloc.map_or(n, |min| min.min(n)));
+        0
+    }
+
+    fn hashmap_allocate(&mut self, n: u64) -> Pgid {
+        // if we have a exact size match just return short path
+        if let Some(&pid) = self.freemaps.get(&n).and_then(|set| set.iter().next()) {
+            self.del_span(pid, n);
+            return pid;
+        }
+
+        // lookup the map to find larger span
+        let found = self
+            .freemaps
+            .iter()
+            .filter(|(&size, _)| size > n)
+            .find_map(|(&size, set)| set.iter().next().map(|&pid| (pid, size)));
+        match found {
+            Some((pid, size)) => {
+                self.del_span(pid, size);
+                self.add_span(pid + n, size - n);
+                pid
+            }
+            None => 0,
+        }
+    }
+
+    /// merge_spans adds the given ids to the available free pages. For the
+    /// hashmap backend each id is merged with any adjacent span.
+    fn merge_spans(&mut self, mut ids: Vec<Pgid>) {
+        match self.freelist_type {
+            FreelistType::Array => {
+                ids.sort_unstable();
+                let mut merged = Vec::with_capacity(self.ids.len() + ids.len());
+                merge_pgids(&mut merged, &self.ids, &ids);
+                self.ids = merged;
+                if !ids.is_empty() {
+                    self.min_failed_alloc = None;
+                }
+            }
+            FreelistType::HashMap => {
+                for id in ids {
+                    self.merge_with_existing_span(id);
+                }
+            }
+        }
+    }
+
+    fn merge_with_existing_span(&mut self, pid: Pgid) {
+        let prev = pid - 1;
+        let next = pid + 1;
+
+        let mut new_start = pid;
+        let mut new_size = 1;
+
+        if let Some(&pre_size) = self.backward_map.get(&prev) {
+            // merge with previous span
+            let start = prev + 1 - pre_size;
+            self.del_span(start, pre_size);
+            new_start -= pre_size;
+            new_size += pre_size;
+        }
+
+        if let Some(&next_size) = self.forward_map.get(&next) {
+            // merge with next span
+            self.del_span(next, next_size);
+            new_size += next_size;
+        }
+
+        self.add_span(new_start, new_size);
+    }
+
+    fn init_spans(&mut self, ids: &[Pgid]) {
+        self.freemaps.clear();
+        self.forward_map.clear();
+        self.backward_map.clear();
+        self.span_page_n = 0;
+
+        let mut iter = ids.iter().copied();
+        let mut start = match iter.next() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut size = 1;
+        let mut last = start;
+        for id in iter {
+            if id == last + 1 {
+                size += 1;
+            } else {
+                self.add_span(start, size);
+                start = id;
+                size = 1;
+            }
+            last = id;
+        }
+        self.add_span(start, size);
+    }
+
+    fn add_span(&mut self, start: Pgid, size: u64) {
+        if size == 0 {
+            return;
+        }
+        self.backward_map.insert(start - 1 + size, size);
+        self.forward_map.insert(start, size);
+        self.freemaps.entry(size).or_default().insert(start);
+        self.span_page_n += size as usize;
+    }
+
+    fn del_span(&mut self, start: Pgid, size: u64) {
+        self.forward_map.remove(&start);
+        self.backward_map.remove(&(start + size - 1));
+        if let Some(set) = self.freemaps.get_mut(&size) {
+            set.remove(&start);
+            if set.is_empty() {
+                self.freemaps.remove(&size);
+            }
+        }
+        self.span_page_n -= size as usize;
+    }
+}
+
+/// read_page_ids returns the page ids stored on a freelist page, as they are
+/// on disk: neither their order nor their uniqueness is verified.
+pub(crate) fn read_page_ids(p: Page<'_>) -> Result<Vec<Pgid>> {
+    if p.flags() & FREELIST_PAGE_FLAG == 0 {
+        return Err(Error::corrupted(p.id(), "not a freelist page"));
+    }
+
+    // If the page.count is at the max u16 value (64k) then it's considered
+    // an overflow and the size of the freelist is stored as the first element.
+    let data = p.data();
+    let (mut idx, mut count) = (0, p.count() as usize);
+    if count == 0xFFFF {
+        if data.len() < PGID_SIZE {
+            return Err(Error::corrupted(p.id(), "freelist count out of bounds"));
+        }
+        idx = 1;
+        count = usize::try_from(read_u64(data, 0))
+            .map_err(|_| Error::corrupted(p.id(), "freelist ids out of bounds"))?;
+    }
+
+    let end = count
+        .checked_add(idx)
+        .and_then(|n| n.checked_mul(PGID_SIZE))
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| Error::corrupted(p.id(), "freelist ids out of bounds"))?;
+
+    // Copy the list of page ids from the freelist.
+    Ok(data[idx * PGID_SIZE..end]
+        .chunks_exact(PGID_SIZE)
+        .map(|b| read_u64(b, 0))
+        .collect())
+}
+
+/// merge_pgids appends the union of two sorted lists to `dst`, keeping it sorted.
+fn merge_pgids(dst: &mut Vec<Pgid>, a: &[Pgid], b: &[Pgid]) {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] <= b[j] {
+            dst.push(a[i]);
+            i += 1;
+        } else {
+            dst.push(b[j]);
+            j += 1;
+        }
+    }
+    dst.extend_from_slice(&a[i..]);
+    dst.extend_from_slice(&b[j..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Options;
+    use proptest::prelude::*;
+
+    fn both() -> [Freelist; 2] {
+        [
+            Freelist::new(FreelistType::Array),
+            Freelist::new(FreelistType::HashMap),
+        ]
+    }
+
+    fn round_trip(freelist_type: FreelistType, n: usize) {
+        let mut f = Freelist::new(freelist_type);
+        // Leave gaps so the hashmap backend has to track many spans.
+        f.read_ids((0..n as Pgid).map(|i| 2 + i * 2).collect());
+
+        let mut buf = vec![0u8; f.size()];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(7);
+        f.write(&mut p);
+        let p = Page::new(&buf);
+        assert_eq!(p.id(), 7);
+        assert_eq!(p.flags(), FREELIST_PAGE_FLAG);
+        assert_eq!(p.count() as usize, n.min(0xFFFF));
+
+        let mut g = Freelist::new(freelist_type);
+        g.read(p).unwrap();
+        assert_eq!(g.free_count(), n);
+        assert_eq!(g.free_page_ids(), f.free_page_ids());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Too slow: it needs 64Ki ids.
+    fn write_read_boundaries() {
+        for &n in &[0, 1, 65534, 65535, 200_000] {
+            round_trip(FreelistType::Array, n);
+            round_trip(FreelistType::HashMap, n);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Too slow: it needs 64Ki ids.
+    fn size_counts_overflow_element() {
+        let mut f = Freelist::new(FreelistType::Array);
+        f.read_ids((2..0xFFFF + 1).collect());
+        assert_eq!(f.count(), 0xFFFE);
+        assert_eq!(f.size(), PAGE_HEADER_SIZE + 0xFFFE * PGID_SIZE);
+        f.read_ids((2..0xFFFF + 2).collect());
+        assert_eq!(f.size(), PAGE_HEADER_SIZE + 0x10000 * PGID_SIZE);
+    }
+
+    #[test]
+    fn default_type() {
+        assert_eq!(FreelistType::default(), FreelistType::HashMap);
+        assert_eq!(Options::default().freelist_type, FreelistType::HashMap);
+    }
+
+    #[test]
+    fn read_rejects_other_pages() {
+        let buf = vec![0u8; 64];
+        let mut f = Freelist::new(FreelistType::Array);
+        assert!(matches!(
+            f.read(Page::new(&buf)),
+            Err(Error::Corrupted { pgid: 0, .. })
+        ));
+
+        // A count that runs off the end of the page is rejected too.
+        let mut buf = vec![0u8; 64];
+        let mut p = PageMut::new(&mut buf);
+        p.set_flags(FREELIST_PAGE_FLAG);
+        p.set_count(100);
+        assert!(matches!(
+            f.read(Page::new(&buf)),
+            Err(Error::Corrupted { pgid: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn read_ids_round_trips_sorted() {
+        for mut f in both() {
+            f.read_ids(vec![3, 4, 5, 9, 12, 13, 20]);
+            assert_eq!(f.free_page_ids(), vec![3, 4, 5, 9, 12, 13, 20]);
+            assert_eq!(f.free_count(), 7);
+            assert!(f.freed(9));
+            assert!(!f.freed(10));
+        }
+    }
+
+    #[test]
+    fn allocate_contiguous() {
+        for mut f in both() {
+            f.read_ids(vec![3, 4, 5, 6, 7, 9, 12, 13, 18]);
+            assert_eq!(f.allocate(1, 5), 3);
+            assert_eq!(f.allocate(1, 2), 12);
+            assert_eq!(f.allocate(1, 3), 0);
+            assert_eq!(f.free_count(), 2);
+            assert!(!f.freed(3));
+            let mut rest = f.free_page_ids();
+            rest.sort_unstable();
+            assert_eq!(rest, vec![9, 18]);
+        }
+    }
+
+    #[test]
+    fn hashmap_splits_larger_span() {
+        let mut f = Freelist::new(FreelistType::HashMap);
+        f.read_ids(vec![10, 11, 12, 13, 14]);
+        assert_eq!(f.allocate(1, 2), 10);
+        assert_eq!(f.free_page_ids(), vec![12, 13, 14]);
+        assert_eq!(f.forward_map.get(&12), Some(&3));
+        assert_eq!(f.backward_map.get(&14), Some(&3));
+    }
+
+    #[test]
+    fn hashmap_merge_spans() {
+        let mut f = Freelist::new(FreelistType::HashMap);
+        f.read_ids(vec![3, 4, 8, 9]);
+        f.merge_spans(vec![5, 7]);
+        assert_eq!(f.free_count(), 6);
+        assert_eq!(f.freemaps.get(&3), Some(&[3, 7].iter().copied().collect()));
+        f.merge_spans(vec![6]);
+        assert_eq!(f.forward_map.len(), 1);
+        assert_eq!(f.forward_map.get(&3), Some(&7));
+        assert_eq!(f.backward_map.get(&9), Some(&7));
+        assert_eq!(f.free_page_ids(), (3..=9).collect::<Vec<_>>());
+        assert_eq!(f.allocate(1, 7), 3);
+        assert_eq!(f.free_count(), 0);
+        assert!(f.freemaps.is_empty());
+    }
+
+    fn page(id: Pgid, overflow: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; PAGE_HEADER_SIZE];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(id);
+        p.set_overflow(overflow);
+        buf
+    }
+
+    #[test]
+    fn free_is_pending_until_release() {
+        for mut f in both() {
+            f.free(100, Page::new(&page(12, 0))).unwrap();
+            assert_eq!(f.pending_count(), 1);
+            assert_eq!(f.free_count(), 0);
+            assert!(f.freed(12));
+
+            // A reader pinned at txid 99 still sees page 12.
+            f.release(99);
+            assert_eq!(f.pending_count(), 1);
+            assert_eq!(f.allocate(101, 1), 0);
+
+            // Once the reader closes the page becomes allocatable.
+            f.release(100);
+            assert_eq!(f.pending_count(), 0);
+            assert_eq!(f.free_page_ids(), vec![12]);
+            assert_eq!(f.allocate(101, 1), 12);
+        }
+    }
+
+    #[test]
+    fn free_overflow() {
+        for mut f in both() {
+            f.free(100, Page::new(&page(12, 3))).unwrap();
+            let mut ids = Vec::new();
+            f.copyall(&mut ids);
+            assert_eq!(ids, vec![12, 13, 14, 15]);
+            assert_eq!(f.count(), 4);
+        }
+    }
+
+    #[test]
+    fn double_free_is_corruption() {
+        for mut f in both() {
+            f.read_ids(vec![14]);
+            f.free(100, Page::new(&page(12, 0))).unwrap();
+            let err = f.free(100, Page::new(&page(12, 0))).unwrap_err();
+            assert_eq!(err.to_string(), "page 12 corrupted: page already freed");
+
+            // An overflow run reaching a free page is rejected without
+            // leaving its head page half freed.
+            let err = f.free(101, Page::new(&page(13, 1))).unwrap_err();
+            assert!(matches!(err, Error::Corrupted { pgid: 14, .. }));
+            assert!(!f.freed(13));
+            assert_eq!(f.pending_count(), 1);
+            assert!(f.check().is_ok());
+
+            let err = f.free(101, Page::new(&page(1, 0))).unwrap_err();
+            assert!(matches!(err, Error::Corrupted { pgid: 1, .. }));
+        }
+    }
+
+    fn freelist_page(ids: &[Pgid]) -> Vec<u8> {
+        let mut buf = vec![0u8; PAGE_HEADER_SIZE + ids.len() * PGID_SIZE];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(2);
+        p.set_flags(FREELIST_PAGE_FLAG);
+        p.set_count(ids.len() as u16);
+        for (i, &id) in ids.iter().enumerate() {
+            write_u64(p.data_mut(), i * PGID_SIZE, id);
+        }
+        buf
+    }
+
+    #[test]
+    fn read_rejects_unsorted_or_duplicate_ids() {
+        for mut f in both() {
+            let err = f.read(Page::new(&freelist_page(&[3, 5, 4]))).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "page 2 corrupted: freelist ids out of order at 4"
+            );
+            let err = f.read(Page::new(&freelist_page(&[3, 4, 4]))).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "page 2 corrupted: freelist contains duplicate id 4"
+            );
+            let err = f.read(Page::new(&freelist_page(&[1, 4]))).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "page 2 corrupted: freelist contains reserved id 1"
+            );
+            f.read(Page::new(&freelist_page(&[3, 4, 9]))).unwrap();
+            assert_eq!(f.free_page_ids(), vec![3, 4, 9]);
+        }
+    }
+
+    #[test]
+    fn check_detects_free_and_pending_overlap() {
+        for mut f in both() {
+            f.read_ids(vec![3, 4]);
+            f.free(100, Page::new(&page(9, 0))).unwrap();
+            assert!(f.check().is_ok());
+
+            // Simulate a page that ended up both free and pending.
+            f.pending.get_mut(&100).unwrap().ids.push(4);
+            f.pending.get_mut(&100).unwrap().alloctx.push(0);
+            let err = f.check().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "page 4 corrupted: page is both free and pending"
+            );
+        }
+    }
+
+    #[test]
+    fn copyall_merges_free_and_pending() {
+        for mut f in both() {
+            f.read_ids(vec![3, 6, 9]);
+            f.free(100, Page::new(&page(4, 0))).unwrap();
+            f.free(101, Page::new(&page(10, 1))).unwrap();
+            let mut ids = Vec::new();
+            f.copyall(&mut ids);
+            assert_eq!(ids, vec![3, 4, 6, 9, 10, 11]);
+            assert_eq!(f.size(), PAGE_HEADER_SIZE + 6 * PGID_SIZE);
+        }
+    }
+
+    #[test]
+    fn release_range() {
+        for mut f in both() {
+            // Page 20 was allocated by tx 2 and page 30 by tx 4; both were
+            // freed by tx 5.
+            f.allocs.insert(20, 2);
+            f.allocs.insert(30, 4);
+            f.free(5, Page::new(&page(20, 0))).unwrap();
+            f.free(5, Page::new(&page(30, 0))).unwrap();
+
+            // With readers at txid 1 and 6 nothing in between can see either page.
+            f.release_range(2, 5);
+            assert_eq!(f.free_page_ids(), vec![20, 30]);
+            assert_eq!(f.pending_count(), 0);
+        }
+        for mut f in both() {
+            f.allocs.insert(20, 2);
+            f.allocs.insert(30, 4);
+            f.free(5, Page::new(&page(20, 0))).unwrap();
+            f.free(5, Page::new(&page(30, 0))).unwrap();
+
+            // A reader at txid 3 still sees page 20 but not page 30.
+            f.release_range(4, 5);
+            assert_eq!(f.free_page_ids(), vec![30]);
+            assert_eq!(f.pending_count(), 1);
+        }
+    }
+
+    #[test]
+    fn rollback() {
+        for mut f in both() {
+            f.read_ids(vec![5, 6, 7]);
+            assert_eq!(f.allocate(10, 2), 5);
+            f.free(10, Page::new(&page(5, 1))).unwrap();
+            f.allocs.insert(40, 3);
+            f.free(10, Page::new(&page(40, 0))).unwrap();
+            f.rollback(10);
+
+            // Pages allocated and freed by the aborted tx are free again,
+            // page 40 goes back to its allocator.
+            assert_eq!(f.pending_count(), 0);
+            assert_eq!(f.free_page_ids(), vec![5, 6, 7]);
+            assert_eq!(f.allocs.get(&40), Some(&3));
+            assert!(f.freed(5));
+            assert!(!f.freed(40));
+        }
+    }
+
+    #[test]
+    fn reload_filters_pending() {
+        for mut f in both() {
+            f.read_ids(vec![3, 4]);
+            f.free(100, Page::new(&page(9, 0))).unwrap();
+
+            let mut buf = vec![0u8; f.size()];
+            f.write(&mut PageMut::new(&mut buf));
+            f.reload(Page::new(&buf)).unwrap();
+            assert_eq!(f.free_page_ids(), vec![3, 4]);
+            assert_eq!(f.pending_count(), 1);
+        }
+    }
+
+    #[test]
+    fn no_sync_reload_filters_pending() {
+        for mut f in both() {
+            f.free(100, Page::new(&page(9, 0))).unwrap();
+            f.no_sync_reload(vec![11, 9, 3]);
+            assert_eq!(f.free_page_ids(), vec![3, 11]);
+            assert!(f.freed(9));
+            assert_eq!(f.count(), 3);
+        }
+    }
+
+    #[test]
+    fn array_allocate_sequence() {
+        let mut f = Freelist::new(FreelistType::Array);
+        f.read_ids(vec![3, 4, 5, 6, 7, 9, 12, 13, 18]);
+        assert_eq!(f.allocate(1, 3), 3);
+        assert_eq!(f.allocate(1, 1), 6);
+        assert_eq!(f.allocate(1, 3), 0);
+        assert_eq!(f.allocate(1, 2), 12);
+        assert_eq!(f.allocate(1, 1), 7);
+        assert_eq!(f.allocate(1, 0), 0);
+        assert_eq!(f.ids, vec![9, 18]);
+
+        assert_eq!(f.allocate(1, 1), 9);
+        assert_eq!(f.allocate(1, 1), 18);
+        assert_eq!(f.allocate(1, 1), 0);
+        assert!(f.ids.is_empty());
+    }
+
+    #[test]
+    fn array_allocate_run_at_end() {
+        let mut f = Freelist::new(FreelistType::Array);
+        f.read_ids(vec![3, 5, 7, 8, 9, 10]);
+        assert_eq!(f.allocate(2, 4), 7);
+        assert_eq!(f.ids, vec![3, 5]);
+        assert_eq!(f.allocs.get(&7), Some(&2));
+        assert!(!f.freed(10));
+    }
+
+    #[test]
+    fn array_allocate_failure_cache() {
+        let mut f = Freelist::new(FreelistType::Array);
+        f.read_ids(vec![3, 4, 6, 7, 9]);
+        assert_eq!(f.allocate(1, 3), 0);
+        assert_eq!(f.min_failed_alloc, Some(3));
+
+        // Longer runs fail without scanning, shorter ones still succeed.
+        assert_eq!(f.allocate(1, 5), 0);
+        assert_eq!(f.min_failed_alloc, Some(3));
+        assert_eq!(f.allocate(1, 2), 3);
+
+        // Freeing pages invalidates the cache.
+        f.free(1, Page::new(&page(5, 0))).unwrap();
+        f.release(1);
+        assert_eq!(f.min_failed_alloc, None);
+        assert_eq!(f.allocate(2, 3), 5);
+    }
+
+    proptest! {
+        /// The on-disk freelist must not depend on the in-memory type.
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn backends_write_identical_pages(
+            ids in prop::collection::btree_map(2..2000u64, prop::option::of(1..5u64), 0..500),
+            released in prop::option::of(1..5u64),
+        ) {
+            let free: Vec<Pgid> = ids.iter().filter(|(_, tx)| tx.is_none()).map(|(&id, _)| id).collect();
+            let mut pages = Vec::new();
+            for mut f in both() {
+                f.read_ids(free.clone());
+                for (&id, txid) in &ids {
+                    if let Some(txid) = txid {
+                        f.free(*txid, Page::new(&page(id, 0))).unwrap();
+                    }
+                }
+                if let Some(txid) = released {
+                    f.release(txid);
+                }
+
+                // Releasing a txid releases every transaction up to it.
+                let pending = ids
+                    .values()
+                    .filter(|tx| tx.is_some_and(|txid| released.is_none_or(|r| txid > r)))
+                    .count();
+                prop_assert_eq!(f.count(), ids.len());
+                prop_assert_eq!(f.pending_count(), pending);
+                prop_assert_eq!(f.free_count(), ids.len() - pending);
+                let mut all = Vec::new();
+                f.copyall(&mut all);
+                prop_assert_eq!(&all, &ids.keys().copied().collect::<Vec<_>>());
+
+                let mut buf = vec![0u8; f.size()];
+                f.write(&mut PageMut::new(&mut buf));
+                pages.push(buf);
+            }
+            prop_assert_eq!(&pages[0], &pages[1]);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::fmt;
+
+use crate::db::DB;
+use crate::errors::{Error, Result};
+use crate::page::{Page, Pgid, LEAF_PAGE_FLAG};
+use crate::tx::Tx;
+
+/// PageDump holds the raw bytes of a page, its overflow pages included, along
+/// with its parsed header.
+///
+/// Its `Display` output prints the header followed by a `hexdump -C` style
+/// dump of the bytes, with repeated lines collapsed into a single `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageDump {
+    pub id: u64,
+    pub typ: String,
+    pub flags: u16,
+    pub count: usize,
+    pub overflow: usize,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for PageDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Page ID:        {}", self.id)?;
+        writeln!(f, "Page Type:      {}", self.typ)?;
+        writeln!(f, "Page Flags:     {:#06x}", self.flags)?;
+        writeln!(f, "Total Size:     {} bytes", self.data.len())?;
+        writeln!(f, "Overflow pages: {}", self.overflow)?;
+        writeln!(f, "Item Count:     {}", self.count)?;
+        writeln!(f)?;
+
+        let mut prev: Option<&[u8]> = None;
+        let mut skipped = false;
+        for (i, line) in self.data.chunks(16).enumerate() {
+            // Collapse lines identical to the previous one.
+            if prev == Some(line) {
+                if !skipped {
+                    writeln!(f, "*")?;
+                    skipped = true;
+                }
+                continue;
+            }
+            prev = Some(line);
+            skipped = false;
+
+            write!(f, "{:08x} ", i * 16)?;
+            for j in 0..16 {
+                if j == 8 {
+                    write!(f, " ")?;
+                }
+                match line.get(j) {
+                    Some(b) => write!(f, " {:02x}", b)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, "  |")?;
+            for &b in line {
+                let c = if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f, "|")?;
+        }
+        writeln!(f, "{:08x}", self.data.len())
+    }
+}
+
+impl DB {
+    /// DumpPage returns the raw bytes and parsed header of the page with the
+    /// given id, its overflow pages included.
+    ///
+    /// The page is read from the mmap through a short-lived read-only
+    /// transaction, so this also works on a database opened read-only.
+    /// `Error::PageNotFound` is returned for ids beyond the high water mark.
+    pub fn dump_page(&self, id: u64) -> Result<PageDump> {
+        self.view_ret(|tx| {
+            let p = page(tx, id)?;
+            Ok(PageDump {
+                id: p.id(),
+                typ: p.typ(),
+                flags: p.flags(),
+                count: p.count() as usize,
+                overflow: p.overflow() as usize,
+                data: p.bytes().to_vec(),
+            })
+        })
+    }
+
+    /// PageItem returns the key and value of the element at `index` on the
+    /// leaf page with the given id. For a bucket element the value is the raw
+    /// bucket header, followed by the inline page for inline buckets.
+    ///
+    /// `Error::PageItemNotFound` is returned when the page is not a leaf page
+    /// or has no element at `index`.
+    pub fn page_item(&self, id: u64, index: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.view_ret(|tx| {
+            let p = page(tx, id)?;
+            if p.flags() & LEAF_PAGE_FLAG == 0 || index >= p.count() as usize {
+                return Err(Error::PageItemNotFound);
+            }
+            let e = p.leaf_element(index)?;
+            Ok((e.key().to_vec(), e.value().to_vec()))
+        })
+    }
+}
+
+/// page returns the page with the given id as stored in the file.
+fn page(tx: &Tx, id: Pgid) -> Result<Page<'_>> {
+    if id >= tx.meta.get().pgid {
+        return Err(Error::PageNotFound);
+    }
+    tx.mmap_page(id)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use crate::db::Options;
+    use crate::errors::Error;
+    use crate::page::BUCKET_LEAF_FLAG;
+    use crate::tx_check::tests::{bucket_value, build, write_leaf, PS};
+    use crate::DB;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn dump_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        build(&path, 5, |buf| {
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"widgets", &bucket_value(4))]);
+            write_leaf(buf, 4, &[(0, b"bar", b"fooz"), (0, b"helloworld", b"bye")]);
+        })
+        .close()
+        .unwrap();
+
+        let db = DB::open(
+            &path,
+            Options {
+                read_only: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let dump = db.dump_page(4).unwrap();
+        assert_eq!(dump.data.len(), PS);
+        assert_eq!(
+            dump.to_string(),
+            "Page ID:        4
+Page Type:      leaf
+Page Flags:     0x0002
+Total Size:     4096 bytes
+Overflow pages: 0
+Item Count:     2
+
+00000000  04 00 00 00 00 00 00 00  02 00 02 00 00 00 00 00  |................|
+00000010  00 00 00 00 20 00 00 00  03 00 00 00 04 00 00 00  |.... ...........|
+00000020  00 00 00 00 17 00 00 00  0a 00 00 00 03 00 00 00  |................|
+00000030  62 61 72 66 6f 6f 7a 68  65 6c 6c 6f 77 6f 72 6c  |barfoozhelloworl|
+00000040  64 62 79 65 00 00 00 00  00 00 00 00 00 00 00 00  |dbye............|
+00000050  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+*
+00001000
+"
+        );
+
+        assert_eq!(db.dump_page(0).unwrap().typ, "meta");
+        assert_eq!(db.dump_page(2).unwrap().typ, "freelist");
+        assert!(matches!(db.dump_page(5), Err(Error::PageNotFound)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn page_item() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        build(&path, 5, |buf| {
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"widgets", &bucket_value(4))]);
+            write_leaf(buf, 4, &[(0, b"bar", b"fooz"), (0, b"helloworld", b"bye")]);
+        })
+        .close()
+        .unwrap();
+
+        let db = DB::open(
+            &path,
+            Options {
+                read_only: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.page_item(3, 0).unwrap(),
+            (b"widgets".to_vec(), bucket_value(4))
+        );
+        assert_eq!(
+            db.page_item(4, 1).unwrap(),
+            (b"helloworld".to_vec(), b"bye".to_vec())
+        );
+        assert!(matches!(db.page_item(4, 2), Err(Error::PageItemNotFound)));
+        assert!(matches!(db.page_item(2, 0), Err(Error::PageItemNotFound)));
+        assert!(matches!(db.page_item(9, 0), Err(Error::PageNotFound)));
+    }
+}
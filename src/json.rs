@@ -0,0 +1,643 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::bucket::Bucket;
+use crate::db::DB;
+use crate::errors::{Error, Result};
+use crate::tx::Tx;
+
+/// JsonEncoding selects how keys, values and bucket names are encoded as JSON
+/// strings by `DB::export_json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonEncoding {
+    /// Lowercase hexadecimal.
+    Hex,
+    /// Standard base64, with padding.
+    Base64,
+}
+
+impl JsonEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            JsonEncoding::Hex => "hex",
+            JsonEncoding::Base64 => "base64",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            JsonEncoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+            JsonEncoding::Base64 => base64_encode(data),
+        }
+    }
+
+    fn decode(self, s: &str) -> Result<Vec<u8>> {
+        match self {
+            JsonEncoding::Hex => hex_decode(s),
+            JsonEncoding::Base64 => base64_decode(s),
+        }
+        .ok_or_else(|| invalid(&format!("invalid {} string {:?}", self.name(), s)))
+    }
+}
+
+impl DB {
+    /// ExportJSON writes every bucket and key/value pair of the database to
+    /// `w` as a JSON document. Byte strings are encoded with `encoding`.
+    ///
+    /// The document is an object with the `encoding` and the top level
+    /// `buckets`. Each bucket holds its `name`, `sequence` and `entries` in key
+    /// order, where an entry is either a `key`/`value` pair or a nested bucket:
+    ///
+    /// ```json
+    /// {
+    ///   "encoding": "hex",
+    ///   "buckets": [
+    ///     {
+    ///       "name": "776964676574",
+    ///       "sequence": 0,
+    ///       "entries": [
+    ///         {"key": "666f6f", "value": "626172"}
+    ///       ]
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// The document is written incrementally while walking a single read-only
+    /// transaction, so the database is never buffered in memory.
+    pub fn export_json<W: Write>(&self, w: W, encoding: JsonEncoding) -> Result<()> {
+        let mut w = BufWriter::new(w);
+        self.view(|tx| {
+            write!(
+                w,
+                "{{\n  \"encoding\": \"{}\",\n  \"buckets\": ",
+                encoding.name()
+            )?;
+            let mut exporter = Exporter {
+                w: &mut w,
+                encoding,
+            };
+            exporter.entries(tx.root(), 1)?;
+            writeln!(w, "\n}}")?;
+            Ok(())
+        })?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// ImportJSON recreates the buckets, sequences and key/value pairs of a
+    /// document written by `export_json` in a single writable transaction.
+    ///
+    /// The database must not already hold any of the imported buckets, or
+    /// `Error::BucketExists` is returned and nothing is imported. The document
+    /// is parsed incrementally, so only the current key and value are held in
+    /// memory besides the transaction itself.
+    pub fn import_json<R: Read>(&self, r: R) -> Result<()> {
+        let mut p = Parser {
+            r: BufReader::new(r).bytes(),
+            peeked: None,
+        };
+        self.update(|tx| p.document(tx))
+    }
+}
+
+/// Exporter writes buckets as pretty-printed JSON.
+struct Exporter<'a, W: Write> {
+    w: &'a mut W,
+    encoding: JsonEncoding,
+}
+
+impl<W: Write> Exporter<'_, W> {
+    /// entries writes the entries of `b` as an array indented by `depth`.
+    fn entries(&mut self, b: Bucket<'_>, depth: usize) -> Result<()> {
+        let mut empty = true;
+        write!(self.w, "[")?;
+        b.for_each(|k, v| {
+            if !empty {
+                write!(self.w, ",")?;
+            }
+            empty = false;
+            write!(self.w, "\n{}", indent(depth + 1))?;
+            match v {
+                Some(v) => write!(
+                    self.w,
+                    "{{\"key\": \"{}\", \"value\": \"{}\"}}",
+                    self.encoding.encode(k),
+                    self.encoding.encode(v)
+                )?,
+                None => self.bucket(b.bucket(k)?, k, depth + 1)?,
+            }
+            Ok(())
+        })?;
+        if !empty {
+            write!(self.w, "\n{}", indent(depth))?;
+        }
+        write!(self.w, "]")?;
+        Ok(())
+    }
+
+    /// bucket writes the bucket `b` named `name` as an object indented by
+    /// `depth`.
+    fn bucket(&mut self, b: Bucket<'_>, name: &[u8], depth: usize) -> Result<()> {
+        let inner = indent(depth + 1);
+        write!(
+            self.w,
+            "{{\n{}\"name\": \"{}\",\n{}\"sequence\": {},\n{}\"entries\": ",
+            inner,
+            self.encoding.encode(name),
+            inner,
+            b.sequence(),
+            inner
+        )?;
+        self.entries(b, depth + 1)?;
+        write!(self.w, "\n{}}}", indent(depth))?;
+        Ok(())
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+/// Parser is a pull parser for the documents written by `export_json`,
+/// reading one byte at a time from a buffered reader.
+struct Parser<R: Read> {
+    r: io::Bytes<BufReader<R>>,
+    peeked: Option<u8>,
+}
+
+impl<R: Read> Parser<R> {
+    /// document parses the top level object into `tx`.
+    fn document(&mut self, tx: &Tx) -> Result<()> {
+        let mut encoding = None;
+        self.object(|p, field| match field {
+            "encoding" => {
+                encoding = Some(match p.string()?.as_str() {
+                    "hex" => JsonEncoding::Hex,
+                    "base64" => JsonEncoding::Base64,
+                    s => return Err(invalid(&format!("unknown encoding {:?}", s))),
+                });
+                Ok(())
+            }
+            "buckets" => {
+                let encoding = encoding.ok_or_else(|| invalid("buckets before encoding"))?;
+                p.array(|p| p.entry(tx.root(), encoding, true))
+            }
+            _ => p.skip(),
+        })?;
+        self.skip_whitespace()?;
+        match self.next()? {
+            None => Ok(()),
+            Some(c) => Err(unexpected(c)),
+        }
+    }
+
+    /// entry parses a key/value pair or a nested bucket into `parent`. Only
+    /// buckets are allowed when `root` is set.
+    fn entry(&mut self, parent: Bucket<'_>, encoding: JsonEncoding, root: bool) -> Result<()> {
+        let mut key = None;
+        let mut value = None;
+        let mut bucket = None;
+        let mut sequence = None;
+        self.object(|p, field| {
+            match field {
+                "key" if !root => key = Some(encoding.decode(&p.string()?)?),
+                "value" if !root => value = Some(encoding.decode(&p.string()?)?),
+                "name" => {
+                    let name = encoding.decode(&p.string()?)?;
+                    bucket = Some(parent.create_bucket(&name)?);
+                }
+                "sequence" => sequence = Some(p.number()?),
+                "entries" => {
+                    let b = bucket.ok_or_else(|| invalid("entries before name"))?;
+                    p.array(|p| p.entry(b, encoding, false))?;
+                }
+                _ => p.skip()?,
+            }
+            Ok(())
+        })?;
+
+        match (bucket, key, value) {
+            (Some(b), None, None) => b.set_sequence(sequence.unwrap_or(0)),
+            (None, Some(k), Some(v)) => parent.put(&k, &v),
+            _ => Err(invalid("entry is neither a bucket nor a key/value pair")),
+        }
+    }
+
+    /// object parses an object, calling `f` with each field name to parse its
+    /// value.
+    fn object<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, &str) -> Result<()>,
+    {
+        self.expect(b'{')?;
+        if self.peek_token()? == Some(b'}') {
+            self.next()?;
+            return Ok(());
+        }
+        loop {
+            let field = self.string()?;
+            self.expect(b':')?;
+            f(self, &field)?;
+            match self.token()? {
+                b',' => continue,
+                b'}' => return Ok(()),
+                c => return Err(unexpected(c)),
+            }
+        }
+    }
+
+    /// array parses an array, calling `f` to parse each element.
+    fn array<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Self) -> Result<()>,
+    {
+        self.expect(b'[')?;
+        if self.peek_token()? == Some(b']') {
+            self.next()?;
+            return Ok(());
+        }
+        loop {
+            f(self)?;
+            match self.token()? {
+                b',' => continue,
+                b']' => return Ok(()),
+                c => return Err(unexpected(c)),
+            }
+        }
+    }
+
+    /// string parses a string, unescaping it.
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut buf = Vec::new();
+        loop {
+            match self.byte()? {
+                b'"' => break,
+                b'\\' => match self.byte()? {
+                    b'"' => buf.push(b'"'),
+                    b'\\' => buf.push(b'\\'),
+                    b'/' => buf.push(b'/'),
+                    b'b' => buf.push(0x08),
+                    b'f' => buf.push(0x0c),
+                    b'n' => buf.push(b'\n'),
+                    b'r' => buf.push(b'\r'),
+                    b't' => buf.push(b'\t'),
+                    b'u' => {
+                        let c = self.unicode_escape()?;
+                        buf.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    c => return Err(unexpected(c)),
+                },
+                c => buf.push(c),
+            }
+        }
+        String::from_utf8(buf).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    /// unicode_escape parses the digits of a `\u` escape, combining surrogate
+    /// pairs.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let hi = self.hex4()?;
+        let c = if (0xd800..0xdc00).contains(&hi) {
+            if self.byte()? != b'\\' || self.byte()? != b'u' {
+                return Err(invalid("unpaired surrogate"));
+            }
+            let lo = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&lo) {
+                return Err(invalid("unpaired surrogate"));
+            }
+            0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)
+        } else {
+            hi
+        };
+        std::char::from_u32(c).ok_or_else(|| invalid("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let d = (self.byte()? as char)
+                .to_digit(16)
+                .ok_or_else(|| invalid("invalid unicode escape"))?;
+            n = n * 16 + d;
+        }
+        Ok(n)
+    }
+
+    /// number parses an unsigned integer.
+    fn number(&mut self) -> Result<u64> {
+        let mut n: u64 = 0;
+        let mut digits = 0;
+        self.skip_whitespace()?;
+        while let Some(c) = self.peek()? {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            self.next()?;
+            n = n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add(u64::from(c - b'0')))
+                .ok_or_else(|| invalid("number out of range"))?;
+            digits += 1;
+        }
+        if digits == 0 {
+            return Err(invalid("expected a number"));
+        }
+        Ok(n)
+    }
+
+    /// skip parses and discards any value.
+    fn skip(&mut self) -> Result<()> {
+        match self.peek_token()? {
+            Some(b'{') => self.object(|p, _| p.skip()),
+            Some(b'[') => self.array(|p| p.skip()),
+            Some(b'"') => self.string().map(drop),
+            Some(_) => {
+                // A number or literal runs until the next delimiter.
+                while let Some(c) = self.peek()? {
+                    if matches!(c, b',' | b'}' | b']') || c.is_ascii_whitespace() {
+                        break;
+                    }
+                    self.next()?;
+                }
+                Ok(())
+            }
+            None => Err(invalid("unexpected end of document")),
+        }
+    }
+
+    fn expect(&mut self, want: u8) -> Result<()> {
+        match self.token()? {
+            c if c == want => Ok(()),
+            c => Err(unexpected(c)),
+        }
+    }
+
+    /// token returns the next byte that is not whitespace.
+    fn token(&mut self) -> Result<u8> {
+        self.skip_whitespace()?;
+        self.byte()
+    }
+
+    fn peek_token(&mut self) -> Result<Option<u8>> {
+        self.skip_whitespace()?;
+        self.peek()
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(c) = self.peek()? {
+            if !c.is_ascii_whitespace() {
+                break;
+            }
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// byte returns the next byte, failing at the end of the document.
+    fn byte(&mut self) -> Result<u8> {
+        self.next()?
+            .ok_or_else(|| invalid("unexpected end of document"))
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = self.r.next().transpose()?;
+        }
+        Ok(self.peeked)
+    }
+
+    fn next(&mut self) -> Result<Option<u8>> {
+        match self.peeked.take() {
+            Some(c) => Ok(Some(c)),
+            None => Ok(self.r.next().transpose()?),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("json: {}", msg)).into()
+}
+
+fn unexpected(c: u8) -> Error {
+    invalid(&format!("unexpected character {:?}", c as char))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let pad = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if pad > 2 || (pad > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - pad] {
+            let d = BASE64.iter().position(|&b| b == c)? as u32;
+            n = n << 6 | d;
+        }
+        n <<= 6 * pad as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - pad]);
+    }
+    Some(out)
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| {
+            let hi = (pair[0] as char).to_digit(16)?;
+            let lo = (pair[1] as char).to_digit(16)?;
+            Some((hi << 4 | lo) as u8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Options;
+
+    fn export(db: &DB, encoding: JsonEncoding) -> String {
+        let mut out = Vec::new();
+        db.export_json(&mut out, encoding).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn open(dir: &tempfile::TempDir, name: &str) -> DB {
+        DB::open(dir.path().join(name), Options::default()).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn export_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir, "db");
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"foo", b"bar")?;
+            b.put(b"\x00", b"\xff")?;
+            let nested = b.create_bucket(b"nested")?;
+            nested.set_sequence(7)?;
+            tx.create_bucket(b"empty")?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            export(&db, JsonEncoding::Hex),
+            r#"{
+  "encoding": "hex",
+  "buckets": [
+    {
+      "name": "656d707479",
+      "sequence": 0,
+      "entries": []
+    },
+    {
+      "name": "77696467657473",
+      "sequence": 0,
+      "entries": [
+        {"key": "00", "value": "ff"},
+        {"key": "666f6f", "value": "626172"},
+        {
+          "name": "6e6573746564",
+          "sequence": 7,
+          "entries": []
+        }
+      ]
+    }
+  ]
+}
+"#
+        );
+        assert!(export(&db, JsonEncoding::Base64).contains(r#"{"key": "Zm9v", "value": "YmFy"}"#));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = open(&dir, "src");
+        src.update(|tx| {
+            for name in [&b"widgets"[..], b"gadgets"] {
+                let b = tx.create_bucket(name)?;
+                b.set_sequence(42)?;
+                for i in 0..2000u32 {
+                    b.put(&i.to_be_bytes(), &i.to_le_bytes().repeat(i as usize % 7))?;
+                }
+                let nested = b.create_bucket(b"nested")?;
+                nested.create_bucket(b"empty")?.set_sequence(u64::MAX)?;
+                nested.put(b"big", &[0xab; 10000])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        for encoding in [JsonEncoding::Hex, JsonEncoding::Base64] {
+            let json = export(&src, encoding);
+            let dst = open(&dir, encoding.name());
+            dst.import_json(json.as_bytes()).unwrap();
+            assert_eq!(export(&dst, encoding), json);
+
+            // The buckets already exist now.
+            assert!(matches!(
+                dst.import_json(json.as_bytes()),
+                Err(Error::BucketExists)
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn import_json_whitespace_and_field_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir, "db");
+        let json = r#"{"buckets_count":1,"encoding":"base64","buckets":[{"name":"d2lkZ2V0cw==",
+            "entries":[{"value":"YmFy","key":"Zm9v"}],"sequence":3}]}"#;
+        db.import_json(json.as_bytes()).unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.get(b"foo")?, Some(&b"bar"[..]));
+            assert_eq!(b.sequence(), 3);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn import_json_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir, "db");
+        for json in [
+            "",
+            "[]",
+            r#"{"buckets":[]}"#,
+            r#"{"encoding":"rot13","buckets":[]}"#,
+            r#"{"encoding":"hex","buckets":[{"key":"00","value":"00"}]}"#,
+            r#"{"encoding":"hex","buckets":[{"name":"0"}]}"#,
+            r#"{"encoding":"hex","buckets":[{"entries":[],"name":"00"}]}"#,
+            r#"{"encoding":"hex","buckets":[{"name":"00","entries":[{"key":"00"}]}]}"#,
+            r#"{"encoding":"hex","buckets":[]} trailing"#,
+            r#"{"encoding":"hex","buckets":[{"name":"00","entries":[]}"#,
+        ] {
+            assert!(
+                matches!(db.import_json(json.as_bytes()), Err(Error::Io(_))),
+                "{}",
+                json
+            );
+        }
+
+        // Nothing was imported by the failed transactions.
+        assert_eq!(
+            export(&db, JsonEncoding::Hex),
+            "{\n  \"encoding\": \"hex\",\n  \"buckets\": []\n}\n"
+        );
+    }
+
+    #[test]
+    fn base64() {
+        for (data, want) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xff\xfe\xfd", "//79"),
+        ] {
+            assert_eq!(base64_encode(data), want);
+            assert_eq!(base64_decode(want).as_deref(), Some(data));
+        }
+        for s in ["Zg=", "Z===", "Zg==Zg==", "Zm9*"] {
+            assert_eq!(base64_decode(s), None, "{}", s);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Order preserving encodings of integer keys.
+//!
+//! Cursors visit keys in lexicographic byte order. Integers encoded with
+//! these functions sort in the same order as their numeric values: they are
+//! written big-endian, and signed integers have their sign bit flipped so
+//! that negative numbers sort before positive ones.
+
+use std::convert::TryInto;
+
+const SIGN_64: u64 = 1 << 63;
+
+/// EncodeU64 returns the big-endian encoding of `v`.
+pub fn encode_u64(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+/// DecodeU64 decodes a key written by `encode_u64`. Returns None if `b` is
+/// not 8 bytes long.
+pub fn decode_u64(b: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(b.try_into().ok()?))
+}
+
+/// EncodeI64 returns the big-endian encoding of `v` with its sign bit
+/// flipped.
+pub fn encode_i64(v: i64) -> [u8; 8] {
+    (v as u64 ^ SIGN_64).to_be_bytes()
+}
+
+/// DecodeI64 decodes a key written by `encode_i64`. Returns None if `b` is
+/// not 8 bytes long.
+pub fn decode_i64(b: &[u8]) -> Option<i64> {
+    decode_u64(b).map(|v| (v ^ SIGN_64) as i64)
+}
+
+/// EncodeU32 returns the big-endian encoding of `v`.
+pub fn encode_u32(v: u32) -> [u8; 4] {
+    v.to_be_bytes()
+}
+
+/// DecodeU32 decodes a key written by `encode_u32`. Returns None if `b` is
+/// not 4 bytes long.
+pub fn decode_u32(b: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(b.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Options, DB};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn u64_order_matches_bytes(a in any::<u64>(), b in any::<u64>()) {
+            prop_assert_eq!(a.cmp(&b), encode_u64(a).cmp(&encode_u64(b)));
+            prop_assert_eq!(decode_u64(&encode_u64(a)), Some(a));
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn i64_order_matches_bytes(a in any::<i64>(), b in any::<i64>()) {
+            prop_assert_eq!(a.cmp(&b), encode_i64(a).cmp(&encode_i64(b)));
+            prop_assert_eq!(decode_i64(&encode_i64(a)), Some(a));
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn u32_order_matches_bytes(a in any::<u32>(), b in any::<u32>()) {
+            prop_assert_eq!(a.cmp(&b), encode_u32(a).cmp(&encode_u32(b)));
+            prop_assert_eq!(decode_u32(&encode_u32(a)), Some(a));
+        }
+    }
+
+    #[test]
+    fn edges() {
+        assert!(encode_i64(-1) < encode_i64(0));
+        assert!(encode_i64(i64::MIN) < encode_i64(i64::MAX));
+        assert_eq!(encode_i64(0), [0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(decode_u64(&[0; 7]), None);
+        assert_eq!(decode_i64(&[0; 9]), None);
+        assert_eq!(decode_u32(&[]), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cursor_visits_numeric_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let ids = [300u64, 1, u64::MAX, 0, 256, 255, 70_000];
+        db.update(|tx| {
+            let b = tx.create_bucket(b"ids")?;
+            for &id in &ids {
+                b.put_u64(id, &encode_i64(-(id as i64)))?;
+            }
+            b.delete_u64(255)
+        })
+        .unwrap();
+
+        let (visited, value) = db
+            .view_ret(|tx| {
+                let b = tx.bucket(b"ids")?;
+                let mut visited = Vec::new();
+                b.for_each(|k, _| {
+                    visited.push(decode_u64(k).unwrap());
+                    Ok(())
+                })?;
+                Ok((visited, b.get_u64(300)?.and_then(decode_i64)))
+            })
+            .unwrap();
+        assert_eq!(visited, vec![0, 1, 256, 300, 70_000, u64::MAX]);
+        assert_eq!(value, Some(-300));
+    }
+}
@@ -1,7 +1,68 @@
+// Corruption, missing state and I/O failures reach callers as an `Err` from
+// the public API, never as a panic. The db, tx and bucket modules deny
+// `unwrap`, `expect` and `panic!` outside of their tests to keep it that way.
+#[cfg(feature = "tokio")]
+mod async_db;
+mod backend;
+mod batch;
+pub mod bench;
+mod bucket;
+mod compact;
 #[cfg(test)]
-mod boltdb {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+mod compat;
+mod cursor;
+mod db;
+mod errors;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod freelist;
+mod inspect;
+mod json;
+pub mod keys;
+mod logger;
+mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod node;
+mod ops;
+mod page;
+mod snapshot;
+pub mod surgery;
+mod transform;
+mod tx;
+mod tx_check;
+#[cfg(feature = "serde")]
+mod typed;
+mod unix;
+mod usage;
+mod value_codec;
+
+#[cfg(feature = "tokio")]
+pub use async_db::AsyncDb;
+pub use bucket::{
+    Bucket, BucketStats, ReservedValue, DEFAULT_FILL_PERCENT, MAX_KEY_SIZE, MAX_VALUE_SIZE,
+};
+pub use cursor::{Cursor, Entry, Iter, Pair};
+pub use db::{FreelistStats, MetaSlot, OpenReport, Options, Stats, DB};
+pub use errors::{Error, Result};
+pub use freelist::FreelistType;
+pub use inspect::PageDump;
+pub use json::JsonEncoding;
+#[cfg(feature = "log")]
+pub use logger::LogLogger;
+pub use logger::{DiscardLogger, Logger};
+pub use ops::SyncMode;
+pub use page::PageInfo;
+pub use snapshot::Snapshot;
+pub use transform::PageTransform;
+pub use tx::{Tx, TxStats};
+pub use tx_check::{
+    CheckError, CheckErrorKind, CheckOptions, HexKvStringer, KvStringer, Utf8KvStringer,
+};
+#[cfg(feature = "serde")]
+pub use typed::{Bincode, Codec, KeyCodec, TypedBucket, TypedIter};
+pub use unix::MadviseMode;
+pub use usage::UsageReport;
+#[cfg(feature = "zstd")]
+pub use value_codec::ZstdCodec;
+pub use value_codec::{IdentityCodec, ValueCodec};
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Logger receives the messages emitted by the database: opening and closing
+/// it, mapping the data file, committing transactions, rebuilding the
+/// freelist and failed consistency checks.
+///
+/// Messages are passed as `fmt::Arguments`, so a logger that discards them
+/// costs nothing.
+pub trait Logger: Send + Sync {
+    fn debug(&self, args: fmt::Arguments<'_>);
+    fn info(&self, args: fmt::Arguments<'_>);
+    fn warn(&self, args: fmt::Arguments<'_>);
+    fn error(&self, args: fmt::Arguments<'_>);
+}
+
+impl fmt::Debug for dyn Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Logger")
+    }
+}
+
+/// DiscardLogger is the default logger, it drops every message.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiscardLogger;
+
+impl Logger for DiscardLogger {
+    fn debug(&self, _: fmt::Arguments<'_>) {}
+    fn info(&self, _: fmt::Arguments<'_>) {}
+    fn warn(&self, _: fmt::Arguments<'_>) {}
+    fn error(&self, _: fmt::Arguments<'_>) {}
+}
+
+/// LogLogger forwards every message to the `log` crate, with the `bolt`
+/// target.
+#[cfg(feature = "log")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogLogger;
+
+#[cfg(feature = "log")]
+impl Logger for LogLogger {
+    fn debug(&self, args: fmt::Arguments<'_>) {
+        log::debug!(target: "bolt", "{}", args);
+    }
+
+    fn info(&self, args: fmt::Arguments<'_>) {
+        log::info!(target: "bolt", "{}", args);
+    }
+
+    fn warn(&self, args: fmt::Arguments<'_>) {
+        log::warn!(target: "bolt", "{}", args);
+    }
+
+    fn error(&self, args: fmt::Arguments<'_>) {
+        log::error!(target: "bolt", "{}", args);
+    }
+}
+
+/// discard returns the logger used when none is configured.
+pub(crate) fn discard() -> Arc<dyn Logger> {
+    Arc::new(DiscardLogger)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::db::{Options, DB};
+    use crate::ops::tests::{FailpointOps, Failure};
+
+    /// Recorder is a logger keeping every message along with its level.
+    #[derive(Default)]
+    pub(crate) struct Recorder(Mutex<Vec<(&'static str, String)>>);
+
+    impl Recorder {
+        pub(crate) fn take(&self) -> Vec<(&'static str, String)> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+
+        fn push(&self, level: &'static str, args: fmt::Arguments<'_>) {
+            self.0.lock().unwrap().push((level, args.to_string()));
+        }
+    }
+
+    impl Logger for Recorder {
+        fn debug(&self, args: fmt::Arguments<'_>) {
+            self.push("debug", args);
+        }
+
+        fn info(&self, args: fmt::Arguments<'_>) {
+            self.push("info", args);
+        }
+
+        fn warn(&self, args: fmt::Arguments<'_>) {
+            self.push("warn", args);
+        }
+
+        fn error(&self, args: fmt::Arguments<'_>) {
+            self.push("error", args);
+        }
+    }
+
+    /// strip replaces the path and durations of the recorded messages, so
+    /// they can be compared.
+    fn strip(msgs: Vec<(&'static str, String)>, path: &str) -> Vec<(&'static str, String)> {
+        msgs.into_iter()
+            .map(|(level, msg)| {
+                let msg = msg.replace(path, "PATH");
+                let msg = match msg.find(" in ") {
+                    Some(i) => format!("{} in DURATION", &msg[..i]),
+                    None => msg,
+                };
+                (level, msg)
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_and_open_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let logger = Arc::new(Recorder::default());
+        let ops = FailpointOps::new();
+        let db = DB::open_with_ops(
+            &path,
+            Options {
+                page_size: 4096,
+                logger: Some(logger.clone()),
+                ..Options::default()
+            },
+            ops.clone(),
+        )
+        .unwrap();
+        let msgs = logger.take();
+        assert_eq!(msgs[0].0, "info");
+        assert!(msgs[0].1.starts_with(&format!(
+            "opening db file ({}) with options: Options {{",
+            path.display()
+        )));
+        assert_eq!(
+            strip(msgs[1..].to_vec(), &path.display().to_string()),
+            vec![
+                (
+                    "debug",
+                    "mapped db file (PATH) with size 32768 bytes".into()
+                ),
+                ("debug", "read freelist from page 2: 0 free pages".into()),
+                ("info", "opened db (PATH) successfully".into()),
+            ]
+        );
+
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        assert_eq!(
+            strip(logger.take(), &path.display().to_string()),
+            vec![
+                ("debug", "committing tx 2".into()),
+                ("info", "committed tx 2: 2 pages written in DURATION".into()),
+            ]
+        );
+
+        // A failing commit is rolled back and reports why.
+        let mut tx = db.begin(true).unwrap();
+        tx.create_bucket(b"gadgets").unwrap();
+        ops.fail_nth(0, Failure::Error, true);
+        assert!(tx.commit().is_err());
+        ops.disarm();
+        let msgs = logger.take();
+        assert_eq!(msgs[0], ("debug", "committing tx 3".into()));
+        assert_eq!(msgs[1].0, "error");
+        assert!(msgs[1].1.starts_with("commit tx 3 failed: "));
+        assert_eq!(msgs[2], ("debug", "reloaded freelist from page 5".into()));
+
+        db.close().unwrap();
+        assert_eq!(
+            strip(logger.take(), &path.display().to_string()),
+            vec![("info", "closing db (PATH)".into())]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::bucket::{InBucket, BUCKET_HEADER_SIZE};
+use crate::errors::{Error, Result};
+use crate::page::{
+    read_u32, read_u64, write_u32, write_u64, PageMut, Pgid, META_PAGE_FLAG, PGID_NO_FREELIST,
+};
+use crate::tx::Txid;
+
+/// MAGIC is the marker value to indicate that a file is a Bolt DB.
+pub(crate) const MAGIC: u32 = 0xED0C_DAED;
+
+/// VERSION represents the data file format version.
+pub(crate) const VERSION: u32 = 2;
+
+/// MAX_PAGE_SIZE is the largest page size a database file may record.
+pub(crate) const MAX_PAGE_SIZE: u32 = 64 << 10;
+
+/// META_SIZE is the on-disk size of the meta struct, checksum included.
+pub(crate) const META_SIZE: usize = 64;
+
+/// Offset of the checksum field, i.e. the number of bytes covered by it.
+const CHECKSUM_OFFSET: usize = 56;
+
+/// Meta holds the database-wide metadata stored in the first two pages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Meta {
+    pub(crate) magic: u32,
+    pub(crate) version: u32,
+    pub(crate) page_size: u32,
+    pub(crate) flags: u32,
+    pub(crate) root: InBucket,
+    pub(crate) freelist: Pgid,
+    pub(crate) pgid: Pgid,
+    pub(crate) txid: Txid,
+    pub(crate) checksum: u64,
+}
+
+impl Meta {
+    /// read decodes a meta from the data section of a meta page.
+    pub(crate) fn read(buf: &[u8]) -> Meta {
+        Meta {
+            magic: read_u32(buf, 0),
+            version: read_u32(buf, 4),
+            page_size: read_u32(buf, 8),
+            flags: read_u32(buf, 12),
+            root: InBucket::read(&buf[16..16 + BUCKET_HEADER_SIZE]),
+            freelist: read_u64(buf, 32),
+            pgid: read_u64(buf, 40),
+            txid: read_u64(buf, 48),
+            checksum: read_u64(buf, CHECKSUM_OFFSET),
+        }
+    }
+
+    /// encode serializes every field, checksum included, into `buf`.
+    fn encode(&self, buf: &mut [u8]) {
+        write_u32(buf, 0, self.magic);
+        write_u32(buf, 4, self.version);
+        write_u32(buf, 8, self.page_size);
+        write_u32(buf, 12, self.flags);
+        self.root.write(&mut buf[16..16 + BUCKET_HEADER_SIZE]);
+        write_u64(buf, 32, self.freelist);
+        write_u64(buf, 40, self.pgid);
+        write_u64(buf, 48, self.txid);
+        write_u64(buf, CHECKSUM_OFFSET, self.checksum);
+    }
+
+    /// validate checks the marker bytes and version of the meta page to ensure it matches this binary.
+    /// A bad magic means the file is not a Bolt DB at all, while a bad version, checksum or page
+    /// size points at a Bolt DB this binary cannot use, so each gets its own error.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.magic != MAGIC {
+            return Err(Error::Invalid);
+        } else if self.version != VERSION {
+            return Err(Error::VersionMismatch {
+                found: self.version,
+                supported: VERSION,
+            });
+        } else if self.checksum != self.sum64() {
+            return Err(Error::Checksum);
+        } else if !valid_page_size(self.page_size) {
+            return Err(Error::InvalidPageSize {
+                page_size: self.page_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// write writes the meta onto a page.
+    pub(crate) fn write(&mut self, p: &mut PageMut<'_>) {
+        assert!(
+            self.root.root < self.pgid,
+            "root bucket pgid ({}) above high water mark ({})",
+            self.root.root,
+            self.pgid
+        );
+        assert!(
+            self.freelist < self.pgid || self.freelist == PGID_NO_FREELIST,
+            "freelist pgid ({}) above high water mark ({})",
+            self.freelist,
+            self.pgid
+        );
+
+        // Page id is either going to be 0 or 1 which we can determine by the transaction ID.
+        p.set_id(self.txid % 2);
+        p.set_flags(p.as_page().flags() | META_PAGE_FLAG);
+
+        // Calculate the checksum.
+        self.checksum = self.sum64();
+
+        self.encode(p.data_mut());
+    }
+
+    /// sum64 generates the FNV-1a checksum for the meta.
+    pub(crate) fn sum64(&self) -> u64 {
+        let mut buf = [0u8; META_SIZE];
+        self.encode(&mut buf);
+        fnv1a64(&buf[..CHECKSUM_OFFSET])
+    }
+}
+
+/// valid_page_size reports whether `page_size` is a power of two no larger
+/// than MAX_PAGE_SIZE.
+pub(crate) fn valid_page_size(page_size: u32) -> bool {
+    page_size.is_power_of_two() && page_size <= MAX_PAGE_SIZE
+}
+
+/// fnv1a64 computes the 64-bit FNV-1a hash of `data`.
+fn fnv1a64(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    data.iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{Page, PAGE_HEADER_SIZE};
+    use proptest::prelude::*;
+
+    fn meta() -> Meta {
+        Meta {
+            magic: MAGIC,
+            version: VERSION,
+            page_size: 4096,
+            flags: 0,
+            root: InBucket {
+                root: 3,
+                sequence: 0,
+            },
+            freelist: 2,
+            pgid: 4,
+            txid: 7,
+            checksum: 0,
+        }
+    }
+
+    #[test]
+    fn fnv1a_vectors() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a64(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn write_read_validate() {
+        let mut m = meta();
+        let mut buf = vec![0u8; 4096];
+        m.write(&mut PageMut::new(&mut buf));
+
+        let p = Page::new(&buf);
+        assert_eq!(p.id(), 1);
+        assert_eq!(p.flags(), META_PAGE_FLAG);
+        let read = Meta::read(&buf[PAGE_HEADER_SIZE..]);
+        assert_eq!(read, m);
+        read.validate().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "root bucket pgid (4) above high water mark (4)")]
+    fn write_root_above_high_water() {
+        let mut m = meta();
+        m.root.root = 4;
+        m.write(&mut PageMut::new(&mut [0u8; 4096]));
+    }
+
+    #[test]
+    #[should_panic(expected = "freelist pgid (5) above high water mark (4)")]
+    fn write_freelist_above_high_water() {
+        let mut m = meta();
+        m.freelist = 5;
+        m.write(&mut PageMut::new(&mut [0u8; 4096]));
+    }
+
+    #[test]
+    fn write_alternates_pages() {
+        let mut buf = vec![0u8; 4096];
+        for txid in 0..4 {
+            let mut m = Meta { txid, ..meta() };
+            m.write(&mut PageMut::new(&mut buf));
+            assert_eq!(Page::new(&buf).id(), txid % 2);
+        }
+    }
+
+    #[test]
+    fn validate_errors() {
+        let mut m = meta();
+        m.checksum = m.sum64();
+        m.validate().unwrap();
+
+        let mut bad = m;
+        bad.magic = 1;
+        assert!(matches!(bad.validate(), Err(Error::Invalid)));
+
+        let mut bad = m;
+        bad.version = 1;
+        assert!(matches!(
+            bad.validate(),
+            Err(Error::VersionMismatch {
+                found: 1,
+                supported: VERSION
+            })
+        ));
+
+        let mut bad = m;
+        bad.pgid += 1;
+        assert!(matches!(bad.validate(), Err(Error::Checksum)));
+
+        for page_size in [0, 3000, 128 << 10] {
+            let mut bad = m;
+            bad.page_size = page_size;
+            bad.checksum = bad.sum64();
+            assert!(matches!(
+                bad.validate(),
+                Err(Error::InvalidPageSize { page_size: p }) if p == page_size
+            ));
+        }
+    }
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore)]
+        fn corrupted_meta_never_validates(
+            flips in prop::collection::vec((0..META_SIZE, 1..=255u8), 1..8),
+        ) {
+            let mut m = meta();
+            let mut buf = vec![0u8; 4096];
+            m.write(&mut PageMut::new(&mut buf));
+
+            let data = &mut buf[PAGE_HEADER_SIZE..];
+            let original = data[..META_SIZE].to_vec();
+            for (off, mask) in flips {
+                data[off] ^= mask;
+            }
+            prop_assume!(data[..META_SIZE] != original[..]);
+            prop_assert!(Meta::read(data).validate().is_err());
+        }
+    }
+}
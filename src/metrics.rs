@@ -0,0 +1,281 @@
+//! Export of the database statistics through the `metrics` facade.
+//!
+//! When the `metrics` feature is enabled, the database statistics are
+//! published to the installed `metrics` recorder whenever a transaction
+//! closes: the freelist and open transaction gauges are set, the started
+//! transactions and page pool counters are raised to their current totals,
+//! and the counters of the closing transaction are added to the `bolt_tx_*`
+//! counters.
+
+use ::metrics::{counter, describe_counter, describe_gauge, gauge, Unit};
+
+use crate::db::Stats;
+use crate::tx::TxStats;
+
+/// Describe registers the unit and description of every metric published by
+/// the database with the installed recorder.
+pub fn describe() {
+    describe_gauge!(
+        "bolt_free_page_n",
+        Unit::Count,
+        "Number of free pages on the freelist."
+    );
+    describe_gauge!(
+        "bolt_pending_page_n",
+        Unit::Count,
+        "Number of pending pages on the freelist."
+    );
+    describe_gauge!(
+        "bolt_free_alloc_bytes",
+        Unit::Bytes,
+        "Bytes allocated in free pages."
+    );
+    describe_gauge!(
+        "bolt_freelist_inuse_bytes",
+        Unit::Bytes,
+        "Bytes used by the freelist."
+    );
+    describe_counter!(
+        "bolt_tx_n",
+        Unit::Count,
+        "Number of started read transactions."
+    );
+    describe_gauge!(
+        "bolt_open_tx_n",
+        Unit::Count,
+        "Number of currently open read transactions."
+    );
+    describe_counter!(
+        "bolt_page_pool_hits",
+        Unit::Count,
+        "Number of single-page buffers taken from the page pool."
+    );
+    describe_counter!(
+        "bolt_page_pool_misses",
+        Unit::Count,
+        "Number of single-page buffers allocated because the page pool was empty."
+    );
+    describe_counter!(
+        "bolt_remap_count",
+        Unit::Count,
+        "Number of times the data file was mapped again."
+    );
+    describe_counter!("bolt_remap_bytes", Unit::Bytes, "Bytes mapped by remaps.");
+    describe_counter!(
+        "bolt_writer_stall_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time write transactions waited for locks when beginning."
+    );
+    describe_counter!(
+        "bolt_reader_wait_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time read transactions waited for locks when beginning."
+    );
+    describe_counter!(
+        "bolt_freelist_rebuild_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time spent rebuilding the freelist when it is not persisted."
+    );
+
+    describe_counter!(
+        "bolt_tx_page_count",
+        Unit::Count,
+        "Number of page allocations."
+    );
+    describe_counter!(
+        "bolt_tx_page_alloc_bytes",
+        Unit::Bytes,
+        "Bytes allocated for pages."
+    );
+    describe_counter!(
+        "bolt_tx_cursor_count",
+        Unit::Count,
+        "Number of cursors created."
+    );
+    describe_counter!(
+        "bolt_tx_node_count",
+        Unit::Count,
+        "Number of node allocations."
+    );
+    describe_counter!(
+        "bolt_tx_node_deref",
+        Unit::Count,
+        "Number of node dereferences."
+    );
+    describe_counter!(
+        "bolt_tx_rebalance",
+        Unit::Count,
+        "Number of node rebalances."
+    );
+    describe_counter!(
+        "bolt_tx_rebalance_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time spent rebalancing."
+    );
+    describe_counter!("bolt_tx_split", Unit::Count, "Number of nodes split.");
+    describe_counter!("bolt_tx_spill", Unit::Count, "Number of nodes spilled.");
+    describe_counter!(
+        "bolt_tx_spill_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time spent spilling."
+    );
+    describe_counter!("bolt_tx_write", Unit::Count, "Number of writes performed.");
+    describe_counter!(
+        "bolt_tx_write_time_nanoseconds",
+        Unit::Nanoseconds,
+        "Time spent writing to disk."
+    );
+}
+
+/// record publishes the database statistics `stats` along with the counters
+/// of a transaction that just closed.
+pub(crate) fn record(stats: &Stats, tx: &TxStats) {
+    gauge!("bolt_free_page_n").set(stats.free_page_n as f64);
+    gauge!("bolt_pending_page_n").set(stats.pending_page_n as f64);
+    gauge!("bolt_free_alloc_bytes").set(stats.free_alloc as f64);
+    gauge!("bolt_freelist_inuse_bytes").set(stats.freelist_inuse as f64);
+    counter!("bolt_tx_n").absolute(stats.tx_n.max(0) as u64);
+    gauge!("bolt_open_tx_n").set(stats.open_tx_n as f64);
+    counter!("bolt_page_pool_hits").absolute(stats.page_pool_hits.max(0) as u64);
+    counter!("bolt_page_pool_misses").absolute(stats.page_pool_misses.max(0) as u64);
+    counter!("bolt_remap_count").absolute(stats.remap_count.max(0) as u64);
+    counter!("bolt_remap_bytes").absolute(stats.remap_bytes.max(0) as u64);
+    counter!("bolt_writer_stall_time_nanoseconds")
+        .absolute(stats.writer_stall_time.as_nanos() as u64);
+    counter!("bolt_reader_wait_time_nanoseconds")
+        .absolute(stats.reader_wait_time.as_nanos() as u64);
+    counter!("bolt_freelist_rebuild_time_nanoseconds")
+        .absolute(stats.freelist_rebuild_time.as_nanos() as u64);
+
+    let n = |v: i64| v.max(0) as u64;
+    counter!("bolt_tx_page_count").increment(n(tx.page_count()));
+    counter!("bolt_tx_page_alloc_bytes").increment(n(tx.page_alloc()));
+    counter!("bolt_tx_cursor_count").increment(n(tx.cursor_count()));
+    counter!("bolt_tx_node_count").increment(n(tx.node_count()));
+    counter!("bolt_tx_node_deref").increment(n(tx.node_deref()));
+    counter!("bolt_tx_rebalance").increment(n(tx.rebalance()));
+    counter!("bolt_tx_rebalance_time_nanoseconds").increment(tx.rebalance_time().as_nanos() as u64);
+    counter!("bolt_tx_split").increment(n(tx.split()));
+    counter!("bolt_tx_spill").increment(n(tx.spill()));
+    counter!("bolt_tx_spill_time_nanoseconds").increment(tx.spill_time().as_nanos() as u64);
+    counter!("bolt_tx_write").increment(n(tx.write()));
+    counter!("bolt_tx_write_time_nanoseconds").increment(tx.write_time().as_nanos() as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use ::metrics::{
+        with_local_recorder, Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder,
+        SharedString,
+    };
+
+    use super::*;
+    use crate::db::{Options, DB};
+
+    /// Registry is a recorder keeping the value of every counter and gauge.
+    #[derive(Default)]
+    struct Registry {
+        counters: Mutex<HashMap<String, Arc<AtomicU64>>>,
+        gauges: Mutex<HashMap<String, Arc<AtomicU64>>>,
+        described: Mutex<Vec<String>>,
+    }
+
+    impl Registry {
+        fn counter(&self, name: &str) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            counters[name].load(Ordering::SeqCst)
+        }
+
+        fn gauge(&self, name: &str) -> f64 {
+            let gauges = self.gauges.lock().unwrap();
+            f64::from_bits(gauges[name].load(Ordering::SeqCst))
+        }
+    }
+
+    impl Recorder for Registry {
+        fn describe_counter(&self, key: KeyName, _: Option<Unit>, _: SharedString) {
+            self.described
+                .lock()
+                .unwrap()
+                .push(key.as_str().to_string());
+        }
+
+        fn describe_gauge(&self, key: KeyName, _: Option<Unit>, _: SharedString) {
+            self.described
+                .lock()
+                .unwrap()
+                .push(key.as_str().to_string());
+        }
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let mut counters = self.counters.lock().unwrap();
+            Counter::from_arc(counters.entry(key.name().to_string()).or_default().clone())
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            let mut gauges = self.gauges.lock().unwrap();
+            Gauge::from_arc(gauges.entry(key.name().to_string()).or_default().clone())
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn counters_follow_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let registry = Registry::default();
+
+        with_local_recorder(&registry, || {
+            describe();
+            db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+                .unwrap();
+            let writes = registry.counter("bolt_tx_write");
+            assert!(writes > 0);
+
+            for i in 0..3u32 {
+                db.update(|tx| tx.bucket(b"widgets")?.put(&i.to_be_bytes(), b"value"))
+                    .unwrap();
+            }
+            assert!(registry.counter("bolt_tx_write") >= writes + 3);
+            assert!(registry.counter("bolt_tx_page_count") >= 4);
+            assert!(registry.counter("bolt_tx_write_time_nanoseconds") > 0);
+
+            db.view(|_| Ok(())).unwrap();
+            db.view(|_| Ok(())).unwrap();
+            assert_eq!(registry.counter("bolt_tx_n"), 2);
+            assert_eq!(registry.gauge("bolt_open_tx_n"), 0.0);
+
+            db.update(|tx| tx.bucket(b"widgets")?.delete(&0u32.to_be_bytes()))
+                .unwrap();
+            let stats = db.freelist_stats();
+            assert_eq!(
+                registry.gauge("bolt_pending_page_n"),
+                stats.pending_page_n as f64
+            );
+            assert_eq!(registry.gauge("bolt_free_page_n"), stats.free_page_n as f64);
+            assert_eq!(
+                registry.counter("bolt_page_pool_hits"),
+                db.stats().page_pool_hits as u64
+            );
+        });
+
+        let described = registry.described.lock().unwrap();
+        assert_eq!(described.len(), 25);
+        for name in registry.counters.lock().unwrap().keys() {
+            assert!(described.contains(name), "{}", name);
+        }
+        for name in registry.gauges.lock().unwrap().keys() {
+            assert!(described.contains(name), "{}", name);
+        }
+    }
+}
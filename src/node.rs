@@ -0,0 +1,964 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::ptr::NonNull;
+use std::slice;
+
+use crate::bucket::{BucketId, MAX_FILL_PERCENT, MIN_FILL_PERCENT};
+use crate::errors::{Error, Result};
+use crate::page::{
+    write_u32, write_u64, Page, PageMut, Pgid, BRANCH_PAGE_ELEMENT_SIZE, BRANCH_PAGE_FLAG,
+    LEAF_PAGE_ELEMENT_SIZE, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+};
+use crate::tx::{Tx, TxState};
+
+/// The minimum number of keys a page must hold before it can be split.
+const MIN_KEYS_PER_PAGE: usize = 2;
+
+/// NodeId indexes a node in the transaction's node arena.
+pub(crate) type NodeId = usize;
+
+/// Bytes is a byte slice whose storage is owned by a transaction: either the
+/// memory map the transaction pinned, or a copy the transaction keeps in its
+/// arena until it is dropped. Nodes store keys and values as Bytes so that
+/// they can hand out slices that live as long as the transaction.
+#[derive(Clone, Copy)]
+pub(crate) struct Bytes {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Safety: Bytes only points into storage owned by the transaction holding it,
+// which moves between threads together with it.
+unsafe impl Send for Bytes {}
+
+impl Bytes {
+    /// new wraps `data`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must stay valid and unchanged for as long as the transaction
+    /// that stores the returned value.
+    pub(crate) unsafe fn new(data: &[u8]) -> Bytes {
+        Bytes {
+            ptr: NonNull::new(data.as_ptr() as *mut u8).unwrap_or(NonNull::dangling()),
+            len: data.len(),
+        }
+    }
+
+    /// get returns the slice, borrowed for as long as the Bytes itself.
+    pub(crate) fn get(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// extend returns the slice with an arbitrary lifetime.
+    ///
+    /// # Safety
+    ///
+    /// `'a` must not outlive the transaction owning the storage.
+    pub(crate) unsafe fn extend<'a>(self) -> &'a [u8] {
+        slice::from_raw_parts(self.ptr.as_ptr(), self.len)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Default for Bytes {
+    fn default() -> Bytes {
+        Bytes {
+            ptr: NonNull::dangling(),
+            len: 0,
+        }
+    }
+}
+
+/// Inode represents an internal node inside of a node.
+/// It can be used to point to elements in a page or point
+/// to an element which hasn't been added to a page yet.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Inode {
+    pub(crate) flags: u32,
+    pub(crate) pgid: Pgid,
+    pub(crate) key: Bytes,
+    pub(crate) value: Bytes,
+}
+
+/// Node represents an in-memory, deserialized page.
+pub(crate) struct Node {
+    pub(crate) bucket: BucketId,
+    pub(crate) is_leaf: bool,
+    pub(crate) unbalanced: bool,
+    pub(crate) spilled: bool,
+    pub(crate) key: Option<Bytes>,
+    pub(crate) pgid: Pgid,
+    pub(crate) parent: Option<NodeId>,
+    pub(crate) children: Vec<NodeId>,
+    pub(crate) inodes: Vec<Inode>,
+}
+
+impl Node {
+    pub(crate) fn new(bucket: BucketId, is_leaf: bool, parent: Option<NodeId>) -> Node {
+        Node {
+            bucket,
+            is_leaf,
+            unbalanced: false,
+            spilled: false,
+            key: None,
+            pgid: 0,
+            parent,
+            children: Vec::new(),
+            inodes: Vec::new(),
+        }
+    }
+
+    /// min_keys returns the minimum number of inodes this node should have.
+    pub(crate) fn min_keys(&self) -> usize {
+        if self.is_leaf {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// size returns the size of the node after serialization.
+    pub(crate) fn size(&self) -> usize {
+        PAGE_HEADER_SIZE
+            + self
+                .inodes
+                .iter()
+                .map(|item| self.inode_size(item))
+                .sum::<usize>()
+    }
+
+    /// size_less_than returns true if the inodes from `start` on make a node
+    /// less than a given size.
+    /// This is an optimization to avoid calculating a large node when we only need
+    /// to know if it fits inside a certain page size.
+    pub(crate) fn size_less_than(&self, start: usize, v: usize) -> bool {
+        let elsz = self.page_element_size();
+        let mut sz = PAGE_HEADER_SIZE;
+        for item in &self.inodes[start..] {
+            sz += elsz + item.key.len() + item.value.len();
+            if sz >= v {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// page_element_size returns the size of each page element based on the type of node.
+    pub(crate) fn page_element_size(&self) -> usize {
+        if self.is_leaf {
+            LEAF_PAGE_ELEMENT_SIZE
+        } else {
+            BRANCH_PAGE_ELEMENT_SIZE
+        }
+    }
+
+    /// search returns the index of the first inode whose key is not less than
+    /// `key`, and whether that key is an exact match.
+    pub(crate) fn search(&self, key: &[u8]) -> (usize, bool) {
+        let index = self.inodes.partition_point(|inode| inode.key.get() < key);
+        let exact = self
+            .inodes
+            .get(index)
+            .is_some_and(|inode| inode.key.get() == key);
+        (index, exact)
+    }
+
+    /// put inserts a key/value, and returns by how much it changed the size
+    /// of the node after serialization.
+    pub(crate) fn put(
+        &mut self,
+        old_key: &[u8],
+        new_key: Bytes,
+        value: Bytes,
+        pgid: Pgid,
+        flags: u32,
+    ) -> Result<isize> {
+        if old_key.is_empty() || new_key.len() == 0 {
+            return Err(Error::KeyRequired);
+        }
+
+        // Find insertion index, then add capacity and shift nodes if we don't
+        // have an exact match and need to insert.
+        let (index, exact) = self.search(old_key);
+        let old_size = if exact {
+            self.inode_size(&self.inodes[index])
+        } else {
+            self.inodes.insert(index, Inode::default());
+            0
+        };
+
+        let inode = &mut self.inodes[index];
+        inode.flags = flags;
+        inode.key = new_key;
+        inode.value = value;
+        inode.pgid = pgid;
+        Ok(self.inode_size(&self.inodes[index]) as isize - old_size as isize)
+    }
+
+    /// del removes a key from the node, and returns by how much it shrank
+    /// the node after serialization.
+    pub(crate) fn del(&mut self, key: &[u8]) -> usize {
+        // Find index of key.
+        let (index, exact) = self.search(key);
+
+        // Exit if the key isn't found.
+        if !exact {
+            return 0;
+        }
+
+        // Delete inode from the node.
+        let inode = self.inodes.remove(index);
+
+        // Mark the node as needing rebalancing.
+        self.unbalanced = true;
+        self.inode_size(&inode)
+    }
+
+    /// inode_size returns the size `inode` takes up on a page of this node.
+    pub(crate) fn inode_size(&self, inode: &Inode) -> usize {
+        self.page_element_size() + inode.key.len() + inode.value.len()
+    }
+
+    /// read initializes the node from a page.
+    ///
+    /// # Safety
+    ///
+    /// The page must be owned by the transaction that owns the node, see Bytes.
+    pub(crate) unsafe fn read(&mut self, p: Page<'_>) -> Result<()> {
+        self.pgid = p.id();
+        self.is_leaf = p.flags() & LEAF_PAGE_FLAG != 0;
+        self.inodes = if self.is_leaf {
+            p.leaf_page_elements()?
+                .into_iter()
+                .map(|elem| Inode {
+                    flags: elem.flags(),
+                    pgid: 0,
+                    key: Bytes::new(elem.key()),
+                    value: Bytes::new(elem.value()),
+                })
+                .collect()
+        } else {
+            p.branch_page_elements()?
+                .into_iter()
+                .map(|elem| Inode {
+                    flags: 0,
+                    pgid: elem.pgid(),
+                    key: Bytes::new(elem.key()),
+                    value: Bytes::default(),
+                })
+                .collect()
+        };
+        if self.inodes.iter().any(|inode| inode.key.len() == 0) {
+            return Err(Error::corrupted(p.id(), "read: zero-length inode key"));
+        }
+
+        // Save first key so we can find the node in the parent when we spill.
+        self.key = self.inodes.first().map(|inode| inode.key);
+        Ok(())
+    }
+
+    /// write writes the items onto one or more pages.
+    /// The page should have p.id (might be 0 for meta or bucket-inline page) and p.overflow set
+    /// and the rest should be zeroed.
+    pub(crate) fn write(&self, p: &mut PageMut<'_>) -> Result<()> {
+        // Initialize page.
+        p.set_flags(if self.is_leaf {
+            LEAF_PAGE_FLAG
+        } else {
+            BRANCH_PAGE_FLAG
+        });
+
+        let id = p.as_page().id();
+        if self.inodes.len() >= 0xFFFF {
+            let reason = format!("inode overflow: {}", self.inodes.len());
+            return Err(Error::corrupted(id, reason));
+        }
+        p.set_count(self.inodes.len() as u16);
+
+        // Stop here if there are no items to write.
+        if self.inodes.is_empty() {
+            return Ok(());
+        }
+
+        // Loop over each item and write it to the page.
+        // off tracks the offset into the data where the next data should be written.
+        let elsz = self.page_element_size();
+        let data = p.data_mut();
+        let mut off = elsz * self.inodes.len();
+        for (i, item) in self.inodes.iter().enumerate() {
+            if item.key.len() == 0 {
+                return Err(Error::corrupted(id, "write: zero-length inode key"));
+            }
+
+            // Write the page element.
+            let elem = i * elsz;
+            let pos = (off - elem) as u32;
+            if self.is_leaf {
+                write_u32(data, elem, item.flags);
+                write_u32(data, elem + 4, pos);
+                write_u32(data, elem + 8, item.key.len() as u32);
+                write_u32(data, elem + 12, item.value.len() as u32);
+            } else {
+                write_u32(data, elem, pos);
+                write_u32(data, elem + 4, item.key.len() as u32);
+                write_u64(data, elem + 8, item.pgid);
+                if item.pgid == id {
+                    return Err(Error::corrupted(id, "write: circular dependency occurred"));
+                }
+            }
+
+            // Write data for the element to the end of the page.
+            let (key, value) = (item.key.get(), item.value.get());
+            data[off..off + key.len()].copy_from_slice(key);
+            off += key.len();
+            data[off..off + value.len()].copy_from_slice(value);
+            off += value.len();
+        }
+        Ok(())
+    }
+
+    /// split_index finds the position where a page will fill a given threshold,
+    /// considering the inodes from `start` on. It returns the index as well as
+    /// the size of the first page.
+    /// This is only be called from split().
+    fn split_index(&self, start: usize, threshold: usize) -> (usize, usize) {
+        let mut sz = PAGE_HEADER_SIZE;
+        let mut index = start;
+
+        // Loop until we only have the minimum number of keys required for the second page.
+        for i in start..self.inodes.len() - MIN_KEYS_PER_PAGE {
+            index = i;
+            let inode = &self.inodes[i];
+            let elsize = self.page_element_size() + inode.key.len() + inode.value.len();
+
+            // If we have at least the minimum number of keys and adding another
+            // node would put us over the threshold then exit and return.
+            if index - start >= MIN_KEYS_PER_PAGE && sz + elsize > threshold {
+                break;
+            }
+
+            // Add the element size to the total size.
+            sz += elsize;
+        }
+        (index, sz)
+    }
+
+    /// split_point returns where the inodes from `start` on have to be split,
+    /// if they do not fit in a single page.
+    fn split_point(&self, start: usize, page_size: usize, threshold: usize) -> Option<usize> {
+        // Ignore the split if the page doesn't have at least enough nodes for
+        // two pages or if the nodes can fit in a single page.
+        if self.inodes.len() - start <= MIN_KEYS_PER_PAGE * 2
+            || self.size_less_than(start, page_size)
+        {
+            return None;
+        }
+        Some(self.split_index(start, threshold).0)
+    }
+}
+
+impl TxState {
+    /// node_root returns the top-level node this node is attached to.
+    pub(crate) fn node_root(&self, mut n: NodeId) -> NodeId {
+        while let Some(parent) = self.nodes[n].parent {
+            n = parent;
+        }
+        n
+    }
+
+    /// child_at returns the child node at a given index.
+    pub(crate) fn child_at(&mut self, tx: &Tx, n: NodeId, index: usize) -> Result<NodeId> {
+        let node = &self.nodes[n];
+        let pgid = match node.inodes.get(index) {
+            Some(inode) if !node.is_leaf => inode.pgid,
+            _ => {
+                let reason = format!("invalid child_at({}) on a node", index);
+                return Err(Error::corrupted(node.pgid, reason));
+            }
+        };
+        self.node(tx, node.bucket, pgid, Some(n))
+    }
+
+    /// child_index returns the index of a given child node.
+    pub(crate) fn child_index(&self, n: NodeId, child: NodeId) -> usize {
+        let key = self.nodes[child].key.unwrap_or_default();
+        self.nodes[n].search(key.get()).0
+    }
+
+    /// next_sibling returns the next node with the same parent.
+    fn next_sibling(&mut self, tx: &Tx, n: NodeId) -> Result<Option<NodeId>> {
+        let parent = match self.nodes[n].parent {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let index = self.child_index(parent, n);
+        if index >= self.nodes[parent].inodes.len() - 1 {
+            return Ok(None);
+        }
+        self.child_at(tx, parent, index + 1).map(Some)
+    }
+
+    /// prev_sibling returns the previous node with the same parent.
+    fn prev_sibling(&mut self, tx: &Tx, n: NodeId) -> Result<Option<NodeId>> {
+        let parent = match self.nodes[n].parent {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let index = self.child_index(parent, n);
+        if index == 0 {
+            return Ok(None);
+        }
+        self.child_at(tx, parent, index - 1).map(Some)
+    }
+
+    /// remove_child removes a node from the list of in-memory children.
+    /// This does not affect the inodes.
+    fn remove_child(&mut self, n: NodeId, target: NodeId) {
+        self.nodes[n].children.retain(|&child| child != target);
+    }
+
+    /// split breaks up a node into multiple smaller nodes, if appropriate.
+    /// This should only be called from the spill() function.
+    ///
+    /// All the split points are found first, so that each inode is moved
+    /// once however many pages the node spans: splitting in two over and
+    /// over moves the whole tail of a large node at every step.
+    fn split(&mut self, tx: &Tx, n: NodeId, page_size: usize) -> Vec<NodeId> {
+        // Determine the threshold before starting a new node.
+        let node = &self.nodes[n];
+        let fill_percent = self.buckets[node.bucket]
+            .fill_percent
+            .clamp(MIN_FILL_PERCENT, MAX_FILL_PERCENT);
+        let threshold = (page_size as f64 * fill_percent) as usize;
+
+        // Determine split positions and sizes of the pages.
+        let mut indexes = Vec::new();
+        let mut start = 0;
+        while let Some(index) = node.split_point(start, page_size, threshold) {
+            indexes.push(index);
+            start = index;
+        }
+        if indexes.is_empty() {
+            return vec![n];
+        }
+        let (bucket, is_leaf) = (node.bucket, node.is_leaf);
+
+        // Split node into separate nodes.
+        // If there's no parent then we'll need to create one.
+        let parent = match self.nodes[n].parent {
+            Some(parent) => parent,
+            None => {
+                let mut parent = Node::new(bucket, false, None);
+                parent.children.push(n);
+                self.nodes.push(parent);
+                let parent = self.nodes.len() - 1;
+                self.nodes[n].parent = Some(parent);
+                parent
+            }
+        };
+
+        // Create the new nodes and add them to the parent.
+        let mut nodes = vec![n];
+        for _ in &indexes {
+            self.nodes.push(Node::new(bucket, is_leaf, Some(parent)));
+            let next = self.nodes.len() - 1;
+            self.nodes[parent].children.push(next);
+            nodes.push(next);
+        }
+
+        // Split inodes across the nodes, from the last one. split_off leaves
+        // the full capacity behind, which adds up over a large node.
+        for (i, &index) in indexes.iter().enumerate().rev() {
+            self.nodes[nodes[i + 1]].inodes = self.nodes[n].inodes.split_off(index);
+        }
+        self.nodes[n].inodes.shrink_to_fit();
+
+        // Update the statistics.
+        tx.stats.inc_split(indexes.len() as i64);
+
+        nodes
+    }
+
+    /// spill writes the nodes to dirty pages and splits nodes as it goes.
+    /// Returns an error if dirty pages cannot be allocated.
+    pub(crate) fn spill_node(&mut self, tx: &Tx, n: NodeId) -> Result<()> {
+        if self.nodes[n].spilled {
+            return Ok(());
+        }
+
+        // Spill child nodes first. Child nodes can materialize sibling nodes in
+        // the case of split-merge so we cannot use a range loop. We have to check
+        // the children size on every loop iteration.
+        let mut children = std::mem::take(&mut self.nodes[n].children);
+        children.sort_by(|&a, &b| {
+            let key = |n: NodeId| self.nodes[n].inodes.first().map(|i| i.key.get());
+            key(a).cmp(&key(b))
+        });
+        self.nodes[n].children = children;
+        let mut i = 0;
+        while i < self.nodes[n].children.len() {
+            let child = self.nodes[n].children[i];
+            self.spill_node(tx, child)?;
+            i += 1;
+        }
+
+        // We no longer need the child list because it's only used for spill tracking.
+        self.nodes[n].children.clear();
+
+        // Split nodes into appropriate sizes. The first node will always be n.
+        let page_size = tx.db.page_size;
+        for node in self.split(tx, n, page_size) {
+            // Add node's page to the freelist if it's not new.
+            if self.nodes[node].pgid > 0 {
+                tx.free_page(self.nodes[node].pgid)?;
+                self.nodes[node].pgid = 0;
+            }
+
+            // Allocate contiguous space for the node.
+            let count = self.nodes[node].size().div_ceil(page_size);
+            let pgid = tx.allocate(count)?;
+
+            // Write the node.
+            let high_water = tx.meta.get().pgid;
+            if pgid >= high_water {
+                let reason = format!("above high water mark ({})", high_water);
+                return Err(Error::corrupted(pgid, reason));
+            }
+            self.nodes[node].pgid = pgid;
+            tx.with_dirty_page(pgid, |p| self.nodes[node].write(p))??;
+            self.nodes[node].spilled = true;
+
+            // Insert into parent inodes.
+            if let Some(parent) = self.nodes[node].parent {
+                let first = self.nodes[node].inodes[0].key;
+                let key = self.nodes[node].key.unwrap_or(first);
+                self.nodes[parent].put(key.get(), first, Bytes::default(), pgid, 0)?;
+                self.nodes[node].key = Some(first);
+            }
+
+            // Update the statistics.
+            tx.stats.inc_spill(1);
+        }
+
+        // If the root node split and created a new root then we need to spill that
+        // as well. We'll clear out the children to make sure it doesn't try to respill.
+        if let Some(parent) = self.nodes[n].parent {
+            if self.nodes[parent].pgid == 0 {
+                self.nodes[n].children.clear();
+                return self.spill_node(tx, parent);
+            }
+        }
+        Ok(())
+    }
+
+    /// rebalance attempts to combine the node with sibling nodes if the node fill
+    /// size is below a threshold or if there are not enough keys.
+    pub(crate) fn rebalance_node(&mut self, tx: &Tx, n: NodeId) -> Result<()> {
+        if !self.nodes[n].unbalanced {
+            return Ok(());
+        }
+        self.nodes[n].unbalanced = false;
+
+        // Update statistics.
+        tx.stats.inc_rebalance(1);
+
+        // Ignore if node is above threshold (25%) and has enough keys.
+        let threshold = tx.db.page_size / 4;
+        let node = &self.nodes[n];
+        if node.size() > threshold && node.inodes.len() > node.min_keys() {
+            return Ok(());
+        }
+        let bucket = node.bucket;
+
+        // Root node has special handling.
+        let parent = match node.parent {
+            Some(parent) => parent,
+            None => {
+                // If root node is a branch and only has one node then collapse it.
+                if !node.is_leaf && node.inodes.len() == 1 {
+                    // Move root's child up.
+                    let child = self.node(tx, bucket, node.inodes[0].pgid, Some(n))?;
+                    self.nodes[n].is_leaf = self.nodes[child].is_leaf;
+                    self.nodes[n].inodes = std::mem::take(&mut self.nodes[child].inodes);
+                    self.nodes[n].children = std::mem::take(&mut self.nodes[child].children);
+
+                    // Reparent all child nodes being moved.
+                    for i in 0..self.nodes[n].inodes.len() {
+                        let pgid = self.nodes[n].inodes[i].pgid;
+                        if let Some(&c) = self.buckets[bucket].nodes.get(&pgid) {
+                            self.nodes[c].parent = Some(n);
+                        }
+                    }
+
+                    // Remove old child.
+                    self.nodes[child].parent = None;
+                    let pgid = self.nodes[child].pgid;
+                    self.buckets[bucket].nodes.remove(&pgid);
+                    self.free_node(tx, child)?;
+                }
+                return Ok(());
+            }
+        };
+
+        // If node has no keys then just remove it.
+        if self.nodes[n].inodes.is_empty() {
+            let key = self.nodes[n].key.unwrap_or_default();
+            self.nodes[parent].del(key.get());
+            self.remove_child(parent, n);
+            let pgid = self.nodes[n].pgid;
+            self.buckets[bucket].nodes.remove(&pgid);
+            self.free_node(tx, n)?;
+            return self.rebalance_node(tx, parent);
+        }
+
+        if self.nodes[parent].inodes.len() <= 1 {
+            let reason = "parent must have at least 2 children";
+            return Err(Error::corrupted(self.nodes[parent].pgid, reason));
+        }
+
+        // Destination node is right sibling if idx == 0, otherwise left sibling.
+        let use_next_sibling = self.child_index(parent, n) == 0;
+        let sibling = if use_next_sibling {
+            self.next_sibling(tx, n)?
+        } else {
+            self.prev_sibling(tx, n)?
+        };
+        let target = match sibling {
+            Some(target) => target,
+            None => return Err(Error::corrupted(self.nodes[n].pgid, "node has no sibling")),
+        };
+
+        // If both this node and the target node are too small then merge them.
+        let (from, into) = if use_next_sibling {
+            (target, n)
+        } else {
+            (n, target)
+        };
+
+        // Reparent all child nodes being moved.
+        for i in 0..self.nodes[from].inodes.len() {
+            let pgid = self.nodes[from].inodes[i].pgid;
+            if let Some(&child) = self.buckets[bucket].nodes.get(&pgid) {
+                if let Some(old) = self.nodes[child].parent {
+                    self.remove_child(old, child);
+                }
+                self.nodes[child].parent = Some(into);
+                self.nodes[into].children.push(child);
+            }
+        }
+
+        // Copy over inodes and remove the emptied node.
+        let moved = std::mem::take(&mut self.nodes[from].inodes);
+        self.nodes[into].inodes.extend(moved);
+        let key = self.nodes[from].key.unwrap_or_default();
+        self.nodes[parent].del(key.get());
+        self.remove_child(parent, from);
+        let pgid = self.nodes[from].pgid;
+        self.buckets[bucket].nodes.remove(&pgid);
+        self.free_node(tx, from)?;
+
+        // Either this node or the target node was deleted from the parent so rebalance it.
+        self.rebalance_node(tx, parent)
+    }
+
+    /// free_node adds the node's underlying page to the freelist.
+    pub(crate) fn free_node(&mut self, tx: &Tx, n: NodeId) -> Result<()> {
+        if self.nodes[n].pgid != 0 {
+            tx.free_page(self.nodes[n].pgid)?;
+            self.nodes[n].pgid = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use super::*;
+    use crate::bucket::ROOT_BUCKET;
+    use crate::db::{Options, DB};
+    use crate::page::BUCKET_LEAF_FLAG;
+
+    fn bytes(data: &[u8]) -> Bytes {
+        // Safety: every test keeps its data alive for longer than its nodes.
+        unsafe { Bytes::new(data) }
+    }
+
+    fn keys(n: &Node) -> Vec<&[u8]> {
+        n.inodes.iter().map(|inode| inode.key.get()).collect()
+    }
+
+    /// round_trip writes `n` to a zeroed buffer sized for it and reads it back.
+    fn round_trip(n: &Node, buf: &mut Vec<u8>) -> Node {
+        buf.clear();
+        buf.resize(n.size(), 0);
+        let mut p = PageMut::new(buf);
+        p.set_id(7);
+        n.write(&mut p).unwrap();
+
+        let mut n2 = Node::new(0, false, None);
+        unsafe { n2.read(Page::new(buf)).unwrap() };
+        n2
+    }
+
+    #[test]
+    fn put() {
+        let (baz, foo, bar, v) = (b"baz", b"foo", b"bar", [b"0", b"1", b"2", b"3"]);
+        let mut n = Node::new(0, true, None);
+        n.put(baz, bytes(baz), bytes(v[0]), 0, 0).unwrap();
+        n.put(foo, bytes(foo), bytes(v[1]), 0, 0).unwrap();
+        n.put(bar, bytes(bar), bytes(v[2]), 0, 0).unwrap();
+        n.put(foo, bytes(foo), bytes(v[3]), 0, BUCKET_LEAF_FLAG)
+            .unwrap();
+
+        assert_eq!(keys(&n), vec![&b"bar"[..], b"baz", b"foo"]);
+        assert_eq!(n.inodes[0].value.get(), b"2");
+        assert_eq!(n.inodes[1].value.get(), b"0");
+        assert_eq!(n.inodes[2].value.get(), b"3");
+        assert_eq!(n.inodes[2].flags, BUCKET_LEAF_FLAG);
+
+        // Empty keys are refused rather than written.
+        assert!(matches!(
+            n.put(b"", bytes(b"a"), bytes(v[0]), 0, 0),
+            Err(Error::KeyRequired)
+        ));
+        assert!(matches!(
+            n.put(baz, Bytes::default(), bytes(v[0]), 0, 0),
+            Err(Error::KeyRequired)
+        ));
+        assert_eq!(n.inodes.len(), 3);
+    }
+
+    #[test]
+    fn put_renames() {
+        let mut n = Node::new(0, false, None);
+        n.put(b"a", bytes(b"a"), Bytes::default(), 1, 0).unwrap();
+        n.put(b"m", bytes(b"m"), Bytes::default(), 2, 0).unwrap();
+
+        // Branches replace the key of a child whose first key changed.
+        n.put(b"m", bytes(b"k"), Bytes::default(), 3, 0).unwrap();
+        assert_eq!(keys(&n), vec![&b"a"[..], b"k"]);
+        assert_eq!(n.inodes[1].pgid, 3);
+    }
+
+    #[test]
+    fn write_circular_dependency() {
+        // A branch must not point at the page it is written to.
+        let mut n = Node::new(0, false, None);
+        n.put(b"a", bytes(b"a"), Bytes::default(), 7, 0).unwrap();
+        let mut buf = vec![0; n.size()];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(7);
+        assert!(matches!(
+            n.write(&mut p),
+            Err(Error::Corrupted { pgid: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn del() {
+        let mut n = Node::new(0, true, None);
+        for k in [b"a", b"b", b"c"] {
+            n.put(k, bytes(k), bytes(k), 0, 0).unwrap();
+        }
+
+        n.del(b"x");
+        assert!(!n.unbalanced);
+        assert_eq!(n.inodes.len(), 3);
+
+        n.del(b"b");
+        assert!(n.unbalanced);
+        assert_eq!(keys(&n), vec![&b"a"[..], b"c"]);
+    }
+
+    #[test]
+    fn read_leaf_page() {
+        // Lay out a leaf page by hand: two elements followed by their data.
+        let mut buf = vec![0u8; 4096];
+        buf[8..10].copy_from_slice(&LEAF_PAGE_FLAG.to_le_bytes());
+        buf[10..12].copy_from_slice(&2u16.to_le_bytes());
+        let data = &mut buf[PAGE_HEADER_SIZE..];
+        for (elem, (pos, ksize, vsize)) in [(32u32, 3u32, 4u32), (23, 10, 3)].iter().enumerate() {
+            let off = elem * LEAF_PAGE_ELEMENT_SIZE;
+            write_u32(data, off + 4, *pos);
+            write_u32(data, off + 8, *ksize);
+            write_u32(data, off + 12, *vsize);
+        }
+        data[32..32 + 20].copy_from_slice(b"barfoozhelloworldbye");
+
+        let mut n = Node::new(0, false, None);
+        unsafe { n.read(Page::new(&buf)).unwrap() };
+
+        assert!(n.is_leaf);
+        assert_eq!(keys(&n), vec![&b"bar"[..], b"helloworld"]);
+        assert_eq!(n.inodes[0].value.get(), b"fooz");
+        assert_eq!(n.inodes[1].value.get(), b"bye");
+        assert_eq!(n.key.unwrap().get(), b"bar");
+    }
+
+    #[test]
+    fn write_leaf_page() {
+        let entries: [(&[u8], &[u8]); 3] = [
+            (b"susy", b"que"),
+            (b"ricki", b"lake"),
+            (b"john", b"johnson"),
+        ];
+        let mut n = Node::new(0, true, None);
+        for (k, v) in &entries {
+            n.put(k, bytes(k), bytes(v), 0, 0).unwrap();
+        }
+
+        let mut buf = Vec::new();
+        let n2 = round_trip(&n, &mut buf);
+        assert!(n2.is_leaf);
+        assert_eq!(n2.pgid, 7);
+        assert_eq!(keys(&n2), vec![&b"john"[..], b"ricki", b"susy"]);
+        let values: Vec<_> = n2.inodes.iter().map(|inode| inode.value.get()).collect();
+        assert_eq!(values, vec![&b"johnson"[..], b"lake", b"que"]);
+        assert_eq!(n2.size(), n.size());
+    }
+
+    #[test]
+    fn write_branch_page() {
+        let mut n = Node::new(0, false, None);
+        for (i, k) in [&b"a"[..], b"bb", b"ccc"].iter().enumerate() {
+            n.put(k, bytes(k), Bytes::default(), 10 + i as Pgid, 0)
+                .unwrap();
+        }
+
+        let mut buf = Vec::new();
+        let n2 = round_trip(&n, &mut buf);
+        assert!(!n2.is_leaf);
+        assert_eq!(keys(&n2), vec![&b"a"[..], b"bb", b"ccc"]);
+        let pgids: Vec<_> = n2.inodes.iter().map(|inode| inode.pgid).collect();
+        assert_eq!(pgids, vec![10, 11, 12]);
+        assert_eq!(
+            n.size(),
+            PAGE_HEADER_SIZE + 3 * BRANCH_PAGE_ELEMENT_SIZE + 6
+        );
+    }
+
+    #[test]
+    fn write_empty_and_bucket_elements() {
+        let mut buf = Vec::new();
+        let n2 = round_trip(&Node::new(0, true, None), &mut buf);
+        assert!(n2.is_leaf);
+        assert!(n2.inodes.is_empty());
+        assert!(n2.key.is_none());
+
+        // Empty values and bucket flags survive the trip.
+        let mut n = Node::new(0, true, None);
+        n.put(b"k", bytes(b"k"), Bytes::default(), 0, 0).unwrap();
+        n.put(b"sub", bytes(b"sub"), bytes(&[0; 16]), 0, BUCKET_LEAF_FLAG)
+            .unwrap();
+        let n2 = round_trip(&n, &mut buf);
+        assert_eq!(n2.inodes[0].value.len(), 0);
+        assert_eq!(n2.inodes[1].flags, BUCKET_LEAF_FLAG);
+        assert_eq!(n2.inodes[1].value.get(), &[0; 16][..]);
+    }
+
+    #[test]
+    fn write_overflow() {
+        // Keys and values near the page size need more than one page.
+        let page_size = 4096;
+        let big_key = vec![b'k'; page_size - PAGE_HEADER_SIZE - LEAF_PAGE_ELEMENT_SIZE];
+        let big_value = vec![b'v'; 3 * page_size];
+        let mut n = Node::new(0, true, None);
+        n.put(b"a", bytes(b"a"), bytes(b"1"), 0, 0).unwrap();
+        n.put(&big_key, bytes(&big_key), bytes(&big_value), 0, 0)
+            .unwrap();
+        assert!(!n.size_less_than(0, page_size));
+        assert!(n.size() > 4 * page_size);
+
+        let mut buf = Vec::new();
+        let n2 = round_trip(&n, &mut buf);
+        assert_eq!(keys(&n2), vec![&b"a"[..], &big_key[..]]);
+        assert_eq!(n2.inodes[1].value.get(), &big_value[..]);
+    }
+
+    #[test]
+    fn size() {
+        let mut n = Node::new(0, true, None);
+        assert_eq!(n.size(), PAGE_HEADER_SIZE);
+        n.put(b"key", bytes(b"key"), bytes(b"value"), 0, 0).unwrap();
+        let want = PAGE_HEADER_SIZE + LEAF_PAGE_ELEMENT_SIZE + 8;
+        assert_eq!(n.size(), want);
+        assert!(n.size_less_than(0, want + 1));
+        assert!(!n.size_less_than(0, want));
+    }
+
+    #[test]
+    fn search() {
+        let mut n = Node::new(0, true, None);
+        for k in [b"b", b"d"] {
+            n.put(k, bytes(k), Bytes::default(), 0, 0).unwrap();
+        }
+        assert_eq!(n.search(b"a"), (0, false));
+        assert_eq!(n.search(b"b"), (0, true));
+        assert_eq!(n.search(b"c"), (1, false));
+        assert_eq!(n.search(b"d"), (1, true));
+        assert_eq!(n.search(b"e"), (2, false));
+    }
+
+    /// split_leaf splits a leaf holding `n` 8-byte keys with 16-byte values.
+    fn split_leaf(n: usize, page_size: usize) -> Vec<usize> {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let tx = db.begin(true).unwrap();
+        let keys: Vec<Vec<u8>> = (1..=n).map(|i| format!("{:08}", i).into_bytes()).collect();
+        let value = b"0123456701234567";
+
+        let mut node = Node::new(ROOT_BUCKET, true, None);
+        for k in &keys {
+            node.put(k, bytes(k), bytes(value), 0, 0).unwrap();
+        }
+        let mut state = tx.state.borrow_mut();
+        state.nodes.push(node);
+        let id = state.nodes.len() - 1;
+        let nodes = state.split(&tx, id, page_size);
+        assert_eq!(nodes[0], id);
+        let counts = nodes.iter().map(|&n| state.nodes[n].inodes.len()).collect();
+        if nodes.len() > 1 {
+            let parent = state.nodes[id].parent.unwrap();
+            assert_eq!(state.nodes[parent].children.len(), nodes.len());
+            assert!(nodes.iter().all(|&n| state.nodes[n].parent == Some(parent)));
+            assert_eq!(tx.stats().split(), nodes.len() as i64 - 1);
+        }
+        counts
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn split() {
+        // Split between 2 & 3.
+        assert_eq!(split_leaf(5, 100), vec![2, 3]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn split_min_keys() {
+        // Nodes with less than the minimum keys for two pages are not split.
+        assert_eq!(split_leaf(2, 20), vec![2]);
+        assert_eq!(split_leaf(4, 20), vec![4]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn split_single_page() {
+        // Nodes that fit in a single page are not split.
+        assert_eq!(split_leaf(5, 4096), vec![5]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn split_many() {
+        let counts = split_leaf(1000, 4096);
+        assert!(counts.len() > 1);
+        assert_eq!(counts.iter().sum::<usize>(), 1000);
+        assert!(counts.iter().all(|&n| n >= MIN_KEYS_PER_PAGE));
+    }
+}
@@ -0,0 +1,651 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::backend::Mmap;
+
+/// SyncMode is the system call used to flush the data file to disk when a
+/// transaction commits, when the file grows and on `DB::sync`.
+///
+/// The default is the cheapest call that is durable on the platform: on
+/// macOS and iOS `fsync` only hands the data to the drive, which may keep it
+/// in its volatile cache, so `FullFsyncDarwin` is used there. Elsewhere it is
+/// `DataSync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// DataSync flushes the data and only the metadata needed to read it
+    /// back, with fdatasync(). Platforms without fdatasync() use fsync().
+    #[cfg_attr(not(target_vendor = "apple"), default)]
+    DataSync,
+    /// FullSync flushes the data and all of the file metadata with fsync().
+    FullSync,
+    /// FullFsyncDarwin asks the drive to flush its cache too, with
+    /// fcntl(F_FULLFSYNC). It falls back to fsync() on file systems that
+    /// don't support it and on platforms other than macOS and iOS.
+    #[cfg_attr(target_vendor = "apple", default)]
+    FullFsyncDarwin,
+}
+
+/// DbOps holds the file operations the database performs on its data file,
+/// so that tests can observe or fail them.
+pub(crate) trait DbOps: Send + Sync {
+    /// WriteAt writes all of `buf` at `offset`.
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// ReadAt fills `buf` with the bytes at `offset`.
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Sync flushes the file to disk the way `mode` asks for.
+    fn sync(&self, file: &File, mode: SyncMode) -> io::Result<()>;
+
+    /// Truncate sets the size of the file.
+    fn truncate(&self, file: &File, size: u64) -> io::Result<()>;
+
+    /// Allocate reserves disk space for the `len` bytes at `offset` without
+    /// changing the size of the file. It succeeds without doing anything
+    /// where that is not supported.
+    fn allocate(&self, file: &File, offset: u64, len: u64) -> io::Result<()>;
+
+    /// Madvise passes `advice` about the mapping of the file to the kernel.
+    fn madvise(&self, mmap: &Mmap, advice: i32) -> io::Result<()>;
+}
+
+/// FileOps performs the operations on the file itself.
+pub(crate) struct FileOps;
+
+impl DbOps for FileOps {
+    fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+        file.write_all_at(buf, offset)
+    }
+
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        file.read_exact_at(buf, offset)
+    }
+
+    fn sync(&self, file: &File, mode: SyncMode) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+        match mode {
+            SyncMode::DataSync => retry(|| fdatasync(fd)),
+            SyncMode::FullSync => retry(|| unsafe { libc::fsync(fd) }),
+            SyncMode::FullFsyncDarwin => full_fsync(fd),
+        }
+    }
+
+    fn truncate(&self, file: &File, size: u64) -> io::Result<()> {
+        file.set_len(size)
+    }
+
+    fn allocate(&self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        fallocate(file.as_raw_fd(), offset, len)
+    }
+
+    fn madvise(&self, mmap: &Mmap, advice: i32) -> io::Result<()> {
+        mmap.advise(advice)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fallocate(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    use std::convert::TryFrom;
+
+    let (offset, len) = match (libc::off_t::try_from(offset), libc::off_t::try_from(len)) {
+        (Ok(offset), Ok(len)) => (offset, len),
+        _ => return Err(io::Error::from_raw_os_error(libc::EFBIG)),
+    };
+    match retry(|| unsafe { libc::fallocate(fd, libc::FALLOC_FL_KEEP_SIZE, offset, len) }) {
+        // The file system can't preallocate; it will allocate on write.
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS)
+            ) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn fallocate(_: RawFd, _: u64, _: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// retry calls `f` until it is not interrupted, turning a negative result
+/// into the last OS error.
+fn retry(mut f: impl FnMut() -> libc::c_int) -> io::Result<()> {
+    loop {
+        if f() >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fdatasync(fd: RawFd) -> libc::c_int {
+    unsafe { libc::fdatasync(fd) }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn fdatasync(fd: RawFd) -> libc::c_int {
+    unsafe { libc::fsync(fd) }
+}
+
+#[cfg(target_vendor = "apple")]
+fn full_fsync(fd: RawFd) -> io::Result<()> {
+    // Some file systems, such as network mounts, reject F_FULLFSYNC.
+    retry(|| unsafe { libc::fcntl(fd, libc::F_FULLFSYNC) })
+        .or_else(|_| retry(|| unsafe { libc::fsync(fd) }))
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn full_fsync(fd: RawFd) -> io::Result<()> {
+    retry(|| unsafe { libc::fsync(fd) })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::db::{lock, Options, DB};
+    use crate::logger::tests::Recorder;
+    use crate::unix::MadviseMode;
+
+    /// Op is an operation recorded by `FailpointOps`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Op {
+        WriteAt { offset: u64, len: usize },
+        ReadAt { offset: u64, len: usize },
+        Sync(SyncMode),
+        Truncate(u64),
+        Allocate { offset: u64, len: u64 },
+        Madvise(i32),
+    }
+
+    /// Failure is how `FailpointOps` fails an operation.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Failure {
+        /// Return an error without touching the file.
+        Error,
+        /// Write only the first bytes of the buffer, then return an error.
+        /// Other operations fail as with `Error`.
+        Torn(usize),
+    }
+
+    /// FailpointOps performs the operations on the file and records them. It
+    /// can be armed to fail the Nth operation from now on, and optionally
+    /// every operation after it, which simulates a crash at that point.
+    #[derive(Default)]
+    pub(crate) struct FailpointOps {
+        ops: Mutex<Vec<Op>>,
+        /// Operations left before the failpoint; usize::MAX when disarmed.
+        left: AtomicUsize,
+        failure: Mutex<Option<Failure>>,
+        /// Whether the operations after the failpoint fail too.
+        crash: AtomicBool,
+        crashed: AtomicBool,
+    }
+
+    impl FailpointOps {
+        pub(crate) fn new() -> Arc<FailpointOps> {
+            let ops = FailpointOps::default();
+            ops.left.store(usize::MAX, Ordering::SeqCst);
+            Arc::new(ops)
+        }
+
+        /// fail_nth fails the `n`th operation from now, counting from 0, with
+        /// `failure`. With `crash` set, every later operation fails as well.
+        pub(crate) fn fail_nth(&self, n: usize, failure: Failure, crash: bool) {
+            *lock(&self.failure) = Some(failure);
+            self.crash.store(crash, Ordering::SeqCst);
+            self.crashed.store(false, Ordering::SeqCst);
+            self.left.store(n, Ordering::SeqCst);
+        }
+
+        /// disarm lets every operation through again.
+        pub(crate) fn disarm(&self) {
+            self.left.store(usize::MAX, Ordering::SeqCst);
+            self.crashed.store(false, Ordering::SeqCst);
+        }
+
+        /// take returns the operations recorded so far and clears them.
+        pub(crate) fn take(&self) -> Vec<Op> {
+            std::mem::take(&mut *lock(&self.ops))
+        }
+
+        /// fire records `op` and returns how it must fail, if it must.
+        fn fire(&self, op: Op) -> Option<Failure> {
+            lock(&self.ops).push(op);
+            if self.crashed.load(Ordering::SeqCst) {
+                return Some(Failure::Error);
+            }
+            let left = self.left.load(Ordering::SeqCst);
+            if left == usize::MAX {
+                return None;
+            } else if left > 0 {
+                self.left.store(left - 1, Ordering::SeqCst);
+                return None;
+            }
+            self.left.store(usize::MAX, Ordering::SeqCst);
+            self.crashed
+                .store(self.crash.load(Ordering::SeqCst), Ordering::SeqCst);
+            *lock(&self.failure)
+        }
+    }
+
+    fn injected() -> io::Error {
+        io::Error::other("injected failure")
+    }
+
+    impl DbOps for FailpointOps {
+        fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+            let op = Op::WriteAt {
+                offset,
+                len: buf.len(),
+            };
+            match self.fire(op) {
+                None => FileOps.write_at(file, buf, offset),
+                Some(Failure::Error) => Err(injected()),
+                Some(Failure::Torn(n)) => {
+                    FileOps.write_at(file, &buf[..n.min(buf.len())], offset)?;
+                    Err(injected())
+                }
+            }
+        }
+
+        fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let op = Op::ReadAt {
+                offset,
+                len: buf.len(),
+            };
+            match self.fire(op) {
+                None => FileOps.read_at(file, buf, offset),
+                Some(_) => Err(injected()),
+            }
+        }
+
+        fn sync(&self, file: &File, mode: SyncMode) -> io::Result<()> {
+            match self.fire(Op::Sync(mode)) {
+                None => FileOps.sync(file, mode),
+                Some(_) => Err(injected()),
+            }
+        }
+
+        fn truncate(&self, file: &File, size: u64) -> io::Result<()> {
+            match self.fire(Op::Truncate(size)) {
+                None => FileOps.truncate(file, size),
+                Some(_) => Err(injected()),
+            }
+        }
+
+        fn allocate(&self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+            match self.fire(Op::Allocate { offset, len }) {
+                None => FileOps.allocate(file, offset, len),
+                Some(_) => Err(injected()),
+            }
+        }
+
+        fn madvise(&self, mmap: &Mmap, advice: i32) -> io::Result<()> {
+            match self.fire(Op::Madvise(advice)) {
+                None => FileOps.madvise(mmap, advice),
+                Some(_) => Err(injected()),
+            }
+        }
+    }
+
+    type State = BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Option<Vec<u8>>>>;
+
+    /// state reads every bucket of the database, checking it on the way.
+    fn state(db: &DB) -> State {
+        db.view_ret(|tx| {
+            let errors = tx.check();
+            assert!(errors.is_empty(), "{:?}", errors);
+            let mut state = State::new();
+            for name in tx.bucket_names()? {
+                let b = tx.bucket(&name)?;
+                let mut kvs = BTreeMap::new();
+                b.for_each(|k, v| {
+                    kvs.insert(k.to_vec(), v.map(<[u8]>::to_vec));
+                    Ok(())
+                })?;
+                state.insert(name, kvs);
+            }
+            Ok(state)
+        })
+        .unwrap()
+    }
+
+    /// setup commits the old state of the crash tests.
+    fn setup(db: &DB) {
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..100u32 {
+                b.put(&i.to_be_bytes(), &[1; 100])?;
+            }
+            tx.create_bucket(b"gadgets")?.put(b"foo", b"bar")
+        })
+        .unwrap();
+    }
+
+    /// change commits the new state: it rewrites, deletes and adds keys,
+    /// adds and deletes buckets, and grows the file.
+    fn change(db: &DB) -> crate::errors::Result<()> {
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            for i in 0..100u32 {
+                if i % 3 == 0 {
+                    b.delete(&i.to_be_bytes())?;
+                } else {
+                    b.put(&i.to_be_bytes(), &[2; 100])?;
+                }
+            }
+            for i in 100..300u32 {
+                b.put(&i.to_be_bytes(), &[3; 500])?;
+            }
+            tx.delete_bucket(b"gadgets")?;
+            tx.create_bucket(b"parts")?.put(b"bar", b"baz")
+        })
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn records_and_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let ops = FailpointOps::new();
+        let db =
+            DB::open_with_ops(&dir.path().join("db"), Options::default(), ops.clone()).unwrap();
+        // A new file is initialized and synced, then its page size read back.
+        let opened = ops.take();
+        assert!(matches!(opened[0], Op::WriteAt { offset: 0, .. }));
+        assert_eq!(opened[1], Op::Sync(SyncMode::default()));
+
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+        let committed = ops.take();
+        assert!(matches!(
+            committed.last(),
+            Some(Op::Sync(_)) | Some(Op::WriteAt { .. })
+        ));
+
+        ops.fail_nth(0, Failure::Error, false);
+        assert!(db
+            .update(|tx| tx.create_bucket(b"gadgets").map(drop))
+            .is_err());
+        // The failpoint only fires once.
+        db.update(|tx| tx.create_bucket(b"gadgets").map(drop))
+            .unwrap();
+        ops.fail_nth(0, Failure::Error, false);
+        assert!(db.sync().is_err());
+        db.sync().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sync_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let syncs = |ops: &FailpointOps| -> Vec<Op> {
+            ops.take()
+                .into_iter()
+                .filter(|op| matches!(op, Op::Sync(_)))
+                .collect()
+        };
+        for (i, &mode) in [
+            SyncMode::DataSync,
+            SyncMode::FullSync,
+            SyncMode::FullFsyncDarwin,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let ops = FailpointOps::new();
+            let options = Options {
+                sync_mode: mode,
+                ..Options::default()
+            };
+            let path = dir.path().join(i.to_string());
+            let db = DB::open_with_ops(&path, options.clone(), ops.clone()).unwrap();
+            assert_eq!(syncs(&ops), vec![Op::Sync(mode)]);
+
+            // A commit syncs the grown file, its pages and its meta page.
+            db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+                .unwrap();
+            let committed = syncs(&ops);
+            assert!(committed.len() >= 2);
+            assert!(committed.iter().all(|&op| op == Op::Sync(mode)));
+            db.sync().unwrap();
+            assert_eq!(syncs(&ops), vec![Op::Sync(mode)]);
+            db.close().unwrap();
+            drop(db);
+
+            // no_sync skips the commit syncs, but DB::sync still syncs.
+            let db = DB::open_with_ops(
+                &path,
+                Options {
+                    no_sync: true,
+                    ..options
+                },
+                ops.clone(),
+            )
+            .unwrap();
+            db.update(|tx| tx.create_bucket(b"gadgets").map(drop))
+                .unwrap();
+            assert_eq!(syncs(&ops), vec![]);
+            db.sync().unwrap();
+            assert_eq!(syncs(&ops), vec![Op::Sync(mode)]);
+        }
+
+        #[cfg(target_vendor = "apple")]
+        assert_eq!(SyncMode::default(), SyncMode::FullFsyncDarwin);
+        #[cfg(not(target_vendor = "apple"))]
+        assert_eq!(SyncMode::default(), SyncMode::DataSync);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn prealloc() {
+        let dir = tempfile::tempdir().unwrap();
+        let fill = |db: &DB| {
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for i in 0..1000u32 {
+                    b.put(&i.to_be_bytes(), &[0; 1000])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        };
+
+        let ops = FailpointOps::new();
+        let options = Options {
+            prealloc: true,
+            ..Options::default()
+        };
+        let db = DB::open_with_ops(&dir.path().join("db"), options, ops.clone()).unwrap();
+        let mut size = std::fs::metadata(db.path()).unwrap().len();
+        ops.take();
+        fill(&db);
+
+        // Every growth reserves exactly the new region before extending the
+        // file to cover it.
+        let recorded = ops.take();
+        let mut grows = 0;
+        for (i, op) in recorded.iter().enumerate() {
+            if let Op::Allocate { offset, len } = *op {
+                assert_eq!(offset, size);
+                assert!(len > 0);
+                size += len;
+                assert_eq!(recorded[i + 1], Op::Truncate(size));
+                grows += 1;
+            }
+        }
+        assert!(grows > 0);
+        assert_eq!(std::fs::metadata(db.path()).unwrap().len(), size);
+        db.close().unwrap();
+
+        // Without the option nothing is reserved.
+        let ops = FailpointOps::new();
+        let db =
+            DB::open_with_ops(&dir.path().join("plain"), Options::default(), ops.clone()).unwrap();
+        fill(&db);
+        let recorded = ops.take();
+        assert!(recorded.iter().any(|op| matches!(op, Op::Truncate(_))));
+        assert!(!recorded.iter().any(|op| matches!(op, Op::Allocate { .. })));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn madvise() {
+        let dir = tempfile::tempdir().unwrap();
+        let advised = |ops: &FailpointOps| -> Vec<i32> {
+            ops.take()
+                .into_iter()
+                .filter_map(|op| match op {
+                    Op::Madvise(advice) => Some(advice),
+                    _ => None,
+                })
+                .collect()
+        };
+        for (i, &(mode, advice)) in [
+            (MadviseMode::Normal, libc::MADV_NORMAL),
+            (MadviseMode::Random, libc::MADV_RANDOM),
+            (MadviseMode::WillNeed, libc::MADV_WILLNEED),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let ops = FailpointOps::new();
+            let options = Options {
+                madvise: mode,
+                force_buffer_backend: false,
+                ..Options::default()
+            };
+            let db =
+                DB::open_with_ops(&dir.path().join(i.to_string()), options, ops.clone()).unwrap();
+            assert_eq!(advised(&ops), vec![advice]);
+
+            // Growing the file remaps it, which advises the new mapping.
+            db.update(|tx| {
+                let b = tx.create_bucket(b"widgets")?;
+                for i in 0..100u32 {
+                    b.put(&i.to_be_bytes(), &[0; 1000])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            let remapped = advised(&ops);
+            assert!(!remapped.is_empty());
+            assert!(remapped.iter().all(|&a| a == advice));
+        }
+        assert_eq!(MadviseMode::default(), MadviseMode::Random);
+
+        // Huge pages are asked for on top of the access pattern, and a
+        // failure of the last advice of the open is only logged.
+        let logger = Arc::new(Recorder::default());
+        let options = Options {
+            huge_pages: true,
+            force_buffer_backend: false,
+            logger: Some(logger.clone()),
+            ..Options::default()
+        };
+        let ops = FailpointOps::new();
+        DB::open_with_ops(&dir.path().join("dry"), options.clone(), ops.clone()).unwrap();
+        let last = ops.take().len() - 1;
+        logger.take();
+        ops.fail_nth(last, Failure::Error, false);
+        let db = DB::open_with_ops(&dir.path().join("huge"), options, ops.clone()).unwrap();
+        let warnings: Vec<String> = logger
+            .take()
+            .into_iter()
+            .filter(|(level, _)| *level == "warn")
+            .map(|(_, msg)| msg)
+            .collect();
+        let ops = advised(&ops);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(ops, vec![libc::MADV_RANDOM, libc::MADV_HUGEPAGE]);
+            assert_eq!(
+                warnings,
+                vec![format!(
+                    "madvise({}) failed: injected failure",
+                    libc::MADV_HUGEPAGE
+                )]
+            );
+        }
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(ops, vec![libc::MADV_RANDOM]);
+        db.update(|tx| tx.create_bucket(b"widgets").map(drop))
+            .unwrap();
+    }
+
+    /// For every operation of a commit, and every way of failing it, a crash
+    /// at that operation leaves either the old or the new state on disk,
+    /// never a mix of both.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn crash_consistency() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        // Count the operations of the commit and record both states.
+        let ops = FailpointOps::new();
+        let db = DB::open_with_ops(&path, Options::default(), ops.clone()).unwrap();
+        setup(&db);
+        let old = state(&db);
+        ops.take();
+        change(&db).unwrap();
+        let n = ops.take().len();
+        let new = state(&db);
+        assert_ne!(old, new);
+        db.close().unwrap();
+        drop(db);
+
+        let failures = [
+            Failure::Error,
+            Failure::Torn(0),
+            Failure::Torn(1),
+            Failure::Torn(512),
+            Failure::Torn(4096),
+        ];
+        let (mut olds, mut news) = (0, 0);
+        for i in 0..n {
+            for &failure in &failures {
+                std::fs::remove_file(&path).unwrap();
+                let ops = FailpointOps::new();
+                let db = DB::open_with_ops(&path, Options::default(), ops.clone()).unwrap();
+                setup(&db);
+
+                ops.fail_nth(i, failure, true);
+                assert!(change(&db).is_err(), "op {} {:?}", i, failure);
+                db.close().unwrap();
+                drop(db);
+
+                let db = DB::open(&path, Options::default()).unwrap();
+                let got = state(&db);
+                if got == old {
+                    olds += 1;
+                } else if got == new {
+                    news += 1;
+                } else {
+                    panic!("op {} {:?}: mixed state after reopen", i, failure);
+                }
+
+                // The reopened database keeps working.
+                db.update(|tx| tx.create_bucket(b"after").map(drop))
+                    .unwrap();
+                state(&db);
+            }
+        }
+        // Failing before the meta page is written in full leaves the old
+        // state behind; failing after it, the new one.
+        assert!(olds > 0);
+        assert!(news > 0);
+    }
+}
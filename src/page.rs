@@ -0,0 +1,457 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use crate::errors::{Error, Result};
+
+/// Pgid is the identifier of a page, i.e. its offset in the file divided by the page size.
+pub(crate) type Pgid = u64;
+
+/// PAGE_HEADER_SIZE is the size of the on-disk page header: id, flags, count and overflow.
+pub(crate) const PAGE_HEADER_SIZE: usize = 16;
+
+/// PGID_SIZE is the on-disk size of a page id.
+pub(crate) const PGID_SIZE: usize = 8;
+
+pub(crate) const BRANCH_PAGE_FLAG: u16 = 0x01;
+pub(crate) const LEAF_PAGE_FLAG: u16 = 0x02;
+pub(crate) const META_PAGE_FLAG: u16 = 0x04;
+pub(crate) const FREELIST_PAGE_FLAG: u16 = 0x10;
+
+/// PGID_NO_FREELIST is the freelist pgid of a meta page written without
+/// persisting the freelist, see `Options::no_freelist_sync`.
+pub(crate) const PGID_NO_FREELIST: Pgid = 0xffff_ffff_ffff_ffff;
+
+/// BUCKET_LEAF_FLAG marks a leaf element whose value is a bucket header.
+pub(crate) const BUCKET_LEAF_FLAG: u32 = 0x01;
+
+/// BRANCH_PAGE_ELEMENT_SIZE is the on-disk size of a branch element: pos, ksize and pgid.
+pub(crate) const BRANCH_PAGE_ELEMENT_SIZE: usize = 16;
+
+/// LEAF_PAGE_ELEMENT_SIZE is the on-disk size of a leaf element: flags, pos, ksize and vsize.
+pub(crate) const LEAF_PAGE_ELEMENT_SIZE: usize = 16;
+
+/// Page is a read-only view over the bytes of a page, header included.
+///
+/// All fields are stored little-endian, matching the layout bbolt writes on
+/// the platforms it supports.
+#[derive(Clone, Copy)]
+pub(crate) struct Page<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Page<'a> {
+    /// new wraps `buf`, which must at least hold a page header.
+    pub(crate) fn new(buf: &'a [u8]) -> Page<'a> {
+        debug_assert!(buf.len() >= PAGE_HEADER_SIZE);
+        Page { buf }
+    }
+
+    pub(crate) fn id(&self) -> Pgid {
+        read_u64(self.buf, 0)
+    }
+
+    pub(crate) fn flags(&self) -> u16 {
+        read_u16(self.buf, 8)
+    }
+
+    pub(crate) fn count(&self) -> u16 {
+        read_u16(self.buf, 10)
+    }
+
+    pub(crate) fn overflow(&self) -> u32 {
+        read_u32(self.buf, 12)
+    }
+
+    /// bytes returns the bytes of the page, header included.
+    pub(crate) fn bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// data returns the bytes following the page header.
+    pub(crate) fn data(&self) -> &'a [u8] {
+        &self.buf[PAGE_HEADER_SIZE..]
+    }
+
+    /// typ returns a human readable page type string used for debugging.
+    pub(crate) fn typ(&self) -> String {
+        let flags = self.flags();
+        if flags & BRANCH_PAGE_FLAG != 0 {
+            "branch".to_string()
+        } else if flags & LEAF_PAGE_FLAG != 0 {
+            "leaf".to_string()
+        } else if flags & META_PAGE_FLAG != 0 {
+            "meta".to_string()
+        } else if flags & FREELIST_PAGE_FLAG != 0 {
+            "freelist".to_string()
+        } else {
+            format!("unknown<{:02x}>", flags)
+        }
+    }
+
+    /// leaf_element retrieves the leaf node element at `index`.
+    ///
+    /// Element offsets come from the file, so they are bounds checked and a
+    /// damaged element is reported as corruption of this page.
+    pub(crate) fn leaf_element(&self, index: usize) -> Result<LeafElement<'a>> {
+        let data = self.data();
+        let off = self.element_offset(index, LEAF_PAGE_ELEMENT_SIZE)?;
+        let pos = off + read_u32(data, off + 4) as usize;
+        let ksize = read_u32(data, off + 8) as usize;
+        let vsize = read_u32(data, off + 12) as usize;
+        let end = pos
+            .checked_add(ksize)
+            .and_then(|n| n.checked_add(vsize))
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| {
+                Error::corrupted(self.id(), format!("leaf element {} out of bounds", index))
+            })?;
+        Ok(LeafElement {
+            flags: read_u32(data, off),
+            key: &data[pos..pos + ksize],
+            value: &data[pos + ksize..end],
+        })
+    }
+
+    /// branch_element retrieves the branch node element at `index`.
+    pub(crate) fn branch_element(&self, index: usize) -> Result<BranchElement<'a>> {
+        let data = self.data();
+        let off = self.element_offset(index, BRANCH_PAGE_ELEMENT_SIZE)?;
+        let pos = off + read_u32(data, off) as usize;
+        let ksize = read_u32(data, off + 4) as usize;
+        let end = pos
+            .checked_add(ksize)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| {
+                Error::corrupted(self.id(), format!("branch element {} out of bounds", index))
+            })?;
+        Ok(BranchElement {
+            pgid: read_u64(data, off + 8),
+            key: &data[pos..end],
+        })
+    }
+
+    /// leaf_page_elements retrieves all the leaf elements of the page.
+    pub(crate) fn leaf_page_elements(&self) -> Result<Vec<LeafElement<'a>>> {
+        (0..self.count() as usize)
+            .map(|i| self.leaf_element(i))
+            .collect()
+    }
+
+    /// branch_page_elements retrieves all the branch elements of the page.
+    pub(crate) fn branch_page_elements(&self) -> Result<Vec<BranchElement<'a>>> {
+        (0..self.count() as usize)
+            .map(|i| self.branch_element(i))
+            .collect()
+    }
+
+    fn element_offset(&self, index: usize, size: usize) -> Result<usize> {
+        if index >= self.count() as usize || (index + 1) * size > self.data().len() {
+            return Err(Error::corrupted(
+                self.id(),
+                format!("element {} out of bounds", index),
+            ));
+        }
+        Ok(index * size)
+    }
+}
+
+/// PageInfo represents human readable information about a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageInfo {
+    pub id: u64,
+    pub typ: String,
+    pub count: usize,
+    pub overflow_count: usize,
+}
+
+/// LeafElement represents a key/value pair stored on a leaf page.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LeafElement<'a> {
+    flags: u32,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> LeafElement<'a> {
+    pub(crate) fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// key returns a byte slice of the node key.
+    pub(crate) fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    /// value returns a byte slice of the node value.
+    pub(crate) fn value(&self) -> &'a [u8] {
+        self.value
+    }
+}
+
+/// BranchElement represents a key and child page stored on a branch page.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BranchElement<'a> {
+    pgid: Pgid,
+    key: &'a [u8],
+}
+
+impl<'a> BranchElement<'a> {
+    pub(crate) fn pgid(&self) -> Pgid {
+        self.pgid
+    }
+
+    /// key returns a byte slice of the node key.
+    pub(crate) fn key(&self) -> &'a [u8] {
+        self.key
+    }
+}
+
+/// PageMut is a writable view over the bytes of a page, header included.
+pub(crate) struct PageMut<'a> {
+    buf: &'a mut [u8],
+}
+
+impl<'a> PageMut<'a> {
+    /// new wraps `buf`, which must at least hold a page header.
+    pub(crate) fn new(buf: &'a mut [u8]) -> PageMut<'a> {
+        debug_assert!(buf.len() >= PAGE_HEADER_SIZE);
+        PageMut { buf }
+    }
+
+    pub(crate) fn as_page(&self) -> Page<'_> {
+        Page::new(self.buf)
+    }
+
+    pub(crate) fn set_id(&mut self, id: Pgid) {
+        write_u64(self.buf, 0, id);
+    }
+
+    pub(crate) fn set_flags(&mut self, flags: u16) {
+        write_u16(self.buf, 8, flags);
+    }
+
+    pub(crate) fn set_count(&mut self, count: u16) {
+        write_u16(self.buf, 10, count);
+    }
+
+    pub(crate) fn set_overflow(&mut self, overflow: u32) {
+        write_u32(self.buf, 12, overflow);
+    }
+
+    /// data_mut returns the bytes following the page header.
+    pub(crate) fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[PAGE_HEADER_SIZE..]
+    }
+}
+
+pub(crate) fn read_u16(buf: &[u8], off: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&buf[off..off + 2]);
+    u16::from_le_bytes(bytes)
+}
+
+pub(crate) fn read_u32(buf: &[u8], off: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[off..off + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub(crate) fn read_u64(buf: &[u8], off: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[off..off + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(crate) fn write_u16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn write_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn write_u64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// page_at returns the page with the given id from a mapped region of the
+/// file, read through `get`, which returns the bytes in a range, or None past
+/// the end of the region.
+///
+/// The returned page spans the page and its overflow pages. The mapping may
+/// extend past the end of the file, so nothing beyond them may be touched.
+pub(crate) fn page_at<'a>(
+    get: impl Fn(Range<usize>) -> Option<&'a [u8]>,
+    page_size: usize,
+    id: Pgid,
+) -> Result<Page<'a>> {
+    let off = usize::try_from(id)
+        .ok()
+        .and_then(|id| id.checked_mul(page_size))
+        .ok_or_else(|| Error::corrupted(id, "page out of bounds"))?;
+    let header = off
+        .checked_add(PAGE_HEADER_SIZE)
+        .and_then(|end| get(off..end))
+        .ok_or_else(|| Error::corrupted(id, "page out of bounds"))?;
+    let overflow = read_u32(header, 12) as usize;
+    let data = (overflow + 1)
+        .checked_mul(page_size)
+        .and_then(|len| off.checked_add(len))
+        .and_then(|end| get(off..end))
+        .ok_or_else(|| Error::corrupted(id, "page overflow out of bounds"))?;
+    Ok(Page::new(data))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use super::*;
+    use crate::node::{Bytes, Node};
+
+    /// A leaf page holding bar=fooz and helloworld=bye, assembled by hand from
+    /// bbolt's page layout: header, element headers, then keys and values back
+    /// to back. It is not a dump of a page written by Go bbolt.
+    #[rustfmt::skip]
+    const LEAF_PAGE: [u8; 68] = [
+        // id, flags, count, overflow
+        0x03, 0, 0, 0, 0, 0, 0, 0, 0x02, 0, 0x02, 0, 0, 0, 0, 0,
+        // flags, pos, ksize, vsize
+        0, 0, 0, 0, 0x20, 0, 0, 0, 0x03, 0, 0, 0, 0x04, 0, 0, 0,
+        0, 0, 0, 0, 0x17, 0, 0, 0, 0x0a, 0, 0, 0, 0x03, 0, 0, 0,
+        b'b', b'a', b'r', b'f', b'o', b'o', b'z',
+        b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd', b'b', b'y', b'e',
+    ];
+
+    /// A branch page pointing at pages 5 and 6 through keys abc and def.
+    #[rustfmt::skip]
+    const BRANCH_PAGE: [u8; 54] = [
+        // id, flags, count, overflow
+        0x04, 0, 0, 0, 0, 0, 0, 0, 0x01, 0, 0x02, 0, 0, 0, 0, 0,
+        // pos, ksize, pgid
+        0x20, 0, 0, 0, 0x03, 0, 0, 0, 0x05, 0, 0, 0, 0, 0, 0, 0,
+        0x13, 0, 0, 0, 0x03, 0, 0, 0, 0x06, 0, 0, 0, 0, 0, 0, 0,
+        b'a', b'b', b'c', b'd', b'e', b'f',
+    ];
+
+    fn is_corrupted<T>(r: Result<T>) -> bool {
+        matches!(r, Err(Error::Corrupted { .. }))
+    }
+
+    #[test]
+    fn header() {
+        let p = Page::new(&LEAF_PAGE);
+        assert_eq!(p.id(), 3);
+        assert_eq!(p.flags(), LEAF_PAGE_FLAG);
+        assert_eq!(p.count(), 2);
+        assert_eq!(p.overflow(), 0);
+        assert_eq!(p.typ(), "leaf");
+        assert_eq!(Page::new(&BRANCH_PAGE).typ(), "branch");
+
+        let mut buf = [0u8; PAGE_HEADER_SIZE];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(0x0102_0304_0506_0708);
+        p.set_flags(FREELIST_PAGE_FLAG);
+        p.set_count(0x0a0b);
+        p.set_overflow(0x0c0d_0e0f);
+        assert_eq!(
+            buf,
+            [8, 7, 6, 5, 4, 3, 2, 1, 0x10, 0, 0x0b, 0x0a, 0x0f, 0x0e, 0x0d, 0x0c]
+        );
+        assert_eq!(Page::new(&buf).typ(), "freelist");
+        buf[8] = 0x20;
+        assert_eq!(Page::new(&buf).typ(), "unknown<20>");
+    }
+
+    #[test]
+    fn leaf_page_elements() {
+        let elems = Page::new(&LEAF_PAGE).leaf_page_elements().unwrap();
+        let pairs: Vec<_> = elems.iter().map(|e| (e.key(), e.value())).collect();
+        assert_eq!(
+            pairs,
+            vec![(&b"bar"[..], &b"fooz"[..]), (b"helloworld", b"bye")]
+        );
+        assert!(elems.iter().all(|e| e.flags() == 0));
+    }
+
+    #[test]
+    fn branch_page_elements() {
+        let elems = Page::new(&BRANCH_PAGE).branch_page_elements().unwrap();
+        let pairs: Vec<_> = elems.iter().map(|e| (e.key(), e.pgid())).collect();
+        assert_eq!(pairs, vec![(&b"abc"[..], 5), (b"def", 6)]);
+    }
+
+    #[test]
+    fn node_write_matches_layout() {
+        // Safety: the keys and values are static.
+        let b = |data: &'static [u8]| unsafe { Bytes::new(data) };
+
+        let mut leaf = Node::new(0, true, None);
+        leaf.put(b"bar", b(b"bar"), b(b"fooz"), 0, 0).unwrap();
+        leaf.put(b"helloworld", b(b"helloworld"), b(b"bye"), 0, 0)
+            .unwrap();
+        let mut buf = vec![0u8; leaf.size()];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(3);
+        leaf.write(&mut p).unwrap();
+        assert_eq!(buf, LEAF_PAGE);
+
+        let mut branch = Node::new(0, false, None);
+        branch
+            .put(b"abc", b(b"abc"), Bytes::default(), 5, 0)
+            .unwrap();
+        branch
+            .put(b"def", b(b"def"), Bytes::default(), 6, 0)
+            .unwrap();
+        let mut buf = vec![0u8; branch.size()];
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(4);
+        branch.write(&mut p).unwrap();
+        assert_eq!(buf, BRANCH_PAGE);
+    }
+
+    #[test]
+    fn corrupted_elements() {
+        // An element past the count, or whose header runs off the page.
+        let p = Page::new(&LEAF_PAGE);
+        assert!(is_corrupted(p.leaf_element(2)));
+        let mut buf = LEAF_PAGE;
+        buf[10] = 0xff;
+        assert!(is_corrupted(Page::new(&buf).leaf_page_elements()));
+
+        // A key or value that runs past the end of the page.
+        let mut buf = LEAF_PAGE;
+        buf[PAGE_HEADER_SIZE + 16 + 12] = 4;
+        assert!(is_corrupted(Page::new(&buf).leaf_element(1)));
+        assert!(Page::new(&buf).leaf_element(0).is_ok());
+
+        // Offsets large enough to overflow are caught too.
+        let mut buf = LEAF_PAGE;
+        buf[PAGE_HEADER_SIZE + 4..PAGE_HEADER_SIZE + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        buf[PAGE_HEADER_SIZE + 8..PAGE_HEADER_SIZE + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(is_corrupted(Page::new(&buf).leaf_element(0)));
+
+        let mut buf = BRANCH_PAGE;
+        buf[PAGE_HEADER_SIZE + 16 + 4] = 0xff;
+        assert!(is_corrupted(Page::new(&buf).branch_page_elements()));
+    }
+
+    #[test]
+    fn page_at_bounds() {
+        let page_size = 64;
+        let mut data = vec![0u8; page_size * 4];
+        write_u64(&mut data, page_size * 2, 2);
+        write_u32(&mut data, page_size * 2 + 12, 1);
+        assert_eq!(
+            page_at(|r| data.get(r), page_size, 2).unwrap().data().len(),
+            2 * page_size - PAGE_HEADER_SIZE
+        );
+
+        // The overflow runs past the end of the mapping.
+        write_u32(&mut data, page_size * 2 + 12, 2);
+        assert!(is_corrupted(page_at(|r| data.get(r), page_size, 2)));
+        assert!(is_corrupted(page_at(|r| data.get(r), page_size, 4)));
+        assert!(is_corrupted(page_at(|r| data.get(r), page_size, u64::MAX)));
+    }
+}
@@ -0,0 +1,205 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::bucket::Bucket;
+use crate::cursor::Cursor;
+use crate::errors::{Error, Result};
+use crate::tx::Tx;
+
+/// Snapshot is a read-only transaction meant to be kept open for a long
+/// time, for instance by an analytics job. It owns its reference to the
+/// database and to the mapping it reads from, so it can be moved to another
+/// thread and outlive the `DB` handle it was started from.
+///
+/// A snapshot pins every page it can see: the writer can't reclaim pages
+/// freed after the snapshot started until it is dropped. Once it has been
+/// open longer than `Options::max_snapshot_age`, every read logs a warning,
+/// or returns `Error::SnapshotExpired` when `Options::strict_snapshot_age`
+/// is set.
+pub struct Snapshot {
+    tx: Tx,
+    started: Instant,
+    warned: Cell<bool>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(tx: Tx) -> Snapshot {
+        Snapshot {
+            tx,
+            started: Instant::now(),
+            warned: Cell::new(false),
+        }
+    }
+
+    /// ID returns the id of the transaction the snapshot reads.
+    pub fn id(&self) -> u64 {
+        self.tx.id()
+    }
+
+    /// Size returns the database size in bytes as seen by the snapshot.
+    pub fn size(&self) -> u64 {
+        self.tx.size()
+    }
+
+    /// Age returns how long the snapshot has been open.
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Tx returns the transaction of the snapshot.
+    pub fn tx(&self) -> Result<&Tx> {
+        self.check_age()?;
+        Ok(&self.tx)
+    }
+
+    /// Bucket retrieves a bucket by name, see `Tx::bucket`.
+    pub fn bucket(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        self.tx()?.bucket(name)
+    }
+
+    /// Cursor creates a cursor over the root bucket, see `Tx::cursor`.
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        self.tx()?.cursor()
+    }
+
+    /// Close closes the snapshot, releasing its pages. Dropping the snapshot
+    /// does the same.
+    pub fn close(mut self) -> Result<()> {
+        self.tx.rollback()
+    }
+
+    /// check_age reports a snapshot that has been open for longer than the
+    /// maximum snapshot age: once through the logger, or every time with an
+    /// error in strict mode.
+    fn check_age(&self) -> Result<()> {
+        let max = match self.tx.db.max_snapshot_age {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let age = self.age();
+        if age <= max {
+            return Ok(());
+        }
+        if self.tx.db.strict_snapshot_age {
+            return Err(Error::SnapshotExpired);
+        }
+        if !self.warned.replace(true) {
+            self.tx.db.logger.warn(format_args!(
+                "snapshot of tx {} open for {:?}, longer than {:?}: its pages can't be reclaimed",
+                self.id(),
+                age,
+                max
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::db::{lock, Options, DB};
+    use crate::logger::tests::Recorder;
+
+    fn open(options: Options) -> (tempfile::TempDir, DB) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), options).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", &[1; 5000]))
+            .unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reads_on_another_thread() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Snapshot>();
+
+        let (_dir, db) = open(Options::default());
+        let snapshot = db.snapshot().unwrap();
+        let id = snapshot.id();
+        db.update(|tx| tx.bucket(b"widgets")?.put(b"foo", b"new"))
+            .unwrap();
+        // The snapshot keeps reading the database as of its transaction, even
+        // once the DB handle it came from is closed.
+        db.close().unwrap();
+
+        let value = thread::spawn(move || {
+            let value = snapshot
+                .bucket(b"widgets")?
+                .get(b"foo")?
+                .map(<[u8]>::to_vec);
+            snapshot.close()?;
+            Ok::<_, Error>(value)
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+        assert_eq!(value, Some(vec![1; 5000]));
+        assert_eq!(id, 2);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn drop_releases_pages() {
+        let (_dir, db) = open(Options::default());
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(db.stats().open_tx_n, 1);
+
+        // Pages freed while the snapshot is open stay pending.
+        db.update(|tx| tx.bucket(b"widgets")?.delete(b"foo"))
+            .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.put(b"bar", b"baz"))
+            .unwrap();
+        let pending = lock(&db.begin(false).unwrap().db.freelist).pending_count();
+        assert!(pending > 0);
+
+        drop(snapshot);
+        assert_eq!(db.stats().open_tx_n, 0);
+        db.update(|tx| tx.bucket(b"widgets")?.delete(b"bar"))
+            .unwrap();
+        let tx = db.begin(false).unwrap();
+        assert!(lock(&tx.db.freelist).pending_count() < pending);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn max_age() {
+        let logger = Arc::new(Recorder::default());
+        let (_dir, db) = open(Options {
+            max_snapshot_age: Some(Duration::from_millis(20)),
+            logger: Some(logger.clone()),
+            ..Options::default()
+        });
+        let snapshot = db.snapshot().unwrap();
+        snapshot.bucket(b"widgets").unwrap();
+        thread::sleep(Duration::from_millis(30));
+        logger.take();
+        snapshot.bucket(b"widgets").unwrap();
+        snapshot.cursor().unwrap();
+        let warnings: Vec<_> = logger
+            .take()
+            .into_iter()
+            .filter(|(level, _)| *level == "warn")
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].1.starts_with("snapshot of tx 2 open for "));
+        snapshot.close().unwrap();
+
+        let (_dir, db) = open(Options {
+            max_snapshot_age: Some(Duration::from_millis(20)),
+            strict_snapshot_age: true,
+            ..Options::default()
+        });
+        let snapshot = db.snapshot().unwrap();
+        snapshot.bucket(b"widgets").unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert!(matches!(
+            snapshot.bucket(b"widgets"),
+            Err(Error::SnapshotExpired)
+        ));
+    }
+}
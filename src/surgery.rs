@@ -0,0 +1,251 @@
+//! Surgery operations for rescuing damaged databases.
+//!
+//! These work on the file directly, below the transaction layer. They never
+//! modify the source file: it is copied to a mandatory output path first and
+//! the surgery is applied to the copy. Both refuse to run while another
+//! process holds the database open.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::db::read_page_size;
+use crate::errors::{Error, Result};
+use crate::meta::{Meta, META_SIZE};
+use crate::ops::FileOps;
+use crate::page::{PageMut, Pgid, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE};
+use crate::unix;
+
+/// RevertMetaPage writes a copy of the database at `src` to `dst`, with the
+/// non-active meta page copied over the active one. This rolls the copy back
+/// by one commit.
+///
+/// The non-active meta page must be valid; reverting to a damaged meta would
+/// leave the copy without any usable meta page.
+pub fn revert_meta_page<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<()> {
+    let file = copy(src.as_ref(), dst.as_ref())?;
+    let page_size = read_page_size(&file, &FileOps)?;
+
+    // The active meta is the valid one with the highest txid, matching the
+    // selection made when the database is opened.
+    let meta0 = read_meta(&file, page_size, 0)?;
+    let meta1 = read_meta(&file, page_size, 1)?;
+    let active = match (meta0.validate(), meta1.validate()) {
+        (Ok(()), Ok(())) if meta1.txid > meta0.txid => 1,
+        (Ok(()), Ok(())) => 0,
+        (Err(err), _) | (_, Err(err)) => return Err(err),
+    };
+
+    copy_page(&file, page_size, 1 - active, active)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// ClearPage writes a copy of the database at `src` to `dst`, with the page
+/// `id` rewritten as an empty leaf page.
+///
+/// The overflow pages of a cleared page are no longer reachable, so `check`
+/// reports them as unreachable until they are freed again. Meta pages cannot
+/// be cleared.
+pub fn clear_page<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, id: u64) -> Result<()> {
+    if id < 2 {
+        return Err(invalid_input("cannot clear a meta page"));
+    }
+
+    let file = copy(src.as_ref(), dst.as_ref())?;
+    let page_size = read_page_size(&file, &FileOps)?;
+    let meta0 = read_meta(&file, page_size, 0)?;
+    let meta1 = read_meta(&file, page_size, 1)?;
+    let hwm = meta0.pgid.max(meta1.pgid);
+    if id >= hwm {
+        return Err(Error::PageNotFound);
+    }
+
+    let mut buf = vec![0u8; page_size];
+    let mut p = PageMut::new(&mut buf);
+    p.set_id(id);
+    p.set_flags(LEAF_PAGE_FLAG);
+    file.write_all_at(&buf, id * page_size as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// copy copies the unlocked database at `src` to `dst` and returns the copy,
+/// opened for writing and locked for the duration of the surgery.
+fn copy(src: &Path, dst: &Path) -> Result<File> {
+    if let (Ok(a), Ok(b)) = (src.canonicalize(), dst.canonicalize()) {
+        if a == b {
+            return Err(invalid_input("output path must differ from the source"));
+        }
+    }
+
+    // Lock both files without waiting, so that a database held open by any
+    // process, even read-only, is refused.
+    let mut from = File::open(src)?;
+    lock(&from)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dst)?;
+    lock(&file)?;
+
+    // Only truncate once the lock is held, an open database must not be
+    // overwritten.
+    file.set_len(0)?;
+    io::copy(&mut from, &mut file)?;
+    file.set_permissions(from.metadata()?.permissions())?;
+    unix::funlock(&from)?;
+    Ok(file)
+}
+
+/// lock takes an exclusive lock on `file`, failing with `Error::DatabaseOpen`
+/// if it is already locked.
+fn lock(file: &File) -> Result<()> {
+    unix::flock(file, true, Some(Duration::from_secs(0))).map_err(|err| match err {
+        Error::Timeout => Error::DatabaseOpen,
+        err => err,
+    })
+}
+
+/// read_meta reads the meta stored on page `id` without validating it.
+fn read_meta(file: &File, page_size: usize, id: Pgid) -> Result<Meta> {
+    let mut buf = [0u8; PAGE_HEADER_SIZE + META_SIZE];
+    file.read_exact_at(&mut buf, id * page_size as u64)?;
+    Ok(Meta::read(&buf[PAGE_HEADER_SIZE..]))
+}
+
+/// copy_page copies the page `src` over the page `dst`, updating the page id.
+fn copy_page(file: &File, page_size: usize, src: Pgid, dst: Pgid) -> Result<()> {
+    let mut buf = vec![0u8; page_size];
+    file.read_exact_at(&mut buf, src * page_size as u64)?;
+    PageMut::new(&mut buf).set_id(dst);
+    file.write_all_at(&buf, dst * page_size as u64)?;
+    Ok(())
+}
+
+fn invalid_input(msg: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::db::{Options, DB};
+    use crate::page::BUCKET_LEAF_FLAG;
+    use crate::tx_check::tests::{bucket_value, build, write_leaf, PS};
+
+    fn get(db: &DB, key: &[u8]) -> Option<Vec<u8>> {
+        let tx = db.begin(false).unwrap();
+        let v = tx
+            .bucket(b"widgets")
+            .unwrap()
+            .get(key)
+            .unwrap()
+            .map(<[u8]>::to_vec);
+        v
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn revert_meta_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let db = DB::open(&src, Options::default()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"1"))
+            .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.put(b"bar", &[2; 5000]))
+            .unwrap();
+
+        // The database is open, so it must be refused.
+        let dst = dir.path().join("dst");
+        assert!(matches!(
+            super::revert_meta_page(&src, &dst),
+            Err(Error::DatabaseOpen)
+        ));
+        let root = db.begin(false).unwrap().meta.get().root.root;
+        db.close().unwrap();
+
+        // Break the last commit by wiping the root page it wrote.
+        let f = OpenOptions::new().write(true).open(&src).unwrap();
+        f.write_all_at(&[0xff; 64], root * PS as u64).unwrap();
+        drop(f);
+        let before = fs::read(&src).unwrap();
+
+        super::revert_meta_page(&src, &dst).unwrap();
+        assert_eq!(fs::read(&src).unwrap(), before);
+
+        let db = DB::open(&dst, Options::default()).unwrap();
+        assert!(db.begin(false).unwrap().check().is_empty());
+        assert_eq!(get(&db, b"foo"), Some(b"1".to_vec()));
+        assert_eq!(get(&db, b"bar"), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn revert_meta_page_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        build(&src, 4, |buf| {
+            // Damage the checksum of the meta on page 1.
+            buf[PS + PAGE_HEADER_SIZE + 56] ^= 0xff;
+        })
+        .close()
+        .unwrap();
+
+        let dst = dir.path().join("dst");
+        assert!(matches!(
+            super::revert_meta_page(&src, &dst),
+            Err(Error::Checksum)
+        ));
+        assert!(matches!(
+            super::revert_meta_page(&src, &src),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn clear_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        build(&src, 5, |buf| {
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"widgets", &bucket_value(4))]);
+            // A leaf page whose element points far beyond the page.
+            write_leaf(buf, 4, &[(0, b"foo", b"bar")]);
+            buf[4 * PS + PAGE_HEADER_SIZE + 4] = 0xff;
+            buf[4 * PS + PAGE_HEADER_SIZE + 5] = 0xff;
+        })
+        .close()
+        .unwrap();
+        let before = fs::read(&src).unwrap();
+        let db = DB::open(&src, Options::default()).unwrap();
+        assert!(!db.begin(false).unwrap().check().is_empty());
+        db.close().unwrap();
+
+        let dst = dir.path().join("dst");
+        super::clear_page(&src, &dst, 4).unwrap();
+        assert_eq!(fs::read(&src).unwrap(), before);
+
+        let db = DB::open(&dst, Options::default()).unwrap();
+        assert!(db.begin(false).unwrap().check().is_empty());
+        assert_eq!(get(&db, b"foo"), None);
+        let dump = db.dump_page(4).unwrap();
+        assert_eq!((dump.id, dump.typ.as_str(), dump.count), (4, "leaf", 0));
+        db.close().unwrap();
+
+        assert!(matches!(
+            super::clear_page(&src, &dst, 1),
+            Err(Error::Io(_))
+        ));
+        assert!(matches!(
+            super::clear_page(&src, &dst, 5),
+            Err(Error::PageNotFound)
+        ));
+    }
+}
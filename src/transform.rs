@@ -0,0 +1,189 @@
+use std::fmt;
+
+use crate::page::Pgid;
+
+/// PageTransform encrypts the pages of the data file at rest.
+///
+/// Pages are encrypted just before they are written to the file and
+/// decrypted when the file is read into memory, one `page_size` block at a
+/// time, with the id of the block. `decrypt` must restore the bytes `encrypt`
+/// was given, in place and without changing their length. Since a block only
+/// has its page id to vary the output, a tweakable cipher such as AES-XTS
+/// suits it best.
+///
+/// The two meta pages are never transformed. They stay readable so that the
+/// page size of a file can be detected before any transform is applied, and
+/// hold no user data: only page ids, transaction ids and the root bucket's
+/// sequence.
+///
+/// Installing a transform switches the database to the buffer backend, see
+/// `Options::force_buffer_backend`: the whole file is decrypted into memory
+/// when it is read, and transactions borrow the plaintext from there. The
+/// `surgery` functions work on the file directly and don't support
+/// transformed files.
+pub trait PageTransform: Send + Sync {
+    /// Encrypt transforms the plaintext of page `pgid` in place.
+    fn encrypt(&self, pgid: u64, page: &mut [u8]);
+    /// Decrypt transforms the ciphertext of page `pgid` back in place.
+    fn decrypt(&self, pgid: u64, page: &mut [u8]);
+}
+
+impl fmt::Debug for dyn PageTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PageTransform")
+    }
+}
+
+/// encrypt_pages encrypts the pages in `buf`, which starts at page `first`,
+/// leaving the meta pages alone.
+pub(crate) fn encrypt_pages(t: &dyn PageTransform, page_size: usize, first: Pgid, buf: &mut [u8]) {
+    for_each_page(page_size, first, buf, |id, p| t.encrypt(id, p));
+}
+
+/// decrypt_pages is the inverse of encrypt_pages.
+pub(crate) fn decrypt_pages(t: &dyn PageTransform, page_size: usize, first: Pgid, buf: &mut [u8]) {
+    for_each_page(page_size, first, buf, |id, p| t.decrypt(id, p));
+}
+
+fn for_each_page(
+    page_size: usize,
+    first: Pgid,
+    buf: &mut [u8],
+    mut f: impl FnMut(Pgid, &mut [u8]),
+) {
+    for (i, p) in buf.chunks_exact_mut(page_size).enumerate() {
+        let id = first + i as Pgid;
+        if id >= 2 {
+            f(id, p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::db::{Options, DB};
+    use crate::errors::Error;
+
+    /// Xor is a toy transform that xors every byte with a key and the low
+    /// byte of its page id.
+    struct Xor(u8);
+
+    impl PageTransform for Xor {
+        fn encrypt(&self, pgid: u64, page: &mut [u8]) {
+            for b in page {
+                *b ^= self.0 ^ pgid as u8;
+            }
+        }
+
+        fn decrypt(&self, pgid: u64, page: &mut [u8]) {
+            self.encrypt(pgid, page)
+        }
+    }
+
+    fn options(key: u8) -> Options {
+        Options {
+            page_transform: Some(Arc::new(Xor(key))),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn skips_meta_pages() {
+        let mut buf = vec![0u8; 4 * 16];
+        encrypt_pages(&Xor(0xf0), 16, 0, &mut buf);
+        assert!(buf[..32].iter().all(|&b| b == 0));
+        assert!(buf[32..48].iter().all(|&b| b == 0xf2));
+        assert!(buf[48..].iter().all(|&b| b == 0xf3));
+        decrypt_pages(&Xor(0xf0), 16, 0, &mut buf);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let secret = b"attack at dawn";
+
+        let db = DB::open(&path, options(0x5a)).unwrap();
+        assert!(db.0.mmap().is_buffer());
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"plans", secret)?;
+            for i in 0..200u32 {
+                b.put(&i.to_be_bytes(), &[7; 512])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+
+        // Neither the bucket nor the value can be found in the file.
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(secret.len()).any(|w| w == secret));
+        assert!(!raw.windows(7).any(|w| w == b"widgets"));
+
+        // Reopening with the transform reads the data back, including after
+        // growing the file.
+        let db = DB::open(&path, options(0x5a)).unwrap();
+        db.update(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            assert_eq!(b.get(b"plans")?, Some(&secret[..]));
+            for i in 200..400u32 {
+                b.put(&i.to_be_bytes(), &[7; 512])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            assert_eq!(tx.bucket(b"widgets")?.stats()?.key_n, 401);
+            Ok(())
+        })
+        .unwrap();
+
+        // A copy keeps the pages encrypted, and so does a compacted copy.
+        db.view(|tx| tx.copy_file(dir.path().join("copy"), 0o600))
+            .unwrap();
+        db.compact_to(dir.path().join("compact"), 0).unwrap();
+        db.close().unwrap();
+        for name in ["copy", "compact"].iter() {
+            let path = dir.path().join(name);
+            let raw = std::fs::read(&path).unwrap();
+            assert!(!raw.windows(secret.len()).any(|w| w == secret));
+            let db = DB::open(&path, options(0x5a)).unwrap();
+            db.view(|tx| {
+                assert_eq!(tx.bucket(b"widgets")?.get(b"plans")?, Some(&secret[..]));
+                Ok(())
+            })
+            .unwrap();
+            db.close().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn unreadable_without_transform() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path, options(0x5a)).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        db.close().unwrap();
+
+        // Without the transform, or with the wrong key, the meta pages still
+        // open the file but the pages they point to are garbage.
+        for options in [Options::default(), options(0xa5)].iter() {
+            let result = DB::open(&path, options.clone()).and_then(|db| {
+                db.view_ret(|tx| Ok(tx.bucket(b"widgets")?.get(b"foo")?.map(<[u8]>::to_vec)))
+            });
+            match result {
+                Err(Error::Corrupted { .. }) | Err(Error::BucketNotFound) => {}
+                other => panic!("read through the wrong transform: {:?}", other),
+            }
+        }
+    }
+}
@@ -1,27 +1,2661 @@
+#![deny(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
 
-// Tx represents a read-only or read/write transaction on the database.
-// Read-only transactions can be used for retrieving values for keys and creating cursors.
-// Read/write transactions can create and remove buckets and create and remove keys.
-//
-// IMPORTANT: You must commit or rollback transactions when you are done with
-// them. Pages can not be reclaimed by the writer until no more transactions
-// are using them. A long running read transaction can cause the database to
-// quickly grow.
-type Tx struct {
-	writable       bool
-	managed        bool
-	db             *DB
-	meta           *meta
-	root           Bucket
-	pages          map[pgid]*page
-	stats          TxStats
-	commitHandlers []func()
-
-	// WriteFlag specifies the flag for write-related methods like WriteTo().
-	// Tx opens the database file with the specified flag to copy the data.
-	//
-	// By default, the flag is unset, which works well for mostly in-memory
-	// workloads. For databases that are much larger than available RAM,
-	// set the flag to syscall.O_DIRECT to avoid trashing the page cache.
-	WriteFlag int
-}
\ No newline at end of file
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backend::Mmap;
+use crate::bucket::{read_bucket_value, Bucket, BucketState, InBucket, ROOT_BUCKET};
+use crate::cursor::Cursor;
+use crate::db::{alloc_size, lock, mmap_offset, RawDB};
+use crate::errors::{Error, Result};
+use crate::meta::Meta;
+use crate::node::{Bytes, Node, NodeId};
+use crate::page::{
+    Page, PageInfo, PageMut, Pgid, BRANCH_PAGE_FLAG, BUCKET_LEAF_FLAG, LEAF_PAGE_FLAG,
+    META_PAGE_FLAG, PAGE_HEADER_SIZE, PGID_NO_FREELIST,
+};
+use crate::tx_check::CheckOptions;
+
+/// MAX_WRITE_SIZE is the largest buffer handed to a single write_at call
+/// when writing dirty pages; larger runs of pages are split into chunks.
+const MAX_WRITE_SIZE: usize = 1 << 20;
+
+/// Txid is the identifier of a transaction.
+pub(crate) type Txid = u64;
+
+/// Tx represents a read-only or read/write transaction on the database.
+/// Read-only transactions can be used for retrieving values for keys and creating cursors.
+/// Read/write transactions can create and remove buckets and create and remove keys.
+///
+/// IMPORTANT: You must commit or rollback transactions when you are done with
+/// them. Pages can not be reclaimed by the writer until no more transactions
+/// are using them. A transaction that is dropped while still open is rolled back.
+///
+/// Once a transaction is committed or rolled back, every method that reads the
+/// database returns `Error::TxClosed`. Data borrowed from a transaction borrows
+/// the `Tx` itself, so it cannot be held across `commit` or `rollback`.
+///
+/// A `Tx` can be moved to another thread, but not shared between threads:
+/// its buckets and cursors borrow it and stay on the thread using it.
+///
+/// ```compile_fail
+/// # let dir = tempfile::tempdir().unwrap();
+/// let db = boltdb_rs::DB::open(dir.path().join("db"), Default::default()).unwrap();
+/// let tx = db.begin(false).unwrap();
+/// std::thread::scope(|s| {
+///     s.spawn(|| tx.bucket(b"widgets").is_ok());
+/// });
+/// ```
+pub struct Tx {
+    pub(crate) db: Arc<RawDB>,
+    /// The mapping this transaction started on; it stays valid even if the
+    /// database is remapped while the transaction is open.
+    pub(crate) mmap: Arc<Mmap>,
+    pub(crate) writable: bool,
+    pub(crate) closed: bool,
+    pub(crate) meta: Cell<Meta>,
+    /// Dirty pages allocated by this transaction, keyed by their first page id.
+    pub(crate) pages: RefCell<HashMap<Pgid, Vec<u8>>>,
+    /// The buckets and nodes materialized by this transaction.
+    pub(crate) state: RefCell<TxState>,
+    pub(crate) stats: TxStats,
+    commit_handlers: RefCell<Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+/// TxState holds the buckets and nodes a transaction has materialized.
+///
+/// Buckets and nodes refer to each other by their index in the arenas below,
+/// which only ever grow while the transaction is open. Keys and values copied
+/// into the transaction are kept in `arena` so that slices into them stay
+/// valid until the transaction is dropped.
+pub(crate) struct TxState {
+    pub(crate) buckets: Vec<BucketState>,
+    pub(crate) nodes: Vec<Node>,
+    // Held as vectors rather than boxes: moving a box asserts unique access
+    // to its contents, which would invalidate the slices handed out.
+    arena: Vec<Vec<u8>>,
+    /// The number of key changes made through buckets, which lets an
+    /// iteration notice that the tree changed under its cursor.
+    pub(crate) writes: u64,
+    /// The serialized size of the materialized nodes, kept current by every
+    /// change so that Tx::pending_bytes needs no walk over the nodes.
+    pub(crate) pending_bytes: usize,
+    /// The first error of a reserved value that could not be stored when
+    /// dropped, returned by commit.
+    pub(crate) deferred_err: Option<Error>,
+}
+
+impl TxState {
+    fn new(root: InBucket) -> TxState {
+        TxState {
+            buckets: vec![BucketState::new(root)],
+            nodes: Vec::new(),
+            arena: Vec::new(),
+            writes: 0,
+            pending_bytes: 0,
+            deferred_err: None,
+        }
+    }
+
+    /// put inserts a key/value into node `n`, see Node::put.
+    pub(crate) fn put(
+        &mut self,
+        n: NodeId,
+        old_key: &[u8],
+        key: Bytes,
+        value: Bytes,
+        pgid: Pgid,
+        flags: u32,
+    ) -> Result<()> {
+        let grown = self.nodes[n].put(old_key, key, value, pgid, flags)?;
+        self.pending_bytes = self.pending_bytes.saturating_add_signed(grown);
+        Ok(())
+    }
+
+    /// del removes a key from node `n`, see Node::del.
+    pub(crate) fn del(&mut self, n: NodeId, key: &[u8]) {
+        let shrunk = self.nodes[n].del(key);
+        self.pending_bytes = self.pending_bytes.saturating_sub(shrunk);
+    }
+
+    /// alloc copies `data` into the transaction and returns a handle to the copy.
+    pub(crate) fn alloc(&mut self, data: &[u8]) -> Bytes {
+        self.keep(data.into())
+    }
+
+    /// keep moves `data` into the transaction, without copying it, and
+    /// returns a handle to it.
+    pub(crate) fn keep(&mut self, data: Box<[u8]>) -> Bytes {
+        // Safety: the boxed slice is never moved out of or dropped before the
+        // transaction state itself.
+        let data = data.into_vec();
+        let bytes = unsafe { Bytes::new(&data) };
+        self.arena.push(data);
+        bytes
+    }
+}
+
+impl Tx {
+    /// new initializes a transaction from a copy of the current meta page.
+    pub(crate) fn new(db: Arc<RawDB>, mmap: Arc<Mmap>, mut meta: Meta, writable: bool) -> Tx {
+        // Increment the transaction id for writable transactions.
+        if writable {
+            meta.txid += 1;
+        }
+        Tx {
+            db,
+            mmap,
+            writable,
+            closed: false,
+            meta: Cell::new(meta),
+            pages: RefCell::new(HashMap::new()),
+            state: RefCell::new(TxState::new(meta.root)),
+            stats: TxStats::default(),
+            commit_handlers: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// ID returns the transaction id.
+    pub fn id(&self) -> u64 {
+        self.meta.get().txid
+    }
+
+    /// Writable returns whether the transaction can perform write operations.
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Size returns current database size in bytes as seen by this transaction.
+    pub fn size(&self) -> u64 {
+        self.meta.get().pgid * self.db.page_size as u64
+    }
+
+    /// PendingBytes returns the serialized size of the nodes this transaction
+    /// has changed, which commit writes out. It is updated by every change
+    /// rather than computed, so bulk loaders can check it after each put to
+    /// decide when to commit.
+    pub fn pending_bytes(&self) -> usize {
+        self.state.borrow().pending_bytes
+    }
+
+    /// PendingPages returns the number of pages the pending bytes fill.
+    ///
+    /// It is a lower bound of what commit allocates: spilling splits nodes
+    /// at the fill percent of their bucket, so at the default of 0.5 a
+    /// large load takes up to twice as many pages, plus the branch pages
+    /// above them and the freelist.
+    pub fn pending_pages(&self) -> usize {
+        self.pending_bytes().div_ceil(self.db.page_size)
+    }
+
+    /// Stats retrieves a copy of the current transaction statistics.
+    ///
+    /// Pages are only allocated while committing, when nodes are spilled, so
+    /// before that the node and cursor counts tell the cost of the changes.
+    /// The stats are merged into `DB::stats` when the transaction closes.
+    pub fn stats(&self) -> TxStats {
+        self.stats.clone()
+    }
+
+    /// root returns the root bucket of the transaction, whose keys name the
+    /// top-level buckets.
+    pub(crate) fn root(&self) -> Bucket<'_> {
+        Bucket::new(self, ROOT_BUCKET)
+    }
+
+    /// Cursor creates a cursor associated with the root bucket.
+    /// All items in the cursor will return a nil value because all root bucket keys point to buckets.
+    /// The cursor is only valid as long as the transaction is open.
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        Ok(self.root().cursor())
+    }
+
+    /// Bucket retrieves a bucket by name.
+    /// Returns `Error::BucketNotFound` if the bucket does not exist.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn bucket(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().bucket(name)
+    }
+
+    /// CreateBucket creates a new bucket.
+    /// Returns an error if the bucket already exists, if the bucket name is blank, or if the bucket name is too long.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn create_bucket(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().create_bucket(name)
+    }
+
+    /// CreateBucketIfNotExists creates a new bucket if it doesn't already exist.
+    /// Returns an error if the bucket name is blank, or if the bucket name is too long.
+    /// The bucket instance is only valid for the lifetime of the transaction.
+    pub fn create_bucket_if_not_exists(&self, name: &[u8]) -> Result<Bucket<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().create_bucket_if_not_exists(name)
+    }
+
+    /// MoveBucket moves the bucket `name` from `src` into `dst`, where None
+    /// stands for the top level of the transaction. See `Bucket::move_bucket`.
+    pub fn move_bucket(
+        &self,
+        name: &[u8],
+        src: Option<&Bucket<'_>>,
+        dst: Option<&Bucket<'_>>,
+    ) -> Result<()> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let root = self.root();
+        src.unwrap_or(&root).move_bucket(name, dst.unwrap_or(&root))
+    }
+
+    /// BucketNames returns the names of the top-level buckets, in key order.
+    pub fn bucket_names(&self) -> Result<Vec<Vec<u8>>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let mut names = Vec::new();
+        self.root().for_each_bucket(|name| {
+            names.push(name.to_vec());
+            Ok(())
+        })?;
+        Ok(names)
+    }
+
+    /// ForEach executes a function for each top-level bucket in key order,
+    /// passing the bucket name and the bucket itself. If the provided function
+    /// returns an error then the iteration is stopped and the error is
+    /// returned to the caller.
+    ///
+    /// The bucket is only lent to the callback, so it cannot be kept beyond a
+    /// single call, and in a read-only transaction every attempt to change it
+    /// fails with `Error::TxNotWritable`.
+    pub fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &Bucket<'_>) -> Result<()>,
+    {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let root = self.root();
+        root.for_each_bucket(|name| f(name, &root.bucket(name)?))
+    }
+
+    /// ForEachRecursive executes a function for every bucket in the
+    /// transaction, nested buckets included, passing the full path of names
+    /// from the top-level bucket down to the bucket itself. Buckets are
+    /// visited depth-first in key order, each before its nested buckets. If
+    /// the provided function returns an error then the walk is stopped and
+    /// the error is returned to the caller.
+    pub fn for_each_recursive<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[&[u8]], &Bucket<'_>) -> Result<()>,
+    {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        walk_buckets(self.root(), &mut Vec::new(), &mut f)
+    }
+
+    /// BucketPath retrieves the bucket at the end of `path`, walking one
+    /// nested bucket per component. Returns `Error::BucketPathNotFound`
+    /// naming the first missing component, or `Error::BucketNameRequired` if
+    /// the path is empty.
+    pub fn bucket_path<N: AsRef<[u8]>>(&self, path: &[N]) -> Result<Bucket<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        } else if path.is_empty() {
+            return Err(Error::BucketNameRequired);
+        }
+        let mut b = self.root();
+        for (depth, name) in path.iter().enumerate() {
+            b = match b.bucket(name.as_ref()) {
+                Err(Error::BucketNotFound) => {
+                    return Err(Error::BucketPathNotFound {
+                        depth,
+                        name: name.as_ref().to_vec(),
+                    })
+                }
+                res => res?,
+            };
+        }
+        Ok(b)
+    }
+
+    /// CreateBucketPath walks `path` like bucket_path(), creating every
+    /// bucket that doesn't exist yet, and returns the last one.
+    /// Returns an error if the path is empty, or if a component is blank,
+    /// too long or names a non-bucket value.
+    pub fn create_bucket_path<N: AsRef<[u8]>>(&self, path: &[N]) -> Result<Bucket<'_>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        } else if path.is_empty() {
+            return Err(Error::BucketNameRequired);
+        }
+        let mut b = self.root();
+        for name in path {
+            b = b.create_bucket_if_not_exists(name.as_ref())?;
+        }
+        Ok(b)
+    }
+
+    /// DeleteBucket deletes a bucket.
+    /// Returns an error if the bucket cannot be found or if the key represents a non-bucket value.
+    pub fn delete_bucket(&self, name: &[u8]) -> Result<()> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().delete_bucket(name)
+    }
+
+    /// GlobalSequence returns the current database-wide sequence number
+    /// without incrementing it.
+    ///
+    /// The sequence lives in the root bucket header of the meta page, which
+    /// bbolt leaves at zero, so it needs no bucket of its own and rolls back
+    /// with the transaction.
+    pub fn global_sequence(&self) -> Result<u64> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        Ok(self.root().sequence())
+    }
+
+    /// SetGlobalSequence updates the database-wide sequence number.
+    pub fn set_global_sequence(&self, v: u64) -> Result<()> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().set_sequence(v)
+    }
+
+    /// NextGlobalSequence returns an autoincrementing integer shared by the
+    /// whole database, independent of every bucket sequence.
+    pub fn next_global_sequence(&self) -> Result<u64> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.root().next_sequence()
+    }
+
+    /// Commit writes all changes to disk and updates the meta page.
+    /// Returns an error if a disk write error occurs, or if commit is
+    /// called on a read-only transaction.
+    ///
+    /// If any step before the meta page is written fails, the transaction is
+    /// rolled back and the previously committed state is left untouched.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        } else if !self.writable {
+            return Err(Error::TxNotWritable);
+        }
+        let txid = self.meta.get().txid;
+        let commit_start = Instant::now();
+        self.db.logger.debug(format_args!("committing tx {}", txid));
+        if let Some(err) = self.state.get_mut().deferred_err.take() {
+            return self.fail(err);
+        }
+
+        // Rebalance nodes which have had deletions.
+        let start = Instant::now();
+        let rebalanced = self.state.borrow_mut().rebalance(self, ROOT_BUCKET);
+        if let Err(err) = rebalanced {
+            return self.fail(err);
+        }
+        if self.stats.rebalance() > 0 {
+            self.stats.inc_rebalance_time(start.elapsed());
+        }
+
+        let opgid = self.meta.get().pgid;
+
+        // Spill data onto dirty pages.
+        let start = Instant::now();
+        let spilled = self.state.borrow_mut().spill(self, ROOT_BUCKET);
+        if let Err(err) = spilled {
+            return self.fail(err);
+        }
+        self.stats.inc_spill_time(start.elapsed());
+
+        // Free the old root bucket.
+        let mut meta = self.meta.get();
+        meta.root = self.state.get_mut().buckets[ROOT_BUCKET].header;
+        self.meta.set(meta);
+
+        // Free the old freelist because commit writes out a fresh freelist.
+        if let Err(err) = self.commit_freelist() {
+            return self.fail(err);
+        }
+
+        // If the high water mark has moved up then attempt to grow the database.
+        if self.meta.get().pgid > opgid {
+            let sz = mmap_offset(self.meta.get().pgid + 1, self.db.page_size);
+            if let Err(err) = sz.and_then(|sz| self.db.grow(sz)) {
+                return self.fail(err);
+            }
+        }
+
+        // If strict mode is enabled then perform a consistency check.
+        if self.db.strict_mode.load(Ordering::Relaxed) {
+            let errors = match self.check_with_options(&CheckOptions::default()) {
+                Ok(errors) => errors,
+                Err(err) => return self.fail(err),
+            };
+            if !errors.is_empty() {
+                for err in &errors {
+                    self.db.logger.error(format_args!("check failed: {}", err));
+                }
+                return self.fail(Error::CheckFailed { errors });
+            }
+        }
+
+        // Write dirty pages to disk.
+        let start = Instant::now();
+        let page_n = self.pages.borrow().len();
+        if let Err(err) = self.write() {
+            return self.fail(err);
+        }
+
+        // Write meta to disk.
+        if let Err(err) = self.write_meta() {
+            return self.fail(err);
+        }
+        self.stats.inc_write_time(start.elapsed());
+
+        // Finalize the transaction.
+        let handlers = self.commit_handlers.take();
+        self.close();
+        self.db.logger.info(format_args!(
+            "committed tx {}: {} pages written in {:?}",
+            txid,
+            page_n,
+            commit_start.elapsed()
+        ));
+
+        // Execute commit handlers now that the locks have been removed.
+        for f in handlers {
+            f();
+        }
+        Ok(())
+    }
+
+    /// fail rolls back a transaction whose commit failed with `err`.
+    fn fail(&mut self, err: Error) -> Result<()> {
+        let txid = self.meta.get().txid;
+        self.db
+            .logger
+            .error(format_args!("commit tx {} failed: {}", txid, err));
+        self.rollback_internal();
+        Err(err)
+    }
+
+    /// OnCommit adds a handler function to be executed after the transaction
+    /// successfully commits, once the meta page is on disk and the writer lock
+    /// has been released. Handlers of a rolled back transaction are dropped
+    /// without running.
+    pub fn on_commit<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.commit_handlers.borrow_mut().push(Box::new(f));
+    }
+
+    /// Rollback closes the transaction and ignores all previous updates. Read-only
+    /// transactions must be rolled back and not committed.
+    pub fn rollback(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        self.rollback_internal();
+        Ok(())
+    }
+
+    /// rollback_internal discards the pages this transaction allocated or freed
+    /// and closes it. Read-only transactions have nothing to undo and are only
+    /// unregistered from the database.
+    fn rollback_internal(&mut self) {
+        if self.closed {
+            return;
+        }
+        if self.writable {
+            // Reload the freelist from the last committed state, so that pages
+            // allocated from the high water mark or freed in memory are forgotten.
+            let mmap = self.db.mmap();
+            let committed = self.db.meta(&mmap);
+
+            // A freelist that was not persisted is rebuilt by scanning the
+            // committed state instead. That begins a read transaction, so it
+            // has to happen before the freelist lock is taken.
+            let rebuilt = match &committed {
+                Ok(meta) if meta.freelist == PGID_NO_FREELIST => Some(self.db.free_page_ids()),
+                _ => None,
+            };
+
+            let mut freelist = lock(&self.db.freelist);
+            freelist.rollback(self.meta.get().txid);
+            match (committed, rebuilt) {
+                (_, Some(Ok(ids))) => freelist.no_sync_reload(ids),
+                (_, Some(Err(err))) => self
+                    .db
+                    .logger
+                    .warn(format_args!("rebuilding freelist failed: {}", err)),
+                (Ok(meta), None) => {
+                    if let Ok(p) = mmap.page(self.db.page_size, meta.freelist) {
+                        // The committed freelist was valid when it was first read,
+                        // so failing to re-read it leaves the in-memory copy as is.
+                        match freelist.reload(p) {
+                            Ok(()) => self.db.logger.debug(format_args!(
+                                "reloaded freelist from page {}",
+                                meta.freelist
+                            )),
+                            Err(err) => self.db.logger.warn(format_args!(
+                                "reloading freelist from page {} failed: {}",
+                                meta.freelist, err
+                            )),
+                        }
+                    }
+                }
+                (Err(_), None) => {}
+            }
+        }
+        self.close();
+    }
+
+    /// close finalizes the transaction: a writer publishes the freelist gauges
+    /// and releases the writer lock, a reader unregisters itself.
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.release_pages();
+        self.commit_handlers.borrow_mut().clear();
+
+        if self.writable {
+            // Grab freelist stats.
+            self.db.update_freelist_stats();
+
+            // Remove transaction ref & writer lock.
+            self.db.release_writer();
+
+            // Merge statistics.
+            self.db.stats.add_tx_stats(&self.stats);
+        } else {
+            self.db.remove_tx(self.meta.get().txid, &self.stats);
+        }
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::record(&self.db.stats.snapshot(), &self.stats);
+    }
+
+    /// WriteTo writes the entire database to a writer.
+    /// Both meta pages are regenerated from this transaction's meta, so the
+    /// copy is consistent with the snapshot no matter what has been committed
+    /// since. It returns the number of bytes written.
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<u64> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let page_size = self.db.page_size;
+
+        // Open a separate handle so the copy does not disturb the shared
+        // file's position.
+        let mut f = File::open(&self.db.path)?;
+
+        // Generate a meta page. We use the same page data for both meta pages.
+        let mut buf = vec![0u8; page_size];
+        let mut meta = self.meta.get();
+        let mut p = PageMut::new(&mut buf);
+        p.set_flags(META_PAGE_FLAG);
+
+        // Write meta 0.
+        meta.write(&mut p);
+        p.set_id(0);
+        w.write_all(&buf)?;
+
+        // Write meta 1 with a lower transaction id.
+        let mut p = PageMut::new(&mut buf);
+        meta.txid = meta.txid.saturating_sub(1);
+        meta.write(&mut p);
+        p.set_id(1);
+        w.write_all(&buf)?;
+        let mut n = 2 * page_size as u64;
+
+        // Move past the meta pages in the file.
+        f.seek(SeekFrom::Start(n))?;
+
+        // Copy data pages.
+        n += io::copy(&mut f.take(self.size() - n), &mut w)?;
+        Ok(n)
+    }
+
+    /// CopyFile copies the entire database to file at the given path.
+    /// A reader transaction is maintained during the copy so it is safe to continue
+    /// using the database while a copy is in progress.
+    ///
+    /// The destination is opened with the flag set by `DB::set_write_flag`. With
+    /// `O_DIRECT` the copy goes through an aligned buffer; filesystems that
+    /// refuse direct I/O get a regular copy instead.
+    pub fn copy_file<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        let path = path.as_ref();
+        let flag = self.db.write_flag.load(Ordering::Relaxed);
+        let open = |flag| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(mode)
+                .custom_flags(flag)
+                .open(path)
+        };
+
+        let mut direct = flag & O_DIRECT != 0;
+        let mut f = match open(flag) {
+            Err(err) if direct && err.raw_os_error() == Some(libc::EINVAL) => {
+                // The filesystem does not support direct I/O.
+                direct = false;
+                open(flag & !O_DIRECT)?
+            }
+            f => f?,
+        };
+        if direct {
+            let mut w = AlignedWriter::new(f);
+            self.write_to(&mut w)?;
+            f = w.finish()?;
+        } else {
+            self.write_to(&mut f)?;
+        }
+        f.sync_all()?;
+        Ok(())
+    }
+
+    /// Page returns page information for a given page number.
+    /// This is only safe for concurrent use when used by a writable transaction.
+    ///
+    /// `Ok(None)` is returned for ids beyond the high water mark. Telling
+    /// free pages apart needs the freelist, which a read-only database loads
+    /// the first time it is needed.
+    pub fn page(&self, id: u64) -> Result<Option<PageInfo>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        } else if id >= self.meta.get().pgid {
+            return Ok(None);
+        }
+        self.db.load_freelist()?;
+
+        // Build the page info. Only the header is read, since a freed page
+        // may hold stale contents.
+        let off = mmap_offset(id, self.db.page_size)?;
+        let header = self
+            .mmap
+            .get(off..off + PAGE_HEADER_SIZE)
+            .ok_or_else(|| Error::corrupted(id, "page out of bounds"))?;
+        let p = Page::new(header);
+        let typ = if lock(&self.db.freelist).freed(id) {
+            "free".to_string()
+        } else {
+            p.typ()
+        };
+        Ok(Some(PageInfo {
+            id,
+            typ,
+            count: p.count() as usize,
+            overflow_count: p.overflow() as usize,
+        }))
+    }
+
+    /// ForEachPage iterates over every page within a given page and executes a
+    /// function with its information and depth below `pgid`, which is at `depth`.
+    pub fn for_each_page<F>(&self, pgid: u64, depth: usize, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&PageInfo, usize),
+    {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let p = self.raw_page(pgid)?;
+
+        // Execute function.
+        f(&page_info(pgid, &p), depth);
+
+        // Recursively loop over children.
+        if p.flags() & BRANCH_PAGE_FLAG != 0 {
+            for i in 0..p.count() as usize {
+                let elem = p.branch_element(i)?;
+                self.for_each_page(elem.pgid(), depth + 1, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// ForEachPageInBucket executes a function for every page of `bucket` and
+    /// of the buckets nested in it, with its depth below the root of `bucket`
+    /// and the id of the page referring to it: the branch page above it, or
+    /// the leaf page holding the header of the bucket it is the root of. The
+    /// root of `bucket` is passed 0.
+    ///
+    /// Inline buckets live in the page of their parent and have no pages of
+    /// their own. The overflow pages of a page are not visited separately,
+    /// see `PageInfo::overflow_count`.
+    ///
+    /// Returns `Error::BucketNotFound` if `bucket` was opened by another
+    /// transaction.
+    pub fn for_each_page_in_bucket<F>(&self, bucket: &Bucket<'_>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&PageInfo, usize, u64),
+    {
+        if self.closed {
+            return Err(Error::TxClosed);
+        } else if !std::ptr::eq(bucket.tx(), self) {
+            return Err(Error::BucketNotFound);
+        }
+        let (header, page) = bucket.header();
+        self.walk_bucket(&header, page, 0, 0, &mut f)
+    }
+
+    /// ReachablePages returns the ids of every page reachable from the meta
+    /// pages, in ascending order: both meta pages, the freelist unless it was
+    /// not persisted, and the
+    /// pages of every bucket, overflow pages included. On a consistent
+    /// database every page below the high water mark is either reachable,
+    /// free or pending.
+    pub fn reachable_pages(&self) -> Result<Vec<u64>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let mut ids = vec![0, 1];
+        let mut mark = |info: &PageInfo| {
+            ids.extend(info.id..=info.id + info.overflow_count as Pgid);
+        };
+        let meta = self.meta.get();
+        if meta.freelist != PGID_NO_FREELIST {
+            mark(&page_info(meta.freelist, &self.raw_page(meta.freelist)?));
+        }
+        self.walk_bucket(&meta.root, None, 0, 0, &mut |info, _, _| mark(info))?;
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// unreachable_pages returns the ids of the pages below the high water
+    /// mark that are not reachable from the meta pages, in ascending order.
+    pub(crate) fn unreachable_pages(&self) -> Result<Vec<Pgid>> {
+        let reachable = self.reachable_pages()?;
+        Ok((2..self.meta.get().pgid)
+            .filter(|id| reachable.binary_search(id).is_err())
+            .collect())
+    }
+
+    /// walk_bucket calls `f` for every page of the bucket with the given
+    /// header, whose root is the inline page `inline` when the bucket is
+    /// inline, and of the buckets nested in it.
+    fn walk_bucket(
+        &self,
+        header: &InBucket,
+        inline: Option<&[u8]>,
+        depth: usize,
+        parent: Pgid,
+        f: &mut PageFunc<'_>,
+    ) -> Result<()> {
+        match inline {
+            Some(page) => self.walk_leaf(&Page::new(page), parent, depth, f),
+            None if header.root != 0 => self.walk_page(header.root, depth, parent, f),
+            None => Ok(()),
+        }
+    }
+
+    fn walk_page(
+        &self,
+        pgid: Pgid,
+        depth: usize,
+        parent: Pgid,
+        f: &mut PageFunc<'_>,
+    ) -> Result<()> {
+        let p = self.raw_page(pgid)?;
+        f(&page_info(pgid, &p), depth, parent);
+        if p.flags() & BRANCH_PAGE_FLAG != 0 {
+            for i in 0..p.count() as usize {
+                self.walk_page(p.branch_element(i)?.pgid(), depth + 1, pgid, f)?;
+            }
+        } else if p.flags() & LEAF_PAGE_FLAG != 0 {
+            self.walk_leaf(&p, pgid, depth, f)?;
+        }
+        Ok(())
+    }
+
+    /// walk_leaf walks the buckets nested in the leaf page `p`, which is
+    /// `pgid` or the inline page of a bucket in `pgid`.
+    fn walk_leaf(
+        &self,
+        p: &Page<'_>,
+        pgid: Pgid,
+        depth: usize,
+        f: &mut PageFunc<'_>,
+    ) -> Result<()> {
+        for i in 0..p.count() as usize {
+            let e = p.leaf_element(i)?;
+            if e.flags() & BUCKET_LEAF_FLAG != 0 {
+                let (child, inline) = read_bucket_value(pgid, e.value())?;
+                self.walk_bucket(&child, inline, depth + 1, pgid, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// raw_page returns a reference to the page with a given id.
+    /// If page has been written to then a temporary buffered page is returned.
+    pub(crate) fn raw_page(&self, id: Pgid) -> Result<Page<'_>> {
+        // Check the dirty pages first.
+        if let Some(buf) = self.pages.borrow().get(&id) {
+            // Safety: dirty page buffers are heap allocations that are only
+            // released or rewritten while the transaction is borrowed mutably.
+            let buf = unsafe { std::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+            return Ok(Page::new(buf));
+        }
+
+        // Otherwise return directly from the mmap.
+        self.mmap_page(id)
+    }
+
+    /// mmap_page returns the committed page with a given id from the mapping
+    /// this transaction started on, ignoring dirty pages.
+    pub(crate) fn mmap_page(&self, id: Pgid) -> Result<Page<'_>> {
+        self.mmap.page(self.db.page_size, id)
+    }
+
+    /// allocate returns a contiguous block of memory starting at a given page.
+    /// Pages are taken from the freelist first, then from the high water mark,
+    /// which only moves in this transaction's meta until it commits; the file
+    /// itself is grown by commit.
+    pub(crate) fn allocate(&self, count: usize) -> Result<Pgid> {
+        let page_size = self.db.page_size;
+        let size = alloc_size(count, page_size)?;
+        let mut buf = self.db.page_buf(count);
+
+        // Use pages from the freelist if they are available.
+        let mut id = lock(&self.db.freelist).allocate(self.meta.get().txid, count);
+        if id == 0 {
+            // Resize mmap() if we're at the end.
+            id = self.meta.get().pgid;
+            let minsz = mmap_offset(id + count as Pgid + 1, page_size)?;
+            if minsz >= self.db.mmap().len() {
+                self.db.remap(minsz)?;
+            }
+
+            // Move the page id high water mark.
+            let mut meta = self.meta.get();
+            meta.pgid += count as Pgid;
+            self.meta.set(meta);
+        }
+
+        let mut p = PageMut::new(&mut buf);
+        p.set_id(id);
+        p.set_overflow((count - 1) as u32);
+        self.pages.borrow_mut().insert(id, buf);
+
+        // Update statistics.
+        self.stats.inc_page_count(1);
+        self.stats.inc_page_alloc(size as i64);
+
+        Ok(id)
+    }
+
+    /// with_dirty_page calls `f` with the dirty page allocated at `id`.
+    pub(crate) fn with_dirty_page<R>(
+        &self,
+        id: Pgid,
+        f: impl FnOnce(&mut PageMut<'_>) -> R,
+    ) -> Result<R> {
+        let mut pages = self.pages.borrow_mut();
+        let buf = pages
+            .get_mut(&id)
+            .ok_or_else(|| Error::corrupted(id, "dirty page not allocated"))?;
+        Ok(f(&mut PageMut::new(buf)))
+    }
+
+    /// free_page releases the page with the given id to the freelist. It
+    /// becomes reusable once no open transaction can read it anymore.
+    pub(crate) fn free_page(&self, id: Pgid) -> Result<()> {
+        let p = self.raw_page(id)?;
+        lock(&self.db.freelist).free(self.meta.get().txid, p)
+    }
+
+    /// commit_freelist frees the current freelist page and writes the freelist
+    /// into newly allocated pages. With no_freelist_sync the freelist is only
+    /// kept in memory, and the meta page records that it was not written.
+    fn commit_freelist(&mut self) -> Result<()> {
+        let freelist = self.meta.get().freelist;
+        if freelist != PGID_NO_FREELIST {
+            self.free_page(freelist)?;
+        }
+        if self.db.no_freelist_sync {
+            let mut meta = self.meta.get();
+            meta.freelist = PGID_NO_FREELIST;
+            self.meta.set(meta);
+            return Ok(());
+        }
+
+        // Allocate new pages for the new free list. This will overestimate
+        // the size of the freelist but not underestimate the size (which would be bad).
+        let size = lock(&self.db.freelist).size();
+        let pgid = self.allocate(size / self.db.page_size + 1)?;
+        self.with_dirty_page(pgid, |p| lock(&self.db.freelist).write(p))?;
+
+        let mut meta = self.meta.get();
+        meta.freelist = pgid;
+        self.meta.set(meta);
+        Ok(())
+    }
+
+    /// write writes any dirty pages to disk.
+    fn write(&mut self) -> Result<()> {
+        // Sort pages by id.
+        let mut pages: Vec<_> = self.pages.get_mut().iter().collect();
+        pages.sort_by_key(|(&id, _)| id);
+
+        // Write pages to disk in order.
+        for (&id, buf) in pages {
+            let mut offset = id * self.db.page_size as u64;
+
+            // Write out page in "max write size" sized chunks.
+            for chunk in buf.chunks(MAX_WRITE_SIZE) {
+                self.db.write_at(chunk, offset)?;
+                offset += chunk.len() as u64;
+
+                // Update statistics.
+                self.stats.inc_write(1);
+            }
+        }
+
+        // Ignore file sync if flag is set on DB.
+        if !self.db.no_sync {
+            self.db.ops.sync(&self.db.file, self.db.sync_mode)?;
+        }
+
+        // The pages are on disk now; release their buffers.
+        self.release_pages();
+        Ok(())
+    }
+
+    /// release_pages drops the dirty pages, returning their buffers to the page pool.
+    fn release_pages(&mut self) {
+        for (_, buf) in self.pages.get_mut().drain() {
+            self.db.put_page_buf(buf);
+        }
+    }
+
+    /// write_meta writes the meta to the disk.
+    fn write_meta(&mut self) -> Result<()> {
+        // Create a temporary buffer for the meta page.
+        let mut buf = self.db.page_buf(1);
+        let mut p = PageMut::new(&mut buf);
+        self.meta.get().write(&mut p);
+        let offset = p.as_page().id() * self.db.page_size as u64;
+
+        // Write the meta page to file. Transactions copy the meta page while
+        // holding the meta lock, so holding it here keeps them from reading
+        // a page being written.
+        let written = {
+            let _metalock = lock(&self.db.metalock);
+            self.db.write_at(&buf, offset)
+        };
+        self.db.put_page_buf(buf);
+        written?;
+        if !self.db.no_sync {
+            self.db.ops.sync(&self.db.file, self.db.sync_mode)?;
+        }
+
+        // Update statistics.
+        self.stats.inc_write(1);
+
+        Ok(())
+    }
+}
+
+/// O_DIRECT requests unbuffered writes where the platform supports it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_DIRECT: i32 = 0;
+
+/// DIRECT_ALIGN is the memory and size alignment used for O_DIRECT writes.
+const DIRECT_ALIGN: usize = 4096;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; DIRECT_ALIGN]);
+
+/// AlignedWriter buffers writes into DIRECT_ALIGN aligned chunks, as required
+/// by files opened with O_DIRECT.
+struct AlignedWriter {
+    file: File,
+    blocks: Vec<AlignedBlock>,
+    len: usize,
+}
+
+impl AlignedWriter {
+    fn new(file: File) -> AlignedWriter {
+        AlignedWriter {
+            file,
+            blocks: vec![AlignedBlock([0; DIRECT_ALIGN]); MAX_WRITE_SIZE / DIRECT_ALIGN],
+            len: 0,
+        }
+    }
+
+    /// write_buffered writes out the buffered bytes; `len` must be aligned.
+    fn write_buffered(&mut self) -> io::Result<()> {
+        self.file.write_all(&bytes(&mut self.blocks)[..self.len])?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// finish writes any remaining data and returns the file. The database
+    /// size is a multiple of the page size, so the tail is aligned unless the
+    /// page size is smaller than DIRECT_ALIGN.
+    fn finish(mut self) -> io::Result<File> {
+        let aligned = self.len - self.len % DIRECT_ALIGN;
+        let tail = bytes(&mut self.blocks)[aligned..self.len].to_vec();
+        self.len = aligned;
+        self.write_buffered()?;
+        if !tail.is_empty() {
+            // Write the unaligned tail through the page cache.
+            let fd = self.file.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !O_DIRECT) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.file.write_all(&tail)?;
+        }
+        Ok(self.file)
+    }
+}
+
+/// bytes views aligned blocks as one contiguous byte slice.
+fn bytes(blocks: &mut [AlignedBlock]) -> &mut [u8] {
+    let len = blocks.len() * DIRECT_ALIGN;
+    // Safety: the blocks are contiguous, padding-free byte arrays.
+    unsafe { std::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut u8, len) }
+}
+
+impl Write for AlignedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let cap = self.blocks.len() * DIRECT_ALIGN;
+        let n = data.len().min(cap - self.len);
+        bytes(&mut self.blocks)[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        if self.len == cap {
+            self.write_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// BucketFunc is the type of the function called by `for_each_recursive` with
+/// the path and contents of every bucket.
+type BucketFunc<'a> = dyn FnMut(&[&[u8]], &Bucket<'_>) -> Result<()> + 'a;
+
+/// walk_buckets calls `f` for every bucket nested in `b`, extending `path`
+/// with the name of each bucket while its own nested buckets are visited.
+fn walk_buckets<'tx>(
+    b: Bucket<'tx>,
+    path: &mut Vec<&'tx [u8]>,
+    f: &mut BucketFunc<'_>,
+) -> Result<()> {
+    b.for_each_bucket(|name| {
+        let child = b.bucket(name)?;
+        path.push(name);
+        f(path, &child)?;
+        walk_buckets(child, path, f)?;
+        path.pop();
+        Ok(())
+    })
+}
+
+/// PageFunc is the type of the function called by `for_each_page_in_bucket`
+/// with every page, its depth and the id of the page referring to it.
+type PageFunc<'a> = dyn FnMut(&PageInfo, usize, u64) + 'a;
+
+/// page_info describes the page `p`, whose id is `id`.
+fn page_info(id: Pgid, p: &Page<'_>) -> PageInfo {
+    PageInfo {
+        id,
+        typ: p.typ(),
+        count: p.count() as usize,
+        overflow_count: p.overflow() as usize,
+    }
+}
+
+impl Drop for Tx {
+    /// Drop rolls back a transaction that was neither committed nor rolled
+    /// back. Read-only transactions are routinely dropped that way, but a
+    /// dropped write transaction loses its changes, which is worth a warning.
+    fn drop(&mut self) {
+        if self.writable && !self.closed {
+            self.db.logger.warn(format_args!(
+                "rolling back write transaction {} dropped without commit or rollback",
+                self.meta.get().txid
+            ));
+        }
+        self.rollback_internal();
+    }
+}
+
+/// TxStats represents statistics about the actions performed by the transaction.
+///
+/// Every counter is an atomic so that a transaction's stats can be merged into
+/// the database totals without a lock. Use the getter methods to read them and
+/// the `inc_*` helpers to update them.
+#[derive(Debug, Default)]
+pub struct TxStats {
+    // Page statistics.
+    /// number of page allocations
+    page_count: AtomicI64,
+    /// total bytes allocated
+    page_alloc: AtomicI64,
+
+    // Cursor statistics.
+    /// number of cursors created
+    cursor_count: AtomicI64,
+
+    // Node statistics
+    /// number of node allocations
+    node_count: AtomicI64,
+    /// number of node dereferences. Always 0: a transaction keeps the
+    /// mapping it started on across remaps, so its nodes never have to copy
+    /// their keys and values out of it.
+    node_deref: AtomicI64,
+
+    // Rebalance statistics.
+    /// number of node rebalances
+    rebalance: AtomicI64,
+    /// total time spent rebalancing, in nanoseconds
+    rebalance_time: AtomicI64,
+
+    // Split/Spill statistics.
+    /// number of nodes split
+    split: AtomicI64,
+    /// number of nodes spilled
+    spill: AtomicI64,
+    /// total time spent spilling, in nanoseconds
+    spill_time: AtomicI64,
+
+    // Write statistics.
+    /// number of writes performed
+    write: AtomicI64,
+    /// total time spent writing to disk, in nanoseconds
+    write_time: AtomicI64,
+}
+
+macro_rules! tx_stats_counters {
+    ($($field:ident, $inc:ident;)*) => {
+        $(
+            #[doc = concat!("Returns the `", stringify!($field), "` counter.")]
+            pub fn $field(&self) -> i64 {
+                self.$field.load(Ordering::Relaxed)
+            }
+
+            #[doc = concat!("Increases the `", stringify!($field), "` counter by `delta`.")]
+            pub fn $inc(&self, delta: i64) {
+                self.$field.fetch_add(delta, Ordering::Relaxed);
+            }
+        )*
+    };
+}
+
+macro_rules! tx_stats_durations {
+    ($($field:ident, $inc:ident;)*) => {
+        $(
+            #[doc = concat!("Returns the `", stringify!($field), "` total.")]
+            pub fn $field(&self) -> Duration {
+                Duration::from_nanos(self.$field.load(Ordering::Relaxed).max(0) as u64)
+            }
+
+            #[doc = concat!("Increases the `", stringify!($field), "` total by `delta`.")]
+            pub fn $inc(&self, delta: Duration) {
+                self.$field.fetch_add(delta.as_nanos() as i64, Ordering::Relaxed);
+            }
+        )*
+    };
+}
+
+macro_rules! tx_stats_fields {
+    ($m:ident, $self:ident, $other:ident) => {
+        $m!($self, $other, page_count);
+        $m!($self, $other, page_alloc);
+        $m!($self, $other, cursor_count);
+        $m!($self, $other, node_count);
+        $m!($self, $other, node_deref);
+        $m!($self, $other, rebalance);
+        $m!($self, $other, rebalance_time);
+        $m!($self, $other, split);
+        $m!($self, $other, spill);
+        $m!($self, $other, spill_time);
+        $m!($self, $other, write);
+        $m!($self, $other, write_time);
+    };
+}
+
+impl TxStats {
+    tx_stats_counters! {
+        page_count, inc_page_count;
+        page_alloc, inc_page_alloc;
+        cursor_count, inc_cursor_count;
+        node_count, inc_node_count;
+        node_deref, inc_node_deref;
+        rebalance, inc_rebalance;
+        split, inc_split;
+        spill, inc_spill;
+        write, inc_write;
+    }
+
+    tx_stats_durations! {
+        rebalance_time, inc_rebalance_time;
+        spill_time, inc_spill_time;
+        write_time, inc_write_time;
+    }
+
+    /// Sub calculates and returns the difference between two sets of transaction stats.
+    /// This is useful when obtaining stats at two different points and time and
+    /// you need the performance counters that occurred within that time span.
+    pub fn sub(&self, other: &TxStats) -> TxStats {
+        let diff = TxStats::default();
+        macro_rules! sub_field {
+            ($self:ident, $other:ident, $f:ident) => {
+                diff.$f.store(
+                    $self.$f.load(Ordering::Relaxed) - $other.$f.load(Ordering::Relaxed),
+                    Ordering::Relaxed,
+                );
+            };
+        }
+        tx_stats_fields!(sub_field, self, other);
+        diff
+    }
+
+    /// Add accumulates the counters of `other` into `self`.
+    pub fn add(&self, other: &TxStats) {
+        macro_rules! add_field {
+            ($self:ident, $other:ident, $f:ident) => {
+                $self
+                    .$f
+                    .fetch_add($other.$f.load(Ordering::Relaxed), Ordering::Relaxed);
+            };
+        }
+        tx_stats_fields!(add_field, self, other);
+    }
+
+    /// Reset zeroes every counter.
+    pub fn reset(&self) {
+        macro_rules! reset_field {
+            ($self:ident, $other:ident, $f:ident) => {
+                $self.$f.store(0, Ordering::Relaxed);
+            };
+        }
+        tx_stats_fields!(reset_field, self, self);
+    }
+}
+
+impl Clone for TxStats {
+    fn clone(&self) -> TxStats {
+        let stats = TxStats::default();
+        stats.add(self);
+        stats
+    }
+}
+
+impl PartialEq for TxStats {
+    fn eq(&self, other: &TxStats) -> bool {
+        let mut eq = true;
+        macro_rules! eq_field {
+            ($self:ident, $other:ident, $f:ident) => {
+                eq &= $self.$f.load(Ordering::Relaxed) == $other.$f.load(Ordering::Relaxed);
+            };
+        }
+        tx_stats_fields!(eq_field, self, other);
+        eq
+    }
+}
+
+impl Eq for TxStats {}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+mod tests {
+    use super::*;
+    use crate::db::{Options, DB};
+    use crate::logger::tests::Recorder;
+    use crate::ops::tests::{FailpointOps, Op};
+    use crate::ops::{DbOps, FileOps, SyncMode};
+    use crate::page::PAGE_HEADER_SIZE;
+    use std::fs::File;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    /// WriteBudget fails every write once `budget` writes have gone through.
+    struct WriteBudget(Arc<AtomicUsize>);
+
+    impl DbOps for WriteBudget {
+        fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+            let left = self.0.load(Ordering::SeqCst);
+            if left == 0 {
+                return Err(io::Error::other("injected write failure"));
+            }
+            self.0.store(left - 1, Ordering::SeqCst);
+            FileOps.write_at(file, buf, offset)
+        }
+
+        fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            FileOps.read_at(file, buf, offset)
+        }
+
+        fn sync(&self, file: &File, mode: SyncMode) -> io::Result<()> {
+            FileOps.sync(file, mode)
+        }
+
+        fn truncate(&self, file: &File, size: u64) -> io::Result<()> {
+            FileOps.truncate(file, size)
+        }
+
+        fn allocate(&self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+            FileOps.allocate(file, offset, len)
+        }
+
+        fn madvise(&self, mmap: &Mmap, advice: i32) -> io::Result<()> {
+            FileOps.madvise(mmap, advice)
+        }
+    }
+
+    fn committed_txid(db: &DB) -> u64 {
+        let mut tx = db.begin(false).unwrap();
+        let id = tx.id();
+        tx.rollback().unwrap();
+        id
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_persists_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let db = DB::open(&path, Options::default()).unwrap();
+        assert_eq!(committed_txid(&db), 1);
+        let mut tx = db.begin(true).unwrap();
+        let pgid = tx.allocate(2).unwrap();
+        assert_eq!(pgid, 4);
+        tx.commit().unwrap();
+        assert!(matches!(tx.commit(), Err(Error::TxClosed)));
+        assert_eq!(committed_txid(&db), 2);
+        db.close().unwrap();
+        drop(db);
+
+        let db = DB::open(&path, Options::default()).unwrap();
+        assert_eq!(committed_txid(&db), 2);
+        let tx = db.begin(false).unwrap();
+        // Meta, meta, freelist, root, the two allocated pages and the new freelist.
+        assert_eq!(tx.size(), 7 * tx.db.page_size as u64);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn global_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let db = DB::open(&path, Options::default()).unwrap();
+        db.update(|tx| {
+            assert_eq!(tx.global_sequence()?, 0);
+            let b = tx.create_bucket(b"widgets")?;
+            assert_eq!(tx.next_global_sequence()?, 1);
+            assert_eq!(b.next_sequence()?, 1);
+            assert_eq!(b.next_sequence()?, 2);
+            assert_eq!(tx.next_global_sequence()?, 2);
+            assert_eq!(b.sequence(), 2);
+            Ok(())
+        })
+        .unwrap();
+
+        // A rolled back increment is never observed.
+        let mut tx = db.begin(true).unwrap();
+        assert_eq!(tx.next_global_sequence().unwrap(), 3);
+        tx.rollback().unwrap();
+
+        db.view(|tx| {
+            assert_eq!(tx.global_sequence()?, 2);
+            assert!(matches!(
+                tx.next_global_sequence(),
+                Err(Error::TxNotWritable)
+            ));
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+        drop(db);
+
+        let db = DB::open(&path, Options::default()).unwrap();
+        db.update(|tx| {
+            assert_eq!(tx.next_global_sequence()?, 3);
+            assert_eq!(tx.bucket(b"widgets")?.sequence(), 2);
+            // The sequence takes no bucket of its own.
+            assert_eq!(tx.bucket_names()?, vec![b"widgets".to_vec()]);
+            let mut n = 0;
+            tx.for_each_recursive(|_, _| {
+                n += 1;
+                Ok(())
+            })?;
+            assert_eq!(n, 1);
+            Ok(())
+        })
+        .unwrap();
+        db.view(|tx| {
+            assert!(tx.check().is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pending_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            page_size: 4096,
+            ..Options::default()
+        };
+        let db = DB::open(dir.path().join("db"), options).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+
+        for fill_percent in [1.0, crate::bucket::DEFAULT_FILL_PERCENT] {
+            let mut tx = db.begin(true).unwrap();
+            assert_eq!((tx.pending_bytes(), tx.pending_pages()), (0, 0));
+            let b = tx.bucket(b"widgets").unwrap();
+            b.set_fill_percent(fill_percent);
+            for i in 0..2000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+            }
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+            }
+            let before = tx.pending_bytes();
+            for i in 1000..1500u32 {
+                b.delete(&i.to_be_bytes()).unwrap();
+            }
+            // Each element takes a leaf header, its key and its value.
+            assert_eq!(before - tx.pending_bytes(), 500 * (16 + 4 + 100));
+
+            let pending = tx.pending_pages();
+            tx.commit().unwrap();
+            // Spilling fills pages up to the fill percent, give or take an
+            // element, and adds branch pages and the freelist.
+            let allocated = tx.stats().page_alloc() as usize / 4096;
+            let most = pending as f64 / fill_percent * 1.1 + 4.0;
+            assert!(
+                pending < allocated && allocated as f64 <= most,
+                "fill percent {}: {} pending pages, {} allocated",
+                fill_percent,
+                pending,
+                allocated
+            );
+            db.update(|tx| {
+                tx.delete_bucket(b"widgets")?;
+                tx.create_bucket(b"widgets").map(|_| ())
+            })
+            .unwrap();
+        }
+
+        // Deleting a bucket drops its changes.
+        let tx = db.begin(true).unwrap();
+        let b = tx.bucket(b"widgets").unwrap();
+        for i in 0..100u32 {
+            b.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        assert!(tx.pending_bytes() > 100 * 120);
+        tx.delete_bucket(b"widgets").unwrap();
+        assert!(tx.pending_bytes() < 4096);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_read_only_tx() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let mut tx = db.begin(false).unwrap();
+        assert!(matches!(tx.commit(), Err(Error::TxNotWritable)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn failed_write_keeps_committed_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        // Two data pages plus the freelist page, then the meta page.
+        for fail_at in 0..4 {
+            let _ = std::fs::remove_file(&path);
+            let budget = Arc::new(AtomicUsize::new(usize::MAX));
+            let db = DB::open_with_ops(
+                &path,
+                Options::default(),
+                Arc::new(WriteBudget(budget.clone())),
+            )
+            .unwrap();
+
+            budget.store(fail_at, Ordering::SeqCst);
+            let mut tx = db.begin(true).unwrap();
+            tx.allocate(1).unwrap();
+            tx.allocate(1).unwrap();
+            assert!(
+                matches!(tx.commit(), Err(Error::Io(_))),
+                "fail_at {}",
+                fail_at
+            );
+            assert!(matches!(tx.rollback(), Err(Error::TxClosed)));
+            assert_eq!(committed_txid(&db), 1);
+
+            // The writer lock was released and the database is still usable.
+            budget.store(usize::MAX, Ordering::SeqCst);
+            db.update(|_| Ok(())).unwrap();
+            assert_eq!(committed_txid(&db), 2);
+            db.close().unwrap();
+            drop(db);
+
+            // Nothing of the failed transaction is visible after a reopen.
+            let db = DB::open(&path, Options::default()).unwrap();
+            assert_eq!(committed_txid(&db), 2);
+            let tx = db.begin(true).unwrap();
+            lock(&tx.db.freelist).check().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_updates_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        let mut tx = db.begin(true).unwrap();
+        tx.allocate(3).unwrap();
+        tx.commit().unwrap();
+        db.view(|_| Ok(())).unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.tx_n, 1);
+        assert_eq!(stats.open_tx_n, 0);
+        // A three-page run and the freelist page, written as two runs plus the meta.
+        assert_eq!(stats.tx_stats.page_count(), 2);
+        assert_eq!(stats.tx_stats.write(), 3);
+        assert!(stats.tx_stats.write_time() > Duration::from_nanos(0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats_track_allocations() {
+        let dir = tempfile::tempdir().unwrap();
+        let ops = FailpointOps::new();
+        let db =
+            DB::open_with_ops(&dir.path().join("db"), Options::default(), ops.clone()).unwrap();
+        let before = db.stats().tx_stats;
+
+        let mut tx = db.begin(true).unwrap();
+        let b = tx.create_bucket(b"widgets").unwrap();
+        for i in 0..10u32 {
+            b.put(&i.to_be_bytes(), &[0; 1000]).unwrap();
+        }
+        let pending = tx.stats();
+        assert_eq!(pending.page_alloc(), 0);
+        assert!(pending.node_count() > 0);
+        assert!(pending.cursor_count() >= 10);
+        ops.take();
+        tx.commit().unwrap();
+
+        // Everything written but the meta page is a page allocated by the
+        // transaction.
+        let writes = ops.take();
+        let (meta, pages) = writes
+            .iter()
+            .filter_map(|op| match op {
+                Op::WriteAt { len, .. } => Some(*len as i64),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .split_last()
+            .map(|(meta, pages)| (*meta, pages.iter().sum::<i64>()))
+            .unwrap();
+        assert_eq!(meta, tx.db.page_size as i64);
+        let stats = tx.stats();
+        assert_eq!(stats.page_alloc(), pages);
+        assert!(stats.page_count() >= 2);
+        assert!(stats.spill() > 0);
+        assert_eq!(stats.node_deref(), 0);
+        assert_eq!(db.stats().tx_stats.sub(&before), stats);
+
+        // Rolled back transactions are merged too.
+        let before = db.stats().tx_stats;
+        let mut tx = db.begin(true).unwrap();
+        tx.bucket(b"widgets").unwrap().cursor().first().unwrap();
+        let stats = tx.stats();
+        assert!(stats.cursor_count() > 0);
+        tx.rollback().unwrap();
+        assert_eq!(db.stats().tx_stats.sub(&before), stats);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn rollback_closes_tx() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        let mut rtx = db.begin(false).unwrap();
+        assert_eq!(db.stats().open_tx_n, 1);
+        rtx.rollback().unwrap();
+        assert!(matches!(rtx.rollback(), Err(Error::TxClosed)));
+        assert_eq!(db.stats().open_tx_n, 0);
+
+        // A dropped reader is rolled back too.
+        drop(db.begin(false).unwrap());
+        assert_eq!(db.stats().open_tx_n, 0);
+        assert_eq!(db.stats().tx_n, 2);
+
+        let mut tx = db.begin(true).unwrap();
+        tx.commit().unwrap();
+        assert!(matches!(tx.rollback(), Err(Error::TxClosed)));
+
+        let mut tx = db.begin(true).unwrap();
+        tx.rollback().unwrap();
+        assert!(matches!(tx.rollback(), Err(Error::TxClosed)));
+        assert!(matches!(tx.commit(), Err(Error::TxClosed)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn drop_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = Arc::new(Recorder::default());
+        let db = DB::open(
+            dir.path().join("db"),
+            Options {
+                logger: Some(logger.clone()),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        logger.take();
+
+        // A writer dropped mid-way releases the writer lock and loses its
+        // writes.
+        let tx = db.begin(true).unwrap();
+        let id = tx.id();
+        let b = tx.bucket(b"widgets").unwrap();
+        b.put(b"foo", b"changed").unwrap();
+        for i in 0..1000u32 {
+            b.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        tx.create_bucket(b"gadgets").unwrap();
+        drop(tx);
+        let warnings: Vec<_> = logger
+            .take()
+            .into_iter()
+            .filter(|(level, _)| *level == "warn")
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![(
+                "warn",
+                format!(
+                    "rolling back write transaction {} dropped without commit or rollback",
+                    id
+                )
+            )]
+        );
+
+        let tx = db.begin(true).unwrap();
+        assert_eq!(tx.id(), id);
+        assert_eq!(
+            tx.bucket(b"widgets").unwrap().get(b"foo").unwrap(),
+            Some(&b"bar"[..])
+        );
+        assert_eq!(tx.bucket(b"widgets").unwrap().stats().unwrap().key_n, 1);
+        assert!(matches!(tx.bucket(b"gadgets"), Err(Error::BucketNotFound)));
+        assert!(tx.check().is_empty());
+        drop(tx);
+
+        // Managed and finished transactions are not rolled back again.
+        logger.take();
+        db.view(|_| Ok(())).unwrap();
+        db.update(|_| Ok(())).unwrap();
+        assert!(db.update(|_| Err(Error::TxNotWritable)).is_err());
+        let mut tx = db.begin(true).unwrap();
+        tx.rollback().unwrap();
+        drop(tx);
+        assert!(logger.take().iter().all(|(level, _)| *level != "warn"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn rollback_returns_allocated_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        // Pages taken from the high water mark are handed out again.
+        let mut tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(2).unwrap(), 4);
+        tx.rollback().unwrap();
+        let mut tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(2).unwrap(), 4);
+        tx.commit().unwrap();
+
+        // The commit freed the original freelist page, so the next writer
+        // allocates it from the freelist; a rollback must put it back.
+        let mut tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(1).unwrap(), 2);
+        tx.rollback().unwrap();
+        let tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(1).unwrap(), 2);
+        drop(tx);
+
+        let tx = db.begin(true).unwrap();
+        assert_eq!(tx.allocate(1).unwrap(), 2);
+        lock(&tx.db.freelist).check().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn allocate_moves_high_water_mark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = Options {
+            initial_mmap_size: 1 << 20,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options).unwrap();
+        let page_size = db.0.page_size;
+        let mmap = db.0.mmap();
+        let file_len = std::fs::metadata(&path).unwrap().len();
+
+        // The high water mark moves in the transaction's meta only, and the
+        // initial mapping is large enough not to be replaced.
+        let mut tx = db.begin(true).unwrap();
+        let pages = (1 << 20) / page_size / 2;
+        assert_eq!(tx.allocate(pages).unwrap(), 4);
+        assert_eq!(tx.meta.get().pgid, 4 + pages as Pgid);
+        assert_eq!(tx.stats().page_count(), 1);
+        assert_eq!(tx.stats().page_alloc(), (pages * page_size) as i64);
+        assert!(Arc::ptr_eq(&mmap, &db.0.mmap()));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+        tx.commit().unwrap();
+
+        // The file grows on commit, the mapping still does not change.
+        assert!(Arc::ptr_eq(&mmap, &db.0.mmap()));
+        let high_water = db.begin(false).unwrap().meta.get().pgid;
+        let file_len = std::fs::metadata(&path).unwrap().len();
+        assert!(file_len >= high_water * page_size as u64);
+
+        // Going past the mapping remaps it.
+        let mut tx = db.begin(true).unwrap();
+        tx.allocate(pages * 2).unwrap();
+        assert!(db.0.mmap().len() > mmap.len());
+        tx.commit().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > file_len);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn no_grow_sync() {
+        for no_grow_sync in [false, true].iter().copied() {
+            let dir = tempfile::tempdir().unwrap();
+            let ops = FailpointOps::new();
+            let options = Options {
+                no_grow_sync,
+                ..Options::default()
+            };
+            let db = DB::open_with_ops(&dir.path().join("db"), options, ops.clone()).unwrap();
+            ops.take();
+
+            let mut tx = db.begin(true).unwrap();
+            tx.allocate(16).unwrap();
+            tx.commit().unwrap();
+
+            // Growing the file truncates it, then syncs unless told not to.
+            let ops = ops.take();
+            let at = ops
+                .iter()
+                .position(|op| matches!(op, Op::Truncate(_)))
+                .unwrap();
+            let synced = matches!(ops[at + 1], Op::Sync(_));
+            assert_eq!(synced, !no_grow_sync, "{:?}", ops);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn readers_survive_growth() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", b"bar"))
+            .unwrap();
+        let initial = db.0.mmap().len();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (db, done) = (db.clone(), done.clone());
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        db.view(|tx| {
+                            let b = tx.bucket(b"widgets").unwrap();
+                            assert_eq!(b.get(b"foo")?, Some(&b"bar"[..]));
+                            let n = b.range(..).count();
+                            assert!(n >= 1);
+                            Ok(())
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        // Write well past the initial mapping, remapping it several times.
+        for i in 0..64u32 {
+            db.update(|tx| {
+                let b = tx.bucket(b"widgets").unwrap();
+                for j in 0..16u32 {
+                    b.put(&(i * 16 + j).to_be_bytes(), &[0; 1024])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert!(db.0.mmap().len() > initial);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn backup_during_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        // Commit a page with recognizable contents.
+        let mut tx = db.begin(true).unwrap();
+        let pgid = tx.allocate(1).unwrap();
+        tx.pages.get_mut().get_mut(&pgid).unwrap()[PAGE_HEADER_SIZE..].fill(0xab);
+        tx.commit().unwrap();
+
+        let snapshot = db.begin(false).unwrap();
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    let mut tx = db.begin(true).unwrap();
+                    tx.allocate(3).unwrap();
+                    tx.commit().unwrap();
+                }
+            })
+        };
+        let backup = dir.path().join("backup");
+        snapshot.copy_file(&backup, 0o600).unwrap();
+        writer.join().unwrap();
+
+        let copy = std::fs::read(&backup).unwrap();
+        assert_eq!(copy.len() as u64, snapshot.size());
+
+        let db2 = DB::open(&backup, Options::default()).unwrap();
+        let tx = db2.begin(false).unwrap();
+        assert_eq!(tx.id(), snapshot.id());
+        assert_eq!(tx.size(), snapshot.size());
+        assert_eq!(
+            tx.raw_page(pgid).unwrap().data(),
+            snapshot.raw_page(pgid).unwrap().data()
+        );
+        assert!(tx.raw_page(pgid).unwrap().data().iter().all(|&b| b == 0xab));
+        drop(tx);
+        lock(&db2.begin(true).unwrap().db.freelist).check().unwrap();
+
+        // The convenience wrapper produces the latest committed state.
+        let latest = dir.path().join("latest");
+        db.backup(&latest).unwrap();
+        let db3 = DB::open(&latest, Options::default()).unwrap();
+        assert_eq!(db3.begin(false).unwrap().id(), snapshot.id() + 20);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn page_info() {
+        use crate::tx_check::tests::{build, write_branch, write_freelist, write_leaf};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = build(&path, 7, |buf| {
+            write_freelist(buf, 2, &[6]);
+            write_branch(buf, 3, &[(b"a", 4), (b"m", 5)]);
+            write_leaf(buf, 4, &[(0, b"a", b"1")]);
+            write_leaf(buf, 5, &[(0, b"m", b"2"), (0, b"n", b"3")]);
+        });
+
+        let tx = db.begin(false).unwrap();
+        let types: Vec<_> = (0..7).map(|id| tx.page(id).unwrap().unwrap().typ).collect();
+        assert_eq!(
+            types,
+            ["meta", "meta", "freelist", "branch", "leaf", "leaf", "free"]
+        );
+        assert_eq!(
+            tx.page(5).unwrap(),
+            Some(PageInfo {
+                id: 5,
+                typ: "leaf".to_string(),
+                count: 2,
+                overflow_count: 0,
+            })
+        );
+        assert_eq!(tx.page(7).unwrap(), None);
+
+        let mut walked = Vec::new();
+        tx.for_each_page(3, 0, &mut |info: &PageInfo, depth| {
+            walked.push((info.id, info.typ.clone(), depth))
+        })
+        .unwrap();
+        assert_eq!(
+            walked,
+            [
+                (3, "branch".to_string(), 0),
+                (4, "leaf".to_string(), 1),
+                (5, "leaf".to_string(), 1)
+            ]
+        );
+        drop(tx);
+        db.close().unwrap();
+        drop(db);
+
+        // A read-only database loads the freelist on demand.
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options).unwrap();
+        let tx = db.begin(false).unwrap();
+        assert!(!tx.db.freelist_loaded());
+        assert_eq!(tx.page(6).unwrap().unwrap().typ, "free");
+        assert!(tx.db.freelist_loaded());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn id_size_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let page_size = db.begin(false).unwrap().db.page_size as u64;
+
+        let tx = db.begin(false).unwrap();
+        assert!(!tx.writable());
+        assert_eq!(tx.id(), 1);
+        assert_eq!(tx.size(), 4 * page_size);
+        drop(tx);
+
+        let mut prev = 4 * page_size;
+        for i in 0..3 {
+            let mut tx = db.begin(true).unwrap();
+            assert!(tx.writable());
+            assert_eq!(tx.id(), 2 + i);
+            tx.allocate(2).unwrap();
+            tx.commit().unwrap();
+
+            let tx = db.begin(false).unwrap();
+            assert!(tx.size() > prev, "size did not grow in commit {}", i);
+            prev = tx.size();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn for_each_page_in_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 20])?;
+            }
+            b.put(b"big", &[1; 10000])?;
+            let sub = b.create_bucket(b"sub")?;
+            for i in 0..1000u32 {
+                sub.put(&i.to_be_bytes(), &[0; 20])?;
+            }
+            b.create_bucket(b"tiny")?.put(b"foo", b"bar")?;
+            tx.create_bucket(b"empty").map(|_| ())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let mut pages = Vec::new();
+            tx.for_each_page_in_bucket(&b, |info, depth, parent| {
+                pages.push((info.clone(), depth, parent))
+            })?;
+
+            // The walk starts at the root, and every other page is referred
+            // to by a page visited before it.
+            assert_eq!(pages[0].0.id, b.root());
+            assert_eq!((pages[0].1, pages[0].2), (0, 0));
+            for (i, (info, depth, parent)) in pages.iter().enumerate().skip(1) {
+                let (up, up_depth, _) = pages[..i]
+                    .iter()
+                    .find(|(p, _, _)| p.id == *parent)
+                    .unwrap_or_else(|| panic!("page {} visited before {}", info.id, parent));
+                assert_eq!(*depth, up_depth + 1);
+                match up.typ.as_str() {
+                    // Only the root of sub hangs off a leaf page.
+                    "leaf" => assert_eq!(info.id, b.bucket(b"sub")?.root()),
+                    typ => assert_eq!(typ, "branch"),
+                }
+            }
+
+            // The pages agree with the stats of the bucket, which has the
+            // inline bucket tiny.
+            let stats = b.stats()?;
+            let count = |typ: &str| pages.iter().filter(|(p, _, _)| p.typ == typ).count();
+            assert_eq!(count("branch"), stats.branch_page_n);
+            assert_eq!(count("leaf"), stats.leaf_page_n);
+            let overflow: usize = pages.iter().map(|(p, _, _)| p.overflow_count).sum();
+            assert_eq!(overflow, stats.branch_overflow_n + stats.leaf_overflow_n);
+            assert_eq!(stats.inline_bucket_n, 1);
+
+            let mut n = 0;
+            tx.for_each_page_in_bucket(&tx.bucket(b"empty")?, |_, _, _| n += 1)?;
+            assert_eq!(n, 0);
+
+            // Buckets of other transactions are rejected.
+            let other = db.begin(false)?;
+            let result = other.for_each_page_in_bucket(&b, |_, _, _| n += 1);
+            assert!(matches!(result, Err(Error::BucketNotFound)));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reachable_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let fill = |n: u32| {
+            db.update(|tx| {
+                let b = tx.create_bucket_if_not_exists(b"widgets")?;
+                for i in 0..n {
+                    b.put(&i.to_be_bytes(), &[0; 100])?;
+                }
+                b.put(b"big", &vec![1; 3 * n as usize])?;
+                let sub = b.create_bucket_if_not_exists(b"sub")?;
+                for i in 0..n {
+                    sub.delete(&(i * 2).to_be_bytes())?;
+                    sub.put(&(i * 3).to_be_bytes(), &[0; 50])?;
+                }
+                b.create_bucket_if_not_exists(b"tiny")?.put(b"foo", b"bar")
+            })
+            .unwrap();
+        };
+        fill(1000);
+        // Keep the pages freed by the next commits pending.
+        let old = db.begin(false).unwrap();
+        fill(2000);
+        fill(500);
+
+        let tx = db.begin(false).unwrap();
+        let reachable = tx.reachable_pages().unwrap();
+        let (free, pending) = {
+            let freelist = lock(&db.0.freelist);
+            (freelist.free_count(), freelist.pending_count())
+        };
+        assert!(pending > 0);
+
+        // Every page below the high water mark is either reachable, the
+        // metas included, or on the freelist.
+        let high_water = tx.meta.get().pgid;
+        assert_eq!(reachable[..2], [0, 1]);
+        assert!(reachable.windows(2).all(|w| w[0] < w[1]));
+        assert!(reachable.iter().all(|&id| id < high_water));
+        assert_eq!(reachable.len() + free + pending, high_water as usize);
+        assert!(tx.check().is_empty());
+        drop(old);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn closed_tx_rejects_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        let mut rtx = db.begin(false).unwrap();
+        rtx.rollback().unwrap();
+        let mut wtx = db.begin(true).unwrap();
+        wtx.commit().unwrap();
+
+        for tx in [&rtx, &wtx] {
+            assert!(matches!(tx.page(0), Err(Error::TxClosed)));
+            assert!(matches!(
+                tx.for_each_page(3, 0, &mut |_, _| {}),
+                Err(Error::TxClosed)
+            ));
+            assert!(matches!(tx.reachable_pages(), Err(Error::TxClosed)));
+            assert!(matches!(tx.write_to(io::sink()), Err(Error::TxClosed)));
+            assert!(matches!(tx.check().as_slice(), [Error::TxClosed]));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_sorted_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ops = FailpointOps::new();
+        let db =
+            DB::open_with_ops(&dir.path().join("db"), Options::default(), ops.clone()).unwrap();
+        let page_size = db.begin(false).unwrap().db.page_size;
+
+        // A run larger than a single write, allocated after smaller pages and
+        // buffered in hash order.
+        let mut tx = db.begin(true).unwrap();
+        let run_pages = MAX_WRITE_SIZE / page_size * 2 + 1;
+        let small = [tx.allocate(1).unwrap(), tx.allocate(1).unwrap()];
+        let run = tx.allocate(run_pages).unwrap();
+        ops.take();
+        tx.commit().unwrap();
+
+        let writes: Vec<_> = ops
+            .take()
+            .into_iter()
+            .filter_map(|op| match op {
+                Op::WriteAt { offset, len } => Some((offset, len)),
+                _ => None,
+            })
+            .collect();
+        let (meta, pages) = writes.split_last().unwrap();
+        assert_eq!(*meta, (0, page_size));
+        assert!(pages.windows(2).all(|w| w[0].0 + w[0].1 as u64 <= w[1].0));
+        assert!(pages.iter().all(|&(_, len)| len <= MAX_WRITE_SIZE));
+
+        let run_offset = run * page_size as u64;
+        let run_end = run_offset + (run_pages * page_size) as u64;
+        let run_writes: Vec<_> = pages
+            .iter()
+            .filter(|w| w.0 >= run_offset && w.0 < run_end)
+            .collect();
+        assert_eq!(
+            run_writes,
+            [
+                &(run_offset, MAX_WRITE_SIZE),
+                &(run_offset + MAX_WRITE_SIZE as u64, MAX_WRITE_SIZE),
+                &(run_offset + 2 * MAX_WRITE_SIZE as u64, page_size),
+            ]
+        );
+        assert_eq!(pages[0].0, small[0] * page_size as u64);
+        assert_eq!(db.stats().tx_stats.write(), writes.len() as i64);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn page_pool_reuses_buffers() {
+        fn page_allocs(db: &DB, page_size: usize) -> usize {
+            let before = counting_alloc::count(page_size);
+            for _ in 0..10_000 {
+                db.update(|_| Ok(())).unwrap();
+            }
+            counting_alloc::count(page_size) - before
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            no_sync: true,
+            ..Options::default()
+        };
+        let pooled = DB::open(dir.path().join("pooled"), options.clone()).unwrap();
+        let unpooled = DB::open(
+            dir.path().join("unpooled"),
+            Options {
+                page_pool_size: 0,
+                ..options
+            },
+        )
+        .unwrap();
+        let page_size = pooled.begin(false).unwrap().db.page_size;
+
+        // Every commit writes a freelist page and a meta page.
+        assert_eq!(page_allocs(&unpooled, page_size), 20_000);
+        assert!(page_allocs(&pooled, page_size) <= 2);
+
+        // A transaction dirtying many single pages only keeps up to the cap.
+        let db = DB::open(
+            dir.path().join("capped"),
+            Options {
+                page_pool_size: 4,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let mut tx = db.begin(true).unwrap();
+        for _ in 0..100 {
+            tx.allocate(1).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(lock(&db.begin(false).unwrap().db.page_pool).len(), 4);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn page_pool_prealloc() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            page_pool_size: 8,
+            page_pool_prealloc: true,
+            ..Options::default()
+        };
+        let db = DB::open(dir.path().join("db"), options.clone()).unwrap();
+        let pool_len = || lock(&db.0.page_pool).len();
+        assert_eq!(pool_len(), 8);
+
+        // The first commit takes its freelist and meta pages from the pool.
+        db.update(|_| Ok(())).unwrap();
+        let stats = db.stats();
+        assert_eq!((stats.page_pool_hits, stats.page_pool_misses), (2, 0));
+
+        // Buffers beyond the capacity go back to the allocator.
+        let mut tx = db.begin(true).unwrap();
+        for _ in 0..100 {
+            tx.allocate(1).unwrap();
+        }
+        assert_eq!(pool_len(), 0);
+        tx.commit().unwrap();
+        assert_eq!(pool_len(), 8);
+        let diff = db.stats().sub(&stats);
+        assert!(diff.page_pool_hits >= 8);
+        assert_eq!(diff.page_pool_hits + diff.page_pool_misses, 102);
+        db.close().unwrap();
+
+        // Without preallocation the first commit has to allocate.
+        let db = DB::open(
+            dir.path().join("lazy"),
+            Options {
+                page_pool_prealloc: false,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        assert!(lock(&db.0.page_pool).is_empty());
+        db.update(|_| Ok(())).unwrap();
+        let stats = db.stats();
+        assert!(stats.page_pool_misses > 0);
+        db.close().unwrap();
+
+        // Read-only databases never write, so they don't fill the pool.
+        let db = DB::open(
+            dir.path().join("db"),
+            Options {
+                read_only: true,
+                ..options
+            },
+        )
+        .unwrap();
+        assert!(lock(&db.0.page_pool).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn backup_with_write_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let mut tx = db.begin(true).unwrap();
+        let pgid = tx.allocate(300).unwrap();
+        tx.pages.get_mut().get_mut(&pgid).unwrap()[PAGE_HEADER_SIZE..].fill(0x5a);
+        tx.commit().unwrap();
+
+        let plain = dir.path().join("plain");
+        db.backup(&plain).unwrap();
+        let plain = std::fs::read(plain).unwrap();
+
+        for &flag in &[O_DIRECT, libc::O_SYNC, O_DIRECT | libc::O_SYNC] {
+            db.set_write_flag(flag);
+            let path = dir.path().join(format!("flag-{}", flag));
+            db.backup(&path).unwrap();
+            assert!(std::fs::read(&path).unwrap() == plain, "flag {:#x}", flag);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn aligned_writer_unaligned_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let data: Vec<u8> = (0..MAX_WRITE_SIZE + 5000).map(|i| i as u8).collect();
+
+        let mut w = AlignedWriter::new(File::create(&path).unwrap());
+        for chunk in data.chunks(3001) {
+            w.write_all(chunk).unwrap();
+        }
+        drop(w.finish().unwrap());
+        assert!(std::fs::read(&path).unwrap() == data);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn on_commit_handlers() {
+        use std::sync::atomic::AtomicBool;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        // Handlers run after the commit, with the writer lock released.
+        let seen = Arc::new(AtomicUsize::new(0));
+        let mut tx = db.begin(true).unwrap();
+        {
+            let (db, seen) = (db.clone(), seen.clone());
+            tx.on_commit(move || {
+                let mut tx = db.begin(true).unwrap();
+                seen.store(tx.id() as usize, Ordering::SeqCst);
+                tx.rollback().unwrap();
+            });
+        }
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+        tx.commit().unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+
+        // Rolled back transactions drop their handlers.
+        let ran = Arc::new(AtomicBool::new(false));
+        let handler = |ran: &Arc<AtomicBool>| {
+            let ran = ran.clone();
+            move || ran.store(true, Ordering::SeqCst)
+        };
+        let mut tx = db.begin(true).unwrap();
+        tx.on_commit(handler(&ran));
+        tx.rollback().unwrap();
+        drop(db.begin(true).unwrap());
+        assert!(!ran.load(Ordering::SeqCst));
+
+        // Managed transactions run them only when the function succeeds.
+        let result = db.update(|tx| {
+            tx.on_commit(handler(&ran));
+            Err(Error::KeyRequired)
+        });
+        assert!(matches!(result, Err(Error::KeyRequired)));
+        assert!(!ran.load(Ordering::SeqCst));
+
+        db.update(|tx| {
+            tx.on_commit(handler(&ran));
+            Ok(())
+        })
+        .unwrap();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn inc_and_get() {
+        let stats = TxStats::default();
+        stats.inc_page_count(2);
+        stats.inc_page_count(3);
+        stats.inc_write_time(Duration::from_millis(4));
+        assert_eq!(stats.page_count(), 5);
+        assert_eq!(stats.write_time(), Duration::from_millis(4));
+        assert_eq!(stats.node_count(), 0);
+    }
+
+    #[test]
+    fn sub_add_reset() {
+        let a = TxStats::default();
+        a.inc_split(10);
+        a.inc_spill_time(Duration::from_secs(2));
+        let b = TxStats::default();
+        b.inc_split(4);
+        b.inc_spill_time(Duration::from_secs(1));
+
+        let diff = a.sub(&b);
+        assert_eq!(diff.split(), 6);
+        assert_eq!(diff.spill_time(), Duration::from_secs(1));
+
+        diff.add(&b);
+        assert_eq!(diff, a);
+
+        diff.reset();
+        assert_eq!(diff, TxStats::default());
+        assert_eq!(a.clone(), a);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn bucket_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            assert!(tx.bucket_names()?.is_empty());
+            for name in [&b"widgets"[..], b"apples", b"zebras"] {
+                tx.create_bucket(name)?;
+            }
+            tx.bucket(b"widgets")?.create_bucket(b"nested")?;
+            Ok(())
+        })
+        .unwrap();
+
+        let mut tx = db.begin(false).unwrap();
+        assert_eq!(
+            tx.bucket_names().unwrap(),
+            vec![b"apples".to_vec(), b"widgets".to_vec(), b"zebras".to_vec()]
+        );
+        tx.rollback().unwrap();
+        assert!(matches!(tx.bucket_names(), Err(Error::TxClosed)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn for_each() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| {
+            for name in [&b"widgets"[..], b"apples", b"zebras"] {
+                tx.create_bucket(name)?.put(b"size", name)?;
+            }
+            let widgets = tx.bucket(b"widgets")?;
+            widgets.create_bucket(b"nested")?.create_bucket(b"deeper")?;
+            widgets.create_bucket(b"another")?.set_sequence(7)?;
+            Ok(())
+        })
+        .unwrap();
+
+        let mut tx = db.begin(false).unwrap();
+        let mut top = Vec::new();
+        tx.for_each(|name, b| {
+            assert_eq!(b.get(b"size")?, Some(name));
+            top.push(name.to_vec());
+            // The walk is read-only.
+            assert!(matches!(b.put(b"k", b"v"), Err(Error::TxNotWritable)));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            top,
+            vec![b"apples".to_vec(), b"widgets".to_vec(), b"zebras".to_vec()]
+        );
+
+        let mut all = Vec::new();
+        tx.for_each_recursive(|path, b| {
+            let path: Vec<&[u8]> = path.to_vec();
+            all.push((path.join(&b'/'), b.sequence()));
+            Ok(())
+        })
+        .unwrap();
+        let names: Vec<(&[u8], u64)> = all.iter().map(|(p, s)| (&p[..], *s)).collect();
+        assert_eq!(
+            names,
+            vec![
+                (&b"apples"[..], 0),
+                (b"widgets", 0),
+                (b"widgets/another", 7),
+                (b"widgets/nested", 0),
+                (b"widgets/nested/deeper", 0),
+                (b"zebras", 0),
+            ]
+        );
+
+        // An error stops the walk and is returned to the caller.
+        let mut visited = 0;
+        let err = tx
+            .for_each_recursive(|path, _| {
+                visited += 1;
+                if path.len() == 2 {
+                    return Err(Error::BucketNotFound);
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::BucketNotFound));
+        assert_eq!(visited, 3);
+        let mut visited = 0;
+        assert!(matches!(
+            tx.for_each(|_, _| {
+                visited += 1;
+                Err(Error::BucketNotFound)
+            }),
+            Err(Error::BucketNotFound)
+        ));
+        assert_eq!(visited, 1);
+
+        tx.rollback().unwrap();
+        assert!(matches!(tx.for_each(|_, _| Ok(())), Err(Error::TxClosed)));
+        assert!(matches!(
+            tx.for_each_recursive(|_, _| Ok(())),
+            Err(Error::TxClosed)
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn bucket_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let empty: [&str; 0] = [];
+
+        db.update(|tx| {
+            assert!(matches!(
+                tx.create_bucket_path(&empty),
+                Err(Error::BucketNameRequired)
+            ));
+            let b = tx.create_bucket_path(&["users", "by-email", "example.com"])?;
+            b.put(b"alice", b"1")?;
+            assert_eq!(
+                b.path(),
+                vec![
+                    b"users".to_vec(),
+                    b"by-email".to_vec(),
+                    b"example.com".to_vec()
+                ]
+            );
+            // Existing components are reused.
+            tx.create_bucket_path(&["users", "by-name"])?
+                .put(b"alice", b"1")?;
+            assert_eq!(tx.root().path(), Vec::<Vec<u8>>::new());
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            assert!(matches!(
+                tx.bucket_path(&empty),
+                Err(Error::BucketNameRequired)
+            ));
+            let users = tx.bucket_path(&["users"])?;
+            assert_eq!(users.path(), vec![b"users".to_vec()]);
+            assert!(users.get(b"by-email")?.is_none());
+            let b = tx.bucket_path(&[&b"users"[..], b"by-email", b"example.com"])?;
+            assert_eq!(b.get(b"alice")?, Some(&b"1"[..]));
+            assert_eq!(
+                users.bucket(b"by-name")?.path(),
+                vec![b"users".to_vec(), b"by-name".to_vec()]
+            );
+
+            let err = tx.bucket_path(&["users", "by-phone", "555"]).err().unwrap();
+            assert!(matches!(
+                &err,
+                Error::BucketPathNotFound { depth: 1, name } if name == b"by-phone"
+            ));
+            assert_eq!(
+                err.to_string(),
+                "bucket not found: \"by-phone\" (path component 1)"
+            );
+            assert!(matches!(
+                tx.bucket_path(&["groups"]),
+                Err(Error::BucketPathNotFound { depth: 0, .. })
+            ));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// counting_alloc counts, per thread, the allocations of each size, so a
+    /// test can observe its own allocator traffic while others run.
+    mod counting_alloc {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        struct Counting;
+
+        thread_local! {
+            static SIZE: Cell<usize> = const { Cell::new(0) };
+            static COUNT: Cell<usize> = const { Cell::new(0) };
+        }
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                record(layout.size());
+                System.alloc(layout)
+            }
+
+            unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+                record(layout.size());
+                System.alloc_zeroed(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        fn record(size: usize) {
+            let _ = SIZE.try_with(|s| {
+                if s.get() == size {
+                    let _ = COUNT.try_with(|c| c.set(c.get() + 1));
+                }
+            });
+        }
+
+        #[global_allocator]
+        static GLOBAL: Counting = Counting;
+
+        /// count returns how many allocations of `size` bytes this thread has
+        /// made since it first asked about that size.
+        pub(super) fn count(size: usize) -> usize {
+            SIZE.with(|s| s.set(size));
+            COUNT.with(|c| c.get())
+        }
+    }
+}
@@ -0,0 +1,917 @@
+use std::fmt;
+
+use crate::bucket::{InBucket, BUCKET_HEADER_SIZE};
+use crate::db::{lock, DB};
+use crate::errors::{Error, Result};
+use crate::freelist::read_page_ids;
+use crate::page::{
+    Page, Pgid, BRANCH_PAGE_FLAG, BUCKET_LEAF_FLAG, LEAF_PAGE_FLAG, PAGE_HEADER_SIZE,
+    PGID_NO_FREELIST,
+};
+use crate::tx::Tx;
+
+/// KvStringer renders keys and values in the messages of check errors.
+pub trait KvStringer {
+    fn key_to_string(&self, key: &[u8]) -> String;
+    fn value_to_string(&self, value: &[u8]) -> String;
+}
+
+/// HexKvStringer serializes both key & value to hex representation. It is
+/// the default of CheckOptions, as keys are often not text.
+pub struct HexKvStringer;
+
+impl KvStringer for HexKvStringer {
+    fn key_to_string(&self, key: &[u8]) -> String {
+        key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn value_to_string(&self, value: &[u8]) -> String {
+        self.key_to_string(value)
+    }
+}
+
+/// Utf8KvStringer renders keys and values as quoted strings, replacing
+/// invalid UTF-8 sequences, for applications storing text keys.
+pub struct Utf8KvStringer;
+
+impl KvStringer for Utf8KvStringer {
+    fn key_to_string(&self, key: &[u8]) -> String {
+        format!("{:?}", String::from_utf8_lossy(key))
+    }
+
+    fn value_to_string(&self, value: &[u8]) -> String {
+        self.key_to_string(value)
+    }
+}
+
+/// CheckOptions configures a consistency check.
+pub struct CheckOptions {
+    /// skip_freelist skips the checks of the freelist, and of pages being
+    /// either reachable or free. This is needed for databases whose freelist
+    /// is not synced to disk.
+    pub skip_freelist: bool,
+    /// kv_stringer renders the keys mentioned in error messages.
+    pub kv_stringer: Box<dyn KvStringer>,
+}
+
+impl Default for CheckOptions {
+    fn default() -> CheckOptions {
+        CheckOptions {
+            skip_freelist: false,
+            kv_stringer: Box::new(HexKvStringer),
+        }
+    }
+}
+
+/// CheckErrorKind classifies the inconsistencies found by a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckErrorKind {
+    /// A page on the freelist lies beyond the high water mark.
+    FreedOutOfBounds,
+    /// A page is on the freelist more than once.
+    AlreadyFreed,
+    /// A reference points beyond the high water mark.
+    OutOfBounds,
+    /// A page is referenced more than once.
+    MultipleReferences,
+    /// A page is referenced while on the freelist.
+    ReachableFreed,
+    /// A page is neither referenced nor on the freelist.
+    UnreachableUnfreed,
+    /// A page header does not carry the id of the page.
+    PageIdMismatch,
+    /// A page has a type that is not allowed where it is referenced.
+    InvalidPageType,
+    /// A branch page has no elements.
+    EmptyBranch,
+    /// A key does not sort after the key preceding it on the page.
+    KeysOutOfOrder,
+    /// A key sorts before the key its parent branch refers to it by.
+    KeyBelowLowerBound,
+    /// A key sorts at or after the key of the next element of its parent.
+    KeyAboveUpperBound,
+    /// A bucket value is too short for a bucket header or inline page.
+    InvalidBucketHeader,
+    /// A page could not be read, e.g. because an element runs off its end.
+    Unreadable,
+}
+
+/// CheckError describes an inconsistency found by a check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckError {
+    /// the page at which the inconsistency was detected
+    pub pgid: u64,
+    /// the names of the nested buckets leading to the page, empty for pages
+    /// of the root bucket or not belonging to any bucket
+    pub bucket: Vec<Vec<u8>>,
+    /// what kind of inconsistency was found
+    pub kind: CheckErrorKind,
+    /// a human readable description, with keys rendered by the KvStringer
+    pub message: String,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {} corrupted: {}", self.pgid, self.message)
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+impl From<CheckError> for Error {
+    fn from(err: CheckError) -> Error {
+        Error::corrupted(err.pgid, err.message)
+    }
+}
+
+/// PageSet is a bitmap of page ids below the high water mark. It keeps the
+/// traversal at one bit per page, so multi-gigabyte files can be checked.
+struct PageSet {
+    bits: Vec<u64>,
+}
+
+impl PageSet {
+    fn new(n: Pgid) -> PageSet {
+        PageSet {
+            bits: vec![0; (n as usize).div_ceil(64)],
+        }
+    }
+
+    /// insert marks `id` and reports whether it was not marked before.
+    fn insert(&mut self, id: Pgid) -> bool {
+        let (word, bit) = ((id / 64) as usize, id % 64);
+        let was_set = self.bits[word] & (1 << bit) != 0;
+        self.bits[word] |= 1 << bit;
+        !was_set
+    }
+
+    fn contains(&self, id: Pgid) -> bool {
+        self.bits[(id / 64) as usize] & (1 << (id % 64)) != 0
+    }
+}
+
+/// Checker accumulates the state of a single check run.
+struct Checker<'tx, 'o> {
+    tx: &'tx Tx,
+    opts: &'o CheckOptions,
+    high_water: Pgid,
+    reachable: PageSet,
+    freed: PageSet,
+    // path holds the names of the nested buckets being checked.
+    path: Vec<Vec<u8>>,
+    errors: Vec<CheckError>,
+}
+
+impl DB {
+    /// Check performs several consistency checks on the database in a
+    /// read-only transaction, see [`Tx::check_with_options`]. It works on
+    /// databases opened read-only too.
+    pub fn check(&self, opts: &CheckOptions) -> Result<Vec<CheckError>> {
+        let mut tx = self.begin(false)?;
+        let errors = tx.check_with_options(opts)?;
+        tx.rollback()?;
+        Ok(errors)
+    }
+}
+
+impl Tx {
+    /// Check performs several consistency checks on the database for this transaction.
+    /// An error is returned for each inconsistency found; an empty list means the
+    /// snapshot is consistent.
+    ///
+    /// It can be run concurrently with writers, because it only reads pages
+    /// pinned by this transaction. Read-only transactions check the freelist as
+    /// it was committed for their snapshot.
+    pub fn check(&self) -> Vec<Error> {
+        match self.check_with_options(&CheckOptions::default()) {
+            Ok(errors) => errors.into_iter().map(Error::from).collect(),
+            Err(err) => vec![err],
+        }
+    }
+
+    /// CheckWithOptions performs the checks of [`Tx::check`] as configured by `opts`,
+    /// and reports each inconsistency as a typed [`CheckError`].
+    /// Returns `Error::TxClosed` if the transaction is closed.
+    pub fn check_with_options(&self, opts: &CheckOptions) -> Result<Vec<CheckError>> {
+        if self.closed {
+            return Err(Error::TxClosed);
+        }
+        let high_water = self.meta.get().pgid;
+        let mut c = Checker {
+            tx: self,
+            opts,
+            high_water,
+            reachable: PageSet::new(high_water),
+            freed: PageSet::new(high_water),
+            path: Vec::new(),
+            errors: Vec::new(),
+        };
+        if !opts.skip_freelist {
+            c.check_freelist();
+        }
+
+        // Track every reachable page.
+        c.mark(0); // meta0
+        c.mark(1); // meta1
+        if !opts.skip_freelist {
+            c.mark_freelist_page();
+        }
+
+        // Recursively check buckets.
+        let root = self.meta.get().root;
+        c.check_bucket(&root);
+
+        // Ensure all pages below high water mark are either reachable or freed.
+        if !opts.skip_freelist {
+            for id in 0..high_water {
+                if !c.reachable.contains(id) && !c.freed.contains(id) {
+                    c.report(
+                        id,
+                        CheckErrorKind::UnreachableUnfreed,
+                        "unreachable unfreed",
+                    );
+                }
+            }
+        }
+        Ok(c.errors)
+    }
+}
+
+impl<'tx> Checker<'tx, '_> {
+    fn report(&mut self, pgid: Pgid, kind: CheckErrorKind, message: impl Into<String>) {
+        self.errors.push(CheckError {
+            pgid,
+            bucket: self.path.clone(),
+            kind,
+            message: message.into(),
+        });
+    }
+
+    /// report_err records an error raised while reading a page.
+    fn report_err(&mut self, err: Error) {
+        match err {
+            Error::Corrupted { pgid, reason } => {
+                self.report(pgid, CheckErrorKind::Unreadable, reason)
+            }
+            err => self.report(0, CheckErrorKind::Unreadable, err.to_string()),
+        }
+    }
+
+    /// check_freelist marks every free or pending page, reporting pages freed twice.
+    fn check_freelist(&mut self) {
+        let mut ids = Vec::new();
+        if self.tx.writable {
+            // The shared freelist checks itself; its duplicates are dropped
+            // below so that they are reported once.
+            let freelist = lock(&self.tx.db.freelist);
+            let checked = freelist.check();
+            freelist.copyall(&mut ids);
+            drop(freelist);
+            if let Err(err) = checked {
+                match err {
+                    Error::Corrupted { pgid, reason } => {
+                        self.report(pgid, CheckErrorKind::AlreadyFreed, reason)
+                    }
+                    err => self.report_err(err),
+                }
+                ids.dedup();
+            }
+        } else if self.tx.meta.get().freelist == PGID_NO_FREELIST {
+            // No freelist was persisted with this snapshot: the pages it
+            // does not reach are free, as when the freelist is rebuilt.
+            match self.tx.unreachable_pages() {
+                Ok(unreachable) => ids = unreachable,
+                Err(err) => {
+                    self.report_err(err);
+                    return;
+                }
+            }
+        } else {
+            // Read the freelist committed with this snapshot; the shared one
+            // may already reflect later transactions. The ids are taken as
+            // stored, so that duplicates are reported below.
+            match self
+                .tx
+                .raw_page(self.tx.meta.get().freelist)
+                .and_then(read_page_ids)
+            {
+                Ok(stored) => ids = stored,
+                Err(err) => {
+                    self.report_err(err);
+                    return;
+                }
+            }
+        }
+
+        for id in ids {
+            if id >= self.high_water {
+                let message = format!("freed page out of bounds: {}", self.high_water);
+                self.report(id, CheckErrorKind::FreedOutOfBounds, message);
+            } else if !self.freed.insert(id) {
+                self.report(id, CheckErrorKind::AlreadyFreed, "already freed");
+            }
+        }
+    }
+
+    fn mark_freelist_page(&mut self) {
+        let id = self.tx.meta.get().freelist;
+        if id == PGID_NO_FREELIST {
+            return;
+        }
+        match self.tx.raw_page(id) {
+            Ok(p) => {
+                for i in 0..=p.overflow() as Pgid {
+                    self.mark(id + i);
+                }
+            }
+            Err(err) => self.report_err(err),
+        }
+    }
+
+    /// mark records `id` as reachable, reporting pages referenced twice or
+    /// reachable while on the freelist.
+    fn mark(&mut self, id: Pgid) -> bool {
+        if id >= self.high_water {
+            let message = format!("out of bounds: {}", self.high_water);
+            self.report(id, CheckErrorKind::OutOfBounds, message);
+            return false;
+        }
+        if !self.reachable.insert(id) {
+            self.report(
+                id,
+                CheckErrorKind::MultipleReferences,
+                "multiple references",
+            );
+            return false;
+        }
+        if self.freed.contains(id) {
+            self.report(id, CheckErrorKind::ReachableFreed, "reachable freed");
+        }
+        true
+    }
+
+    /// check_bucket checks the tree of a bucket and, recursively, its sub-buckets.
+    fn check_bucket(&mut self, b: &InBucket) {
+        // Ignore inline buckets: their single leaf lives in the parent's value.
+        if b.root == 0 {
+            return;
+        }
+        self.check_page(b.root, None, None);
+    }
+
+    /// check_page checks the page `id` and its children. Every key
+    /// on it must fall within `[min, max)`, the range its parent assigned it.
+    fn check_page(&mut self, id: Pgid, min: Option<&[u8]>, max: Option<&[u8]>) {
+        if !self.mark(id) {
+            return;
+        }
+        let tx = self.tx;
+        let p = match tx.raw_page(id) {
+            Ok(p) => p,
+            Err(err) => return self.report_err(err),
+        };
+        if p.id() != id {
+            let message = format!("page header has id {}", p.id());
+            return self.report(id, CheckErrorKind::PageIdMismatch, message);
+        }
+        for i in 1..=p.overflow() as Pgid {
+            self.mark(id + i);
+        }
+
+        let flags = p.flags();
+        let result = if flags & BRANCH_PAGE_FLAG != 0 {
+            self.check_branch(p, min, max)
+        } else if flags & LEAF_PAGE_FLAG != 0 {
+            self.check_leaf(p, min, max)
+        } else {
+            let message = format!("invalid type: {}", p.typ());
+            self.report(id, CheckErrorKind::InvalidPageType, message);
+            Ok(())
+        };
+        if let Err(err) = result {
+            self.report_err(err);
+        }
+    }
+
+    /// check_branch checks a branch page. Errors reading the page are
+    /// returned, inconsistencies are reported.
+    fn check_branch(&mut self, p: Page<'tx>, min: Option<&[u8]>, max: Option<&[u8]>) -> Result<()> {
+        let count = p.count() as usize;
+        if count == 0 {
+            self.report(p.id(), CheckErrorKind::EmptyBranch, "empty branch page");
+            return Ok(());
+        }
+        let mut prev: Option<&[u8]> = None;
+        for i in 0..count {
+            let elem = p.branch_element(i)?;
+            if !self.check_key_order(p.id(), "branch", elem.key(), prev, min, max) {
+                return Ok(());
+            }
+            prev = Some(elem.key());
+
+            // The child covers the keys up to the next element, or the parent's upper bound.
+            let next = if i + 1 < count {
+                Some(p.branch_element(i + 1)?.key())
+            } else {
+                max
+            };
+            self.check_page(elem.pgid(), Some(elem.key()), next);
+        }
+        Ok(())
+    }
+
+    /// check_leaf checks a leaf page and the buckets stored on it.
+    fn check_leaf(&mut self, p: Page<'tx>, min: Option<&[u8]>, max: Option<&[u8]>) -> Result<()> {
+        let mut prev: Option<&[u8]> = None;
+        for i in 0..p.count() as usize {
+            let elem = p.leaf_element(i)?;
+            if !self.check_key_order(p.id(), "leaf", elem.key(), prev, min, max) {
+                return Ok(());
+            }
+            prev = Some(elem.key());
+
+            if elem.flags() & BUCKET_LEAF_FLAG != 0 {
+                self.path.push(elem.key().to_vec());
+                self.check_sub_bucket(p.id(), elem.value());
+                self.path.pop();
+            }
+        }
+        Ok(())
+    }
+
+    /// check_sub_bucket checks the bucket whose header is stored in `value`.
+    fn check_sub_bucket(&mut self, parent: Pgid, value: &'tx [u8]) {
+        if value.len() < BUCKET_HEADER_SIZE {
+            let kind = CheckErrorKind::InvalidBucketHeader;
+            return self.report(parent, kind, "bucket header too short");
+        }
+        let b = InBucket::read(value);
+        if b.root != 0 {
+            return self.check_bucket(&b);
+        }
+
+        // Inline buckets carry their leaf page right after the header.
+        let inline = &value[BUCKET_HEADER_SIZE..];
+        if inline.len() < PAGE_HEADER_SIZE {
+            let kind = CheckErrorKind::InvalidBucketHeader;
+            return self.report(parent, kind, "inline bucket page too short");
+        }
+        let p = Page::new(inline);
+        if p.flags() & LEAF_PAGE_FLAG == 0 {
+            let message = format!("inline bucket has invalid type: {}", p.typ());
+            self.report(parent, CheckErrorKind::InvalidPageType, message);
+        } else if let Err(err) = self.check_leaf(p, None, None) {
+            self.report_err(err);
+        }
+    }
+
+    /// check_key_order verifies `key` sorts after the previous key on the page
+    /// and within the bounds set by the parent branch, and reports whether it
+    /// does.
+    fn check_key_order(
+        &mut self,
+        id: Pgid,
+        kind: &str,
+        key: &[u8],
+        prev: Option<&[u8]>,
+        min: Option<&[u8]>,
+        max: Option<&[u8]>,
+    ) -> bool {
+        let render = || self.opts.kv_stringer.key_to_string(key);
+        let (error, message) = if prev.is_some_and(|prev| prev >= key) {
+            let message = format!("{} keys out of order: {}", kind, render());
+            (CheckErrorKind::KeysOutOfOrder, message)
+        } else if min.is_some_and(|min| key < min) {
+            let message = format!("{} key {} below parent lower bound", kind, render());
+            (CheckErrorKind::KeyBelowLowerBound, message)
+        } else if max.is_some_and(|max| key >= max) {
+            let message = format!("{} key {} not below parent upper bound", kind, render());
+            (CheckErrorKind::KeyAboveUpperBound, message)
+        } else {
+            return true;
+        };
+        self.report(id, error, message);
+        false
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bucket::InBucket;
+    use crate::db::{Options, DB};
+    use crate::freelist::{Freelist, FreelistType};
+    use crate::meta::{Meta, MAGIC, VERSION};
+    use crate::page::*;
+    use std::path::Path;
+
+    pub(crate) const PS: usize = 4096;
+
+    /// build writes a database of `pages` pages: the two metas, an empty
+    /// freelist at page 2 and an empty root leaf at page 3, which `f` can then
+    /// overwrite to plant corruption.
+    pub(crate) fn build(path: &Path, pages: Pgid, f: impl FnOnce(&mut [u8])) -> DB {
+        let mut buf = vec![0u8; PS * pages as usize];
+        for i in 0..2 {
+            let mut m = Meta {
+                magic: MAGIC,
+                version: VERSION,
+                page_size: PS as u32,
+                root: InBucket {
+                    root: 3,
+                    sequence: 0,
+                },
+                freelist: 2,
+                pgid: pages,
+                txid: i,
+                ..Meta::default()
+            };
+            m.write(&mut PageMut::new(&mut buf[i as usize * PS..]));
+        }
+        write_freelist(&mut buf, 2, &[]);
+        write_leaf(&mut buf, 3, &[]);
+        f(&mut buf);
+        std::fs::write(path, &buf).unwrap();
+        DB::open(path, Options::default()).unwrap()
+    }
+
+    fn header(buf: &mut [u8], id: Pgid, flags: u16, count: usize) -> &mut [u8] {
+        let page = &mut buf[id as usize * PS..(id as usize + 1) * PS];
+        let mut p = PageMut::new(page);
+        p.set_id(id);
+        p.set_flags(flags);
+        p.set_count(count as u16);
+        &mut page[PAGE_HEADER_SIZE..]
+    }
+
+    pub(crate) fn write_freelist(buf: &mut [u8], id: Pgid, ids: &[Pgid]) {
+        let data = header(buf, id, FREELIST_PAGE_FLAG, ids.len());
+        for (i, &id) in ids.iter().enumerate() {
+            write_u64(data, i * PGID_SIZE, id);
+        }
+    }
+
+    pub(crate) fn write_leaf(buf: &mut [u8], id: Pgid, elems: &[(u32, &[u8], &[u8])]) {
+        let data = header(buf, id, LEAF_PAGE_FLAG, elems.len());
+        let mut pos = elems.len() * LEAF_PAGE_ELEMENT_SIZE;
+        for (i, (flags, key, value)) in elems.iter().enumerate() {
+            let off = i * LEAF_PAGE_ELEMENT_SIZE;
+            write_u32(data, off, *flags);
+            write_u32(data, off + 4, (pos - off) as u32);
+            write_u32(data, off + 8, key.len() as u32);
+            write_u32(data, off + 12, value.len() as u32);
+            data[pos..pos + key.len()].copy_from_slice(key);
+            pos += key.len();
+            data[pos..pos + value.len()].copy_from_slice(value);
+            pos += value.len();
+        }
+    }
+
+    pub(crate) fn write_branch(buf: &mut [u8], id: Pgid, elems: &[(&[u8], Pgid)]) {
+        let data = header(buf, id, BRANCH_PAGE_FLAG, elems.len());
+        let mut pos = elems.len() * BRANCH_PAGE_ELEMENT_SIZE;
+        for (i, (key, pgid)) in elems.iter().enumerate() {
+            let off = i * BRANCH_PAGE_ELEMENT_SIZE;
+            write_u32(data, off, (pos - off) as u32);
+            write_u32(data, off + 4, key.len() as u32);
+            write_u64(data, off + 8, *pgid);
+            data[pos..pos + key.len()].copy_from_slice(key);
+            pos += key.len();
+        }
+    }
+
+    pub(crate) fn bucket_value(root: Pgid) -> Vec<u8> {
+        let mut v = vec![0u8; crate::bucket::BUCKET_HEADER_SIZE];
+        InBucket { root, sequence: 0 }.write(&mut v);
+        v
+    }
+
+    fn check(db: &DB) -> Vec<String> {
+        let ro = db.begin(false).unwrap().check();
+        let rw = db.begin(true).unwrap().check();
+        let ro: Vec<_> = ro.iter().map(|e| e.to_string()).collect();
+        let rw: Vec<_> = rw.iter().map(|e| e.to_string()).collect();
+        assert_eq!(ro, rw);
+        ro
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        assert!(check(&db).is_empty());
+
+        let db = build(&dir.path().join("tree"), 7, |buf| {
+            write_branch(buf, 3, &[(b"a", 4), (b"m", 5)]);
+            write_leaf(buf, 4, &[(0, b"a", b"1"), (0, b"b", b"2")]);
+            let sub = bucket_value(6);
+            write_leaf(buf, 5, &[(0, b"m", b"3"), (BUCKET_LEAF_FLAG, b"sub", &sub)]);
+            write_leaf(buf, 6, &[(0, b"x", b"")]);
+        });
+        assert!(check(&db).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn unreachable_unfreed() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        let mut tx = db.begin(true).unwrap();
+        tx.allocate(1).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(check(&db), ["page 4 corrupted: unreachable unfreed"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn multiple_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 5, |buf| {
+            write_branch(buf, 3, &[(b"a", 4), (b"b", 4)]);
+            write_leaf(buf, 4, &[(0, b"b", b"")]);
+        });
+        assert_eq!(
+            check(&db),
+            [
+                "page 4 corrupted: leaf key 62 not below parent upper bound",
+                "page 4 corrupted: multiple references"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn keys_out_of_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("leaf"), 4, |buf| {
+            write_leaf(buf, 3, &[(0, b"b", b""), (0, b"a", b"")]);
+        });
+        assert_eq!(check(&db), ["page 3 corrupted: leaf keys out of order: 61"]);
+
+        let db = build(&dir.path().join("branch"), 6, |buf| {
+            write_branch(buf, 3, &[(b"b", 4), (b"a", 5)]);
+            write_leaf(buf, 4, &[(0, b"b", b"")]);
+            write_leaf(buf, 5, &[(0, b"a", b"")]);
+        });
+        assert_eq!(
+            check(&db),
+            [
+                "page 4 corrupted: leaf key 62 not below parent upper bound",
+                "page 3 corrupted: branch keys out of order: 61",
+                "page 5 corrupted: unreachable unfreed"
+            ]
+        );
+
+        let db = build(&dir.path().join("bound"), 5, |buf| {
+            write_branch(buf, 3, &[(b"b", 4)]);
+            write_leaf(buf, 4, &[(0, b"a", b"")]);
+        });
+        assert_eq!(
+            check(&db),
+            ["page 4 corrupted: leaf key 61 below parent lower bound"]
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reachable_freed() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 4, |buf| {
+            write_freelist(buf, 2, &[3]);
+        });
+        assert_eq!(check(&db), ["page 3 corrupted: reachable freed"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn already_freed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        drop(build(&path, 6, |buf| {
+            write_freelist(buf, 2, &[4, 5]);
+        }));
+
+        // Opening rejects a freelist with duplicates, so damage it afterwards,
+        // behind a mapping that shows the damage.
+        let options = Options {
+            force_buffer_backend: false,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options).unwrap();
+        let mut buf = std::fs::read(&path).unwrap();
+        write_freelist(&mut buf, 2, &[4, 5, 5]);
+        std::fs::write(&path, &buf).unwrap();
+
+        let errors = db.check(&CheckOptions::default()).unwrap();
+        assert_eq!(kinds(&errors), [(5, CheckErrorKind::AlreadyFreed)]);
+        assert_eq!(errors[0].to_string(), "page 5 corrupted: already freed");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn shared_freelist_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 6, |buf| {
+            write_freelist(buf, 2, &[4, 5]);
+        });
+
+        // A writable transaction checks the shared freelist, which reports
+        // the page it holds twice.
+        let mut freelist = Freelist::new(FreelistType::Array);
+        freelist.read_ids(vec![4, 5, 5]);
+        *lock(&db.0.freelist) = freelist;
+        let errors = db
+            .begin(true)
+            .unwrap()
+            .check_with_options(&CheckOptions::default())
+            .unwrap();
+        assert_eq!(kinds(&errors), [(5, CheckErrorKind::AlreadyFreed)]);
+        assert_eq!(
+            errors[0].to_string(),
+            "page 5 corrupted: page is free twice"
+        );
+        assert!(db.begin(false).unwrap().check().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pending_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+
+        // A reader pins the pages the next commit frees, keeping them pending.
+        let reader = db.begin(false).unwrap();
+        db.update(|tx| tx.bucket(b"widgets").unwrap().put(b"foo", b"bar"))
+            .unwrap();
+        assert!(check(&db).is_empty());
+        assert!(reader.check().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn invalid_bucket_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 4, |buf| {
+            let sub = bucket_value(99);
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"sub", &sub)]);
+        });
+        assert_eq!(check(&db), ["page 99 corrupted: out of bounds: 4"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn invalid_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 4, |buf| {
+            write_freelist(buf, 3, &[]);
+        });
+        assert_eq!(check(&db), ["page 3 corrupted: invalid type: freelist"]);
+    }
+
+    fn kinds(errors: &[CheckError]) -> Vec<(Pgid, CheckErrorKind)> {
+        errors.iter().map(|e| (e.pgid, e.kind)).collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn typed_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("dup"), 5, |buf| {
+            write_branch(buf, 3, &[(b"a", 4), (b"b", 4)]);
+            write_leaf(buf, 4, &[(0, b"a", b"")]);
+        });
+        let errors = db.check(&CheckOptions::default()).unwrap();
+        assert_eq!(kinds(&errors), [(4, CheckErrorKind::MultipleReferences)]);
+        assert_eq!(
+            errors[0].to_string(),
+            "page 4 corrupted: multiple references"
+        );
+
+        let db = build(&dir.path().join("order"), 4, |buf| {
+            write_leaf(buf, 3, &[(0, b"b", b""), (0, b"a", b"")]);
+        });
+        let errors = db.check(&CheckOptions::default()).unwrap();
+        assert_eq!(kinds(&errors), [(3, CheckErrorKind::KeysOutOfOrder)]);
+        assert!(errors[0].bucket.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn bucket_path_and_stringer() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = build(&dir.path().join("db"), 6, |buf| {
+            let sub = bucket_value(4);
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"sub", &sub)]);
+            let nested = bucket_value(5);
+            write_leaf(buf, 4, &[(BUCKET_LEAF_FLAG, b"nested", &nested)]);
+            write_leaf(buf, 5, &[(0, b"\x02", b""), (0, b"\x01", b"")]);
+        });
+        let opts = CheckOptions {
+            kv_stringer: Box::new(Utf8KvStringer),
+            ..CheckOptions::default()
+        };
+        let errors = db.check(&opts).unwrap();
+        assert_eq!(kinds(&errors), [(5, CheckErrorKind::KeysOutOfOrder)]);
+        assert_eq!(errors[0].bucket, [b"sub".to_vec(), b"nested".to_vec()]);
+        assert_eq!(errors[0].message, "leaf keys out of order: \"\\u{1}\"");
+
+        // The same error through Tx::check, with the default hex stringer.
+        assert_eq!(check(&db), ["page 5 corrupted: leaf keys out of order: 01"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn skip_freelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = build(&path, 5, |buf| {
+            // Page 4 is neither reachable nor free, and the freelist claims
+            // the root leaf.
+            write_freelist(buf, 2, &[3]);
+        });
+        assert_eq!(
+            kinds(&db.check(&CheckOptions::default()).unwrap()),
+            [
+                (3, CheckErrorKind::ReachableFreed),
+                (4, CheckErrorKind::UnreachableUnfreed)
+            ]
+        );
+        drop(db);
+
+        // Read-only databases can be checked too.
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        let db = DB::open(&path, options).unwrap();
+        let opts = CheckOptions {
+            skip_freelist: true,
+            ..CheckOptions::default()
+        };
+        assert!(db.check(&opts).unwrap().is_empty());
+        assert_eq!(db.check(&CheckOptions::default()).unwrap().len(), 2);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn strict_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            strict_mode: true,
+            ..Options::default()
+        };
+        let db = DB::open(dir.path().join("db"), options).unwrap();
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            for i in 0..1000u32 {
+                b.put(&i.to_be_bytes(), &[0; 100])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        db.update(|tx| tx.delete_bucket(b"widgets")).unwrap();
+
+        // Leaking a page makes the next commit fail and roll back.
+        let mut tx = db.begin(true).unwrap();
+        let leaked = tx.allocate(1).unwrap();
+        let errors = match tx.commit() {
+            Err(Error::CheckFailed { errors }) => errors,
+            result => panic!("unexpected commit result: {:?}", result),
+        };
+        assert_eq!(
+            kinds(&errors),
+            [(leaked, CheckErrorKind::UnreachableUnfreed)]
+        );
+        assert!(matches!(tx.commit(), Err(Error::TxClosed)));
+        drop(tx);
+        assert!(db.begin(false).unwrap().check().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn set_strict_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+
+        // Off by default: a leaked page is committed unnoticed.
+        let mut tx = db.begin(true).unwrap();
+        tx.allocate(1).unwrap();
+        tx.commit().unwrap();
+
+        db.set_strict_mode(true);
+        let mut tx = db.begin(true).unwrap();
+        assert!(matches!(tx.commit(), Err(Error::CheckFailed { .. })));
+        assert!(matches!(
+            tx.check_with_options(&CheckOptions::default()),
+            Err(Error::TxClosed)
+        ));
+        drop(tx);
+
+        db.set_strict_mode(false);
+        db.update(|tx| tx.create_bucket(b"widgets").map(|_| ()))
+            .unwrap();
+    }
+}
@@ -0,0 +1,956 @@
+//! Buckets of serialized keys and values, enabled by the `serde` feature.
+//!
+//! `TypedBucket` wraps a `Bucket` and encodes keys with `KeyCodec`, whose
+//! output sorts in the same order as the keys themselves, so range scans over
+//! integer or string keys keep working. Values are encoded with a pluggable
+//! `Codec`, bincode by default.
+
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use crate::bucket::Bucket;
+use crate::cursor::Iter;
+use crate::errors::{Error, Result};
+
+/// Codec encodes and decodes the values stored in a `TypedBucket`.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Bincode is the default value codec, using bincode's default options.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|err| invalid(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|err| invalid(err.to_string()))
+    }
+}
+
+/// KeyCodec is the order preserving codec used for the keys of a
+/// `TypedBucket`: comparing two encoded keys byte by byte gives the same
+/// result as comparing the keys.
+///
+/// Integers are written big-endian, with the sign bit of signed integers
+/// flipped. Strings and byte strings have their zero bytes escaped as
+/// `00 ff` and end with `00 00`. Sequences and maps prefix every element with
+/// `01` and end with `00`, options are written as `00` or `01` followed by
+/// the value, enum variants as their big-endian `u32` index followed by their
+/// content, and tuples and structs as their fields one after the other.
+///
+/// The format is not self-describing, so types that deserialize with
+/// `deserialize_any` can't be used as keys.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyCodec;
+
+impl Codec for KeyCodec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        value.serialize(&mut KeySerializer { out: &mut out })?;
+        Ok(out)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        let mut de = KeyDeserializer { input: bytes };
+        let value = T::deserialize(&mut de)?;
+        if !de.input.is_empty() {
+            return Err(invalid("key: trailing bytes"));
+        }
+        Ok(value)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg.into()))
+}
+
+/// Types marks the key, value and codec types of a `TypedBucket` without
+/// owning any of them.
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// TypedBucket is a bucket holding keys of type `K` and values of type `V`,
+/// encoded with `KeyCodec` and `C` respectively.
+///
+/// It is only valid for the lifetime of the transaction, like the bucket it
+/// wraps.
+pub struct TypedBucket<'tx, K, V, C = Bincode> {
+    bucket: Bucket<'tx>,
+    _marker: Types<K, V, C>,
+}
+
+impl<K, V, C> Clone for TypedBucket<'_, K, V, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, C> Copy for TypedBucket<'_, K, V, C> {}
+
+impl<K, V, C> fmt::Debug for TypedBucket<'_, K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedBucket")
+            .field("root", &self.bucket.root())
+            .finish()
+    }
+}
+
+impl<'tx, K, V, C> TypedBucket<'tx, K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// New wraps `bucket`.
+    pub fn new(bucket: Bucket<'tx>) -> Self {
+        TypedBucket {
+            bucket,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Bucket returns the wrapped bucket.
+    pub fn bucket(&self) -> Bucket<'tx> {
+        self.bucket
+    }
+
+    /// PutT encodes and stores `value` under `key`, see `Bucket::put`.
+    pub fn put_t(&self, key: &K, value: &V) -> Result<()> {
+        self.bucket.put(&KeyCodec::encode(key)?, &C::encode(value)?)
+    }
+
+    /// GetT retrieves and decodes the value of `key`. Returns `None` if the
+    /// key does not exist or if it is a nested bucket.
+    pub fn get_t(&self, key: &K) -> Result<Option<V>> {
+        match self.bucket.get(&KeyCodec::encode(key)?)? {
+            Some(value) => C::decode(value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// DeleteT removes `key` from the bucket, see `Bucket::delete`.
+    pub fn delete_t(&self, key: &K) -> Result<()> {
+        self.bucket.delete(&KeyCodec::encode(key)?)
+    }
+
+    /// RangeT returns an iterator over the decoded key/value pairs whose keys
+    /// fall within `range`, in key order. Nested buckets are skipped.
+    pub fn range_t<R: RangeBounds<K>>(&self, range: R) -> Result<TypedIter<'tx, K, V, C>> {
+        let encode = |bound: Bound<&K>| -> Result<Bound<Vec<u8>>> {
+            Ok(match bound {
+                Bound::Included(key) => Bound::Included(KeyCodec::encode(key)?),
+                Bound::Excluded(key) => Bound::Excluded(KeyCodec::encode(key)?),
+                Bound::Unbounded => Bound::Unbounded,
+            })
+        };
+        let start = encode(range.start_bound())?;
+        let end = encode(range.end_bound())?;
+        Ok(TypedIter {
+            iter: Iter::new(self.bucket, start, end),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// TypedIter iterates over the decoded key/value pairs of a `TypedBucket`.
+pub struct TypedIter<'tx, K, V, C = Bincode> {
+    iter: Iter<'tx>,
+    _marker: Types<K, V, C>,
+}
+
+impl<K, V, C> TypedIter<'_, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn decode(item: (&[u8], &[u8])) -> Result<(K, V)> {
+        Ok((KeyCodec::decode(item.0)?, C::decode(item.1)?))
+    }
+}
+
+impl<K, V, C> Iterator for TypedIter<'_, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|item| item.and_then(Self::decode))
+    }
+}
+
+impl<K, V, C> DoubleEndedIterator for TypedIter<'_, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back()
+            .map(|item| item.and_then(Self::decode))
+    }
+}
+
+/// CodecError carries the messages of serde failures until they are turned
+/// into an `Error`.
+#[derive(Debug)]
+struct CodecError(String);
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl ser::Error for CodecError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        CodecError(msg.to_string())
+    }
+}
+
+impl de::Error for CodecError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        CodecError(msg.to_string())
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(err: CodecError) -> Error {
+        invalid(format!("key: {}", err))
+    }
+}
+
+type CodecResult<T> = std::result::Result<T, CodecError>;
+
+struct KeySerializer<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl KeySerializer<'_> {
+    fn write_bytes(&mut self, v: &[u8]) {
+        for &b in v {
+            self.out.push(b);
+            if b == 0 {
+                self.out.push(0xff);
+            }
+        }
+        self.out.extend_from_slice(&[0, 0]);
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut KeySerializer<'b> {
+    type Ok = ();
+    type Error = CodecError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> CodecResult<()> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> CodecResult<()> {
+        self.serialize_u8(v as u8 ^ 0x80)
+    }
+
+    fn serialize_i16(self, v: i16) -> CodecResult<()> {
+        self.serialize_u16(v as u16 ^ 0x8000)
+    }
+
+    fn serialize_i32(self, v: i32) -> CodecResult<()> {
+        self.serialize_u32(v as u32 ^ 0x8000_0000)
+    }
+
+    fn serialize_i64(self, v: i64) -> CodecResult<()> {
+        self.serialize_u64(v as u64 ^ (1 << 63))
+    }
+
+    fn serialize_i128(self, v: i128) -> CodecResult<()> {
+        self.serialize_u128(v as u128 ^ (1 << 127))
+    }
+
+    fn serialize_u8(self, v: u8) -> CodecResult<()> {
+        self.out.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> CodecResult<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> CodecResult<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> CodecResult<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> CodecResult<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> CodecResult<()> {
+        // Negative numbers have every bit flipped so that they sort in
+        // reverse, positive ones only the sign bit so they sort after them.
+        let bits = v.to_bits();
+        let mask = if bits >> 31 == 1 { u32::MAX } else { 1 << 31 };
+        self.serialize_u32(bits ^ mask)
+    }
+
+    fn serialize_f64(self, v: f64) -> CodecResult<()> {
+        let bits = v.to_bits();
+        let mask = if bits >> 63 == 1 { u64::MAX } else { 1 << 63 };
+        self.serialize_u64(bits ^ mask)
+    }
+
+    fn serialize_char(self, v: char) -> CodecResult<()> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> CodecResult<()> {
+        self.write_bytes(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> CodecResult<()> {
+        self.write_bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> CodecResult<()> {
+        self.serialize_u8(0)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> CodecResult<()> {
+        self.out.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> CodecResult<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> CodecResult<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+    ) -> CodecResult<()> {
+        self.serialize_u32(index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> CodecResult<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        value: &T,
+    ) -> CodecResult<()> {
+        self.out.extend_from_slice(&index.to_be_bytes());
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> CodecResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> CodecResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> CodecResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> CodecResult<Self> {
+        self.out.extend_from_slice(&index.to_be_bytes());
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> CodecResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> CodecResult<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> CodecResult<Self> {
+        self.out.extend_from_slice(&index.to_be_bytes());
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut KeySerializer<'_> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> CodecResult<()> {
+        self.out.push(1);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> CodecResult<()> {
+        self.out.push(0);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut KeySerializer<'_> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> CodecResult<()> {
+        self.out.push(1);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> CodecResult<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> CodecResult<()> {
+        self.out.push(0);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_fields {
+    ($($trait:ident),*) => {$(
+        impl ser::$trait for &mut KeySerializer<'_> {
+            type Ok = ();
+            type Error = CodecError;
+
+            fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> CodecResult<()> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> CodecResult<()> {
+                Ok(())
+            }
+        }
+    )*};
+}
+
+serialize_fields!(SerializeTupleStruct, SerializeTupleVariant);
+
+impl ser::SerializeTuple for &mut KeySerializer<'_> {
+    type Ok = ();
+    type Error = CodecError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> CodecResult<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> CodecResult<()> {
+        Ok(())
+    }
+}
+
+macro_rules! serialize_named_fields {
+    ($($trait:ident),*) => {$(
+        impl ser::$trait for &mut KeySerializer<'_> {
+            type Ok = ();
+            type Error = CodecError;
+
+            fn serialize_field<T: Serialize + ?Sized>(
+                &mut self,
+                _: &'static str,
+                value: &T,
+            ) -> CodecResult<()> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> CodecResult<()> {
+                Ok(())
+            }
+        }
+    )*};
+}
+
+serialize_named_fields!(SerializeStruct, SerializeStructVariant);
+
+struct KeyDeserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> KeyDeserializer<'de> {
+    fn take<const N: usize>(&mut self) -> CodecResult<[u8; N]> {
+        if self.input.len() < N {
+            return Err(CodecError("unexpected end of key".into()));
+        }
+        let mut buf = [0; N];
+        buf.copy_from_slice(&self.input[..N]);
+        self.input = &self.input[N..];
+        Ok(buf)
+    }
+
+    fn read_bytes(&mut self) -> CodecResult<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            match self.take::<1>()?[0] {
+                0 => match self.take::<1>()?[0] {
+                    0 => return Ok(out),
+                    0xff => out.push(0),
+                    _ => return Err(CodecError("invalid escape in key".into())),
+                },
+                b => out.push(b),
+            }
+        }
+    }
+
+    /// more reads the marker written before every element of a sequence or
+    /// map, and reports whether another element follows.
+    fn more(&mut self) -> CodecResult<bool> {
+        match self.take::<1>()?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(CodecError("invalid sequence marker in key".into())),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut KeyDeserializer<'de> {
+    type Error = CodecError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> CodecResult<V::Value> {
+        Err(CodecError("key encoding is not self-describing".into()))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        match self.take::<1>()?[0] {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(CodecError("invalid bool in key".into())),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_i8((self.take::<1>()?[0] ^ 0x80) as i8)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_i16((u16::from_be_bytes(self.take()?) ^ 0x8000) as i16)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_i32((u32::from_be_bytes(self.take()?) ^ 0x8000_0000) as i32)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_i64((u64::from_be_bytes(self.take()?) ^ (1 << 63)) as i64)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_i128((u128::from_be_bytes(self.take()?) ^ (1 << 127)) as i128)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_u8(self.take::<1>()?[0])
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_u16(u16::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_u32(u32::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_u64(u64::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_u128(u128::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        let bits = u32::from_be_bytes(self.take()?);
+        let mask = if bits >> 31 == 1 { 1 << 31 } else { u32::MAX };
+        visitor.visit_f32(f32::from_bits(bits ^ mask))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        let bits = u64::from_be_bytes(self.take()?);
+        let mask = if bits >> 63 == 1 { 1 << 63 } else { u64::MAX };
+        visitor.visit_f64(f64::from_bits(bits ^ mask))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        match char::from_u32(u32::from_be_bytes(self.take()?)) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(CodecError("invalid char in key".into())),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        match String::from_utf8(self.read_bytes()?) {
+            Ok(s) => visitor.visit_string(s),
+            Err(_) => Err(CodecError("invalid utf-8 in key".into())),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_byte_buf(self.read_bytes()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        match self.take::<1>()?[0] {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(CodecError("invalid option in key".into())),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_seq(Elements(self))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_seq(Fields(self, len))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_seq(Fields(self, len))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_map(Elements(self))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_seq(Fields(self, fields.len()))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _: V) -> CodecResult<V::Value> {
+        Err(CodecError("key encoding is not self-describing".into()))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _: V) -> CodecResult<V::Value> {
+        Err(CodecError("key encoding is not self-describing".into()))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Elements reads the elements of a sequence or map, up to its end marker.
+struct Elements<'a, 'de>(&'a mut KeyDeserializer<'de>);
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = CodecError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> CodecResult<Option<T::Value>> {
+        if !self.0.more()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = CodecError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> CodecResult<Option<K::Value>> {
+        if !self.0.more()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> CodecResult<V::Value> {
+        seed.deserialize(&mut *self.0)
+    }
+}
+
+/// Fields reads the fixed number of fields of a tuple or struct.
+struct Fields<'a, 'de>(&'a mut KeyDeserializer<'de>, usize);
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, 'de> {
+    type Error = CodecError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> CodecResult<Option<T::Value>> {
+        if self.1 == 0 {
+            return Ok(None);
+        }
+        self.1 -= 1;
+        seed.deserialize(&mut *self.0).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.1)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut KeyDeserializer<'de> {
+    type Error = CodecError;
+    type Variant = Self;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> CodecResult<(T::Value, Self)> {
+        let index = u32::from_be_bytes(self.take()?);
+        let value = seed.deserialize(index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut KeyDeserializer<'de> {
+    type Error = CodecError;
+
+    fn unit_variant(self) -> CodecResult<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> CodecResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> CodecResult<V::Value> {
+        visitor.visit_seq(Fields(self, len))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> CodecResult<V::Value> {
+        visitor.visit_seq(Fields(self, fields.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Options, DB};
+
+    fn db() -> (tempfile::TempDir, DB) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open(dir.path().join("db"), Options::default()).unwrap();
+        (dir, db)
+    }
+
+    fn ascending<T: Serialize>(values: &[T]) {
+        for w in values.windows(2) {
+            assert!(KeyCodec::encode(&w[0]).unwrap() < KeyCodec::encode(&w[1]).unwrap());
+        }
+    }
+
+    #[test]
+    fn key_order() {
+        ascending(&[i64::MIN, -300, -1, 0, 1, 255, 256, i64::MAX]);
+        ascending(&[f64::NEG_INFINITY, -2.5, -0.0, 0.0, 1e-9, 3.0, f64::INFINITY]);
+        ascending(&["", "\0", "\0\0", "a", "a\0b", "aa", "b"]);
+        ascending(&[("a", 2u32), ("a", 10), ("ab", 1), ("b", 0)]);
+
+        assert_eq!(KeyCodec::encode(&258u64).unwrap(), [0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(KeyCodec::encode("a\0").unwrap(), b"a\0\xff\0\0");
+    }
+
+    #[test]
+    fn key_round_trip() {
+        fn check<T: Serialize + DeserializeOwned + PartialEq + fmt::Debug>(v: T) {
+            let bytes = KeyCodec::encode(&v).unwrap();
+            assert_eq!(KeyCodec::decode::<T>(&bytes).unwrap(), v);
+        }
+        check(-7i8);
+        check(u128::MAX);
+        check(-1.5f32);
+        check('é');
+        check(String::from("a\0b\u{ff}"));
+        check(Some(vec![1u16, 2, 3]));
+        check(None::<bool>);
+        check((1u8, String::from("x"), vec![(2i32, false)]));
+        check(std::collections::BTreeMap::from([(
+            1u8,
+            String::from("one"),
+        )]));
+
+        assert!(KeyCodec::decode::<u64>(&[0; 9]).is_err());
+        assert!(KeyCodec::decode::<u64>(&[0; 7]).is_err());
+        assert!(KeyCodec::decode::<String>(b"abc").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn u64_keys() {
+        let (_dir, db) = db();
+        db.update(|tx| {
+            let b: TypedBucket<u64, (String, u32)> = TypedBucket::new(tx.create_bucket(b"ids")?);
+            for i in (0..1000u64).rev() {
+                b.put_t(&(i * 300), &(format!("item {}", i), i as u32))?;
+            }
+            b.delete_t(&0)
+        })
+        .unwrap();
+
+        let (value, missing, range, last) = db
+            .view_ret(|tx| {
+                let b: TypedBucket<u64, (String, u32)> = TypedBucket::new(tx.bucket(b"ids")?);
+                let range = b
+                    .range_t(300..=1500)?
+                    .map(|item| item.map(|(k, _)| k))
+                    .collect::<Result<Vec<_>>>()?;
+                let last = b.range_t(..)?.next_back().unwrap()?;
+                Ok((b.get_t(&600)?, b.get_t(&0)?, range, last))
+            })
+            .unwrap();
+        assert_eq!(value, Some(("item 2".to_string(), 2)));
+        assert_eq!(missing, None);
+        assert_eq!(range, vec![300, 600, 900, 1200, 1500]);
+        assert_eq!(last, (999 * 300, ("item 999".to_string(), 999)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn string_keys() {
+        let (_dir, db) = db();
+        let mut names = vec!["b", "a", "ab", "", "ba", "a\0", "zz", "z"];
+        db.update(|tx| {
+            let b: TypedBucket<String, Vec<u8>> = TypedBucket::new(tx.create_bucket(b"names")?);
+            for name in &names {
+                b.put_t(&name.to_string(), &name.as_bytes().to_vec())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let all = db
+            .view_ret(|tx| {
+                let b: TypedBucket<String, Vec<u8>> = TypedBucket::new(tx.bucket(b"names")?);
+                b.range_t(..)?.collect::<Result<Vec<_>>>()
+            })
+            .unwrap();
+        names.sort_unstable();
+        let keys: Vec<_> = all.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, names);
+        assert!(all.iter().all(|(k, v)| k.as_bytes() == &v[..]));
+
+        let from_b = db
+            .view_ret(|tx| {
+                let b: TypedBucket<String, Vec<u8>> = TypedBucket::new(tx.bucket(b"names")?);
+                b.range_t("b".to_string().."z".to_string())?
+                    .map(|item| item.map(|(k, _)| k))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .unwrap();
+        assert_eq!(from_b, vec!["b", "ba"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn decode_errors() {
+        let (_dir, db) = db();
+        db.update(|tx| tx.create_bucket(b"raw")?.put(b"\0\0\0\0\0\0\0\x01", b"x"))
+            .unwrap();
+        let err = db
+            .view_ret(|tx| {
+                let b: TypedBucket<u64, String> = TypedBucket::new(tx.bucket(b"raw")?);
+                b.get_t(&1)
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(ref e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+}
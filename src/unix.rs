@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::{Error, Result};
+
+/// flock acquires an advisory lock on a file descriptor.
+///
+/// An exclusive lock is taken for read-write databases, a shared one for
+/// read-only databases. When `timeout` is set the call gives up with
+/// `Error::Timeout` once it has elapsed, otherwise it waits forever.
+pub(crate) fn flock(file: &File, exclusive: bool, timeout: Option<Duration>) -> Result<()> {
+    let start = Instant::now();
+    let mut flag = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    flag |= libc::LOCK_NB;
+    loop {
+        // Attempt to obtain an exclusive lock.
+        if unsafe { libc::flock(file.as_raw_fd(), flag) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err.into());
+        }
+
+        // If we timed out then return an error.
+        if let Some(timeout) = timeout {
+            if start.elapsed() > timeout {
+                return Err(Error::Timeout);
+            }
+        }
+
+        // Wait for a bit and try again.
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// funlock releases an advisory lock on a file descriptor.
+pub(crate) fn funlock(file: &File) -> Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// page_size returns the operating system page size.
+pub(crate) fn page_size() -> usize {
+    let sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if sz <= 0 {
+        4096
+    } else {
+        sz as usize
+    }
+}
+
+/// MadviseMode is the access pattern of the database mapping passed to the
+/// kernel with madvise(2) every time the data file is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MadviseMode {
+    /// Normal leaves the kernel's default read-ahead in place.
+    Normal,
+    /// Random disables read-ahead, which suits B+tree lookups that jump
+    /// between distant pages.
+    #[default]
+    Random,
+    /// WillNeed asks the kernel to read the whole mapping ahead of use, which
+    /// suits databases that fit in memory and are read all over.
+    WillNeed,
+}
+
+impl MadviseMode {
+    /// advice returns the madvise(2) advice of the mode.
+    pub(crate) fn advice(self) -> i32 {
+        match self {
+            MadviseMode::Normal => libc::MADV_NORMAL,
+            MadviseMode::Random => libc::MADV_RANDOM,
+            MadviseMode::WillNeed => libc::MADV_WILLNEED,
+        }
+    }
+}
+
+/// MADV_HUGEPAGE asks for the mapping to be backed by transparent huge pages.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const MADV_HUGEPAGE: Option<i32> = Some(libc::MADV_HUGEPAGE);
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) const MADV_HUGEPAGE: Option<i32> = None;
@@ -0,0 +1,175 @@
+use std::convert::TryFrom;
+
+use crate::db::{lock, DB};
+use crate::errors::{Error, Result};
+use crate::page::PGID_NO_FREELIST;
+
+/// UsageReport describes how the pages of a database file are used, for
+/// instance to decide whether it is worth compacting.
+///
+/// Page counts cover the pages below the high water mark; the file may be
+/// larger, since it grows ahead of use.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct UsageReport {
+    /// size of a page in bytes
+    pub page_size: usize,
+    /// size of the file on disk in bytes
+    pub file_size: u64,
+    /// number of pages below the high water mark
+    pub page_n: u64,
+    /// number of pages reachable from the meta pages: metas, freelist and
+    /// B+tree pages, overflow pages included
+    pub inuse_page_n: u64,
+    /// number of free pages, ready for reuse
+    pub free_page_n: u64,
+    /// number of pages freed by a transaction that are still in use by open
+    /// read transactions
+    pub pending_page_n: u64,
+    /// bytes holding B+tree data, page headers included
+    pub data_bytes: u64,
+    /// bytes left unused in the B+tree pages
+    pub fragmented_bytes: u64,
+    /// percentage of the pages below the high water mark holding B+tree data
+    pub utilization: f64,
+}
+
+impl DB {
+    /// Size returns the size of the database file on disk, in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.0.file.metadata()?.len())
+    }
+
+    /// PageCount returns the number of pages below the high water mark, as of
+    /// the last committed transaction.
+    pub fn page_count(&self) -> Result<u64> {
+        self.view_ret(|tx| Ok(tx.meta.get().pgid))
+    }
+
+    /// UsageReport walks every bucket of the database through a read-only
+    /// transaction and reports how its pages are used.
+    ///
+    /// Pages which are not reachable are free or pending. Telling them apart
+    /// requires the freelist of the writer, so on a database opened read-only
+    /// all of them are reported as free.
+    pub fn usage_report(&self) -> Result<UsageReport> {
+        let file_size = self.size()?;
+        self.view_ret(|tx| {
+            let page_size = tx.db.page_size;
+            let meta = tx.meta.get();
+            let freelist_page_n = if meta.freelist == PGID_NO_FREELIST {
+                0
+            } else {
+                1 + u64::from(tx.raw_page(meta.freelist)?.overflow())
+            };
+            let stats = tx.root().stats()?;
+            let tree_page_n = stats.branch_page_n
+                + stats.branch_overflow_n
+                + stats.leaf_page_n
+                + stats.leaf_overflow_n;
+            let inuse_page_n = 2 + freelist_page_n + tree_page_n as u64;
+            let unreachable = meta
+                .pgid
+                .checked_sub(inuse_page_n)
+                .ok_or_else(|| Error::corrupted(meta.pgid, "more pages in use than allocated"))?;
+
+            let pending_page_n = if tx.db.freelist_loaded() {
+                let pending = lock(&tx.db.freelist).pending_count();
+                u64::try_from(pending).unwrap_or(u64::MAX).min(unreachable)
+            } else {
+                0
+            };
+            let data_bytes = (stats.branch_inuse + stats.leaf_inuse) as u64;
+            let alloc = (stats.branch_alloc + stats.leaf_alloc) as u64;
+            let total = meta.pgid * page_size as u64;
+            Ok(UsageReport {
+                page_size,
+                file_size,
+                page_n: meta.pgid,
+                inuse_page_n,
+                free_page_n: unreachable - pending_page_n,
+                pending_page_n,
+                data_bytes,
+                fragmented_bytes: alloc - data_bytes,
+                utilization: if total == 0 {
+                    0.0
+                } else {
+                    data_bytes as f64 * 100.0 / total as f64
+                },
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Options, DB};
+    use crate::page::BUCKET_LEAF_FLAG;
+    use crate::tx_check::tests::{bucket_value, build, write_freelist, write_leaf, PS};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn hand_built() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = build(&path, 8, |buf| {
+            write_freelist(buf, 2, &[5, 6, 7]);
+            write_leaf(buf, 3, &[(BUCKET_LEAF_FLAG, b"widgets", &bucket_value(4))]);
+            write_leaf(buf, 4, &[(0, b"a", b"1"), (0, b"b", b"2")]);
+        });
+        assert_eq!(db.size().unwrap(), 8 * PS as u64);
+        assert_eq!(db.page_count().unwrap(), 8);
+
+        let report = db.usage_report().unwrap();
+        assert_eq!(report.page_size, PS);
+        assert_eq!(report.file_size, 8 * PS as u64);
+        assert_eq!(report.page_n, 8);
+        // Two metas, the freelist, the root leaf and the leaf of "widgets".
+        assert_eq!(report.inuse_page_n, 5);
+        assert_eq!(report.free_page_n, 3);
+        assert_eq!(report.pending_page_n, 0);
+        // Page headers and elements are 16 bytes each, plus keys and values.
+        let root = 16 + 16 + 7 + 16;
+        let widgets = 16 + 2 * 16 + 4;
+        assert_eq!(report.data_bytes, root + widgets);
+        assert_eq!(report.fragmented_bytes, 2 * PS as u64 - (root + widgets));
+        let utilization = (root + widgets) as f64 * 100.0 / (8 * PS) as f64;
+        assert!((report.utilization - utilization).abs() < 1e-9);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pending_and_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = DB::open(&path, Options::default()).unwrap();
+        db.update(|tx| tx.create_bucket(b"widgets")?.put(b"foo", &[1; 10_000]))
+            .unwrap();
+        db.update(|tx| tx.bucket(b"widgets")?.delete(b"foo"))
+            .unwrap();
+
+        // The pages freed by the last commit are pending until the next
+        // writable transaction begins.
+        let report = db.usage_report().unwrap();
+        assert!(report.pending_page_n > 0);
+        assert_eq!(
+            report.inuse_page_n + report.free_page_n + report.pending_page_n,
+            report.page_n
+        );
+        db.close().unwrap();
+
+        let db = DB::open(
+            &path,
+            Options {
+                read_only: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let ro = db.usage_report().unwrap();
+        assert_eq!(ro.pending_page_n, 0);
+        assert_eq!(ro.free_page_n, report.free_page_n + report.pending_page_n);
+        assert_eq!(ro.inuse_page_n, report.inuse_page_n);
+        assert_eq!(ro.data_bytes, report.data_bytes);
+    }
+}
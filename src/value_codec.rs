@@ -0,0 +1,276 @@
+use std::borrow::Cow;
+use std::fmt;
+
+/// ValueCodec transforms the values of a bucket between the form callers
+/// put and get and the form stored in the file, typically to compress them.
+///
+/// A codec is installed on a bucket with `Bucket::set_value_codec` and is
+/// not persisted: a bucket opened without it reads its values as stored.
+/// Keys are never transformed, so the order of a bucket is unaffected.
+pub trait ValueCodec: Send + Sync {
+    /// Encode returns the bytes to store for `value`.
+    fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]>;
+
+    /// Decode returns the value stored as `stored`. Values the codec does
+    /// not recognize, such as ones put before the codec was installed,
+    /// should be returned as they are.
+    fn decode<'a>(&self, stored: &'a [u8]) -> Cow<'a, [u8]>;
+}
+
+impl fmt::Debug for dyn ValueCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueCodec")
+    }
+}
+
+/// IdentityCodec stores values as they are. It behaves like a bucket with
+/// no codec installed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityCodec;
+
+impl ValueCodec for IdentityCodec {
+    fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Borrowed(value)
+    }
+
+    fn decode<'a>(&self, stored: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Borrowed(stored)
+    }
+}
+
+/// ZstdCodec compresses values with zstd at the given level, zero meaning
+/// zstd's default. Stored values that are not zstd frames are returned as
+/// they are, so a codec can be installed on a bucket that already holds
+/// uncompressed values.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdCodec {
+    /// Level is the zstd compression level.
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl ValueCodec for ZstdCodec {
+    fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        match zstd::encode_all(value, self.level) {
+            Ok(encoded) => Cow::Owned(encoded),
+            Err(_) => Cow::Borrowed(value),
+        }
+    }
+
+    fn decode<'a>(&self, stored: &'a [u8]) -> Cow<'a, [u8]> {
+        match zstd::decode_all(stored) {
+            Ok(decoded) => Cow::Owned(decoded),
+            Err(_) => Cow::Borrowed(stored),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::db::{Options, DB};
+    use crate::errors::Result;
+
+    /// Runs is a toy codec that stores a run of one byte as the byte and
+    /// the length of the run, and anything else as it is.
+    struct Runs;
+
+    impl ValueCodec for Runs {
+        fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+            match value.first() {
+                Some(&b) if value.len() > 6 && value.iter().all(|&c| c == b) => {
+                    let mut encoded = vec![b'*', b];
+                    encoded.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                    Cow::Owned(encoded)
+                }
+                _ => Cow::Borrowed(value),
+            }
+        }
+
+        fn decode<'a>(&self, stored: &'a [u8]) -> Cow<'a, [u8]> {
+            match stored {
+                [b'*', b, n @ ..] if n.len() == 4 => {
+                    let n = u32::from_be_bytes([n[0], n[1], n[2], n[3]]);
+                    Cow::Owned(vec![*b; n as usize])
+                }
+                _ => Cow::Borrowed(stored),
+            }
+        }
+    }
+
+    fn open(dir: &tempfile::TempDir) -> DB {
+        DB::open(dir.path().join("db"), Options::default()).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            assert!(b.value_codec().is_none());
+            b.set_value_codec(Arc::new(Runs));
+            b.put(b"foo", &[b'x'; 100])?;
+            b.put(b"bar", b"short")?;
+            b.put_sorted(vec![(b"qux".to_vec(), vec![b'y'; 50])].into_iter())?;
+            b.put_reserve(b"baz", 20)?.fill(b'z');
+            // The transaction that put the values reads them decoded.
+            assert_eq!(b.get(b"foo")?, Some(&[b'x'; 100][..]));
+            assert_eq!(b.get_owned(b"baz")?, Some(vec![b'z'; 20]));
+            Ok(())
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            // The codec is not persisted: without it, get returns the values
+            // as stored.
+            assert!(b.value_codec().is_none());
+            assert_eq!(b.get(b"foo")?, Some(&b"*x\0\0\0\x64"[..]));
+            assert_eq!(b.get(b"bar")?, Some(&b"short"[..]));
+
+            b.set_value_codec(Arc::new(Runs));
+            assert_eq!(b.get(b"foo")?, Some(&[b'x'; 100][..]));
+            assert_eq!(b.get(b"bar")?, Some(&b"short"[..]));
+            assert_eq!(b.get(b"baz")?, Some(&[b'z'; 20][..]));
+            assert_eq!(b.get(b"qux")?, Some(&[b'y'; 50][..]));
+            Ok(())
+        })
+        .unwrap();
+        db.close().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cursor_decodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.set_value_codec(Arc::new(Runs));
+            for i in 0..100u8 {
+                b.put(&[i], &[i; 10])?;
+            }
+            b.create_bucket(b"sub")?.put(b"foo", &[b'x'; 10])
+        })
+        .unwrap();
+
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            b.set_value_codec(Arc::new(Runs));
+
+            let mut c = b.cursor();
+            let (mut k, mut v) = c.first()?;
+            let mut n = 0;
+            while let Some(key) = k {
+                match key {
+                    b"sub" => assert_eq!(v, None),
+                    _ => {
+                        assert_eq!(v, Some(&[key[0]; 10][..]));
+                        n += 1;
+                    }
+                }
+                let next = c.next()?;
+                k = next.0;
+                v = next.1;
+            }
+            assert_eq!(n, 100);
+            assert_eq!(c.seek(&[42])?, (Some(&[42][..]), Some(&[42; 10][..])));
+            assert_eq!(c.current()?, Some((&[42][..], Some(&[42; 10][..]))));
+
+            let values = b
+                .range(&[10u8][..]..&[13u8][..])
+                .map(|item| item.map(|(_, v)| v))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(values, [&[10; 10][..], &[11; 10], &[12; 10]]);
+            assert_eq!(
+                b.iter_rev().next().transpose()?,
+                Some((&[99][..], &[99; 10][..]))
+            );
+
+            let mut n = 0;
+            b.for_each(|k, v| {
+                if let Some(v) = v {
+                    assert_eq!(v, &[k[0]; 10][..]);
+                    n += 1;
+                }
+                Ok(())
+            })?;
+            assert_eq!(n, 100);
+
+            // The nested bucket did not inherit the codec, so its value was
+            // stored as it is.
+            let sub = b.bucket(b"sub")?;
+            assert!(sub.value_codec().is_none());
+            assert_eq!(sub.get(b"foo")?, Some(&[b'x'; 10][..]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.set_value_codec(Arc::new(Runs));
+            b.put(b"foo", &[b'x'; 1000])?;
+            b.put(b"bar", b"abc")?;
+            b.create_bucket(b"sub")?.put(b"baz", &[b'x'; 10])
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let stats = b.stats()?;
+            assert_eq!(stats.value_bytes, 6 + 3 + 10);
+            assert_eq!(stats.logical_value_bytes, stats.value_bytes);
+
+            b.set_value_codec(Arc::new(IdentityCodec));
+            assert_eq!(b.stats()?.logical_value_bytes, stats.value_bytes);
+            b.set_value_codec(Arc::new(Runs));
+            let stats = b.stats()?;
+            assert_eq!(stats.value_bytes, 6 + 3 + 10);
+            assert_eq!(stats.logical_value_bytes, 1000 + 3 + 10);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    #[cfg_attr(miri, ignore)]
+    fn zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir);
+        let value = b"the quick brown fox jumps over the lazy dog ".repeat(100);
+        db.update(|tx| {
+            let b = tx.create_bucket(b"widgets")?;
+            b.put(b"plain", b"not compressed")?;
+            b.set_value_codec(Arc::new(ZstdCodec { level: 3 }));
+            b.put(b"fox", &value)
+        })
+        .unwrap();
+        db.view(|tx| {
+            let b = tx.bucket(b"widgets")?;
+            let stored = b.get(b"fox")?.unwrap();
+            assert!(stored.len() < value.len() / 10);
+            assert_eq!(zstd::decode_all(stored).unwrap(), value);
+
+            b.set_value_codec(Arc::new(ZstdCodec::default()));
+            assert_eq!(b.get(b"fox")?, Some(&value[..]));
+            // Values put without the codec read as they are.
+            assert_eq!(b.get(b"plain")?, Some(&b"not compressed"[..]));
+            let stats = b.stats()?;
+            assert_eq!(stats.logical_value_bytes, value.len() + 14);
+            assert!(stats.value_bytes < stats.logical_value_bytes / 10);
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * Exercises the C ABI from C: put, get and iterate. Run by the ffi unit
+ * tests, which pass the path of a fresh database file.
+ */
+#include <string.h>
+
+#include "blot.h"
+
+#define CHECK(cond)          \
+    do {                     \
+        if (!(cond))         \
+            return __LINE__; \
+    } while (0)
+
+static const uint8_t BUCKET[] = "widgets";
+#define BUCKET_LEN (sizeof(BUCKET) - 1)
+
+static int equal(const uint8_t *buf, size_t len, const char *want)
+{
+    return len == strlen(want) && memcmp(buf, want, len) == 0;
+}
+
+static int put(BlotDb *db, const char *key, const char *value)
+{
+    return blot_update_put(db, BUCKET, BUCKET_LEN, (const uint8_t *)key, strlen(key),
+                           (const uint8_t *)value, strlen(value));
+}
+
+static int check(BlotDb *db)
+{
+    static const char *const keys[] = {"bar", "baz", "foo"};
+    static const char *const values[] = {"2", "", "1"};
+    const uint8_t *key, *value;
+    size_t key_len, value_len, n;
+    uint8_t *got;
+    size_t got_len;
+    BlotCursor *cursor;
+    BlotStatus status;
+
+    CHECK(put(db, "foo", "1") == BLOT_STATUS_OK);
+    CHECK(put(db, "bar", "2") == BLOT_STATUS_OK);
+    CHECK(put(db, "baz", "") == BLOT_STATUS_OK);
+    CHECK(put(db, "", "x") == BLOT_STATUS_KEY_REQUIRED);
+
+    CHECK(blot_view_get(db, BUCKET, BUCKET_LEN, (const uint8_t *)"foo", 3, &got, &got_len) ==
+          BLOT_STATUS_OK);
+    CHECK(equal(got, got_len, "1"));
+    blot_free(got);
+    CHECK(blot_view_get(db, BUCKET, BUCKET_LEN, (const uint8_t *)"baz", 3, &got, &got_len) ==
+          BLOT_STATUS_OK);
+    CHECK(got != NULL && got_len == 0);
+    blot_free(got);
+    CHECK(blot_view_get(db, BUCKET, BUCKET_LEN, (const uint8_t *)"qux", 3, &got, &got_len) ==
+          BLOT_STATUS_NOT_FOUND);
+    CHECK(blot_view_get(db, (const uint8_t *)"gadgets", 7, (const uint8_t *)"foo", 3, &got,
+                        &got_len) == BLOT_STATUS_BUCKET_NOT_FOUND);
+    CHECK(strcmp(blot_status_message(BLOT_STATUS_BUCKET_NOT_FOUND), "bucket not found") == 0);
+
+    CHECK(blot_cursor_open(db, BUCKET, BUCKET_LEN, &cursor) == BLOT_STATUS_OK);
+    n = 0;
+    for (status = blot_cursor_first(cursor, &key, &key_len, &value, &value_len);
+         status == BLOT_STATUS_OK;
+         status = blot_cursor_next(cursor, &key, &key_len, &value, &value_len)) {
+        CHECK(n < 3);
+        CHECK(equal(key, key_len, keys[n]));
+        CHECK(equal(value, value_len, values[n]));
+        n++;
+    }
+    CHECK(status == BLOT_STATUS_NOT_FOUND);
+    CHECK(n == 3);
+
+    CHECK(blot_cursor_seek(cursor, (const uint8_t *)"bb", 2, &key, &key_len, &value,
+                           &value_len) == BLOT_STATUS_OK);
+    CHECK(equal(key, key_len, "foo"));
+    CHECK(blot_cursor_prev(cursor, &key, &key_len, &value, &value_len) == BLOT_STATUS_OK);
+    CHECK(equal(key, key_len, "baz"));
+    CHECK(blot_cursor_last(cursor, &key, &key_len, &value, &value_len) == BLOT_STATUS_OK);
+    CHECK(equal(key, key_len, "foo"));
+    CHECK(blot_cursor_close(cursor) == BLOT_STATUS_OK);
+    return 0;
+}
+
+int blot_ffi_smoke(const char *path)
+{
+    BlotDb *db;
+    uint8_t *got;
+    int line;
+
+    CHECK(blot_open(path, false, &db) == BLOT_STATUS_OK);
+    line = check(db);
+    CHECK(blot_close(db) == BLOT_STATUS_OK);
+    if (line != 0)
+        return line;
+
+    CHECK(blot_open(path, true, &db) == BLOT_STATUS_OK);
+    CHECK(put(db, "foo", "2") == BLOT_STATUS_DATABASE_READ_ONLY);
+    CHECK(blot_view_get(db, BUCKET, BUCKET_LEN, (const uint8_t *)"bar", 3, &got, NULL) ==
+          BLOT_STATUS_INVALID_ARGUMENT);
+    CHECK(blot_close(db) == BLOT_STATUS_OK);
+    return 0;
+}
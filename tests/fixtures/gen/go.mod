@@ -0,0 +1,5 @@
+module github.com/savechina/blot-rs/tests/fixtures/gen
+
+go 1.17
+
+require go.etcd.io/bbolt v1.3.7
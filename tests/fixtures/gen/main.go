@@ -0,0 +1,167 @@
+// Command gen writes the fixtures under tests/fixtures with bbolt, or checks
+// that existing files hold the expected entries.
+//
+//	go run . write <dir>
+//	go run . verify <file>...
+package main
+
+import (
+	"bytes"
+	"encoding/binary"
+	"fmt"
+	"log"
+	"os"
+	"path/filepath"
+
+	bolt "go.etcd.io/bbolt"
+)
+
+type fixture struct {
+	name     string
+	pageSize int
+	freelist bolt.FreelistType
+}
+
+var fixtures = []fixture{
+	{"bbolt-1024.db", 1024, bolt.FreelistArrayType},
+	{"bbolt-4096.db", 4096, bolt.FreelistArrayType},
+	{"bbolt-16384.db", 16384, bolt.FreelistArrayType},
+	{"bbolt-hashmap-4096.db", 4096, bolt.FreelistMapType},
+}
+
+func key(i uint32) []byte {
+	k := make([]byte, 4)
+	binary.BigEndian.PutUint32(k, i)
+	return k
+}
+
+func big() []byte {
+	v := make([]byte, 20000)
+	for i := range v {
+		v[i] = byte(i % 251)
+	}
+	return v
+}
+
+func write(dir string, f fixture) error {
+	path := filepath.Join(dir, f.name)
+	if err := os.Remove(path); err != nil && !os.IsNotExist(err) {
+		return err
+	}
+	db, err := bolt.Open(path, 0600, &bolt.Options{
+		PageSize:     f.pageSize,
+		FreelistType: f.freelist,
+	})
+	if err != nil {
+		return err
+	}
+	defer db.Close()
+
+	if err := db.Update(func(tx *bolt.Tx) error {
+		b, err := tx.CreateBucket([]byte("widgets"))
+		if err != nil {
+			return err
+		}
+		if err := b.SetSequence(7); err != nil {
+			return err
+		}
+		// Every third key is written and deleted again in a later
+		// transaction, leaving free pages behind.
+		for i := uint32(0); i < 100; i++ {
+			if err := b.Put(key(i), bytes.Repeat([]byte{byte(i)}, 50)); err != nil {
+				return err
+			}
+		}
+		inline, err := b.CreateBucket([]byte("inline"))
+		if err != nil {
+			return err
+		}
+		if err := inline.Put([]byte("k"), []byte("value")); err != nil {
+			return err
+		}
+		nested, err := b.CreateBucket([]byte("nested"))
+		if err != nil {
+			return err
+		}
+		if err := nested.Put([]byte("big"), big()); err != nil {
+			return err
+		}
+		deep, err := nested.CreateBucket([]byte("deep"))
+		if err != nil {
+			return err
+		}
+		return deep.Put([]byte("x"), []byte("y"))
+	}); err != nil {
+		return err
+	}
+	return db.Update(func(tx *bolt.Tx) error {
+		b := tx.Bucket([]byte("widgets"))
+		for i := uint32(0); i < 100; i += 3 {
+			if err := b.Delete(key(i)); err != nil {
+				return err
+			}
+		}
+		return nil
+	})
+}
+
+func verify(path string) error {
+	db, err := bolt.Open(path, 0600, &bolt.Options{ReadOnly: true})
+	if err != nil {
+		return err
+	}
+	defer db.Close()
+
+	return db.View(func(tx *bolt.Tx) error {
+		for err := range tx.Check() {
+			return err
+		}
+		b := tx.Bucket([]byte("widgets"))
+		if b == nil || b.Sequence() != 7 {
+			return fmt.Errorf("widgets missing or wrong sequence")
+		}
+		for i := uint32(0); i < 100; i++ {
+			v := b.Get(key(i))
+			if i%3 == 0 {
+				if v != nil {
+					return fmt.Errorf("key %d not deleted", i)
+				}
+			} else if !bytes.Equal(v, bytes.Repeat([]byte{byte(i)}, 50)) {
+				return fmt.Errorf("key %d: wrong value", i)
+			}
+		}
+		if v := b.Bucket([]byte("inline")).Get([]byte("k")); string(v) != "value" {
+			return fmt.Errorf("inline/k: %q", v)
+		}
+		nested := b.Bucket([]byte("nested"))
+		if !bytes.Equal(nested.Get([]byte("big")), big()) {
+			return fmt.Errorf("nested/big: wrong value")
+		}
+		if v := nested.Bucket([]byte("deep")).Get([]byte("x")); string(v) != "y" {
+			return fmt.Errorf("nested/deep/x: %q", v)
+		}
+		return nil
+	})
+}
+
+func main() {
+	if len(os.Args) < 3 {
+		log.Fatal("usage: gen write <dir> | gen verify <file>...")
+	}
+	switch os.Args[1] {
+	case "write":
+		for _, f := range fixtures {
+			if err := write(os.Args[2], f); err != nil {
+				log.Fatalf("%s: %v", f.name, err)
+			}
+		}
+	case "verify":
+		for _, path := range os.Args[2:] {
+			if err := verify(path); err != nil {
+				log.Fatalf("%s: %v", path, err)
+			}
+		}
+	default:
+		log.Fatalf("unknown command %q", os.Args[1])
+	}
+}